@@ -69,6 +69,9 @@ class Opcode(IntEnum):
     VLOAD = 0x34
     VSTORE = 0x35
     VBROADCAST = 0x36
+    BRK = 0x37
+    EI = 0x38
+    DI = 0x39
 
 class InstructionFormat(IntEnum):
     """Instruction encoding formats"""
@@ -127,6 +130,9 @@ class Assembler:
             'SYSCALL': (Opcode.SYSCALL, InstructionFormat.J_TYPE),
             'HALT': (Opcode.HALT, InstructionFormat.J_TYPE),
             'NOP': (Opcode.NOP, InstructionFormat.J_TYPE),
+            'BRK': (Opcode.BRK, InstructionFormat.J_TYPE),
+            'EI': (Opcode.EI, InstructionFormat.J_TYPE),
+            'DI': (Opcode.DI, InstructionFormat.J_TYPE),
             
             # V-type instructions
             'VADD.F64': (Opcode.VADD_F64, InstructionFormat.V_TYPE),
@@ -338,7 +344,7 @@ class Assembler:
         if opcode == Opcode.RET:
             # RET has no operands
             offset = 0
-        elif opcode in [Opcode.HALT, Opcode.NOP]:
+        elif opcode in [Opcode.HALT, Opcode.NOP, Opcode.BRK, Opcode.EI, Opcode.DI]:
             # These have no operands
             offset = 0
         elif opcode == Opcode.SYSCALL:
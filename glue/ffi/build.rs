@@ -0,0 +1,6 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // Reported by `nanocore_build_info`
+    println!("cargo:rustc-env=NANOCORE_TARGET={}", std::env::var("TARGET").unwrap());
+}
@@ -3,11 +3,22 @@
  * Provides a stable C API over the assembly VM core
  */
 
+#include <stdarg.h>
 #include <stdint.h>
 #include <stdlib.h>
 #include <string.h>
 #include <stdio.h>
 #include <stdbool.h>
+#include <errno.h>
+#include <time.h>
+#ifdef _WIN32
+#include <windows.h>
+#else
+#include <fcntl.h>
+#include <sys/mman.h>
+#include <sys/stat.h>
+#include <unistd.h>
+#endif
 
 // VM state structure (matches assembly layout)
 typedef struct {
@@ -26,12 +37,53 @@ typedef struct {
     vm_state_t state;
     uint8_t* memory;
     size_t memory_size;
+    bool file_backed;           // memory is a shared mapping of a file
+    void (*release)(void*);     // frees memory supplied by the embedder, if set
+    void* release_context;      // argument passed to release
     bool halted;
+    int32_t exit_code;          // R1 captured when HALT executes
+    int guest_break_mode;       // GUEST_BREAK_STOP or GUEST_BREAK_NOP
+    bool guest_break;           // Last step stopped on a guest BRK
+    uint64_t guest_break_pc;    // Address of that BRK
     uint64_t breakpoints[64];  // Simple breakpoint array
     int num_breakpoints;
     int vm_id;
+    uint64_t* dirty;            // One bit per page written since the last take
 } vm_instance_t;
 
+// Granularity of dirty page tracking
+#define DIRTY_PAGE_SHIFT 12
+
+// Words in the dirty bitmap of a VM with `memory_size` bytes of memory
+static uint64_t dirty_words(uint64_t memory_size) {
+    uint64_t pages = (memory_size + (1ull << DIRTY_PAGE_SHIFT) - 1) >> DIRTY_PAGE_SHIFT;
+    return (pages + 63) / 64;
+}
+
+// Record that `size` bytes at `address` were written
+static void mark_dirty(vm_instance_t* vm, uint64_t address, uint64_t size) {
+    if (size == 0) {
+        return;
+    }
+    uint64_t last = (address + size - 1) >> DIRTY_PAGE_SHIFT;
+    for (uint64_t page = address >> DIRTY_PAGE_SHIFT; page <= last; page++) {
+        vm->dirty[page / 64] |= 1ull << (page % 64);
+    }
+}
+
+// Register holding the guest exit code at HALT
+#define EXIT_CODE_REGISTER 1
+
+// Guest breakpoint instruction and what executing it does
+#define OP_BRK 0x37
+#define GUEST_BREAK_STOP 0
+#define GUEST_BREAK_NOP 1
+
+// Guest instructions that set and clear FLAGS.IE
+#define OP_EI 0x38
+#define OP_DI 0x39
+#define FLAG_INTERRUPT_ENABLE 0x10
+
 // Global VM instances (simple management)
 static vm_instance_t* vms[256] = {0};
 static int next_vm_id = 1;
@@ -53,18 +105,43 @@ enum {
     EVENT_DEVICE_INTERRUPT = 3
 };
 
+#ifdef _MSC_VER
+#define THREAD_LOCAL __declspec(thread)
+#else
+#define THREAD_LOCAL _Thread_local
+#endif
+
+// Why the last failed call on this thread failed, for nanocore_last_error_message
+static THREAD_LOCAL char last_error[256];
+
+// Record the reason for a failure; returns `code` so callers can `return fail(...)`
+static int fail(int code, const char* format, ...) {
+    va_list args;
+    va_start(args, format);
+    vsnprintf(last_error, sizeof(last_error), format, args);
+    va_end(args);
+    return code;
+}
+
+// Copy why the last failed VM creation on this thread failed into `buf`,
+// NUL-terminated and truncated to `len`; returns the full message length, 0 when
+// nothing has failed yet. Like errno, success does not clear it.
+int nanocore_last_error_message(char* buf, size_t len) {
+    size_t length = strlen(last_error);
+    if (buf && len > 0) {
+        snprintf(buf, len, "%s", last_error);
+    }
+    return (int)length;
+}
+
 // Initialize the NanoCore library
 int nanocore_init(void) {
     // Initialize any global state
     return NANOCORE_OK;
 }
 
-// Create a new VM instance
-int nanocore_vm_create(uint64_t memory_size, int* vm_handle) {
-    if (!vm_handle || memory_size == 0) {
-        return NANOCORE_EINVAL;
-    }
-    
+// Register a VM instance running out of `memory`, which it then owns
+static int create_instance(uint8_t* memory, uint64_t memory_size, bool file_backed, int* vm_handle) {
     // Find free slot
     int id = -1;
     for (int i = 0; i < 256; i++) {
@@ -75,29 +152,28 @@ int nanocore_vm_create(uint64_t memory_size, int* vm_handle) {
     }
     
     if (id == -1) {
-        return NANOCORE_ERROR;  // Too many VMs
+        return fail(NANOCORE_ERROR, "all 256 VM slots are in use");
     }
     
     // Allocate VM instance
     vm_instance_t* vm = calloc(1, sizeof(vm_instance_t));
-    if (!vm) {
-        return NANOCORE_ENOMEM;
-    }
-    
-    // Allocate memory
-    vm->memory = calloc(memory_size, 1);
-    if (!vm->memory) {
+    uint64_t* dirty = calloc(dirty_words(memory_size), sizeof(uint64_t));
+    if (!vm || !dirty) {
         free(vm);
-        return NANOCORE_ENOMEM;
+        free(dirty);
+        return fail(NANOCORE_ENOMEM, "out of memory allocating VM state");
     }
     
     // Initialize VM
+    vm->memory = memory;
     vm->memory_size = memory_size;
+    vm->file_backed = file_backed;
     vm->state.sp = memory_size - 8;  // Stack at top
     vm->state.pc = 0x10000;          // Default entry point
     vm->vm_id = next_vm_id++;
     vm->halted = false;
     vm->num_breakpoints = 0;
+    vm->dirty = dirty;
     
     vms[id] = vm;
     *vm_handle = id;
@@ -105,6 +181,110 @@ int nanocore_vm_create(uint64_t memory_size, int* vm_handle) {
     return NANOCORE_OK;
 }
 
+// Create a new VM instance
+int nanocore_vm_create(uint64_t memory_size, int* vm_handle) {
+    if (!vm_handle) {
+        return fail(NANOCORE_EINVAL, "no handle out-parameter");
+    }
+    if (memory_size == 0) {
+        return fail(NANOCORE_EINVAL, "memory size must be nonzero");
+    }
+    
+    // Allocate memory
+    uint8_t* memory = calloc(memory_size, 1);
+    if (!memory) {
+        return fail(NANOCORE_ENOMEM, "cannot allocate %llu bytes of guest memory",
+                    (unsigned long long)memory_size);
+    }
+    
+    int result = create_instance(memory, memory_size, false, vm_handle);
+    if (result != NANOCORE_OK) {
+        free(memory);
+    }
+    return result;
+}
+
+// Create a VM running out of `memory`, allocated by the embedder
+// On success the VM owns the memory and calls release(context) when it is
+// destroyed; on failure the caller keeps it. The memory should be zeroed.
+int nanocore_vm_create_with_memory(uint8_t* memory, uint64_t memory_size,
+                                   void (*release)(void*), void* context, int* vm_handle) {
+    if (!memory || !release || !vm_handle) {
+        return fail(NANOCORE_EINVAL, "no memory, release function or handle out-parameter");
+    }
+    if (memory_size == 0) {
+        return fail(NANOCORE_EINVAL, "memory size must be nonzero");
+    }
+    
+    int result = create_instance(memory, memory_size, false, vm_handle);
+    if (result == NANOCORE_OK) {
+        vms[*vm_handle]->release = release;
+        vms[*vm_handle]->release_context = context;
+    }
+    return result;
+}
+
+// Create a VM whose memory is a shared mapping of the file at `path`
+// The file is created if missing and extended to memory_size if shorter;
+// guest writes reach the file and are synced when the VM is destroyed
+int nanocore_vm_create_file(const char* path, uint64_t memory_size, int* vm_handle) {
+    if (!path || !vm_handle) {
+        return fail(NANOCORE_EINVAL, "no path or handle out-parameter");
+    }
+    if (memory_size == 0) {
+        return fail(NANOCORE_EINVAL, "memory size must be nonzero");
+    }
+    
+#ifdef _WIN32
+    // Not supported by the reference interpreter
+    return fail(NANOCORE_ERROR, "file-backed memory is not supported on Windows");
+#else
+    int fd = open(path, O_RDWR | O_CREAT, 0644);
+    if (fd < 0) {
+        return fail(NANOCORE_EINVAL, "cannot open %s: %s", path, strerror(errno));
+    }
+    
+    struct stat info;
+    if (fstat(fd, &info) != 0 ||
+        ((uint64_t)info.st_size < memory_size && ftruncate(fd, (off_t)memory_size) != 0)) {
+        int error = errno;
+        close(fd);
+        return fail(NANOCORE_EINVAL, "cannot size %s to %llu bytes: %s", path,
+                    (unsigned long long)memory_size, strerror(error));
+    }
+    
+    uint8_t* memory = mmap(NULL, memory_size, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+    int error = errno;
+    close(fd);
+    if (memory == MAP_FAILED) {
+        return fail(NANOCORE_ENOMEM, "cannot map %llu bytes of guest memory: %s",
+                    (unsigned long long)memory_size, strerror(error));
+    }
+    
+    int result = create_instance(memory, memory_size, true, vm_handle);
+    if (result != NANOCORE_OK) {
+        munmap(memory, memory_size);
+    }
+    return result;
+#endif
+}
+
+// Release a VM's memory, writing file-backed memory back first
+static void release_memory(vm_instance_t* vm) {
+    if (vm->release) {
+        vm->release(vm->release_context);
+        return;
+    }
+#ifndef _WIN32
+    if (vm->file_backed) {
+        msync(vm->memory, vm->memory_size, MS_SYNC);
+        munmap(vm->memory, vm->memory_size);
+        return;
+    }
+#endif
+    free(vm->memory);
+}
+
 // Destroy VM instance
 int nanocore_vm_destroy(int vm_handle) {
     if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle]) {
@@ -112,7 +292,8 @@ int nanocore_vm_destroy(int vm_handle) {
     }
     
     vm_instance_t* vm = vms[vm_handle];
-    free(vm->memory);
+    release_memory(vm);
+    free(vm->dirty);
     free(vm);
     vms[vm_handle] = NULL;
     
@@ -134,11 +315,77 @@ int nanocore_vm_reset(int vm_handle) {
     vm->state.sp = vm->memory_size - 8;
     vm->state.pc = 0x10000;
     vm->halted = false;
+    vm->exit_code = 0;
     vm->num_breakpoints = 0;
+    vm->guest_break = false;
+    
+    return NANOCORE_OK;
+}
+
+// Choose whether a guest BRK stops execution or runs as a NOP
+int nanocore_vm_set_guest_break_mode(int vm_handle, int mode) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] ||
+        (mode != GUEST_BREAK_STOP && mode != GUEST_BREAK_NOP)) {
+        return NANOCORE_EINVAL;
+    }
+    
+    vms[vm_handle]->guest_break_mode = mode;
+    return NANOCORE_OK;
+}
+
+// Report the address of the BRK if the last step stopped on one
+int nanocore_vm_get_guest_break(int vm_handle, uint64_t* address) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] || !address) {
+        return NANOCORE_EINVAL;
+    }
+    
+    vm_instance_t* vm = vms[vm_handle];
+    
+    if (!vm->guest_break) {
+        return NANOCORE_ERROR;
+    }
+    
+    *address = vm->guest_break_pc;
+    return NANOCORE_OK;
+}
+
+// Get the exit code reported by the last HALT
+int nanocore_vm_get_exit_code(int vm_handle, int* exit_code) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] || !exit_code) {
+        return NANOCORE_EINVAL;
+    }
+    
+    vm_instance_t* vm = vms[vm_handle];
+    
+    if (!(vm->state.flags & 0x80)) {
+        return NANOCORE_ERROR;  // Not halted
+    }
     
+    *exit_code = vm->exit_code;
     return NANOCORE_OK;
 }
 
+// Little-endian data access helpers
+static uint64_t load_le(const uint8_t* p, int width) {
+    uint64_t value = 0;
+    for (int i = width - 1; i >= 0; i--) {
+        value = (value << 8) | p[i];
+    }
+    return value;
+}
+
+static void store_le(uint8_t* p, uint64_t value, int width) {
+    for (int i = 0; i < width; i++) {
+        p[i] = (uint8_t)(value >> (8 * i));
+    }
+}
+
+// Sign extend the low `width` bytes of a value
+static uint64_t sign_extend(uint64_t value, int width) {
+    int shift = 64 - 8 * width;
+    return (uint64_t)((int64_t)(value << shift) >> shift);
+}
+
 // Simple instruction decoder and executor
 static int execute_instruction(vm_instance_t* vm, uint32_t instruction) {
     uint8_t opcode = (instruction >> 26) & 0x3F;
@@ -217,12 +464,31 @@ static int execute_instruction(vm_instance_t* vm, uint32_t instruction) {
             }
             break;
             
-        case 0x13:  // ST (simplified)
+        case 0x10:  // LW
+        case 0x11:  // LH
+        case 0x12:  // LB
+            {
+                int width = opcode == 0x10 ? 4 : (opcode == 0x11 ? 2 : 1);
+                uint64_t addr = vm->state.gprs[rs1] + imm;
+                if ((uint64_t)width <= vm->memory_size && addr <= vm->memory_size - width && rd != 0) {
+                    vm->state.gprs[rd] = sign_extend(load_le(vm->memory + addr, width), width);
+                }
+                vm->state.perf_counters[6]++;  // Memory operations
+            }
+            break;
+            
+        case 0x13:  // ST
+        case 0x14:  // SW
+        case 0x15:  // SH
+        case 0x16:  // SB
             {
+                int width = 8 >> (opcode - 0x13);
                 uint64_t addr = vm->state.gprs[rs1] + imm;
-                if (addr + 8 <= vm->memory_size) {
-                    *(uint64_t*)(vm->memory + addr) = vm->state.gprs[rd];
+                if ((uint64_t)width <= vm->memory_size && addr <= vm->memory_size - width) {
+                    store_le(vm->memory + addr, vm->state.gprs[rd], width);
+                    mark_dirty(vm, addr, width);
                 }
+                vm->state.perf_counters[6]++;  // Memory operations
             }
             break;
             
@@ -244,14 +510,52 @@ static int execute_instruction(vm_instance_t* vm, uint32_t instruction) {
             }
             break;
             
+        case 0x1D:  // JMP rd, imm(rs1): jump and link
+            {
+                uint64_t target = vm->state.gprs[rs1] + imm;
+                if (rd != 0) {
+                    vm->state.gprs[rd] = vm->state.pc;
+                }
+                vm->state.pc = target;
+            }
+            break;
+            
+        case 0x1E:  // CALL: return address to R31, PC-relative word offset
+            {
+                int64_t offset = (int32_t)(instruction << 6) >> 6;
+                vm->state.gprs[31] = vm->state.pc;
+                vm->state.pc += offset * 4 - 4;
+            }
+            break;
+            
+        case 0x1F:  // RET
+            vm->state.pc = vm->state.gprs[31];
+            break;
+            
         case 0x21:  // HALT
             vm->halted = true;
+            vm->exit_code = (int32_t)vm->state.gprs[EXIT_CODE_REGISTER];
             vm->state.flags |= 0x80;
-            return EVENT_HALTED;
+            break;  // HALT retires like any other instruction
             
         case 0x22:  // NOP
             break;
             
+        case OP_BRK:  // BRK
+            if (vm->guest_break_mode == GUEST_BREAK_STOP) {
+                vm->guest_break = true;
+                vm->guest_break_pc = vm->state.pc - 4;
+            }
+            break;
+            
+        case OP_EI:  // EI
+            vm->state.flags |= FLAG_INTERRUPT_ENABLE;
+            break;
+            
+        case OP_DI:  // DI
+            vm->state.flags &= ~(uint64_t)FLAG_INTERRUPT_ENABLE;
+            break;
+            
         default:
             // Unknown instruction
             vm->halted = true;
@@ -262,7 +566,8 @@ static int execute_instruction(vm_instance_t* vm, uint32_t instruction) {
     vm->state.perf_counters[0]++;  // Instruction count
     vm->state.perf_counters[1]++;  // Cycle count
     
-    return NANOCORE_OK;
+    // A guest BRK retires, then stops like a breakpoint
+    return vm->guest_break ? EVENT_BREAKPOINT : NANOCORE_OK;
 }
 
 // Execute single instruction
@@ -272,13 +577,14 @@ int nanocore_vm_step(int vm_handle) {
     }
     
     vm_instance_t* vm = vms[vm_handle];
+    vm->guest_break = false;
     
     if (vm->halted) {
         return EVENT_HALTED;
     }
     
     // Check bounds
-    if (vm->state.pc + 4 > vm->memory_size) {
+    if (vm->memory_size < 4 || vm->state.pc > vm->memory_size - 4) {
         vm->halted = true;
         return NANOCORE_ERROR;
     }
@@ -290,8 +596,10 @@ int nanocore_vm_step(int vm_handle) {
         }
     }
     
-    // Fetch instruction
-    uint32_t instruction = *(uint32_t*)(vm->memory + vm->state.pc);
+    // Fetch instruction (stored most-significant byte first)
+    const uint8_t* code = vm->memory + vm->state.pc;
+    uint32_t instruction = ((uint32_t)code[0] << 24) | ((uint32_t)code[1] << 16) |
+                           ((uint32_t)code[2] << 8) | (uint32_t)code[3];
     
     // Execute
     vm->state.pc += 4;
@@ -328,6 +636,24 @@ int nanocore_vm_get_state(int vm_handle, vm_state_t* state) {
     return NANOCORE_OK;
 }
 
+// Replace VM state
+int nanocore_vm_set_state(int vm_handle, const vm_state_t* state) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] || !state) {
+        return NANOCORE_EINVAL;
+    }
+    
+    vm_instance_t* vm = vms[vm_handle];
+    bool was_halted = vm->state.flags & 0x80;
+    vm->state = *state;
+    vm->state.gprs[0] = 0;  // R0 is hardwired to zero
+    vm->halted = (vm->state.flags & 0x80) != 0;
+    if (vm->halted && !was_halted) {
+        // Halting from the host reports an exit code just like HALT
+        vm->exit_code = (int32_t)vm->state.gprs[EXIT_CODE_REGISTER];
+    }
+    return NANOCORE_OK;
+}
+
 // Get register value
 int nanocore_vm_get_register(int vm_handle, int reg_index, uint64_t* value) {
     if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] || 
@@ -353,6 +679,48 @@ int nanocore_vm_set_register(int vm_handle, int reg_index, uint64_t value) {
     return NANOCORE_OK;
 }
 
+// Get all 32 general purpose registers
+int nanocore_vm_get_registers(int vm_handle, uint64_t* values) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] || !values) {
+        return NANOCORE_EINVAL;
+    }
+
+    memcpy(values, vms[vm_handle]->state.gprs, sizeof(uint64_t) * 32);
+    return NANOCORE_OK;
+}
+
+// Set all 32 general purpose registers; the value for R0 is ignored
+int nanocore_vm_set_registers(int vm_handle, const uint64_t* values) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] || !values) {
+        return NANOCORE_EINVAL;
+    }
+
+    memcpy(&vms[vm_handle]->state.gprs[1], &values[1], sizeof(uint64_t) * 31);
+    return NANOCORE_OK;
+}
+
+// Get vector register lanes
+int nanocore_vm_get_vreg(int vm_handle, int vreg_index, uint64_t* lanes) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] ||
+        vreg_index < 0 || vreg_index >= 16 || !lanes) {
+        return NANOCORE_EINVAL;
+    }
+
+    memcpy(lanes, vms[vm_handle]->state.vregs[vreg_index], sizeof(uint64_t) * 4);
+    return NANOCORE_OK;
+}
+
+// Set vector register lanes
+int nanocore_vm_set_vreg(int vm_handle, int vreg_index, const uint64_t* lanes) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] ||
+        vreg_index < 0 || vreg_index >= 16 || !lanes) {
+        return NANOCORE_EINVAL;
+    }
+
+    memcpy(vms[vm_handle]->state.vregs[vreg_index], lanes, sizeof(uint64_t) * 4);
+    return NANOCORE_OK;
+}
+
 // Load program into memory
 int nanocore_vm_load_program(int vm_handle, const uint8_t* data, uint64_t size, uint64_t address) {
     if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] || !data) {
@@ -361,11 +729,12 @@ int nanocore_vm_load_program(int vm_handle, const uint8_t* data, uint64_t size,
     
     vm_instance_t* vm = vms[vm_handle];
     
-    if (address + size > vm->memory_size) {
+    if (size > vm->memory_size || address > vm->memory_size - size) {
         return NANOCORE_EINVAL;
     }
     
     memcpy(vm->memory + address, data, size);
+    mark_dirty(vm, address, size);
     vm->state.pc = address;  // Set PC to start of program
     
     return NANOCORE_OK;
@@ -379,7 +748,7 @@ int nanocore_vm_read_memory(int vm_handle, uint64_t address, uint8_t* buffer, ui
     
     vm_instance_t* vm = vms[vm_handle];
     
-    if (address + size > vm->memory_size) {
+    if (size > vm->memory_size || address > vm->memory_size - size) {
         return NANOCORE_EINVAL;
     }
     
@@ -395,11 +764,144 @@ int nanocore_vm_write_memory(int vm_handle, uint64_t address, const uint8_t* dat
     
     vm_instance_t* vm = vms[vm_handle];
     
-    if (address + size > vm->memory_size) {
+    if (size > vm->memory_size || address > vm->memory_size - size) {
         return NANOCORE_EINVAL;
     }
     
     memcpy(vm->memory + address, data, size);
+    mark_dirty(vm, address, size);
+    return NANOCORE_OK;
+}
+
+// Set `size` bytes starting at `address` to `value`
+int nanocore_vm_fill_memory(int vm_handle, uint64_t address, uint8_t value, uint64_t size) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle]) {
+        return NANOCORE_EINVAL;
+    }
+    
+    vm_instance_t* vm = vms[vm_handle];
+    
+    if (size > vm->memory_size || address > vm->memory_size - size) {
+        return NANOCORE_EINVAL;
+    }
+    
+    memset(vm->memory + address, value, size);
+    mark_dirty(vm, address, size);
+    return NANOCORE_OK;
+}
+
+// Copy `size` bytes from `src` to `dst` within guest memory; the ranges may overlap
+int nanocore_vm_copy_memory(int vm_handle, uint64_t dst, uint64_t src, uint64_t size) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle]) {
+        return NANOCORE_EINVAL;
+    }
+    
+    vm_instance_t* vm = vms[vm_handle];
+    
+    if (size > vm->memory_size || dst > vm->memory_size - size || src > vm->memory_size - size) {
+        return NANOCORE_EINVAL;
+    }
+    
+    memmove(vm->memory + dst, vm->memory + src, size);
+    mark_dirty(vm, dst, size);
+    return NANOCORE_OK;
+}
+
+// Borrow guest memory in place; valid until the VM is destroyed or its image replaced
+int nanocore_vm_get_memory(int vm_handle, const uint8_t** data, uint64_t* size) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] || !data || !size) {
+        return NANOCORE_EINVAL;
+    }
+    
+    *data = vms[vm_handle]->memory;
+    *size = vms[vm_handle]->memory_size;
+    return NANOCORE_OK;
+}
+
+// Replace the whole memory image (size must equal the memory size)
+int nanocore_vm_load_memory_image(int vm_handle, const uint8_t* image, uint64_t size) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] || !image) {
+        return NANOCORE_EINVAL;
+    }
+    
+    vm_instance_t* vm = vms[vm_handle];
+    
+    if (size != vm->memory_size) {
+        return NANOCORE_EINVAL;
+    }
+    
+    memcpy(vm->memory, image, size);
+    mark_dirty(vm, 0, size);
+    return NANOCORE_OK;
+}
+
+// Copy out which pages were written since the last call and start over;
+// `words` must be the bitmap length, one bit per 4 KiB page
+int nanocore_vm_take_dirty_pages(int vm_handle, uint64_t* bitmap, uint64_t words) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] || !bitmap) {
+        return NANOCORE_EINVAL;
+    }
+    
+    vm_instance_t* vm = vms[vm_handle];
+    
+    if (words != dirty_words(vm->memory_size)) {
+        return NANOCORE_EINVAL;
+    }
+    
+    memcpy(bitmap, vm->dirty, words * sizeof(uint64_t));
+    memset(vm->dirty, 0, words * sizeof(uint64_t));
+    return NANOCORE_OK;
+}
+
+// Copy out the whole memory image (size must equal the memory size)
+int nanocore_vm_save_memory_image(int vm_handle, uint8_t* buffer, uint64_t size) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] || !buffer) {
+        return NANOCORE_EINVAL;
+    }
+    
+    vm_instance_t* vm = vms[vm_handle];
+    
+    if (size != vm->memory_size) {
+        return NANOCORE_EINVAL;
+    }
+    
+    memcpy(buffer, vm->memory, size);
+    return NANOCORE_OK;
+}
+
+int nanocore_vm_flush_range(int vm_handle, uint64_t address, uint64_t size);
+
+// Flush memory to its backing store (heap memory has none, so this only validates)
+int nanocore_vm_flush_memory(int vm_handle) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle]) {
+        return NANOCORE_EINVAL;
+    }
+    
+    return nanocore_vm_flush_range(vm_handle, 0, vms[vm_handle]->memory_size);
+}
+
+// Flush part of memory to its backing store
+int nanocore_vm_flush_range(int vm_handle, uint64_t address, uint64_t size) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle]) {
+        return NANOCORE_EINVAL;
+    }
+    
+    vm_instance_t* vm = vms[vm_handle];
+    if (size > vm->memory_size || address > vm->memory_size - size) {
+        return NANOCORE_EINVAL;
+    }
+    
+#ifndef _WIN32
+    if (vm->file_backed && size > 0) {
+        // msync needs a page-aligned start
+        uint64_t page = (uint64_t)sysconf(_SC_PAGESIZE);
+        uint64_t start = address & ~(page - 1);
+        if (msync(vm->memory + start, address + size - start, MS_SYNC) != 0) {
+            return NANOCORE_ERROR;
+        }
+    }
+#endif
+    
     return NANOCORE_OK;
 }
 
@@ -452,6 +954,16 @@ int nanocore_vm_get_perf_counter(int vm_handle, int counter_index, uint64_t* val
     return NANOCORE_OK;
 }
 
+// Zero all performance counters
+int nanocore_vm_reset_perf_counters(int vm_handle) {
+    if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle]) {
+        return NANOCORE_EINVAL;
+    }
+    
+    memset(vms[vm_handle]->state.perf_counters, 0, sizeof(vms[vm_handle]->state.perf_counters));
+    return NANOCORE_OK;
+}
+
 // Poll for events (simplified)
 int nanocore_vm_poll_event(int vm_handle, int* event_type, uint64_t* event_data) {
     if (vm_handle < 0 || vm_handle >= 256 || !vms[vm_handle] || !event_type || !event_data) {
@@ -462,10 +974,36 @@ int nanocore_vm_poll_event(int vm_handle, int* event_type, uint64_t* event_data)
     
     if (vm->halted) {
         *event_type = EVENT_HALTED;
-        *event_data = 0;
+        *event_data = (uint64_t)(int64_t)vm->exit_code;
         return NANOCORE_OK;
     }
     
     // No events pending
     return NANOCORE_ERROR;
-}
\ No newline at end of file
+}
+
+// Sleep for about one millisecond between event polls
+static void wait_tick(void) {
+#ifdef _WIN32
+    Sleep(1);
+#else
+    struct timespec tick = { 0, 1000000 };
+    nanosleep(&tick, NULL);
+#endif
+}
+
+// Wait for an event; timeout_ms of 0 polls, UINT64_MAX waits forever
+int nanocore_vm_wait_event(int vm_handle, uint64_t timeout_ms, int* event_type, uint64_t* event_data) {
+    uint64_t waited_ms = 0;
+
+    for (;;) {
+        int result = nanocore_vm_poll_event(vm_handle, event_type, event_data);
+        if (result != NANOCORE_ERROR || waited_ms >= timeout_ms) {
+            return result;
+        }
+        wait_tick();
+        if (timeout_ms != UINT64_MAX) {
+            waited_ms++;
+        }
+    }
+}
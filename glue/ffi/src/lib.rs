@@ -3,15 +3,20 @@
 //! Provides safe, zero-copy bindings to the NanoCore VM for use from
 //! higher-level languages like Python, JavaScript, and others.
 
+use std::cell::RefCell;
 use std::ffi::{c_void, CStr, CString};
+use std::fs::{File, OpenOptions};
 use std::os::raw::{c_char, c_int, c_ulonglong};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
 use std::ptr;
 use std::slice;
-use std::sync::Arc;
+use std::sync::{Arc, Once};
+use std::time::Duration;
 
 use bitflags::bitflags;
 use crossbeam_channel::{bounded, Receiver, Sender};
-use memmap2::{Mmap, MmapMut};
+use memmap2::{Mmap, MmapMut, MmapOptions};
 use once_cell::sync::Lazy;
 use parking_lot::{Mutex, RwLock};
 
@@ -24,6 +29,9 @@ mod vm;
 mod memory;
 mod devices;
 mod perf;
+mod registry;
+
+use registry::Registry;
 
 /// Result type for FFI operations
 pub type NanoResult = c_int;
@@ -39,6 +47,27 @@ pub const NANO_EINVAL: NanoResult = -3;
 /// Not initialized
 pub const NANO_EINIT: NanoResult = -4;
 
+/// Log levels passed to a `NanoLogCallback`
+pub const NANO_LOG_ERROR: c_int = 0;
+pub const NANO_LOG_WARN: c_int = 1;
+pub const NANO_LOG_INFO: c_int = 2;
+pub const NANO_LOG_DEBUG: c_int = 3;
+
+/// Host function receiving diagnostics; `msg` is only valid during the call
+pub type NanoLogCallback = extern "C" fn(level: c_int, msg: *const c_char);
+
+/// Register holding the guest's exit code when it executes HALT
+pub const EXIT_CODE_REGISTER: usize = 1;
+
+/// Set in a breakpoint event's data when the guest executed `BRK`
+pub const GUEST_BREAKPOINT_FLAG: u64 = 1 << 63;
+
+/// Set in a watchpoint event's data when the access was a store
+pub const WATCHPOINT_WRITE_FLAG: u64 = 1 << 63;
+
+/// Exception code raised when an MMIO access violates a device's width policy
+pub const EXC_ALIGNMENT: u32 = 3;
+
 bitflags! {
     /// VM state flags
     #[derive(Debug, Clone, Copy)]
@@ -53,6 +82,26 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Features the library supports, as reported by `nanocore_capabilities`
+    ///
+    /// The bit values are shared with the `nanocore` crate's `Capabilities`,
+    /// so bindings can test them the same way whichever library they load.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Capabilities: u64 {
+        /// Vector instructions execute
+        const SIMD = 1 << 0;
+        /// MMIO devices can be attached
+        const DEVICES = 1 << 1;
+        /// `nanocore_vm_set_watchpoint`
+        const WATCHPOINTS = 1 << 2;
+        /// Whole state and memory can be saved and restored
+        const SNAPSHOT = 1 << 3;
+        /// Executed instructions can be traced
+        const TRACE = 1 << 4;
+    }
+}
+
 /// VM state structure matching assembly definition
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -75,15 +124,50 @@ pub struct VmInstance {
     event_tx: Sender<VmEvent>,
     event_rx: Receiver<VmEvent>,
     breakpoints: Arc<RwLock<Vec<u64>>>,
+    /// `(start, len, kind)` of each watched range
+    watchpoints: Arc<RwLock<Vec<(u64, u64, WatchKind)>>>,
+    exit_code: Option<i32>,
+}
+
+/// Which guest accesses a watchpoint fires on
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read = 0,
+    Write = 1,
+    ReadWrite = 2,
+}
+
+impl WatchKind {
+    fn from_code(code: c_int) -> Option<Self> {
+        match code {
+            0 => Some(WatchKind::Read),
+            1 => Some(WatchKind::Write),
+            2 => Some(WatchKind::ReadWrite),
+            _ => None,
+        }
+    }
+
+    fn matches(self, store: bool) -> bool {
+        match self {
+            WatchKind::Read => !store,
+            WatchKind::Write => store,
+            WatchKind::ReadWrite => true,
+        }
+    }
 }
 
 /// VM events for async notification
 #[derive(Debug, Clone)]
 pub enum VmEvent {
-    Halted,
-    Breakpoint(u64),
+    /// Guest executed HALT with the given exit code
+    Halted(i32),
+    /// Stopped at `address`; `guest` is set when the guest executed `BRK`
+    Breakpoint { address: u64, guest: bool },
     Exception(u32),
     DeviceInterrupt(u32),
+    /// A load (`Read`) or store (`Write`) starting at `address` touched a watched range
+    Watchpoint { address: u64, kind: WatchKind },
 }
 
 /// Device manager for MMIO devices
@@ -97,6 +181,108 @@ pub trait Device: Send + Sync {
     fn read(&mut self, offset: u64) -> u64;
     fn write(&mut self, offset: u64, value: u64);
     fn reset(&mut self);
+
+    /// Access widths the device accepts; others raise `EXC_ALIGNMENT`
+    fn access_width(&self) -> AccessWidthPolicy {
+        AccessWidthPolicy::Any
+    }
+}
+
+/// Width/alignment constraint enforced on accesses to a device range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessWidthPolicy {
+    /// Any width at any offset
+    Any,
+    /// Naturally aligned 32-bit accesses only
+    Word32,
+    /// Naturally aligned 64-bit accesses only
+    Word64,
+    /// Naturally aligned 32- or 64-bit accesses
+    Word32Or64,
+}
+
+impl AccessWidthPolicy {
+    /// Check an access of `width` bytes at `offset` into the device range
+    pub fn permits(self, offset: u64, width: u64) -> bool {
+        let aligned = |w: u64| width == w && offset % w == 0;
+        match self {
+            AccessWidthPolicy::Any => true,
+            AccessWidthPolicy::Word32 => aligned(4),
+            AccessWidthPolicy::Word64 => aligned(8),
+            AccessWidthPolicy::Word32Or64 => aligned(4) || aligned(8),
+        }
+    }
+}
+
+/// Source of the backing region for VM memory
+///
+/// Implement this to place guest memory somewhere other than ordinary
+/// anonymous pages, e.g. huge pages or a NUMA-bound mapping.
+pub trait MemoryAllocator: Send + Sync {
+    /// Allocate a region of exactly `size` bytes
+    ///
+    /// Fresh memory must be zeroed; file-backed memory starts out as the
+    /// file's contents.
+    fn allocate(&self, size: usize) -> std::io::Result<MmapMut>;
+}
+
+/// Default allocator: a private anonymous mapping
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AnonAllocator;
+
+impl MemoryAllocator for AnonAllocator {
+    fn allocate(&self, size: usize) -> std::io::Result<MmapMut> {
+        MmapMut::map_anon(size)
+    }
+}
+
+/// Anonymous mapping backed by huge pages (`MAP_HUGETLB`)
+///
+/// `page_bits` selects the page size as a power of two (21 for 2 MiB, 30 for
+/// 1 GiB); `None` uses the system default huge page size. Allocation fails
+/// if no huge pages are reserved.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HugePageAllocator {
+    pub page_bits: Option<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl MemoryAllocator for HugePageAllocator {
+    fn allocate(&self, size: usize) -> std::io::Result<MmapMut> {
+        MmapOptions::new().len(size).huge(self.page_bits).map_anon()
+    }
+}
+
+/// Shared mapping of an open file, so guest writes land in the file
+///
+/// A file shorter than the VM memory is extended with zeros first. The
+/// mapping is flushed back to disk when the VM is destroyed.
+#[derive(Debug)]
+pub struct FileAllocator {
+    file: File,
+}
+
+impl FileAllocator {
+    /// Open (or create) `path` for use as VM memory
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(FileAllocator { file })
+    }
+}
+
+impl MemoryAllocator for FileAllocator {
+    fn allocate(&self, size: usize) -> std::io::Result<MmapMut> {
+        if self.file.metadata()?.len() < size as u64 {
+            self.file.set_len(size as u64)?;
+        }
+        unsafe { MmapOptions::new().len(size).map_mut(&self.file) }
+    }
 }
 
 // External C functions from assembly
@@ -105,24 +291,229 @@ extern "C" {
     fn vm_reset();
     fn vm_run(max_instructions: u64) -> c_int;
     fn vm_step() -> c_int;
-    fn vm_get_state() -> *const VmState;
+    fn vm_get_state() -> *mut VmState;
     fn vm_set_breakpoint(address: u64);
     fn vm_dump_state();
 }
 
+/// Serializes use of the assembly core
+///
+/// The core executes out of a single global `VmState`. Each instance keeps
+/// its own copy, which `with_core_state` swaps in for the duration of a call
+/// into the core, so instances never see each other's registers.
+static CORE: Mutex<()> = Mutex::new(());
+
 /// Global VM instances registry
-static VM_INSTANCES: Lazy<RwLock<Vec<Option<Arc<Mutex<VmInstance>>>>>> = 
-    Lazy::new(|| RwLock::new(Vec::new()));
+static VM_INSTANCES: Lazy<RwLock<Registry<Arc<Mutex<VmInstance>>>>> = 
+    Lazy::new(|| RwLock::new(Registry::new()));
+
+/// Callback set by `nanocore_set_log_callback`; stderr when unset
+static LOG_CALLBACK: RwLock<Option<NanoLogCallback>> = RwLock::new(None);
+
+/// Report a diagnostic through the host's callback, or stderr without one
+fn log_message(level: c_int, message: &str) {
+    match *LOG_CALLBACK.read() {
+        Some(callback) => {
+            // Interior NULs would truncate the message on the C side
+            let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+            callback(level, message.as_ptr());
+        }
+        None => eprintln!("{}", message),
+    }
+}
+
+thread_local! {
+    /// Why the most recent failing call on this thread failed
+    static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+    /// Detail given to `fail` during the call in progress
+    static DETAIL: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Explain why the call in progress is failing and return `code`
+///
+/// `ffi_boundary` prefixes the message with the function's name when the
+/// call returns and stores it for `nanocore_last_error`.
+fn fail(code: NanoResult, message: impl Into<String>) -> NanoResult {
+    DETAIL.with(|detail| *detail.borrow_mut() = Some(message.into()));
+    code
+}
+
+/// `fail` for a range outside guest memory
+fn out_of_bounds(address: u64, size: u64, memory_size: usize) -> NanoResult {
+    fail(
+        NANO_EINVAL,
+        format!("address {:#x} out of bounds (size {:#x}, memory {:#x})", address, size, memory_size),
+    )
+}
+
+/// Generic explanation for an error code returned without a `fail` detail
+fn describe(code: NanoResult) -> String {
+    match code {
+        NANO_EINVAL => "invalid argument".to_string(),
+        NANO_ENOMEM => "out of memory".to_string(),
+        NANO_EINIT => "not initialized".to_string(),
+        NANO_ERROR => "operation failed".to_string(),
+        code => format!("error {}", code),
+    }
+}
+
+/// Copy the last error message into `buf`, returning its full length
+fn copy_last_error(buf: *mut c_char, len: usize) -> usize {
+    LAST_ERROR.with(|last| {
+        let last = last.borrow();
+        if !buf.is_null() && len > 0 {
+            let copied = last.len().min(len - 1);
+            unsafe {
+                ptr::copy_nonoverlapping(last.as_ptr().cast(), buf, copied);
+                *buf.add(copied) = 0;
+            }
+        }
+        last.len()
+    })
+}
+
+/// Copy why the most recent failing call on this thread failed into `buf`
+///
+/// Every exported function that returns a negative `NANO_*` code first
+/// records a message such as `read_memory: address 0x... out of bounds
+/// (size 0x..., memory 0x...)`, prefixed with the function's name without
+/// `nanocore_vm_`. Like `errno`, the message belongs to the calling thread
+/// and a later success does not clear it. It is NUL-terminated and
+/// truncated to fit `cap` bytes; returns `NANO_EINVAL` if `buf` is null or
+/// `cap` is 0, and the message is empty if nothing has failed yet.
+#[no_mangle]
+pub extern "C" fn nanocore_last_error(buf: *mut c_char, cap: usize) -> NanoResult {
+    ffi_boundary("last_error", || {
+        if buf.is_null() || cap == 0 {
+            return fail(NANO_EINVAL, "null or empty buffer");
+        }
+        copy_last_error(buf, cap);
+        NANO_OK
+    })
+}
+
+/// Like `nanocore_last_error`, but returns the full length of the message
+///
+/// `buf` may be null, so a caller can ask for the length first and then
+/// size the buffer; 0 means nothing has failed yet.
+#[no_mangle]
+pub extern "C" fn nanocore_last_error_message(buf: *mut c_char, len: usize) -> c_int {
+    ffi_boundary("last_error_message", || copy_last_error(buf, len) as c_int)
+}
+
+/// Run the body of an exported function, turning a panic into `NANO_ERROR`
+///
+/// Unwinding out of an `extern "C"` function is undefined behavior, so every
+/// exported function runs its body through this. The panic hook installed by
+/// `nanocore_init` still reports the panic through `log_message`. When the
+/// body returns a negative code, the reason it gave `fail` (or a generic
+/// one) is stored for `nanocore_last_error` as `"<name>: <reason>"`.
+fn ffi_boundary(name: &str, f: impl FnOnce() -> NanoResult) -> NanoResult {
+    DETAIL.with(|detail| detail.borrow_mut().take());
+    let result = panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|_| fail(NANO_ERROR, "panicked; see the log for details"));
+    let detail = DETAIL.with(|detail| detail.borrow_mut().take());
+    if result < 0 {
+        let message = format!("{}: {}", name, detail.unwrap_or_else(|| describe(result)));
+        LAST_ERROR.with(|last| *last.borrow_mut() = message.clone());
+        // Lets an exported function that calls another one pass its reason on
+        fail(result, message);
+    }
+    result
+}
+
+/// Guards the one-time setup done by `nanocore_init`
+static INIT: Once = Once::new();
+
+/// Times `nanocore_init` has installed its panic hook; never more than one
+#[cfg(test)]
+static HOOK_INSTALLS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// Description returned by `nanocore_build_info`
+const BUILD_INFO: &str = concat!(
+    "nanocore-ffi ",
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("NANOCORE_TARGET"),
+    ", assembly core)\0"
+);
+
+/// Report the library version
+///
+/// Bindings should compare it with the version they were built against
+/// before making any other call. Any of the pointers may be null.
+#[no_mangle]
+pub extern "C" fn nanocore_version(major: *mut c_int, minor: *mut c_int, patch: *mut c_int) -> NanoResult {
+    ffi_boundary("version", || {
+        let parts = [
+            (major, env!("CARGO_PKG_VERSION_MAJOR")),
+            (minor, env!("CARGO_PKG_VERSION_MINOR")),
+            (patch, env!("CARGO_PKG_VERSION_PATCH")),
+        ];
+        for (out, text) in parts {
+            if !out.is_null() {
+                unsafe {
+                    *out = text.parse().unwrap_or(0);
+                }
+            }
+        }
+        NANO_OK
+    })
+}
+
+/// Describe this build: crate version, target triple and backend
+///
+/// Returns a static NUL-terminated string, valid for the life of the
+/// process, such as `nanocore-ffi 0.1.0 (x86_64-unknown-linux-gnu, assembly
+/// core)`. The format is meant for people; compare versions with
+/// `nanocore_version`.
+#[no_mangle]
+pub extern "C" fn nanocore_build_info() -> *const c_char {
+    BUILD_INFO.as_ptr().cast()
+}
+
+/// Report which `Capabilities` this library supports
+///
+/// Bindings can skip operations whose bit is clear instead of calling them
+/// and getting an error. Bits not listed in `Capabilities` are reserved and
+/// read as zero; a feature sets its bit when it lands.
+#[no_mangle]
+pub extern "C" fn nanocore_capabilities() -> u64 {
+    // The assembly core executes vector instructions; snapshots are the
+    // state and memory image calls
+    (Capabilities::SIMD | Capabilities::DEVICES | Capabilities::WATCHPOINTS | Capabilities::SNAPSHOT).bits()
+}
 
 /// Initialize the NanoCore FFI library
+///
+/// Safe to call any number of times from any thread; only the first call
+/// installs the panic hook, and the others wait for it to finish.
 #[no_mangle]
 pub extern "C" fn nanocore_init() -> NanoResult {
-    // Initialize logging, allocators, etc.
-    std::panic::set_hook(Box::new(|info| {
-        eprintln!("NanoCore panic: {}", info);
-    }));
-    
-    NANO_OK
+    ffi_boundary("init", || {
+        INIT.call_once(|| {
+            panic::set_hook(Box::new(|info| {
+                log_message(NANO_LOG_ERROR, &format!("NanoCore panic: {}", info));
+            }));
+            #[cfg(test)]
+            HOOK_INSTALLS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        });
+        
+        NANO_OK
+    })
+}
+
+/// Route diagnostics, including caught panics, to `callback`
+///
+/// Passing NULL goes back to printing them to stderr. The callback may be
+/// invoked from any thread that calls into the library and must not call
+/// `nanocore_set_log_callback` itself.
+#[no_mangle]
+pub extern "C" fn nanocore_set_log_callback(callback: Option<NanoLogCallback>) -> NanoResult {
+    ffi_boundary("set_log_callback", || {
+        *LOG_CALLBACK.write() = callback;
+        NANO_OK
+    })
 }
 
 /// Create a new VM instance
@@ -135,29 +526,103 @@ pub extern "C" fn nanocore_vm_create(
     memory_size: c_ulonglong,
     handle_out: *mut c_int,
 ) -> NanoResult {
-    if handle_out.is_null() {
-        return NANO_EINVAL;
+    ffi_boundary("create", || {
+        if handle_out.is_null() {
+            return fail(NANO_EINVAL, "no handle out-parameter");
+        }
+        
+        match vm_create_with_allocator(memory_size, Box::new(AnonAllocator)) {
+            Ok(handle) => {
+                unsafe {
+                    *handle_out = handle;
+                }
+                NANO_OK
+            }
+            Err(code) => code,
+        }
+    })
+}
+
+/// Create a VM instance whose memory is a shared mapping of the file at `path`
+///
+/// Returns `NANO_EINVAL` if `path` is null, not UTF-8 or cannot be opened.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_create_file(
+    path: *const c_char,
+    memory_size: c_ulonglong,
+    handle_out: *mut c_int,
+) -> NanoResult {
+    ffi_boundary("create_file", || {
+        if path.is_null() || handle_out.is_null() {
+            return fail(NANO_EINVAL, "no path or handle out-parameter");
+        }
+        
+        let Ok(path) = unsafe { CStr::from_ptr(path) }.to_str() else {
+            return fail(NANO_EINVAL, "memory file path is not UTF-8");
+        };
+        let allocator = match FileAllocator::open(Path::new(path)) {
+            Ok(allocator) => allocator,
+            Err(error) => return fail(NANO_EINVAL, format!("cannot open {}: {}", path, error)),
+        };
+        match vm_create_with_allocator(memory_size, Box::new(allocator)) {
+            Ok(handle) => {
+                unsafe {
+                    *handle_out = handle;
+                }
+                NANO_OK
+            }
+            Err(code) => code,
+        }
+    })
+}
+
+/// Create a VM instance whose memory comes from `allocator`
+///
+/// Returns the new handle, or the `NanoResult` error code on failure, with
+/// the reason left for `nanocore_last_error_message`. A `memory_size` of
+/// zero is rejected with `NANO_EINVAL`.
+pub fn vm_create_with_allocator(
+    memory_size: u64,
+    allocator: Box<dyn MemoryAllocator>,
+) -> Result<c_int, NanoResult> {
+    if memory_size == 0 {
+        return Err(fail(NANO_EINVAL, "memory size must be nonzero"));
     }
+    let Ok(size) = usize::try_from(memory_size) else {
+        return Err(fail(NANO_ENOMEM, format!("memory size {} exceeds the address space", memory_size)));
+    };
     
-    // Initialize VM through assembly
-    let result = unsafe { vm_init(memory_size) };
-    if result != 0 {
-        return NANO_ERROR;
-    }
+    // Initialize VM through assembly and take its initial state
+    let state = {
+        let _core = CORE.lock();
+        let code = unsafe { vm_init(memory_size) };
+        if code != 0 {
+            // The core only reports that its memory, cache or device setup failed
+            return Err(fail(
+                NANO_ERROR,
+                format!(
+                    "assembly core failed to set up memory, caches or devices for a {}-byte VM (vm_init returned {})",
+                    memory_size, code
+                ),
+            ));
+        }
+        unsafe { (*vm_get_state()).clone() }
+    };
     
     // Create memory mapping
-    let memory = match MmapMut::map_anon(memory_size as usize) {
+    let memory = match allocator.allocate(size) {
         Ok(m) => m,
-        Err(_) => return NANO_ENOMEM,
+        Err(error) => {
+            return Err(fail(
+                NANO_ENOMEM,
+                format!("cannot allocate {} bytes of guest memory: {}", memory_size, error),
+            ))
+        }
     };
     
     // Create event channels
     let (event_tx, event_rx) = bounded(1024);
     
-    // Get initial state
-    let state_ptr = unsafe { vm_get_state() };
-    let state = unsafe { (*state_ptr).clone() };
-    
     // Create instance
     let instance = VmInstance {
         state: Arc::new(RwLock::new(state)),
@@ -166,39 +631,71 @@ pub extern "C" fn nanocore_vm_create(
         event_tx,
         event_rx,
         breakpoints: Arc::new(RwLock::new(Vec::new())),
+        watchpoints: Arc::new(RwLock::new(Vec::new())),
+        exit_code: None,
     };
     
     // Register instance
     let mut instances = VM_INSTANCES.write();
-    let handle = instances.len() as c_int;
-    instances.push(Some(Arc::new(Mutex::new(instance))));
-    
-    unsafe {
-        *handle_out = handle;
-    }
-    
-    NANO_OK
+    instances
+        .insert(Arc::new(Mutex::new(instance)))
+        .ok_or_else(|| fail(NANO_ENOMEM, "no free VM handles"))
 }
 
 /// Destroy a VM instance
 #[no_mangle]
 pub extern "C" fn nanocore_vm_destroy(handle: c_int) -> NanoResult {
-    let mut instances = VM_INSTANCES.write();
-    
-    if handle < 0 || handle as usize >= instances.len() {
-        return NANO_EINVAL;
-    }
-    
-    instances[handle as usize] = None;
-    NANO_OK
+    ffi_boundary("destroy", || {
+        match VM_INSTANCES.write().remove(handle) {
+            Some(_) => NANO_OK,
+            None => fail(NANO_EINVAL, format!("invalid VM handle {}", handle)),
+        }
+    })
+}
+
+/// Check whether `handle` refers to a live VM
+///
+/// Returns `NANO_OK` for a live handle and `NANO_EINVAL` for one that was
+/// never issued or whose VM has been destroyed, even if its slot has since
+/// been reused by a new VM.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_is_valid(handle: c_int) -> NanoResult {
+    ffi_boundary("is_valid", || {
+        match VM_INSTANCES.read().get(handle) {
+            Some(_) => NANO_OK,
+            None => fail(NANO_EINVAL, format!("invalid VM handle {}", handle)),
+        }
+    })
 }
 
 /// Reset VM to initial state
+///
+/// Only the CPU state is reset; memory keeps whatever the previous program
+/// left in it. `nanocore_vm_reset_full` clears memory too.
 #[no_mangle]
 pub extern "C" fn nanocore_vm_reset(handle: c_int) -> NanoResult {
-    with_vm_instance(handle, |_vm| {
-        unsafe { vm_reset() };
-        NANO_OK
+    ffi_boundary("reset", || {
+        with_vm_instance(handle, |vm| {
+            with_core_state(vm, || unsafe { vm_reset() });
+            vm.exit_code = None;
+            NANO_OK
+        })
+    })
+}
+
+/// Reset the CPU state as `nanocore_vm_reset` does and zero all of memory
+///
+/// Devices are not touched. For file-backed memory the zeros reach the
+/// file like any other write.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_reset_full(handle: c_int) -> NanoResult {
+    ffi_boundary("reset_full", || {
+        with_vm_instance(handle, |vm| {
+            with_core_state(vm, || unsafe { vm_reset() });
+            vm.exit_code = None;
+            vm.memory.write().fill(0);
+            NANO_OK
+        })
     })
 }
 
@@ -208,36 +705,104 @@ pub extern "C" fn nanocore_vm_run(
     handle: c_int,
     max_instructions: c_ulonglong,
 ) -> NanoResult {
-    with_vm_instance(handle, |vm| {
-        // Update breakpoints in assembly
-        let breakpoints = vm.breakpoints.read();
-        for &bp in breakpoints.iter() {
-            unsafe { vm_set_breakpoint(bp) };
-        }
-        
-        // Run VM
-        let result = unsafe { vm_run(max_instructions) };
-        
-        // Update cached state
-        let state_ptr = unsafe { vm_get_state() };
-        let new_state = unsafe { (*state_ptr).clone() };
-        *vm.state.write() = new_state;
-        
-        // Check for events
-        if result == 2 {
-            // Breakpoint hit
-            let pc = vm.state.read().pc;
-            let _ = vm.event_tx.try_send(VmEvent::Breakpoint(pc));
+    ffi_boundary("run", || {
+        with_vm_instance(handle, |vm| {
+            // Run VM with this instance's state loaded into the core
+            let breakpoints = vm.breakpoints.read().clone();
+            let watchpoints = vm.watchpoints.read().clone();
+            let mut watch_hit = None;
+            let result = with_core_state(vm, || {
+                for &bp in breakpoints.iter() {
+                    unsafe { vm_set_breakpoint(bp) };
+                }
+                if watchpoints.is_empty() {
+                    return unsafe { vm_run(max_instructions) };
+                }
+                
+                // The core cannot watch accesses itself, so step it and check
+                // each load and store before it executes; 0 means no limit,
+                // as it does for the core
+                let mut executed = 0;
+                while max_instructions == 0 || executed < max_instructions {
+                    let access = {
+                        let state = unsafe { &*vm_get_state() };
+                        let memory = vm.memory.read();
+                        fetch(&memory, state.pc).and_then(|raw| decode_access(raw, &state.gprs))
+                    };
+                    let result = unsafe { vm_step() };
+                    if result != 0 {
+                        return result;
+                    }
+                    watch_hit = access.and_then(|access| watched(&watchpoints, access));
+                    let halted = VmFlags::from_bits_truncate(unsafe { (*vm_get_state()).flags })
+                        .contains(VmFlags::HALTED);
+                    if watch_hit.is_some() || halted {
+                        break;
+                    }
+                    executed += 1;
+                }
+                0
+            });
+            
+            // Check for events
+            let (halted, exit_code) = {
+                let state = vm.state.read();
+                (
+                    VmFlags::from_bits_truncate(state.flags).contains(VmFlags::HALTED),
+                    state.gprs[EXIT_CODE_REGISTER] as i32,
+                )
+            };
+            if halted && vm.exit_code.is_none() {
+                vm.exit_code = Some(exit_code);
+                let _ = vm.event_tx.try_send(VmEvent::Halted(exit_code));
+            }
+            
+            if result == 2 {
+                // Breakpoint hit
+                let pc = vm.state.read().pc;
+                let _ = vm.event_tx.try_send(VmEvent::Breakpoint { address: pc, guest: false });
+            }
+            
+            if let Some((address, kind)) = watch_hit {
+                let _ = vm.event_tx.try_send(VmEvent::Watchpoint { address, kind });
+            }
+            
+            result
+        })
+    })
+}
+
+/// Get the exit code reported by the last HALT
+///
+/// Returns `NANO_ERROR` if the guest has not halted.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_get_exit_code(
+    handle: c_int,
+    exit_code_out: *mut c_int,
+) -> NanoResult {
+    ffi_boundary("get_exit_code", || {
+        if exit_code_out.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
         }
         
-        result
+        with_vm_instance(handle, |vm| match vm.exit_code {
+            Some(exit_code) => {
+                unsafe {
+                    *exit_code_out = exit_code;
+                }
+                NANO_OK
+            }
+            None => fail(NANO_ERROR, "guest has not halted"),
+        })
     })
 }
 
 /// Single step VM execution
 #[no_mangle]
 pub extern "C" fn nanocore_vm_step(handle: c_int) -> NanoResult {
-    nanocore_vm_run(handle, 1)
+    ffi_boundary("step", || {
+        nanocore_vm_run(handle, 1)
+    })
 }
 
 /// Get VM state
@@ -246,33 +811,62 @@ pub extern "C" fn nanocore_vm_get_state(
     handle: c_int,
     state_out: *mut VmState,
 ) -> NanoResult {
-    if state_out.is_null() {
-        return NANO_EINVAL;
-    }
-    
-    with_vm_instance(handle, |vm| {
-        let state = vm.state.read();
-        unsafe {
-            *state_out = state.clone();
+    ffi_boundary("get_state", || {
+        if state_out.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
         }
-        NANO_OK
+        
+        with_vm_instance(handle, |vm| {
+            let state = vm.state.read();
+            unsafe {
+                *state_out = state.clone();
+            }
+            NANO_OK
+        })
+    })
+}
+
+/// Replace VM state
+#[no_mangle]
+pub extern "C" fn nanocore_vm_set_state(
+    handle: c_int,
+    state: *const VmState,
+) -> NanoResult {
+    ffi_boundary("set_state", || {
+        if state.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
+        }
+        
+        with_vm_instance(handle, |vm| {
+            let mut new_state = unsafe { (*state).clone() };
+            new_state.gprs[0] = 0;
+            *vm.state.write() = new_state;
+            NANO_OK
+        })
     })
 }
 
 /// Set VM register
+///
+/// Writes to R0 are accepted and ignored.
 #[no_mangle]
 pub extern "C" fn nanocore_vm_set_register(
     handle: c_int,
     reg: c_int,
     value: c_ulonglong,
 ) -> NanoResult {
-    if reg < 0 || reg >= 32 {
-        return NANO_EINVAL;
-    }
-    
-    with_vm_instance(handle, |vm| {
-        vm.state.write().gprs[reg as usize] = value;
-        NANO_OK
+    ffi_boundary("set_register", || {
+        if reg < 0 || reg >= 32 {
+            return fail(NANO_EINVAL, format!("register {} out of range", reg));
+        }
+        
+        with_vm_instance(handle, |vm| {
+            // R0 is hardwired to zero
+            if reg != 0 {
+                vm.state.write().gprs[reg as usize] = value;
+            }
+            NANO_OK
+        })
     })
 }
 
@@ -283,119 +877,530 @@ pub extern "C" fn nanocore_vm_get_register(
     reg: c_int,
     value_out: *mut c_ulonglong,
 ) -> NanoResult {
-    if reg < 0 || reg >= 32 || value_out.is_null() {
-        return NANO_EINVAL;
-    }
-    
-    with_vm_instance(handle, |vm| {
-        let value = vm.state.read().gprs[reg as usize];
-        unsafe {
-            *value_out = value;
+    ffi_boundary("get_register", || {
+        if reg < 0 || reg >= 32 {
+            return fail(NANO_EINVAL, format!("register {} out of range", reg));
         }
-        NANO_OK
+        if value_out.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
+        }
+        
+        with_vm_instance(handle, |vm| {
+            let value = vm.state.read().gprs[reg as usize];
+            unsafe {
+                *value_out = value;
+            }
+            NANO_OK
+        })
     })
 }
 
-/// Load program into VM memory
+/// Get all 32 general purpose registers in one call
 #[no_mangle]
-pub extern "C" fn nanocore_vm_load_program(
+pub extern "C" fn nanocore_vm_get_registers(
     handle: c_int,
-    program: *const u8,
-    size: c_ulonglong,
-    address: c_ulonglong,
+    values_out: *mut c_ulonglong,
 ) -> NanoResult {
-    if program.is_null() {
-        return NANO_EINVAL;
-    }
-    
-    with_vm_instance(handle, |vm| {
-        let mut memory = vm.memory.write();
-        let program_slice = unsafe { slice::from_raw_parts(program, size as usize) };
-        
-        if address as usize + size as usize > memory.len() {
-            return NANO_EINVAL;
+    ffi_boundary("get_registers", || {
+        if values_out.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
         }
         
-        memory[address as usize..(address + size) as usize]
-            .copy_from_slice(program_slice);
-            
-        NANO_OK
+        with_vm_instance(handle, |vm| {
+            let gprs = vm.state.read().gprs;
+            unsafe {
+                ptr::write_unaligned(values_out as *mut [u64; 32], gprs);
+            }
+            NANO_OK
+        })
     })
 }
 
-/// Read VM memory
+/// Set all 32 general purpose registers in one call
+///
+/// The value given for R0 is ignored; R0 always reads as zero.
 #[no_mangle]
-pub extern "C" fn nanocore_vm_read_memory(
+pub extern "C" fn nanocore_vm_set_registers(
     handle: c_int,
-    address: c_ulonglong,
-    buffer: *mut u8,
-    size: c_ulonglong,
+    values: *const c_ulonglong,
 ) -> NanoResult {
-    if buffer.is_null() {
-        return NANO_EINVAL;
-    }
-    
-    with_vm_instance(handle, |vm| {
-        let memory = vm.memory.read();
-        
-        if address as usize + size as usize > memory.len() {
-            return NANO_EINVAL;
+    ffi_boundary("set_registers", || {
+        if values.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
         }
         
-        let buffer_slice = unsafe { slice::from_raw_parts_mut(buffer, size as usize) };
-        buffer_slice.copy_from_slice(&memory[address as usize..(address + size) as usize]);
-        
-        NANO_OK
+        let mut gprs = unsafe { ptr::read_unaligned(values as *const [u64; 32]) };
+        gprs[0] = 0;
+        with_vm_instance(handle, |vm| {
+            vm.state.write().gprs = gprs;
+            NANO_OK
+        })
     })
 }
 
-/// Write VM memory
+/// Set the four lanes of a vector register
 #[no_mangle]
-pub extern "C" fn nanocore_vm_write_memory(
+pub extern "C" fn nanocore_vm_set_vreg(
     handle: c_int,
-    address: c_ulonglong,
-    data: *const u8,
-    size: c_ulonglong,
+    vreg: c_int,
+    lanes: *const c_ulonglong,
 ) -> NanoResult {
-    if data.is_null() {
-        return NANO_EINVAL;
-    }
-    
-    with_vm_instance(handle, |vm| {
-        let mut memory = vm.memory.write();
-        
-        if address as usize + size as usize > memory.len() {
-            return NANO_EINVAL;
+    ffi_boundary("set_vreg", || {
+        if vreg < 0 || vreg >= 16 {
+            return fail(NANO_EINVAL, format!("vector register {} out of range", vreg));
+        }
+        if lanes.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
         }
         
-        let data_slice = unsafe { slice::from_raw_parts(data, size as usize) };
-        memory[address as usize..(address + size) as usize].copy_from_slice(data_slice);
-        
-        NANO_OK
+        let value = unsafe { ptr::read_unaligned(lanes as *const [u64; 4]) };
+        with_vm_instance(handle, |vm| {
+            vm.state.write().vregs[vreg as usize] = value;
+            NANO_OK
+        })
     })
 }
 
-/// Set breakpoint
+/// Get the four lanes of a vector register
 #[no_mangle]
-pub extern "C" fn nanocore_vm_set_breakpoint(
+pub extern "C" fn nanocore_vm_get_vreg(
     handle: c_int,
-    address: c_ulonglong,
+    vreg: c_int,
+    lanes_out: *mut c_ulonglong,
 ) -> NanoResult {
-    with_vm_instance(handle, |vm| {
-        vm.breakpoints.write().push(address);
-        NANO_OK
+    ffi_boundary("get_vreg", || {
+        if vreg < 0 || vreg >= 16 {
+            return fail(NANO_EINVAL, format!("vector register {} out of range", vreg));
+        }
+        if lanes_out.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
+        }
+        
+        with_vm_instance(handle, |vm| {
+            let value = vm.state.read().vregs[vreg as usize];
+            unsafe {
+                ptr::write_unaligned(lanes_out as *mut [u64; 4], value);
+            }
+            NANO_OK
+        })
     })
 }
 
-/// Clear breakpoint
+/// Load program into VM memory
+#[no_mangle]
+pub extern "C" fn nanocore_vm_load_program(
+    handle: c_int,
+    program: *const u8,
+    size: c_ulonglong,
+    address: c_ulonglong,
+) -> NanoResult {
+    ffi_boundary("load_program", || {
+        if program.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
+        }
+        
+        with_vm_instance(handle, |vm| {
+            let mut memory = vm.memory.write();
+            let program_slice = unsafe { slice::from_raw_parts(program, size as usize) };
+            
+            if address.checked_add(size).is_none_or(|end| end > memory.len() as u64) {
+                return out_of_bounds(address, size, memory.len());
+            }
+            
+            memory[address as usize..(address + size) as usize]
+                .copy_from_slice(program_slice);
+                
+            NANO_OK
+        })
+    })
+}
+
+/// Read VM memory
+#[no_mangle]
+pub extern "C" fn nanocore_vm_read_memory(
+    handle: c_int,
+    address: c_ulonglong,
+    buffer: *mut u8,
+    size: c_ulonglong,
+) -> NanoResult {
+    ffi_boundary("read_memory", || {
+        if buffer.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
+        }
+        
+        with_vm_instance(handle, |vm| {
+            let buffer_slice = unsafe { slice::from_raw_parts_mut(buffer, size as usize) };
+            match vm.devices.lock().read_bytes(address, buffer_slice) {
+                Ok(true) => return NANO_OK,
+                Ok(false) => {}
+                Err(()) => {
+                    return fail(
+                        NANO_EINVAL,
+                        format!("access at {:#x} straddles a device or has a width it rejects", address),
+                    )
+                }
+            }
+            
+            let memory = vm.memory.read();
+            
+            if address.checked_add(size).is_none_or(|end| end > memory.len() as u64) {
+                return out_of_bounds(address, size, memory.len());
+            }
+            
+            buffer_slice.copy_from_slice(&memory[address as usize..(address + size) as usize]);
+            
+            NANO_OK
+        })
+    })
+}
+
+/// Read a NUL-terminated string from VM memory into a caller buffer
+///
+/// Copies bytes up to the first NUL, `buf_len - 1` bytes or the end of
+/// memory, whichever comes first, and always NUL-terminates `buf`. `len_out`
+/// receives the number of bytes copied, excluding the NUL. `terminated_out`,
+/// if not null, is set to 1 when the guest string's NUL was reached and 0
+/// when the result was cut short.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_read_cstring(
+    handle: c_int,
+    address: c_ulonglong,
+    buf: *mut c_char,
+    buf_len: c_ulonglong,
+    len_out: *mut c_ulonglong,
+    terminated_out: *mut c_int,
+) -> NanoResult {
+    ffi_boundary("read_cstring", || {
+        if buf.is_null() || len_out.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
+        }
+        if buf_len == 0 {
+            return fail(NANO_EINVAL, "empty buffer");
+        }
+        
+        with_vm_instance(handle, |vm| {
+            let memory = vm.memory.read();
+            let Some(available) = (memory.len() as u64).checked_sub(address) else {
+                return out_of_bounds(address, 0, memory.len());
+            };
+            
+            let start = address as usize;
+            let window = &memory[start..start + available.min(buf_len - 1) as usize];
+            let (len, terminated) = match window.iter().position(|&b| b == 0) {
+                Some(end) => (end, true),
+                None => (window.len(), false),
+            };
+            
+            unsafe {
+                ptr::copy_nonoverlapping(window.as_ptr(), buf as *mut u8, len);
+                *buf.add(len) = 0;
+                *len_out = len as c_ulonglong;
+                if !terminated_out.is_null() {
+                    *terminated_out = terminated as c_int;
+                }
+            }
+            NANO_OK
+        })
+    })
+}
+
+/// Write VM memory
+#[no_mangle]
+pub extern "C" fn nanocore_vm_write_memory(
+    handle: c_int,
+    address: c_ulonglong,
+    data: *const u8,
+    size: c_ulonglong,
+) -> NanoResult {
+    ffi_boundary("write_memory", || {
+        if data.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
+        }
+        
+        with_vm_instance(handle, |vm| {
+            let data_slice = unsafe { slice::from_raw_parts(data, size as usize) };
+            match vm.devices.lock().write_bytes(address, data_slice) {
+                Ok(true) => return NANO_OK,
+                Ok(false) => {}
+                Err(()) => {
+                    return fail(
+                        NANO_EINVAL,
+                        format!("access at {:#x} straddles a device or has a width it rejects", address),
+                    )
+                }
+            }
+            
+            let mut memory = vm.memory.write();
+            
+            if address.checked_add(size).is_none_or(|end| end > memory.len() as u64) {
+                return out_of_bounds(address, size, memory.len());
+            }
+            
+            memory[address as usize..(address + size) as usize].copy_from_slice(data_slice);
+            
+            NANO_OK
+        })
+    })
+}
+
+/// Set `size` bytes of VM memory starting at `address` to `value`
+///
+/// Operates on guest RAM directly; MMIO devices are not involved.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_fill_memory(
+    handle: c_int,
+    address: c_ulonglong,
+    value: u8,
+    size: c_ulonglong,
+) -> NanoResult {
+    ffi_boundary("fill_memory", || {
+        with_vm_instance(handle, |vm| {
+            let mut memory = vm.memory.write();
+            
+            if address.checked_add(size).is_none_or(|end| end > memory.len() as u64) {
+                return out_of_bounds(address, size, memory.len());
+            }
+            
+            memory[address as usize..(address + size) as usize].fill(value);
+            
+            NANO_OK
+        })
+    })
+}
+
+/// Copy `size` bytes of VM memory from `src` to `dst`
+///
+/// The ranges may overlap. Like `nanocore_vm_fill_memory`, this bypasses
+/// MMIO devices.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_copy_memory(
+    handle: c_int,
+    dst: c_ulonglong,
+    src: c_ulonglong,
+    size: c_ulonglong,
+) -> NanoResult {
+    ffi_boundary("copy_memory", || {
+        with_vm_instance(handle, |vm| {
+            let mut memory = vm.memory.write();
+            let len = memory.len() as u64;
+            
+            if dst.checked_add(size).is_none_or(|end| end > len) {
+                return out_of_bounds(dst, size, memory.len());
+            }
+            if src.checked_add(size).is_none_or(|end| end > len) {
+                return out_of_bounds(src, size, memory.len());
+            }
+            
+            memory.copy_within(src as usize..(src + size) as usize, dst as usize);
+            
+            NANO_OK
+        })
+    })
+}
+
+/// Replace the whole of VM memory
+///
+/// `size` must equal the memory size. The copy happens under a single write
+/// lock, so no other access observes a partially installed image.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_load_memory_image(
+    handle: c_int,
+    image: *const u8,
+    size: c_ulonglong,
+) -> NanoResult {
+    ffi_boundary("load_memory_image", || {
+        if image.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
+        }
+        
+        with_vm_instance(handle, |vm| {
+            let mut memory = vm.memory.write();
+            
+            if size as usize != memory.len() {
+                return fail(
+                    NANO_EINVAL,
+                    format!("image size {:#x} does not match memory size {:#x}", size, memory.len()),
+                );
+            }
+            
+            let image_slice = unsafe { slice::from_raw_parts(image, size as usize) };
+            memory.copy_from_slice(image_slice);
+            
+            NANO_OK
+        })
+    })
+}
+
+/// Copy out the whole of VM memory
+///
+/// `size` must equal the memory size.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_save_memory_image(
+    handle: c_int,
+    buffer: *mut u8,
+    size: c_ulonglong,
+) -> NanoResult {
+    ffi_boundary("save_memory_image", || {
+        if buffer.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
+        }
+        
+        with_vm_instance(handle, |vm| {
+            let memory = vm.memory.read();
+            
+            if size as usize != memory.len() {
+                return fail(
+                    NANO_EINVAL,
+                    format!("image size {:#x} does not match memory size {:#x}", size, memory.len()),
+                );
+            }
+            
+            let buffer_slice = unsafe { slice::from_raw_parts_mut(buffer, size as usize) };
+            buffer_slice.copy_from_slice(&memory);
+            
+            NANO_OK
+        })
+    })
+}
+
+/// Flush VM memory to its backing store
+///
+/// Blocks until every modified page has been written back (`msync`). For a
+/// file-backed region, data written before this call is durable once it
+/// returns `NANO_OK`; anonymous memory has no backing file, so this is a
+/// no-op for it.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_flush_memory(handle: c_int) -> NanoResult {
+    ffi_boundary("flush_memory", || {
+        with_vm_instance(handle, |vm| match vm.memory.read().flush() {
+            Ok(()) => NANO_OK,
+            Err(error) => fail(NANO_ERROR, format!("msync failed: {}", error)),
+        })
+    })
+}
+
+/// Flush `size` bytes of VM memory starting at `address`
+///
+/// Same guarantees as `nanocore_vm_flush_memory`, limited to the pages
+/// overlapping the range.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_flush_range(
+    handle: c_int,
+    address: c_ulonglong,
+    size: c_ulonglong,
+) -> NanoResult {
+    ffi_boundary("flush_range", || {
+        with_vm_instance(handle, |vm| {
+            let memory = vm.memory.read();
+            
+            if address.checked_add(size).is_none_or(|end| end > memory.len() as u64) {
+                return out_of_bounds(address, size, memory.len());
+            }
+            
+            match memory.flush_range(address as usize, size as usize) {
+                Ok(()) => NANO_OK,
+                Err(error) => fail(NANO_ERROR, format!("msync failed: {}", error)),
+            }
+        })
+    })
+}
+
+/// Set breakpoint
+#[no_mangle]
+pub extern "C" fn nanocore_vm_set_breakpoint(
+    handle: c_int,
+    address: c_ulonglong,
+) -> NanoResult {
+    ffi_boundary("set_breakpoint", || {
+        with_vm_instance(handle, |vm| {
+            vm.breakpoints.write().push(address);
+            NANO_OK
+        })
+    })
+}
+
+/// Clear breakpoint
 #[no_mangle]
 pub extern "C" fn nanocore_vm_clear_breakpoint(
     handle: c_int,
     address: c_ulonglong,
 ) -> NanoResult {
-    with_vm_instance(handle, |vm| {
-        vm.breakpoints.write().retain(|&x| x != address);
-        NANO_OK
+    ffi_boundary("clear_breakpoint", || {
+        with_vm_instance(handle, |vm| {
+            vm.breakpoints.write().retain(|&x| x != address);
+            NANO_OK
+        })
+    })
+}
+
+/// List the breakpoint addresses, in the order they were set
+///
+/// Up to `cap` addresses are written to `buf`, and `count_out` receives the
+/// total number of breakpoints. `buf` may be null when `cap` is 0, so a
+/// caller can ask for the count first and then size the buffer.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_list_breakpoints(
+    handle: c_int,
+    buf: *mut c_ulonglong,
+    cap: c_ulonglong,
+    count_out: *mut c_ulonglong,
+) -> NanoResult {
+    ffi_boundary("list_breakpoints", || {
+        if count_out.is_null() || (buf.is_null() && cap != 0) {
+            return fail(NANO_EINVAL, "null pointer argument");
+        }
+        
+        with_vm_instance(handle, |vm| {
+            let breakpoints = vm.breakpoints.read();
+            let copied = breakpoints.len().min(cap as usize);
+            unsafe {
+                if copied > 0 {
+                    ptr::copy_nonoverlapping(breakpoints.as_ptr(), buf, copied);
+                }
+                *count_out = breakpoints.len() as c_ulonglong;
+            }
+            NANO_OK
+        })
+    })
+}
+
+/// Watch `len` bytes from `address` for accesses of `kind` (a `WatchKind` code)
+///
+/// While any watchpoint is set, `nanocore_vm_run` stops after the first
+/// matching access and queues a watchpoint event.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_set_watchpoint(
+    handle: c_int,
+    address: c_ulonglong,
+    len: c_ulonglong,
+    kind: c_int,
+) -> NanoResult {
+    ffi_boundary("set_watchpoint", || {
+        let kind = match WatchKind::from_code(kind) {
+            Some(kind) if len > 0 => kind,
+            Some(_) => return fail(NANO_EINVAL, "watched range is empty"),
+            None => return fail(NANO_EINVAL, format!("unknown watch kind {}", kind)),
+        };
+        with_vm_instance(handle, |vm| {
+            let mut watchpoints = vm.watchpoints.write();
+            watchpoints.retain(|&(start, _, _)| start != address);
+            watchpoints.push((address, len, kind));
+            NANO_OK
+        })
+    })
+}
+
+/// Clear the watchpoint starting at `address`
+#[no_mangle]
+pub extern "C" fn nanocore_vm_clear_watchpoint(
+    handle: c_int,
+    address: c_ulonglong,
+) -> NanoResult {
+    ffi_boundary("clear_watchpoint", || {
+        with_vm_instance(handle, |vm| {
+            vm.watchpoints.write().retain(|&(start, _, _)| start != address);
+            NANO_OK
+        })
     })
 }
 
@@ -406,19 +1411,166 @@ pub extern "C" fn nanocore_vm_get_perf_counter(
     counter: c_int,
     value_out: *mut c_ulonglong,
 ) -> NanoResult {
-    if counter < 0 || counter >= 8 || value_out.is_null() {
-        return NANO_EINVAL;
-    }
+    ffi_boundary("get_perf_counter", || {
+        if counter < 0 || counter >= 8 {
+            return fail(NANO_EINVAL, format!("performance counter {} out of range", counter));
+        }
+        if value_out.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
+        }
+        
+        with_vm_instance(handle, |vm| {
+            let value = vm.state.read().perf_counters[counter as usize];
+            unsafe {
+                *value_out = value;
+            }
+            NANO_OK
+        })
+    })
+}
+
+/// Zero all performance counters
+///
+/// The counters live in the instance's state, which is swapped into the
+/// core for every run, so counting resumes from zero on the next run.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_reset_perf_counters(handle: c_int) -> NanoResult {
+    ffi_boundary("reset_perf_counters", || {
+        with_vm_instance(handle, |vm| {
+            vm.state.write().perf_counters = [0; 8];
+            NANO_OK
+        })
+    })
+}
+
+/// Render a VM state as text: PC, SP, decoded flags, nonzero GPRs and
+/// the performance counters, one item per line
+fn format_state(state: &VmState) -> String {
+    const FLAG_NAMES: [(VmFlags, &str); 7] = [
+        (VmFlags::ZERO, "Z"),
+        (VmFlags::CARRY, "C"),
+        (VmFlags::OVERFLOW, "V"),
+        (VmFlags::NEGATIVE, "N"),
+        (VmFlags::INTERRUPT_ENABLE, "IE"),
+        (VmFlags::USER_MODE, "U"),
+        (VmFlags::HALTED, "H"),
+    ];
+    const PERF_COUNTER_NAMES: [&str; 8] = [
+        "instructions",
+        "cycles",
+        "l1_miss",
+        "l2_miss",
+        "branch_miss",
+        "stalls",
+        "memory_ops",
+        "simd_ops",
+    ];
     
-    with_vm_instance(handle, |vm| {
-        let value = vm.state.read().perf_counters[counter as usize];
-        unsafe {
-            *value_out = value;
+    let flags = VmFlags::from_bits_retain(state.flags);
+    let names: Vec<&str> = FLAG_NAMES
+        .iter()
+        .filter(|(bit, _)| flags.contains(*bit))
+        .map(|&(_, name)| name)
+        .collect();
+    
+    let mut text = format!("pc    {:#018x}\n", state.pc);
+    text += &format!("sp    {:#018x}\n", state.sp);
+    text += &format!("flags {:#018x} [{}]\n", state.flags, names.join(" "));
+    for (index, value) in state.gprs.iter().enumerate().filter(|&(_, &value)| value != 0) {
+        text += &format!("{:<5} {:#018x}\n", format!("r{}", index), value);
+    }
+    text += "perf ";
+    for (name, value) in PERF_COUNTER_NAMES.iter().zip(&state.perf_counters) {
+        text += &format!(" {}={}", name, value);
+    }
+    text.push('\n');
+    text
+}
+
+/// Write the VM state as readable text into a caller buffer
+///
+/// The text is what the core's `vm_dump_state` prints to stderr, in a form
+/// that can be captured instead. Up to `buf_len - 1` bytes are copied and
+/// the result is always NUL-terminated when `buf_len` is nonzero.
+/// `written` receives the full length of the text, excluding the NUL, so a
+/// value of `buf_len` or more means the output was truncated; call again
+/// with a buffer of `*written + 1` bytes to get all of it.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_format_state(
+    handle: c_int,
+    buf: *mut c_char,
+    buf_len: c_ulonglong,
+    written: *mut c_ulonglong,
+) -> NanoResult {
+    ffi_boundary("format_state", || {
+        if written.is_null() || (buf.is_null() && buf_len != 0) {
+            return fail(NANO_EINVAL, "null pointer argument");
         }
-        NANO_OK
+        
+        with_vm_instance(handle, |vm| {
+            let text = format_state(&vm.state.read());
+            if buf_len != 0 {
+                let copied = text.len().min(buf_len as usize - 1);
+                unsafe {
+                    ptr::copy_nonoverlapping(text.as_ptr(), buf as *mut u8, copied);
+                    *buf.add(copied) = 0;
+                }
+            }
+            unsafe {
+                *written = text.len() as c_ulonglong;
+            }
+            NANO_OK
+        })
     })
 }
 
+/// Event type code and data word reported to C callers
+fn encode_event(event: VmEvent) -> (c_int, u64) {
+    match event {
+        VmEvent::Halted(exit_code) => (0, exit_code as i64 as u64),
+        VmEvent::Breakpoint { address, guest } => {
+            (1, if guest { address | GUEST_BREAKPOINT_FLAG } else { address })
+        }
+        VmEvent::Exception(code) => (2, code as u64),
+        VmEvent::DeviceInterrupt(id) => (3, id as u64),
+        VmEvent::Watchpoint { address, kind } => {
+            (4, if kind == WatchKind::Write { address | WATCHPOINT_WRITE_FLAG } else { address })
+        }
+    }
+}
+
+/// Big-endian instruction word at `pc`, if it lies in memory
+fn fetch(memory: &[u8], pc: u64) -> Option<u32> {
+    let start = usize::try_from(pc).ok()?;
+    let bytes = memory.get(start..start.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Memory access made by `raw` as `(store, address, width)`, if it is a load or store
+fn decode_access(raw: u32, gprs: &[u64; 32]) -> Option<(bool, u64, u64)> {
+    let (store, width) = match raw >> 26 {
+        0x10 => (false, 4), // LW
+        0x11 => (false, 2), // LH
+        0x12 => (false, 1), // LB
+        0x13 => (true, 8),  // ST
+        0x14 => (true, 4),  // SW
+        0x15 => (true, 2),  // SH
+        0x16 => (true, 1),  // SB
+        _ => return None,
+    };
+    let base = gprs[((raw >> 16) & 0x1F) as usize];
+    Some((store, base.wrapping_add(raw as u16 as i16 as u64), width))
+}
+
+/// Start and kind of `access` if it touches one of `watchpoints`
+fn watched(watchpoints: &[(u64, u64, WatchKind)], (store, address, width): (bool, u64, u64)) -> Option<(u64, WatchKind)> {
+    let end = address.saturating_add(width);
+    watchpoints
+        .iter()
+        .any(|&(start, len, kind)| kind.matches(store) && address < start.saturating_add(len) && start < end)
+        .then_some((address, if store { WatchKind::Write } else { WatchKind::Read }))
+}
+
 /// Poll for VM events (non-blocking)
 #[no_mangle]
 pub extern "C" fn nanocore_vm_poll_event(
@@ -426,62 +1578,118 @@ pub extern "C" fn nanocore_vm_poll_event(
     event_type_out: *mut c_int,
     event_data_out: *mut c_ulonglong,
 ) -> NanoResult {
-    if event_type_out.is_null() || event_data_out.is_null() {
-        return NANO_EINVAL;
-    }
-    
-    with_vm_instance(handle, |vm| {
-        match vm.event_rx.try_recv() {
-            Ok(event) => {
-                let (event_type, event_data) = match event {
-                    VmEvent::Halted => (0, 0),
-                    VmEvent::Breakpoint(addr) => (1, addr),
-                    VmEvent::Exception(code) => (2, code as u64),
-                    VmEvent::DeviceInterrupt(id) => (3, id as u64),
-                };
-                
+    ffi_boundary("poll_event", || {
+        nanocore_vm_wait_event(handle, 0, event_type_out, event_data_out)
+    })
+}
+
+/// Wait for the next VM event
+///
+/// `timeout_ms` of 0 polls and `u64::MAX` waits indefinitely. Returns
+/// `NANO_ERROR` if no event arrived in time.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_wait_event(
+    handle: c_int,
+    timeout_ms: c_ulonglong,
+    event_type_out: *mut c_int,
+    event_data_out: *mut c_ulonglong,
+) -> NanoResult {
+    ffi_boundary("wait_event", || {
+        if event_type_out.is_null() || event_data_out.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
+        }
+        
+        // Wait on a clone of the receiver so the instance stays unlocked and
+        // can keep running while we block
+        let mut receiver = None;
+        let result = with_vm_instance(handle, |vm| {
+            receiver = Some(vm.event_rx.clone());
+            NANO_OK
+        });
+        let Some(receiver) = receiver else {
+            return result;
+        };
+        
+        let event = match timeout_ms {
+            0 => receiver.try_recv().ok(),
+            u64::MAX => receiver.recv().ok(),
+            ms => receiver.recv_timeout(Duration::from_millis(ms)).ok(),
+        };
+        match event {
+            Some(event) => {
+                let (event_type, event_data) = encode_event(event);
                 unsafe {
                     *event_type_out = event_type;
                     *event_data_out = event_data;
                 }
                 NANO_OK
             }
-            Err(_) => NANO_ERROR, // No event available
+            None => fail(NANO_ERROR, "no event available"),
+        }
+    })
+}
+
+/// Run until the VM raises an event or `max_instructions` run out
+///
+/// `status_out` receives what `nanocore_vm_run` would have returned, and the
+/// oldest pending event is taken as by `nanocore_vm_poll_event`. Returns
+/// `NANO_ERROR` if the run ended with no event pending. Events beyond the
+/// first stay queued, so nothing raised during the run is lost.
+#[no_mangle]
+pub extern "C" fn nanocore_vm_run_until_event(
+    handle: c_int,
+    max_instructions: c_ulonglong,
+    event_type_out: *mut c_int,
+    event_data_out: *mut c_ulonglong,
+    status_out: *mut NanoResult,
+) -> NanoResult {
+    ffi_boundary("run_until_event", || {
+        if event_type_out.is_null() || event_data_out.is_null() || status_out.is_null() {
+            return fail(NANO_EINVAL, "null pointer argument");
         }
+        
+        let status = nanocore_vm_run(handle, max_instructions);
+        if status == NANO_EINVAL {
+            return NANO_EINVAL;
+        }
+        unsafe {
+            *status_out = status;
+        }
+        nanocore_vm_poll_event(handle, event_type_out, event_data_out)
     })
 }
 
+/// Run `f` against the assembly core with `vm`'s state loaded into it
+///
+/// The state is copied in before `f` and back out after, all under the core
+/// lock, so the core's global state only ever belongs to one instance.
+fn with_core_state<R>(vm: &VmInstance, f: impl FnOnce() -> R) -> R {
+    let _core = CORE.lock();
+    let core_state = unsafe { vm_get_state() };
+    unsafe {
+        *core_state = vm.state.read().clone();
+    }
+    let result = f();
+    *vm.state.write() = unsafe { (*core_state).clone() };
+    result
+}
+
 // Helper function to access VM instance
-fn with_vm_instance<F, R>(handle: c_int, f: F) -> R
+//
+// Fails with `NANO_EINVAL` without calling `f` if `handle` is out of range or
+// has been destroyed.
+fn with_vm_instance<F>(handle: c_int, f: F) -> NanoResult
 where
-    F: FnOnce(&mut VmInstance) -> R,
+    F: FnOnce(&mut VmInstance) -> NanoResult,
 {
     let instances = VM_INSTANCES.read();
     
-    if handle < 0 || handle as usize >= instances.len() {
-        return f(&mut VmInstance {
-            state: Arc::new(RwLock::new(VmState::default())),
-            memory: Arc::new(RwLock::new(unsafe { MmapMut::map_anon(0).unwrap() })),
-            devices: Arc::new(Mutex::new(DeviceManager::new())),
-            event_tx: bounded(0).0,
-            event_rx: bounded(0).1,
-            breakpoints: Arc::new(RwLock::new(Vec::new())),
-        });
-    }
-    
-    match &instances[handle as usize] {
+    match instances.get(handle) {
         Some(instance) => {
             let mut vm = instance.lock();
             f(&mut vm)
         }
-        None => f(&mut VmInstance {
-            state: Arc::new(RwLock::new(VmState::default())),
-            memory: Arc::new(RwLock::new(unsafe { MmapMut::map_anon(0).unwrap() })),
-            devices: Arc::new(Mutex::new(DeviceManager::new())),
-            event_tx: bounded(0).0,
-            event_rx: bounded(0).1,
-            breakpoints: Arc::new(RwLock::new(Vec::new())),
-        }),
+        None => fail(NANO_EINVAL, format!("invalid VM handle {}", handle)),
     }
 }
 
@@ -507,4 +1715,802 @@ impl DeviceManager {
             mmio_map: Vec::new(),
         }
     }
-}
\ No newline at end of file
+
+    /// Find the device mapped at `address`, returning its index and offset
+    fn lookup(&self, address: u64) -> Option<(usize, u64)> {
+        self.mmio_map
+            .iter()
+            .find(|&&(start, end, _)| address >= start && address < end)
+            .map(|&(start, _, index)| (index, address - start))
+    }
+
+    /// Dispatch a `width`-byte read at `address`
+    ///
+    /// Returns `Ok(None)` when no device is mapped there, or the exception
+    /// code to raise when the device's width policy rejects the access.
+    pub fn read(&mut self, address: u64, width: u64) -> Result<Option<u64>, u32> {
+        let Some((index, offset)) = self.lookup(address) else {
+            return Ok(None);
+        };
+        let device = &mut self.devices[index];
+        if !device.access_width().permits(offset, width) {
+            return Err(EXC_ALIGNMENT);
+        }
+        Ok(Some(device.read(offset)))
+    }
+
+    /// Dispatch a `width`-byte write at `address`
+    ///
+    /// Returns `Ok(false)` when no device is mapped there, or the exception
+    /// code to raise when the device's width policy rejects the access.
+    pub fn write(&mut self, address: u64, width: u64, value: u64) -> Result<bool, u32> {
+        let Some((index, offset)) = self.lookup(address) else {
+            return Ok(false);
+        };
+        let device = &mut self.devices[index];
+        if !device.access_width().permits(offset, width) {
+            return Err(EXC_ALIGNMENT);
+        }
+        device.write(offset, value);
+        Ok(true)
+    }
+
+    /// Find the single device range holding all of `[address, address + size)`
+    ///
+    /// Returns `Ok(None)` when the range touches no device, and `Err(())`
+    /// when it straddles a device boundary.
+    fn span(&self, address: u64, size: u64) -> Result<Option<(usize, u64)>, ()> {
+        let end = address.checked_add(size).ok_or(())?;
+        let mut overlapping = self.mmio_map.iter().filter(|&&(start, stop, _)| address < stop && start < end);
+        match (overlapping.next(), overlapping.next()) {
+            (None, _) => Ok(None),
+            (Some(&(start, stop, index)), None) if start <= address && end <= stop => {
+                Ok(Some((index, address - start)))
+            }
+            _ => Err(()),
+        }
+    }
+
+    /// Serve a host read of `buffer.len()` bytes at `address` from a device
+    ///
+    /// The range is read in 8-byte little-endian chunks, each dispatched to
+    /// the device as one access. Returns `Ok(false)` when the range is plain
+    /// memory, or `Err` when it straddles a device boundary or the device
+    /// rejects an access width.
+    fn read_bytes(&mut self, address: u64, buffer: &mut [u8]) -> Result<bool, ()> {
+        let Some((index, offset)) = self.span(address, buffer.len() as u64)? else {
+            return Ok(false);
+        };
+        let device = &mut self.devices[index];
+        for (chunk_offset, chunk) in (offset..).step_by(8).zip(buffer.chunks_mut(8)) {
+            if !device.access_width().permits(chunk_offset, chunk.len() as u64) {
+                return Err(());
+            }
+            let value = device.read(chunk_offset).to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+        Ok(true)
+    }
+
+    /// Serve a host write of `data` at `address` to a device
+    ///
+    /// The counterpart of `read_bytes`, with the same chunking and errors.
+    fn write_bytes(&mut self, address: u64, data: &[u8]) -> Result<bool, ()> {
+        let Some((index, offset)) = self.span(address, data.len() as u64)? else {
+            return Ok(false);
+        };
+        let device = &mut self.devices[index];
+        for (chunk_offset, chunk) in (offset..).step_by(8).zip(data.chunks(8)) {
+            if !device.access_width().permits(chunk_offset, chunk.len() as u64) {
+                return Err(());
+            }
+            let mut value = [0u8; 8];
+            value[..chunk.len()].copy_from_slice(chunk);
+            device.write(chunk_offset, u64::from_le_bytes(value));
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct WordRegister {
+        value: u64,
+    }
+
+    impl Device for WordRegister {
+        fn read(&mut self, _offset: u64) -> u64 {
+            self.value
+        }
+
+        fn write(&mut self, _offset: u64, value: u64) {
+            self.value = value;
+        }
+
+        fn reset(&mut self) {
+            self.value = 0;
+        }
+
+        fn access_width(&self) -> AccessWidthPolicy {
+            AccessWidthPolicy::Word32
+        }
+    }
+
+    fn word_device_manager() -> DeviceManager {
+        let mut manager = DeviceManager::new();
+        manager.devices.push(Box::new(WordRegister { value: 7 }));
+        manager.mmio_map.push((0x1000, 0x1010, 0));
+        manager
+    }
+
+    #[test]
+    fn test_anon_allocator_is_zeroed() {
+        let memory = AnonAllocator.allocate(0x1000).unwrap();
+        assert_eq!(memory.len(), 0x1000);
+        assert!(memory.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_access_width_policy() {
+        assert!(AccessWidthPolicy::Any.permits(3, 1));
+        assert!(AccessWidthPolicy::Word32.permits(4, 4));
+        assert!(!AccessWidthPolicy::Word32.permits(2, 4));
+        assert!(!AccessWidthPolicy::Word32.permits(4, 8));
+        assert!(AccessWidthPolicy::Word32Or64.permits(8, 8));
+        assert!(!AccessWidthPolicy::Word64.permits(4, 4));
+    }
+
+    #[test]
+    fn test_mmio_dispatch_rejects_bad_width() {
+        let mut manager = word_device_manager();
+
+        assert_eq!(manager.read(0x1004, 4), Ok(Some(7)));
+        assert_eq!(manager.read(0x1004, 1), Err(EXC_ALIGNMENT));
+        assert_eq!(manager.write(0x1002, 4, 1), Err(EXC_ALIGNMENT));
+        assert_eq!(manager.write(0x1008, 4, 9), Ok(true));
+        assert_eq!(manager.read(0x1000, 4), Ok(Some(9)));
+
+        // Addresses outside the map are not MMIO
+        assert_eq!(manager.read(0x2000, 1), Ok(None));
+    }
+
+    #[test]
+    fn test_instances_keep_separate_core_state() {
+        let mut a = 0;
+        let mut b = 0;
+        assert_eq!(nanocore_vm_create(0x40000, &mut a), NANO_OK);
+        assert_eq!(nanocore_vm_create(0x40000, &mut b), NANO_OK);
+
+        // Both run from zeroed memory (ADD R0, R0, R0), so only the
+        // per-instance PC and registers tell them apart
+        for (handle, pc, value) in [(a, 0x10000, 0xAAAA), (b, 0x20000, 0xBBBB)] {
+            let mut state = VmState::default();
+            assert_eq!(nanocore_vm_get_state(handle, &mut state), NANO_OK);
+            state.pc = pc;
+            state.flags = 0;
+            state.gprs[5] = value;
+            assert_eq!(nanocore_vm_set_state(handle, &state), NANO_OK);
+        }
+
+        for step in 1..=3u64 {
+            for (handle, pc, value) in [(a, 0x10000, 0xAAAA), (b, 0x20000, 0xBBBB)] {
+                nanocore_vm_step(handle);
+                let mut state = VmState::default();
+                assert_eq!(nanocore_vm_get_state(handle, &mut state), NANO_OK);
+                assert_eq!(state.pc, pc + 4 * step);
+                assert_eq!(state.gprs[5], value);
+            }
+        }
+
+        assert_eq!(nanocore_vm_destroy(a), NANO_OK);
+        assert_eq!(nanocore_vm_destroy(b), NANO_OK);
+    }
+
+    #[test]
+    fn test_destroyed_handle_is_rejected() {
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x1000, &mut handle), NANO_OK);
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+
+        let mut value = 0;
+        assert_eq!(nanocore_vm_get_register(handle, 1, &mut value), NANO_EINVAL);
+        assert_eq!(nanocore_vm_set_register(handle, 1, 5), NANO_EINVAL);
+        assert_eq!(nanocore_vm_run(handle, 1), NANO_EINVAL);
+        assert_eq!(nanocore_vm_get_register(-1, 1, &mut value), NANO_EINVAL);
+    }
+
+    #[test]
+    fn test_create_destroy_loop_keeps_registry_bounded() {
+        for _ in 0..1000 {
+            let mut handle = 0;
+            assert_eq!(nanocore_vm_create(0x1000, &mut handle), NANO_OK);
+            assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+        }
+        // Other tests may hold a few instances concurrently, but nowhere
+        // near one slot per iteration
+        assert!(VM_INSTANCES.read().capacity() < 64);
+    }
+
+    #[test]
+    fn test_stale_handle_is_rejected_after_slot_reuse() {
+        let mut old = 0;
+        assert_eq!(nanocore_vm_create(0x1000, &mut old), NANO_OK);
+        assert_eq!(nanocore_vm_is_valid(old), NANO_OK);
+        assert_eq!(nanocore_vm_set_register(old, 1, 5), NANO_OK);
+        assert_eq!(nanocore_vm_destroy(old), NANO_OK);
+        assert_eq!(nanocore_vm_is_valid(old), NANO_EINVAL);
+
+        // Keep creating until the old slot comes back; another test may
+        // briefly hold it
+        let mut held = Vec::new();
+        let new = loop {
+            let mut handle = 0;
+            assert_eq!(nanocore_vm_create(0x1000, &mut handle), NANO_OK);
+            if handle & 0xFFFF == old & 0xFFFF {
+                break handle;
+            }
+            held.push(handle);
+        };
+        assert_ne!(new, old);
+        assert_eq!(nanocore_vm_is_valid(new), NANO_OK);
+
+        let mut value = 0;
+        assert_eq!(nanocore_vm_get_register(old, 1, &mut value), NANO_EINVAL);
+        assert_eq!(nanocore_vm_set_register(old, 1, 7), NANO_EINVAL);
+        assert_eq!(nanocore_vm_destroy(old), NANO_EINVAL);
+        assert_eq!(nanocore_vm_get_register(new, 1, &mut value), NANO_OK);
+        assert_eq!(value, 0);
+
+        for handle in held.into_iter().chain([new]) {
+            assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+        }
+    }
+
+    #[test]
+    fn test_fill_and_copy_memory() {
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x10000, &mut handle), NANO_OK);
+        let data = [1u8, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(nanocore_vm_write_memory(handle, 0x100, data.as_ptr(), 8), NANO_OK);
+        
+        assert_eq!(nanocore_vm_copy_memory(handle, 0x102, 0x100, 6), NANO_OK);
+        assert_eq!(nanocore_vm_fill_memory(handle, 0x100, 0xAA, 2), NANO_OK);
+        let mut buffer = [0u8; 8];
+        assert_eq!(nanocore_vm_read_memory(handle, 0x100, buffer.as_mut_ptr(), 8), NANO_OK);
+        assert_eq!(buffer, [0xAA, 0xAA, 1, 2, 3, 4, 5, 6]);
+        
+        assert_eq!(nanocore_vm_fill_memory(handle, 0xFFF9, 0, 8), NANO_EINVAL);
+        assert_eq!(nanocore_vm_fill_memory(handle, u64::MAX, 0, 2), NANO_EINVAL);
+        assert_eq!(nanocore_vm_copy_memory(handle, 0x100, 0xFFF9, 8), NANO_EINVAL);
+        assert_eq!(nanocore_vm_copy_memory(handle, u64::MAX, 0x100, 2), NANO_EINVAL);
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+    }
+    
+    #[test]
+    fn test_wait_event_times_out_and_delivers() {
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x1000, &mut handle), NANO_OK);
+
+        let (mut event_type, mut event_data) = (0, 0);
+        assert_eq!(nanocore_vm_wait_event(handle, 10, &mut event_type, &mut event_data), NANO_ERROR);
+
+        let sender = {
+            let instances = VM_INSTANCES.read();
+            let instance = instances.get(handle).unwrap().lock();
+            instance.event_tx.clone()
+        };
+        let producer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            sender.send(VmEvent::Halted(3)).unwrap();
+        });
+        assert_eq!(nanocore_vm_wait_event(handle, u64::MAX, &mut event_type, &mut event_data), NANO_OK);
+        assert_eq!((event_type, event_data), (0, 3));
+        producer.join().unwrap();
+
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+    }
+
+    #[test]
+    fn test_file_allocator_extends_and_persists() {
+        let path = std::env::temp_dir().join(format!("nanocore-ffi-file-{}", std::process::id()));
+        std::fs::write(&path, b"seed").unwrap();
+
+        {
+            let mut memory = FileAllocator::open(&path).unwrap().allocate(0x2000).unwrap();
+            assert_eq!(&memory[..4], b"seed");
+            assert!(memory[4..].iter().all(|&b| b == 0));
+            memory[0x1000] = 0xAB;
+        }
+
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents.len(), 0x2000);
+        assert_eq!(contents[0x1000], 0xAB);
+
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create_file(ptr::null(), 0x1000, &mut handle), NANO_EINVAL);
+        let missing = CString::new("/nonexistent-dir/memory.img").unwrap();
+        assert_eq!(nanocore_vm_create_file(missing.as_ptr(), 0x1000, &mut handle), NANO_EINVAL);
+    }
+    
+    #[test]
+    fn test_watchpoint_matching() {
+        let watchpoints = [(0x2000, 4, WatchKind::Write), (0x3000, 8, WatchKind::ReadWrite)];
+        // SW R1, 0x2002(R0)
+        let store = decode_access(0x5020_2002, &[0; 32]).unwrap();
+        assert_eq!(store, (true, 0x2002, 4));
+        assert_eq!(watched(&watchpoints, store), Some((0x2002, WatchKind::Write)));
+        assert_eq!(watched(&watchpoints, (false, 0x2000, 4)), None);
+        assert_eq!(watched(&watchpoints, (false, 0x2FFF, 2)), Some((0x2FFF, WatchKind::Read)));
+        assert_eq!(watched(&watchpoints, (true, 0x3008, 1)), None);
+        assert_eq!(encode_event(VmEvent::Watchpoint { address: 0x2002, kind: WatchKind::Write }), (4, 0x2002 | WATCHPOINT_WRITE_FLAG));
+    }
+    
+    #[test]
+    fn test_watched_run_without_limit() {
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x20000, &mut handle), NANO_OK);
+        // LD R1, 7; SW R1, 0x2000(R0); HALT
+        let program: Vec<u8> = [0x3C20_0007u32, 0x5020_2000, 0x8400_0000]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect();
+        assert_eq!(
+            nanocore_vm_load_program(handle, program.as_ptr(), program.len() as u64, 0x10000),
+            NANO_OK
+        );
+        assert_eq!(nanocore_vm_set_watchpoint(handle, 0x2000, 4, 1), NANO_OK);
+        
+        // 0 is unlimited here too, so the run reaches the store
+        assert_eq!(nanocore_vm_run(handle, 0), NANO_OK);
+        let (mut event_type, mut data) = (0, 0);
+        assert_eq!(nanocore_vm_poll_event(handle, &mut event_type, &mut data), NANO_OK);
+        assert_eq!((event_type, data), (4, 0x2000 | WATCHPOINT_WRITE_FLAG));
+        
+        assert_eq!(nanocore_vm_run(handle, 0), NANO_OK);
+        let mut exit_code = 0;
+        assert_eq!(nanocore_vm_get_exit_code(handle, &mut exit_code), NANO_OK);
+        assert_eq!(exit_code, 7);
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+    }
+    
+    #[test]
+    fn test_batch_register_access() {
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x10000, &mut handle), NANO_OK);
+        
+        let values: [u64; 32] = std::array::from_fn(|i| i as u64 * 3 + 1);
+        assert_eq!(nanocore_vm_set_registers(handle, values.as_ptr()), NANO_OK);
+        let mut read = [0u64; 32];
+        assert_eq!(nanocore_vm_get_registers(handle, read.as_mut_ptr()), NANO_OK);
+        assert_eq!(read[0], 0);
+        assert_eq!(read[1..], values[1..]);
+        assert_eq!(nanocore_vm_get_registers(handle, ptr::null_mut()), NANO_EINVAL);
+        
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+    }
+    
+    #[test]
+    fn test_r0_is_hardwired_to_zero() {
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x10000, &mut handle), NANO_OK);
+        
+        assert_eq!(nanocore_vm_set_register(handle, 0, 42), NANO_OK);
+        let mut value = u64::MAX;
+        assert_eq!(nanocore_vm_get_register(handle, 0, &mut value), NANO_OK);
+        assert_eq!(value, 0);
+        
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+    }
+    
+    #[test]
+    fn test_memory_bounds_do_not_overflow() {
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x10000, &mut handle), NANO_OK);
+        
+        let mut buffer = [0u8; 16];
+        assert_eq!(nanocore_vm_read_memory(handle, u64::MAX, buffer.as_mut_ptr(), 16), NANO_EINVAL);
+        assert_eq!(nanocore_vm_write_memory(handle, u64::MAX, buffer.as_ptr(), 16), NANO_EINVAL);
+        assert_eq!(nanocore_vm_load_program(handle, buffer.as_ptr(), 16, u64::MAX), NANO_EINVAL);
+        assert_eq!(nanocore_vm_flush_range(handle, u64::MAX, 16), NANO_EINVAL);
+        assert_eq!(nanocore_vm_read_memory(handle, 0x10000 - 16, buffer.as_mut_ptr(), 16), NANO_OK);
+        
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+    }
+    
+    #[test]
+    fn test_host_memory_access_reaches_devices() {
+        struct Counter {
+            reads: u64,
+            last_write: u64,
+        }
+        
+        impl Device for Counter {
+            fn read(&mut self, offset: u64) -> u64 {
+                self.reads += 1;
+                self.reads << 8 | offset
+            }
+            
+            fn write(&mut self, _offset: u64, value: u64) {
+                self.last_write = value;
+            }
+            
+            fn reset(&mut self) {
+                self.reads = 0;
+            }
+        }
+        
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x10000, &mut handle), NANO_OK);
+        with_vm_instance(handle, |vm| {
+            let mut devices = vm.devices.lock();
+            devices.devices.push(Box::new(Counter { reads: 0, last_write: 0 }));
+            devices.mmio_map.push((0x4000, 0x4010, 0));
+            NANO_OK
+        });
+        
+        let mut value = [0u8; 8];
+        assert_eq!(nanocore_vm_read_memory(handle, 0x4008, value.as_mut_ptr(), 8), NANO_OK);
+        assert_eq!(u64::from_le_bytes(value), 0x108);
+        assert_eq!(nanocore_vm_read_memory(handle, 0x4008, value.as_mut_ptr(), 8), NANO_OK);
+        assert_eq!(u64::from_le_bytes(value), 0x208);
+        
+        // Writes go to the device, not backing memory
+        let data = 0xABCDu64.to_le_bytes();
+        assert_eq!(nanocore_vm_write_memory(handle, 0x4000, data.as_ptr(), 8), NANO_OK);
+        with_vm_instance(handle, |vm| {
+            assert!(vm.memory.read()[0x4000..0x4008].iter().all(|&b| b == 0));
+            NANO_OK
+        });
+        
+        // Ranges straddling the device boundary are rejected
+        assert_eq!(nanocore_vm_read_memory(handle, 0x3FFC, value.as_mut_ptr(), 8), NANO_EINVAL);
+        assert_eq!(nanocore_vm_write_memory(handle, 0x400C, data.as_ptr(), 8), NANO_EINVAL);
+        assert_eq!(nanocore_vm_read_memory(handle, 0x3FF8, value.as_mut_ptr(), 8), NANO_OK);
+        
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+    }
+    
+    #[test]
+    fn test_zero_size_memory_is_rejected() {
+        let mut handle = -1;
+        assert_eq!(nanocore_vm_create(0, &mut handle), NANO_EINVAL);
+        assert_eq!(handle, -1);
+        assert_eq!(vm_create_with_allocator(0, Box::new(AnonAllocator)).err(), Some(NANO_EINVAL));
+    }
+    
+    #[test]
+    fn test_failed_create_explains_why() {
+        let last_error = || {
+            let mut buf = [0 as c_char; 256];
+            let len = nanocore_last_error_message(buf.as_mut_ptr(), buf.len());
+            let message = unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned();
+            assert_eq!(len as usize, message.len());
+            message
+        };
+        
+        let mut handle = -1;
+        assert_eq!(nanocore_vm_create(0, &mut handle), NANO_EINVAL);
+        assert!(last_error().contains("nonzero"));
+        
+        assert_ne!(nanocore_vm_create(u64::MAX, &mut handle), NANO_OK);
+        assert_eq!(handle, -1);
+        assert!(last_error().contains("memory"));
+        
+        // Truncated copies stay NUL-terminated and still report the full length
+        let mut short = [0x7F as c_char; 8];
+        let len = nanocore_last_error_message(short.as_mut_ptr(), short.len());
+        assert!(len > 8);
+        assert_eq!(short[7], 0);
+    }
+    
+    #[test]
+    fn test_version_matches_build_info() {
+        let (mut major, mut minor, mut patch) = (-1, -1, -1);
+        assert_eq!(nanocore_version(&mut major, &mut minor, &mut patch), NANO_OK);
+        assert_eq!(format!("{}.{}.{}", major, minor, patch), env!("CARGO_PKG_VERSION"));
+        assert_eq!(nanocore_version(ptr::null_mut(), &mut minor, ptr::null_mut()), NANO_OK);
+        
+        let info = unsafe { CStr::from_ptr(nanocore_build_info()) }.to_str().unwrap();
+        assert!(info.starts_with(&format!("nanocore-ffi {} (", env!("CARGO_PKG_VERSION"))));
+        assert!(info.ends_with(", assembly core)"));
+    }
+    
+    #[test]
+    fn test_reset_full_zeroes_memory() {
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x10000, &mut handle), NANO_OK);
+        let pattern: Vec<u8> = (0..=255).collect();
+        assert_eq!(nanocore_vm_write_memory(handle, 0x2000, pattern.as_ptr(), 256), NANO_OK);
+        
+        let mut buffer = [0xAAu8; 256];
+        assert_eq!(nanocore_vm_reset(handle), NANO_OK);
+        assert_eq!(nanocore_vm_read_memory(handle, 0x2000, buffer.as_mut_ptr(), 256), NANO_OK);
+        assert_eq!(buffer[..], pattern[..]);
+        
+        assert_eq!(nanocore_vm_reset_full(handle), NANO_OK);
+        assert_eq!(nanocore_vm_read_memory(handle, 0x2000, buffer.as_mut_ptr(), 256), NANO_OK);
+        assert_eq!(buffer, [0; 256]);
+        
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+        assert_eq!(nanocore_vm_reset_full(handle), NANO_EINVAL);
+    }
+    
+    #[test]
+    fn test_capabilities_match_this_backend() {
+        let capabilities = Capabilities::from_bits(nanocore_capabilities()).unwrap();
+        assert!(capabilities.contains(Capabilities::SIMD | Capabilities::WATCHPOINTS));
+        assert!(!capabilities.contains(Capabilities::TRACE));
+    }
+    
+    #[test]
+    fn test_last_error_names_the_failing_call() {
+        let last_error = || {
+            let mut buf = [0 as c_char; 256];
+            assert_eq!(nanocore_last_error(buf.as_mut_ptr(), buf.len()), NANO_OK);
+            unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned()
+        };
+        
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x1000, &mut handle), NANO_OK);
+        let mut byte = 0u8;
+        assert_eq!(nanocore_vm_read_memory(handle, 0xFFF, &mut byte, 2), NANO_EINVAL);
+        assert_eq!(last_error(), "read_memory: address 0xfff out of bounds (size 0x2, memory 0x1000)");
+        
+        // Successful calls leave the message alone
+        assert_eq!(nanocore_vm_read_memory(handle, 0, &mut byte, 1), NANO_OK);
+        assert!(last_error().starts_with("read_memory:"));
+        
+        assert_eq!(nanocore_vm_set_register(handle, 32, 0), NANO_EINVAL);
+        assert_eq!(last_error(), "set_register: register 32 out of range");
+        
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+        assert_eq!(nanocore_vm_step(handle), NANO_EINVAL);
+        assert_eq!(last_error(), format!("step: run: invalid VM handle {}", handle));
+        
+        // Each thread sees only its own failures
+        std::thread::spawn(|| {
+            let mut buf = [0x7F as c_char; 4];
+            assert_eq!(nanocore_last_error(buf.as_mut_ptr(), buf.len()), NANO_OK);
+            assert_eq!(buf[0], 0);
+        })
+        .join()
+        .unwrap();
+        
+        assert_eq!(nanocore_last_error(ptr::null_mut(), 0), NANO_EINVAL);
+        assert_eq!(last_error(), "last_error: null or empty buffer");
+    }
+    
+    #[test]
+    fn test_panics_do_not_cross_the_ffi_boundary() {
+        struct Registers([u64; 2]);
+        
+        impl Device for Registers {
+            fn read(&mut self, offset: u64) -> u64 {
+                // Out of range for any offset past the second register
+                self.0[offset as usize / 8]
+            }
+            
+            fn write(&mut self, offset: u64, value: u64) {
+                self.0[offset as usize / 8] = value;
+            }
+            
+            fn reset(&mut self) {}
+        }
+        
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x10000, &mut handle), NANO_OK);
+        with_vm_instance(handle, |vm| {
+            let mut devices = vm.devices.lock();
+            devices.devices.push(Box::new(Registers([1, 2])));
+            devices.mmio_map.push((0x4000, 0x4020, 0));
+            NANO_OK
+        });
+        
+        let mut value = [0u8; 8];
+        assert_eq!(nanocore_vm_read_memory(handle, 0x4008, value.as_mut_ptr(), 8), NANO_OK);
+        assert_eq!(nanocore_vm_read_memory(handle, 0x4010, value.as_mut_ptr(), 8), NANO_ERROR);
+        
+        // The instance is still usable afterwards
+        assert_eq!(nanocore_vm_read_memory(handle, 0x4000, value.as_mut_ptr(), 8), NANO_OK);
+        assert_eq!(u64::from_le_bytes(value), 1);
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+    }
+    
+    #[test]
+    fn test_init_is_idempotent_across_threads() {
+        let threads: Vec<_> = (0..8).map(|_| std::thread::spawn(|| nanocore_init())).collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), NANO_OK);
+        }
+        assert_eq!(nanocore_init(), NANO_OK);
+        assert_eq!(HOOK_INSTALLS.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+    
+    #[test]
+    fn test_log_callback_receives_caught_panics() {
+        static MESSAGES: Mutex<Vec<(c_int, String)>> = Mutex::new(Vec::new());
+        
+        extern "C" fn record(level: c_int, msg: *const c_char) {
+            let msg = unsafe { CStr::from_ptr(msg) }.to_string_lossy().into_owned();
+            MESSAGES.lock().push((level, msg));
+        }
+        
+        struct Exploding;
+        
+        impl Device for Exploding {
+            fn read(&mut self, _offset: u64) -> u64 {
+                panic!("device exploded");
+            }
+            
+            fn write(&mut self, _offset: u64, _value: u64) {}
+            
+            fn reset(&mut self) {}
+        }
+        
+        assert_eq!(nanocore_init(), NANO_OK);
+        assert_eq!(nanocore_set_log_callback(Some(record)), NANO_OK);
+        
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x10000, &mut handle), NANO_OK);
+        with_vm_instance(handle, |vm| {
+            let mut devices = vm.devices.lock();
+            devices.devices.push(Box::new(Exploding));
+            devices.mmio_map.push((0x4000, 0x4008, 0));
+            NANO_OK
+        });
+        let mut value = [0u8; 8];
+        assert_eq!(nanocore_vm_read_memory(handle, 0x4000, value.as_mut_ptr(), 8), NANO_ERROR);
+        assert_eq!(nanocore_set_log_callback(None), NANO_OK);
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+        
+        // Panics from tests running alongside may have been recorded too
+        assert!(MESSAGES
+            .lock()
+            .iter()
+            .any(|(level, msg)| *level == NANO_LOG_ERROR && msg.contains("device exploded")));
+    }
+    
+    #[test]
+    fn test_format_state_reports_truncation() {
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x10000, &mut handle), NANO_OK);
+        assert_eq!(nanocore_vm_set_register(handle, 5, 0x2A), NANO_OK);
+        
+        let mut needed = 0;
+        assert_eq!(nanocore_vm_format_state(handle, ptr::null_mut(), 0, &mut needed), NANO_OK);
+        
+        let mut buf = vec![0 as c_char; needed as usize + 1];
+        let mut written = 0;
+        assert_eq!(
+            nanocore_vm_format_state(handle, buf.as_mut_ptr(), buf.len() as u64, &mut written),
+            NANO_OK
+        );
+        assert_eq!(written, needed);
+        let text = unsafe { CStr::from_ptr(buf.as_ptr()) }.to_str().unwrap();
+        assert!(text.contains("r5    0x000000000000002a\n"));
+        assert!(!text.contains("r6 "));
+        
+        // A short buffer gets a NUL-terminated prefix and the full length
+        let mut short = [0x7F as c_char; 8];
+        assert_eq!(nanocore_vm_format_state(handle, short.as_mut_ptr(), 8, &mut written), NANO_OK);
+        assert_eq!(written, needed);
+        assert_eq!(unsafe { CStr::from_ptr(short.as_ptr()) }.to_bytes(), b"pc    0");
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+    }
+    
+    #[test]
+    fn test_read_cstring() {
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x10000, &mut handle), NANO_OK);
+        let text = b"hello\0world";
+        assert_eq!(nanocore_vm_write_memory(handle, 0x2000, text.as_ptr(), text.len() as u64), NANO_OK);
+        
+        let mut buf = [0 as c_char; 16];
+        let (mut len, mut terminated) = (0, 0);
+        assert_eq!(
+            nanocore_vm_read_cstring(handle, 0x2000, buf.as_mut_ptr(), 16, &mut len, &mut terminated),
+            NANO_OK
+        );
+        assert_eq!((len, terminated), (5, 1));
+        assert_eq!(unsafe { CStr::from_ptr(buf.as_ptr()) }.to_bytes(), b"hello");
+        
+        // Cut short by the buffer: the prefix is kept and flagged
+        assert_eq!(
+            nanocore_vm_read_cstring(handle, 0x2000, buf.as_mut_ptr(), 4, &mut len, &mut terminated),
+            NANO_OK
+        );
+        assert_eq!((len, terminated), (3, 0));
+        assert_eq!(unsafe { CStr::from_ptr(buf.as_ptr()) }.to_bytes(), b"hel");
+        
+        assert_eq!(
+            nanocore_vm_read_cstring(handle, 0x10001, buf.as_mut_ptr(), 16, &mut len, ptr::null_mut()),
+            NANO_EINVAL
+        );
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+    }
+    
+    #[test]
+    fn test_reset_perf_counters() {
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x10000, &mut handle), NANO_OK);
+        with_vm_instance(handle, |vm| {
+            vm.state.write().perf_counters = [7; 8];
+            NANO_OK
+        });
+        
+        assert_eq!(nanocore_vm_reset_perf_counters(handle), NANO_OK);
+        let mut value = 1;
+        for counter in 0..8 {
+            assert_eq!(nanocore_vm_get_perf_counter(handle, counter, &mut value), NANO_OK);
+            assert_eq!(value, 0);
+        }
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+        assert_eq!(nanocore_vm_reset_perf_counters(handle), NANO_EINVAL);
+    }
+    
+    #[test]
+    fn test_list_breakpoints() {
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x10000, &mut handle), NANO_OK);
+        with_vm_instance(handle, |vm| {
+            vm.breakpoints.write().extend([0x1000, 0x2000, 0x3000]);
+            NANO_OK
+        });
+        
+        let mut count = 0;
+        assert_eq!(nanocore_vm_list_breakpoints(handle, ptr::null_mut(), 0, &mut count), NANO_OK);
+        assert_eq!(count, 3);
+        
+        let mut addresses = [0; 2];
+        assert_eq!(nanocore_vm_list_breakpoints(handle, addresses.as_mut_ptr(), 2, &mut count), NANO_OK);
+        assert_eq!((addresses, count), ([0x1000, 0x2000], 3));
+        assert_eq!(nanocore_vm_clear_breakpoint(handle, 0x2000), NANO_OK);
+        
+        let mut addresses = [0; 4];
+        assert_eq!(nanocore_vm_list_breakpoints(handle, addresses.as_mut_ptr(), 4, &mut count), NANO_OK);
+        assert_eq!(&addresses[..count as usize], [0x1000, 0x3000]);
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+    }
+    
+    #[test]
+    fn test_run_until_event() {
+        let mut handle = 0;
+        assert_eq!(nanocore_vm_create(0x20000, &mut handle), NANO_OK);
+        // LD R1, 7; HALT
+        let program: Vec<u8> = [0x0Fu32 << 26 | 1 << 21 | 7, 0x21 << 26]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect();
+        assert_eq!(
+            nanocore_vm_load_program(handle, program.as_ptr(), program.len() as u64, 0x10000),
+            NANO_OK
+        );
+        
+        let (mut event_type, mut event_data, mut status) = (-1, 0, 0);
+        assert_eq!(
+            nanocore_vm_run_until_event(handle, 100, &mut event_type, &mut event_data, &mut status),
+            NANO_OK
+        );
+        assert_eq!((event_type, event_data), (0, 7));
+        
+        // Zeroed memory decodes as ADD R0, R0, R0, which never stops
+        assert_eq!(nanocore_vm_reset(handle), NANO_OK);
+        with_vm_instance(handle, |vm| {
+            vm.state.write().pc = 0x18000;
+            NANO_OK
+        });
+        assert_eq!(
+            nanocore_vm_run_until_event(handle, 10, &mut event_type, &mut event_data, &mut status),
+            NANO_ERROR
+        );
+        assert_eq!(status, NANO_OK);
+        assert_eq!(
+            nanocore_vm_run_until_event(handle, 10, ptr::null_mut(), &mut event_data, &mut status),
+            NANO_EINVAL
+        );
+        assert_eq!(nanocore_vm_destroy(handle), NANO_OK);
+    }
+}
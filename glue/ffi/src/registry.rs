@@ -0,0 +1,148 @@
+//! Handle registry for live VM instances
+//!
+//! Handles pack a slot index in the low 16 bits and that slot's generation
+//! in the bits above. Removing a value bumps its slot's generation and puts
+//! the slot on a free list; the lowest free slot is reused by the next
+//! insert, so the registry only grows to the peak number of live instances
+//! and stale handles are rejected rather than aliasing whatever now
+//! occupies the slot.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::os::raw::c_int;
+
+const INDEX_BITS: u32 = 16;
+const INDEX_MASK: u32 = (1 << INDEX_BITS) - 1;
+/// Generations wrap within 15 bits so handles stay positive
+const GENERATION_MASK: u32 = 0x7FFF;
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+/// Slot map from handles to values
+pub(crate) struct Registry<T> {
+    slots: Vec<Slot<T>>,
+    /// Freed indices, popped lowest first
+    free: BinaryHeap<Reverse<usize>>,
+}
+
+impl<T> Registry<T> {
+    pub const fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            free: BinaryHeap::new(),
+        }
+    }
+
+    fn handle(index: usize, generation: u32) -> c_int {
+        ((generation << INDEX_BITS) | index as u32) as c_int
+    }
+
+    /// Split a handle into its slot index and generation, if well formed
+    fn decode(handle: c_int) -> Option<(usize, u32)> {
+        if handle < 0 {
+            return None;
+        }
+        let handle = handle as u32;
+        Some(((handle & INDEX_MASK) as usize, handle >> INDEX_BITS))
+    }
+
+    /// Store `value`, reusing the lowest free slot if there is one
+    ///
+    /// Returns `None` once every index is in use.
+    pub fn insert(&mut self, value: T) -> Option<c_int> {
+        if let Some(Reverse(index)) = self.free.pop() {
+            let slot = &mut self.slots[index];
+            slot.value = Some(value);
+            return Some(Self::handle(index, slot.generation));
+        }
+
+        let index = self.slots.len();
+        if index > INDEX_MASK as usize {
+            return None;
+        }
+        self.slots.push(Slot {
+            generation: 0,
+            value: Some(value),
+        });
+        Some(Self::handle(index, 0))
+    }
+
+    /// Look up the value for a live handle
+    pub fn get(&self, handle: c_int) -> Option<&T> {
+        let (index, generation) = Self::decode(handle)?;
+        let slot = self.slots.get(index)?;
+        if slot.generation != generation {
+            return None;
+        }
+        slot.value.as_ref()
+    }
+
+    /// Remove and return the value for a live handle, freeing its slot
+    pub fn remove(&mut self, handle: c_int) -> Option<T> {
+        let (index, generation) = Self::decode(handle)?;
+        let slot = self.slots.get_mut(index)?;
+        if slot.generation != generation {
+            return None;
+        }
+        let value = slot.value.take()?;
+        slot.generation = (slot.generation + 1) & GENERATION_MASK;
+        self.free.push(Reverse(index));
+        Some(value)
+    }
+
+    /// Number of slots allocated, live or free
+    #[cfg(test)]
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_are_reused() {
+        let mut registry = Registry::new();
+        let a = registry.insert("a").unwrap();
+        let b = registry.insert("b").unwrap();
+        assert_eq!(registry.remove(a), Some("a"));
+
+        let c = registry.insert("c").unwrap();
+        assert_eq!(registry.capacity(), 2);
+        assert_eq!(registry.get(b), Some(&"b"));
+        assert_eq!(registry.get(c), Some(&"c"));
+    }
+
+    #[test]
+    fn test_lowest_free_slot_is_reused_first() {
+        let mut registry = Registry::new();
+        let handles: Vec<_> = (0..4).map(|i| registry.insert(i).unwrap()).collect();
+        registry.remove(handles[3]);
+        registry.remove(handles[1]);
+        registry.remove(handles[2]);
+
+        let reused = registry.insert(10).unwrap();
+        assert_eq!(Registry::<i32>::decode(reused), Some((1, 1)));
+        let reused = registry.insert(11).unwrap();
+        assert_eq!(Registry::<i32>::decode(reused), Some((2, 1)));
+        assert_eq!(registry.capacity(), 4);
+    }
+
+    #[test]
+    fn test_stale_handles_are_rejected() {
+        let mut registry = Registry::new();
+        let old = registry.insert(1).unwrap();
+        registry.remove(old);
+        let new = registry.insert(2).unwrap();
+
+        assert_ne!(old, new);
+        assert_eq!(registry.get(old), None);
+        assert_eq!(registry.remove(old), None);
+        assert_eq!(registry.get(new), Some(&2));
+        assert_eq!(registry.get(-1), None);
+    }
+}
@@ -1,10 +1,24 @@
 use std::env;
-use std::path::PathBuf;
 
 fn main() {
+    println!("cargo:rustc-check-cfg=cfg(nanocore_native)");
+    println!("cargo:rerun-if-env-changed=NANOCORE_NO_NATIVE");
+
+    // Reported by `nanocore::build_info`
+    println!("cargo:rustc-env=NANOCORE_TARGET={}", env::var("TARGET").unwrap());
+
+    // Tell cargo to invalidate the built crate whenever the wrapper changes
+    println!("cargo:rerun-if-changed=../../glue/ffi/nanocore_ffi.c");
+
+    // The C core does not build for wasm; the `interp` feature stands in for it
+    let target_arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    if target_arch == "wasm32" || env::var_os("NANOCORE_NO_NATIVE").is_some() {
+        return;
+    }
+
     // Get the output directory
     let out_dir = env::var("OUT_DIR").unwrap();
-    
+
     // Build the FFI library
     cc::Build::new()
         .file("../../glue/ffi/nanocore_ffi.c")
@@ -12,11 +26,9 @@ fn main() {
         .opt_level(2)
         .flag("-fPIC")
         .compile("nanocore_ffi");
-    
-    // Tell cargo to invalidate the built crate whenever the wrapper changes
-    println!("cargo:rerun-if-changed=../../glue/ffi/nanocore_ffi.c");
-    
+
     // Link to the library
     println!("cargo:rustc-link-lib=static=nanocore_ffi");
     println!("cargo:rustc-link-search=native={}", out_dir);
-}
\ No newline at end of file
+    println!("cargo:rustc-cfg=nanocore_native");
+}
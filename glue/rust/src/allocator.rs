@@ -0,0 +1,180 @@
+//! Choosing where guest memory lives
+//!
+//! `VM::new` lets the core allocate guest memory itself. Embedders that need
+//! it somewhere specific, such as huge pages to cut TLB pressure on large
+//! guests or a mapping bound to one NUMA node, pass a `MemoryAllocator` to
+//! `VM::new_with_allocator` instead. Any owned, mutable byte buffer works as
+//! the region, including `memmap2::MmapMut`:
+//!
+//! ```ignore
+//! struct HugePages;
+//!
+//! impl MemoryAllocator for HugePages {
+//!     fn allocate(&self, size: usize) -> std::io::Result<GuestMemory> {
+//!         Ok(Box::new(memmap2::MmapOptions::new().len(size).huge(None).map_anon()?))
+//!     }
+//! }
+//! ```
+
+use std::ffi::c_void;
+use std::io;
+use std::ops::DerefMut;
+
+use crate::{check_create, ffi, Error, Result, Status, VM};
+
+/// A region of guest memory, freed when dropped
+pub type GuestMemory = Box<dyn DerefMut<Target = [u8]> + Send>;
+
+/// Source of the region backing a VM's memory
+pub trait MemoryAllocator {
+    /// Allocate exactly `size` zeroed bytes
+    fn allocate(&self, size: usize) -> io::Result<GuestMemory>;
+}
+
+/// Ordinary zeroed heap memory, as `VM::new` uses
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapAllocator;
+
+impl MemoryAllocator for HeapAllocator {
+    fn allocate(&self, size: usize) -> io::Result<GuestMemory> {
+        let mut memory = Vec::new();
+        memory
+            .try_reserve_exact(size)
+            .map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        memory.resize(size, 0);
+        Ok(Box::new(memory))
+    }
+}
+
+/// Called by the core when a VM created by `new_with_allocator` is destroyed
+extern "C" fn release_guest_memory(context: *mut c_void) {
+    drop(unsafe { Box::from_raw(context.cast::<GuestMemory>()) });
+}
+
+impl VM {
+    /// Create a VM whose memory comes from `alloc`
+    ///
+    /// The region is handed to the core for the life of the VM and dropped
+    /// when the VM is. Fails if the allocator fails or returns a region of
+    /// the wrong size.
+    pub fn new_with_allocator(memory_size: u64, alloc: Box<dyn MemoryAllocator>) -> Result<Self> {
+        let size = usize::try_from(memory_size).map_err(|_| Error {
+            status: Status::OutOfMemory,
+            message: format!("Cannot allocate {} bytes of guest memory", memory_size),
+        })?;
+        let mut memory = alloc.allocate(size).map_err(|error| Error {
+            status: Status::OutOfMemory,
+            message: format!("Cannot allocate {} bytes of guest memory: {}", memory_size, error),
+        })?;
+        if memory.len() != size {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Allocator returned {} bytes, expected {}", memory.len(), size),
+            });
+        }
+
+        let data = memory.as_mut_ptr();
+        let context = Box::into_raw(Box::new(memory)).cast::<c_void>();
+        let mut handle = 0;
+        let result = unsafe {
+            ffi::nanocore_vm_create_with_memory(data, memory_size, release_guest_memory, context, &mut handle)
+        };
+        if result != 0 {
+            // The core only takes the region on success
+            release_guest_memory(context);
+        }
+        check_create(result, "create VM")?;
+
+        Ok(VM::with_handle(handle, memory_size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Heap memory that records its allocation and release
+    struct Tracked {
+        calls: Arc<AtomicUsize>,
+        released: Arc<AtomicBool>,
+    }
+
+    struct TrackedMemory {
+        bytes: Vec<u8>,
+        released: Arc<AtomicBool>,
+    }
+
+    impl std::ops::Deref for TrackedMemory {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            &self.bytes
+        }
+    }
+
+    impl DerefMut for TrackedMemory {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            &mut self.bytes
+        }
+    }
+
+    impl Drop for TrackedMemory {
+        fn drop(&mut self) {
+            self.released.store(true, Ordering::SeqCst);
+        }
+    }
+
+    impl MemoryAllocator for Tracked {
+        fn allocate(&self, size: usize) -> io::Result<GuestMemory> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            // Marked so the test can tell this region is the one in use
+            let mut bytes = vec![0; size];
+            bytes[0x100] = 0xA5;
+            Ok(Box::new(TrackedMemory {
+                bytes,
+                released: self.released.clone(),
+            }))
+        }
+    }
+
+    #[test]
+    fn test_allocator_backs_guest_memory() {
+        init().unwrap();
+        let (calls, released) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicBool::new(false)));
+        let alloc = Tracked {
+            calls: calls.clone(),
+            released: released.clone(),
+        };
+
+        let mut vm = VM::new_with_allocator(0x20000, Box::new(alloc)).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(vm.read_memory(0x100, 1).unwrap(), [0xA5]);
+        vm.write_memory(0x200, b"guest").unwrap();
+        assert_eq!(vm.read_memory(0x200, 5).unwrap(), b"guest");
+        assert!(!released.load(Ordering::SeqCst));
+        drop(vm);
+        assert!(released.load(Ordering::SeqCst));
+
+        let vm = VM::new_with_allocator(0x1000, Box::new(HeapAllocator)).unwrap();
+        assert_eq!(vm.read_memory(0, 0x1000).unwrap(), vec![0; 0x1000]);
+    }
+
+    #[test]
+    fn test_allocator_size_mismatch_is_rejected() {
+        struct Short;
+
+        impl MemoryAllocator for Short {
+            fn allocate(&self, size: usize) -> io::Result<GuestMemory> {
+                Ok(Box::new(vec![0u8; size / 2]))
+            }
+        }
+
+        init().unwrap();
+        let error = VM::new_with_allocator(0x1000, Box::new(Short)).err().unwrap();
+        assert_eq!(error.status, Status::InvalidParameter);
+        assert!(VM::new_with_allocator(0, Box::new(HeapAllocator)).is_err());
+    }
+}
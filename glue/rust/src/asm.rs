@@ -0,0 +1,250 @@
+//! Line assembler for test programs and patching guest code
+//!
+//! Accepts the syntax printed by the disassembler, one instruction per line:
+//!
+//! ```text
+//! LD R1, 42          ; comments start with a semicolon
+//! ST R1, -8(R2)
+//! BEQ R1, R2, 0x10008
+//! .word 0xdeadbeef
+//! ```
+//!
+//! Immediates are decimal or `0x` hex. Branch and call targets are absolute
+//! addresses, so `assemble` lays code out for the VM's entry point at
+//! 0x10000; use `assemble_at` for code loaded anywhere else:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! nanocore::init()?;
+//! let mut vm = nanocore::VM::new(1024 * 1024)?;
+//! let program = nanocore::asm::assemble("LD R1, 42\nADD R3, R1, R1\nHALT")?;
+//! vm.load_program(&program, 0x10000)?;
+//! vm.run(None)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Labels and directives other than `.word` are not supported; use the
+//! Python assembler for larger programs.
+
+use crate::disasm::{shape, Shape};
+use crate::opcode::Opcode;
+use crate::{Error, Result, Status, VM};
+
+/// Where `VM::new` starts executing
+const ENTRY_POINT: u64 = 0x10000;
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn register(text: &str, prefix: char, count: u32) -> std::result::Result<u32, String> {
+    text.strip_prefix(prefix)
+        .or_else(|| text.strip_prefix(prefix.to_ascii_lowercase()))
+        .and_then(|index| index.parse().ok())
+        .filter(|&index| index < count)
+        .ok_or_else(|| format!("expected {}0-{}{}, got '{}'", prefix, prefix, count - 1, text))
+}
+
+fn imm16(text: &str) -> std::result::Result<u32, String> {
+    parse_number(text)
+        .filter(|value| (-0x8000..=0x7FFF).contains(value))
+        .map(|value| value as u32 & 0xFFFF)
+        .ok_or_else(|| format!("expected a 16-bit signed immediate, got '{}'", text))
+}
+
+/// Parse `offset(Rn)` into `(base, imm16)`
+fn memory(text: &str) -> std::result::Result<(u32, u32), String> {
+    let (offset, rest) = text
+        .split_once('(')
+        .ok_or_else(|| format!("expected offset(Rn), got '{}'", text))?;
+    let base = rest
+        .strip_suffix(')')
+        .ok_or_else(|| format!("expected offset(Rn), got '{}'", text))?;
+    let offset = if offset.is_empty() { 0 } else { imm16(offset)? };
+    Ok((register(base.trim(), 'R', 32)?, offset))
+}
+
+/// PC-relative offset to `text`, in units of `scale` bytes, fitting `bits`
+fn relative(address: u64, text: &str, scale: i64, bits: u32) -> std::result::Result<u32, String> {
+    let target = parse_number(text).ok_or_else(|| format!("expected a target address, got '{}'", text))?;
+    let delta = target.wrapping_sub(address as i64);
+    let limit = 1i64 << (bits - 1);
+    if delta % scale != 0 || !(-limit..limit).contains(&(delta / scale)) {
+        return Err(format!("target {} is out of range from {:#x}", text, address));
+    }
+    Ok((delta / scale) as u32 & ((1 << bits) - 1))
+}
+
+fn encode(address: u64, opcode: Opcode, operands: &[&str]) -> std::result::Result<u32, String> {
+    let expected = match shape(opcode) {
+        Shape::None => 0,
+        Shape::Reg | Shape::Call | Shape::Trap | Shape::Mem | Shape::Imm => 1,
+        Shape::RegReg | Shape::RegImm | Shape::RegMem | Shape::Reserved | Shape::VectorMem | Shape::Broadcast => 2,
+        Shape::RegRegReg | Shape::Branch | Shape::Atomic | Shape::Vector => 3,
+    };
+    if operands.len() != expected {
+        return Err(format!("{} takes {} operands, got {}", opcode, expected, operands.len()));
+    }
+
+    let rd = |text| register(text, 'R', 32).map(|r| r << 21);
+    let rs1 = |text| register(text, 'R', 32).map(|r| r << 16);
+    let rs2 = |text| register(text, 'R', 32).map(|r| r << 11);
+    let vd = |text| register(text, 'V', 16).map(|v| v << 21);
+    let mem = |text| memory(text).map(|(base, offset)| base << 16 | offset);
+
+    let fields = match shape(opcode) {
+        Shape::None => 0,
+        Shape::Reg => rd(operands[0])?,
+        Shape::RegReg => rd(operands[0])? | rs1(operands[1])?,
+        Shape::RegRegReg => rd(operands[0])? | rs1(operands[1])? | rs2(operands[2])?,
+        Shape::RegImm => rd(operands[0])? | imm16(operands[1])?,
+        Shape::RegMem => rd(operands[0])? | mem(operands[1])?,
+        Shape::Branch => rd(operands[0])? | rs1(operands[1])? | relative(address, operands[2], 2, 16)?,
+        Shape::Call => relative(address, operands[0], 4, 26)?,
+        Shape::Trap => parse_number(operands[0])
+            .filter(|value| (0..1 << 26).contains(value))
+            .ok_or_else(|| format!("expected a 26-bit immediate, got '{}'", operands[0]))?
+            as u32,
+        Shape::Mem => mem(operands[0])?,
+        Shape::Imm => imm16(operands[0])?,
+        Shape::Reserved => rd(operands[0])? | reserved_base(operands[1])?,
+        Shape::Atomic => rd(operands[0])? | rs2(operands[1])? | reserved_base(operands[2])?,
+        Shape::Vector => vd(operands[0])? | register(operands[1], 'V', 16)? << 16 | register(operands[2], 'V', 16)? << 11,
+        Shape::VectorMem => vd(operands[0])? | mem(operands[1])?,
+        Shape::Broadcast => vd(operands[0])? | rs1(operands[1])?,
+    };
+    Ok((opcode.as_u8() as u32) << 26 | fields)
+}
+
+/// Base register of an `LR`/`SC`/AMO address, which takes no offset
+fn reserved_base(text: &str) -> std::result::Result<u32, String> {
+    match memory(text)? {
+        (base, 0) => Ok(base << 16),
+        _ => Err(format!("'{}' cannot have an offset", text)),
+    }
+}
+
+fn assemble_line(address: u64, line: &str) -> std::result::Result<u32, String> {
+    let (mnemonic, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let operands: Vec<&str> = rest.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+
+    if mnemonic == ".word" {
+        return match operands[..] {
+            [value] => parse_number(value)
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| format!("invalid word '{}'", value)),
+            _ => Err(".word takes one operand".to_string()),
+        };
+    }
+    let opcode = Opcode::ALL
+        .iter()
+        .copied()
+        .find(|op| op.mnemonic().eq_ignore_ascii_case(mnemonic))
+        .ok_or_else(|| format!("unknown mnemonic '{}'", mnemonic))?;
+    encode(address, opcode, &operands)
+}
+
+/// Assemble `source` as a program loaded at the entry point, 0x10000
+///
+/// Returns the instruction words, most-significant byte first as
+/// `VM::load_program` expects.
+pub fn assemble(source: &str) -> Result<Vec<u8>> {
+    assemble_at(ENTRY_POINT, source)
+}
+
+/// Assemble `source` as if placed at `address`
+pub fn assemble_at(address: u64, source: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let pc = address + bytes.len() as u64;
+        let word = assemble_line(pc, line).map_err(|message| Error {
+            status: Status::InvalidParameter,
+            message: format!("line {}: {}", index + 1, message),
+        })?;
+        bytes.extend_from_slice(&word.to_be_bytes());
+    }
+    Ok(bytes)
+}
+
+impl VM {
+    /// Assemble `asm_source` and write it over the code at `address`
+    ///
+    /// Returns the number of bytes written. The address must be
+    /// instruction-aligned and the patch must fit in guest memory; use
+    /// `patch_within` to also bound it to a region.
+    pub fn patch(&mut self, address: u64, asm_source: &str) -> Result<usize> {
+        self.patch_within(address, asm_source, self.memory_size.saturating_sub(address))
+    }
+
+    /// Like `patch`, but refuse patches longer than `limit` bytes
+    ///
+    /// Nothing is written if the check fails, so a patch meant to replace
+    /// one instruction (`limit` 4) cannot spill into the next.
+    pub fn patch_within(&mut self, address: u64, asm_source: &str, limit: u64) -> Result<usize> {
+        if !address.is_multiple_of(4) {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Patch address {:#x} is not instruction-aligned", address),
+            });
+        }
+        let bytes = assemble_at(address, asm_source)?;
+        let len = bytes.len() as u64;
+        if len > limit || address.checked_add(len).is_none_or(|end| end > self.memory_size) {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Patch of {} bytes at {:#x} exceeds its {} byte region", len, address, limit),
+            });
+        }
+
+        self.write_memory(address, &bytes)?;
+        Ok(bytes.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::decode;
+
+    #[test]
+    fn test_round_trip_through_disassembler() {
+        let words = [0x3C20002Au32, 0x00611000, 0x84000000, 0xDC000000, 0x4C22FFF8, 0x5C220004];
+        for &word in &words {
+            let text = decode(0x10000, word).to_string();
+            let bytes = assemble(&text).unwrap();
+            assert_eq!(bytes, word.to_be_bytes(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_multiple_lines_and_comments() {
+        let source = "; setup\n  ld r1, 0x10\n\n  BNE R1, R0, 0x1000  ; back to start\n  .word 0xdeadbeef\n";
+        let bytes = assemble_at(0x1000, source).unwrap();
+        assert_eq!(bytes.len(), 12);
+        assert_eq!(decode(0x1004, u32::from_be_bytes(bytes[4..8].try_into().unwrap())).to_string(), "BNE R1, R0, 0x1000");
+        assert_eq!(bytes[8..], [0xDE, 0xAD, 0xBE, 0xEF]);
+    }
+
+    #[test]
+    fn test_errors_name_the_line() {
+        let error = assemble_at(0, "NOP\nADD R1, R2").unwrap_err();
+        assert_eq!(error.message, "line 2: ADD takes 3 operands, got 2");
+        assert!(assemble_at(0, "LD R32, 1").is_err());
+        assert!(assemble_at(0, "LD R1, 40000").is_err());
+        assert!(assemble_at(0, "BEQ R1, R2, 0x3").is_err());
+        assert!(assemble_at(0, "FROB R1").is_err());
+    }
+}
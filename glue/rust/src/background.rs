@@ -0,0 +1,201 @@
+//! Running a VM on its own thread
+//!
+//! `VM::spawn` moves the VM into a worker thread that runs it in batches of
+//! `BATCH` instructions. Between batches the worker checks the stop flag and
+//! the pause state and forwards queued events, so control requests take
+//! effect within one batch.
+
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::log::{log, panic_message, Level};
+use crate::{Event, RunOutcome, VM};
+
+/// Instructions run between checks of the control state
+const BATCH: u64 = 10_000;
+
+/// State shared between a `RunningVm` and its worker
+#[derive(Default)]
+struct Control {
+    stop: AtomicBool,
+    paused: Mutex<bool>,
+    resumed: Condvar,
+}
+
+impl Control {
+    /// Block while paused; `false` once a stop has been requested
+    fn wait_runnable(&self) -> bool {
+        let mut paused = self.paused.lock().unwrap();
+        while *paused && !self.stop.load(Ordering::Acquire) {
+            paused = self.resumed.wait(paused).unwrap();
+        }
+        !self.stop.load(Ordering::Acquire)
+    }
+}
+
+/// Handle to a VM running on a background thread
+///
+/// Created by `VM::spawn`. The worker runs until the guest halts, faults or
+/// hits a breakpoint, or until `stop` is called. Dropping the handle stops
+/// the worker and joins it, dropping the VM.
+pub struct RunningVm {
+    control: Arc<Control>,
+    events: Receiver<Event>,
+    worker: Option<JoinHandle<VM>>,
+}
+
+impl RunningVm {
+    /// Suspend execution after the current batch
+    pub fn pause(&self) {
+        *self.control.paused.lock().unwrap() = true;
+    }
+
+    /// Continue after `pause`
+    pub fn resume(&self) {
+        *self.control.paused.lock().unwrap() = false;
+        self.control.resumed.notify_all();
+    }
+
+    /// Whether the worker has stopped on its own or been stopped
+    pub fn is_finished(&self) -> bool {
+        self.worker.as_ref().is_none_or(|worker| worker.is_finished())
+    }
+
+    /// Wait for the next event the VM raises
+    ///
+    /// Delivers the same events as `VM::poll_event`, plus a final `Halted`,
+    /// `Breakpoint` or `Exception` event when the run ends that way. Returns `None` once
+    /// the worker has finished and every event has been received. Blocks
+    /// indefinitely while the VM is paused.
+    pub fn recv_event(&self) -> Option<Event> {
+        self.events.recv().ok()
+    }
+
+    /// Stop the worker after its current batch and take the VM back
+    ///
+    /// A panic on the worker thread is propagated to the caller.
+    pub fn stop(mut self) -> VM {
+        match self.shutdown() {
+            Some(Ok(vm)) => vm,
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => unreachable!("worker is only taken by stop or drop"),
+        }
+    }
+
+    fn shutdown(&mut self) -> Option<thread::Result<VM>> {
+        self.control.stop.store(true, Ordering::Release);
+        self.resume();
+        self.worker.take().map(JoinHandle::join)
+    }
+}
+
+impl Drop for RunningVm {
+    fn drop(&mut self) {
+        if let Some(Err(payload)) = self.shutdown() {
+            log(
+                Level::Error,
+                format_args!("background VM worker panicked: {}", panic_message(&*payload)),
+            );
+        }
+    }
+}
+
+impl VM {
+    /// Run the VM on a background thread
+    ///
+    /// Execution starts immediately and continues until the guest halts,
+    /// faults or hits a breakpoint, or the returned handle stops it. An
+    /// error from the core also ends the run.
+    pub fn spawn(self) -> RunningVm {
+        let control = Arc::new(Control::default());
+        let (sender, events) = mpsc::channel();
+        let worker = {
+            let control = control.clone();
+            thread::spawn(move || self.run_in_background(&control, &sender))
+        };
+        RunningVm {
+            control,
+            events,
+            worker: Some(worker),
+        }
+    }
+
+    fn run_in_background(mut self, control: &Control, sender: &Sender<Event>) -> VM {
+        while control.wait_runnable() {
+            let outcome = self.run_outcome(Some(BATCH));
+
+            let queued: Vec<Event> = self.events.lock().unwrap().events.drain(..).collect();
+            for event in queued {
+                let _ = sender.send(event);
+            }
+
+            match outcome {
+                Ok(RunOutcome::Limit) => continue,
+                Ok(_) => {
+                    if let Some(event) = self.stop_event() {
+                        let _ = sender.send(event);
+                    }
+                }
+                Err(error) => log(Level::Error, format_args!("background run failed: {}", error)),
+            }
+            break;
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init, EventType};
+
+    #[test]
+    fn test_spawned_vm_runs_to_halt() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        // LD R1, 7; HALT
+        let program = [0x0Fu32 << 26 | 1 << 21 | 7, 0x21 << 26]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect::<Vec<u8>>();
+        vm.load_program(&program, 0x10000).unwrap();
+
+        let running = vm.spawn();
+        let event = running.recv_event().unwrap();
+        assert_eq!((event.event_type, event.data), (EventType::Halted, 7));
+        assert!(running.recv_event().is_none());
+        assert_eq!(running.stop().get_register(1).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_pause_resume_and_stop() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        // JMP R0, 0(R2) back to itself forever
+        vm.set_register(2, 0x10000).unwrap();
+        vm.load_program(&(0x1Du32 << 26 | 2 << 16).to_be_bytes(), 0x10000).unwrap();
+
+        let running = vm.spawn();
+        running.pause();
+        running.resume();
+        assert!(!running.is_finished());
+
+        let vm = running.stop();
+        assert_eq!(vm.get_state().unwrap().pc, 0x10000);
+    }
+
+    #[test]
+    fn test_dropping_the_handle_stops_the_worker() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        vm.set_register(2, 0x10000).unwrap();
+        vm.load_program(&(0x1Du32 << 26 | 2 << 16).to_be_bytes(), 0x10000).unwrap();
+
+        let running = vm.spawn();
+        running.pause();
+        drop(running);
+    }
+}
@@ -0,0 +1,108 @@
+//! Memory bandwidth ceiling
+//!
+//! Models a memory system that can move at most a fixed number of bytes per
+//! cycle. Bandwidth accrues as cycles pass, up to a short burst allowance;
+//! an access that needs more than is available stalls until enough has
+//! accrued, and the stall cycles are charged to the cycle and pipeline-stall
+//! counters.
+
+use crate::exec::decode_access;
+use crate::opcode::{Category, Opcode};
+use crate::{Error, PerfCounter, Result, Status, VM};
+
+/// Cycles of unused bandwidth that may be banked for a burst
+const BURST_WINDOW_CYCLES: f64 = 64.0;
+/// Slowest cap accepted, so a single access stalls for a bounded number of cycles
+const MIN_BYTES_PER_CYCLE: f64 = 1e-6;
+
+pub(crate) struct BandwidthMeter {
+    bytes_per_cycle: f64,
+    /// Bytes that can be moved without stalling
+    available: f64,
+    /// Cycle count when `available` was last topped up
+    last_cycle: u64,
+}
+
+impl BandwidthMeter {
+    fn new(bytes_per_cycle: f64, cycle: u64) -> Self {
+        Self {
+            bytes_per_cycle,
+            available: bytes_per_cycle * BURST_WINDOW_CYCLES,
+            last_cycle: cycle,
+        }
+    }
+
+    /// Account for `bytes` of traffic at `cycle`, returning the stall cycles
+    fn transfer(&mut self, bytes: u64, cycle: u64) -> u64 {
+        let elapsed = cycle.saturating_sub(self.last_cycle) as f64;
+        let burst = self.bytes_per_cycle * BURST_WINDOW_CYCLES;
+        self.available = (self.available + elapsed * self.bytes_per_cycle).min(burst);
+        self.available -= bytes as f64;
+
+        let stall = if self.available < 0.0 {
+            (-self.available / self.bytes_per_cycle).ceil() as u64
+        } else {
+            0
+        };
+        self.available += stall as f64 * self.bytes_per_cycle;
+        self.last_cycle = cycle.saturating_add(stall);
+        stall
+    }
+}
+
+/// Bytes of memory traffic generated by `instruction`
+fn traffic(instruction: u32, gprs: &[u64; 32]) -> u64 {
+    match Opcode::of(instruction) {
+        Some(Opcode::Vload | Opcode::Vstore) => 32,
+        Some(op) if op.category() == Category::Atomic => 8,
+        Some(Opcode::Lr | Opcode::Sc) => 8,
+        _ => decode_access(instruction, gprs).map_or(0, |access| access.width),
+    }
+}
+
+impl VM {
+    /// Cap guest memory traffic at `bytes_per_cycle`
+    ///
+    /// Accesses beyond the cap add stall cycles to `CycleCount` and
+    /// `PipelineStall`. A value of zero (or any negative value) removes
+    /// the cap. Fails on NaN, infinity and positive caps below one byte
+    /// per million cycles. While a cap is set the VM is stepped from the
+    /// host.
+    pub fn set_memory_bandwidth(&mut self, bytes_per_cycle: f64) -> Result<()> {
+        if !bytes_per_cycle.is_finite() || (bytes_per_cycle > 0.0 && bytes_per_cycle < MIN_BYTES_PER_CYCLE) {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Unsupported memory bandwidth of {} bytes per cycle", bytes_per_cycle),
+            });
+        }
+        self.bandwidth = if bytes_per_cycle > 0.0 {
+            let cycle = self.raw_state()?.perf_counters[PerfCounter::CycleCount as usize];
+            Some(BandwidthMeter::new(bytes_per_cycle, cycle))
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    /// Charge the traffic of a retired instruction against the cap
+    pub(crate) fn charge_bandwidth(&mut self, instruction: u32, gprs: &[u64; 32]) -> Result<()> {
+        let bytes = traffic(instruction, gprs);
+        if bytes == 0 {
+            return Ok(());
+        }
+
+        let mut state = self.raw_state()?;
+        let Some(meter) = self.bandwidth.as_mut() else {
+            return Ok(());
+        };
+        let stall = meter.transfer(bytes, state.perf_counters[PerfCounter::CycleCount as usize]);
+        if stall == 0 {
+            return Ok(());
+        }
+        for counter in [PerfCounter::CycleCount, PerfCounter::PipelineStall] {
+            let count = &mut state.perf_counters[counter as usize];
+            *count = count.saturating_add(stall);
+        }
+        self.set_raw_state(&state)
+    }
+}
@@ -0,0 +1,140 @@
+//! Configuring a VM before it starts
+//!
+//! `VmBuilder` collects everything `VM::new` leaves at its defaults, so a
+//! VM can be set up in one expression:
+//!
+//! ```no_run
+//! use nanocore::builder::VmBuilder;
+//! use nanocore::devices::TimerDevice;
+//!
+//! let vm = VmBuilder::new()
+//!     .memory_size(16 * 1024 * 1024)
+//!     .stack_top(0x80_0000)
+//!     .with_device(0x4000, 0x4010, Box::new(TimerDevice::new()))
+//!     .trace_capacity(256)
+//!     .build()?;
+//! # Ok::<(), nanocore::Error>(())
+//! ```
+
+use std::path::PathBuf;
+
+use crate::devices::Device;
+use crate::{init, Result, VM};
+
+/// Builder for a `VM`; see the module docs
+pub struct VmBuilder {
+    memory_size: u64,
+    file: Option<PathBuf>,
+    stack_top: Option<u64>,
+    devices: Vec<(u64, u64, Box<dyn Device>)>,
+    trace_capacity: usize,
+}
+
+impl Default for VmBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VmBuilder {
+    /// 64 MiB of anonymous memory, no devices and tracing off
+    pub fn new() -> Self {
+        VmBuilder {
+            memory_size: 64 * 1024 * 1024,
+            file: None,
+            stack_top: None,
+            devices: Vec::new(),
+            trace_capacity: 0,
+        }
+    }
+
+    pub fn memory_size(mut self, bytes: u64) -> Self {
+        self.memory_size = bytes;
+        self
+    }
+
+    /// Back guest memory with the file at `path`, as `VM::new_with_file` does
+    pub fn file_backed(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file = Some(path.into());
+        self
+    }
+
+    /// Initial stack pointer, set with `VM::set_sp`
+    pub fn stack_top(mut self, address: u64) -> Self {
+        self.stack_top = Some(address);
+        self
+    }
+
+    /// Map `device` at `[start, end)`, as `VM::attach_device` does
+    pub fn with_device(mut self, start: u64, end: u64, device: Box<dyn Device>) -> Self {
+        self.devices.push((start, end, device));
+        self
+    }
+
+    /// Keep the last `capacity` executed instructions, as `VM::enable_trace` does
+    pub fn trace_capacity(mut self, capacity: usize) -> Self {
+        self.trace_capacity = capacity;
+        self
+    }
+
+    /// Initialize the library if needed and create the configured VM
+    ///
+    /// Fails with the error of the first step that fails, such as an
+    /// overlapping device range or a stack top outside memory.
+    pub fn build(self) -> Result<VM> {
+        init()?;
+        let mut vm = match &self.file {
+            Some(path) => VM::new_with_file(path, self.memory_size)?,
+            None => VM::new(self.memory_size)?,
+        };
+        if let Some(stack_top) = self.stack_top {
+            vm.set_sp(stack_top)?;
+        }
+        for (start, end, device) in self.devices {
+            vm.attach_device(start, end, device)?;
+        }
+        vm.enable_trace(self.trace_capacity);
+        Ok(vm)
+    }
+}
+
+impl VM {
+    /// Start configuring a VM; shorthand for `VmBuilder::new`
+    pub fn builder() -> VmBuilder {
+        VmBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::TimerDevice;
+
+    #[test]
+    fn test_builder_applies_every_setting() {
+        let path = std::env::temp_dir().join(format!("nanocore-builder-{}.bin", std::process::id()));
+        let vm = VM::builder()
+            .memory_size(0x20000)
+            .file_backed(&path)
+            .stack_top(0x1F000)
+            .with_device(0x4000, 0x4010, Box::new(TimerDevice::new()))
+            .trace_capacity(8)
+            .build()
+            .unwrap();
+
+        assert_eq!(vm.memory_size(), 0x20000);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0x20000);
+        assert_eq!(vm.get_sp().unwrap(), 0x1F000);
+        assert_eq!(vm.export_config().devices.len(), 1);
+        assert!(vm.trace_ring.is_some());
+        drop(vm);
+        std::fs::remove_file(&path).unwrap();
+
+        let overlapping = VM::builder()
+            .memory_size(0x20000)
+            .with_device(0x4000, 0x4010, Box::new(TimerDevice::new()))
+            .with_device(0x4008, 0x4018, Box::new(TimerDevice::new()));
+        assert!(overlapping.build().is_err());
+        assert!(VM::builder().memory_size(0x20000).stack_top(0x20001).build().is_err());
+    }
+}
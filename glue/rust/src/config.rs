@@ -0,0 +1,411 @@
+//! Machine configuration export and import
+//!
+//! A `VmConfig` captures the structure of a VM (memory size, attached
+//! devices, read-only overlays, memory protection and breakpoints) rather
+//! than its runtime state, so a setup can be defined once, saved as text
+//! and rebuilt elsewhere. Breakpoint hit counts are runtime state and are
+//! not included.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::devices::{ConsoleDevice, Framebuffer, RngDevice, RtcSim, TimerDevice};
+use crate::protection::Protection;
+use crate::{BreakCondition, CmpOp, Error, Result, Status, VM};
+
+/// Serializable descriptor of an attached device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// `devices::RtcSim`
+    RtcSim { ns_per_instruction: u64, epoch_ns: u64 },
+    /// `devices::Framebuffer`
+    Framebuffer { width: u32, height: u32, bpp: u8 },
+    /// `devices::ConsoleDevice`
+    Console { echo: bool },
+    /// `devices::TimerDevice`
+    Timer,
+    /// `devices::RngDevice`, drawing from the VM's own `Entropy`
+    Rng,
+    /// A user-defined device that cannot be reconstructed from a descriptor
+    Custom,
+}
+
+/// An attached device and the MMIO range it occupies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceConfig {
+    pub base: u64,
+    pub size: u64,
+    pub kind: DeviceKind,
+}
+
+/// A range of memory set with `VM::protect`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtectionConfig {
+    pub address: u64,
+    pub len: u64,
+    pub protection: Protection,
+}
+
+/// Contents shown read-only at `address` by `VM::map_readonly`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadonlyConfig {
+    pub address: u64,
+    pub data: Arc<[u8]>,
+}
+
+/// How a breakpoint decides whether to stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointKind {
+    /// `VM::set_breakpoint`
+    Always,
+    /// `VM::set_conditional_breakpoint`
+    Conditional(BreakCondition),
+    /// `VM::set_breakpoint_with_count`
+    Counted { ignore_count: u64 },
+    /// `VM::set_temp_breakpoint`
+    Temporary,
+}
+
+/// A breakpoint and how it was set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakpointConfig {
+    pub address: u64,
+    pub kind: BreakpointKind,
+}
+
+/// Structure of a VM, independent of its execution state
+///
+/// The text form produced by `Display` and accepted by `FromStr` has one
+/// item per line:
+///
+/// ```text
+/// memory_size 0x100000
+/// device rtc_sim 0x7000 0x10 ns_per_instruction=10 epoch_ns=0
+/// device framebuffer 0x4000 0x20 width=4 height=2 bpp=4
+/// device console 0x5000 0x10 echo=0
+/// readonly 0x3000 48656c6c6f
+/// protect 0x2000 0x1000 read
+/// breakpoint 0x10008
+/// breakpoint 0x1000c temporary
+/// breakpoint 0x10010 ignore=3
+/// breakpoint 0x10014 if r1 ge 0x5
+/// ```
+///
+/// Overlay contents are written as hex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmConfig {
+    pub memory_size: u64,
+    pub devices: Vec<DeviceConfig>,
+    /// Read-only overlays, in address order
+    pub readonly: Vec<ReadonlyConfig>,
+    /// Ranges not readable and writable, in address order
+    pub protections: Vec<ProtectionConfig>,
+    /// Breakpoints in the order they were set
+    pub breakpoints: Vec<BreakpointConfig>,
+}
+
+const CMP_OPS: [(CmpOp, &str); 6] = [
+    (CmpOp::Eq, "eq"),
+    (CmpOp::Ne, "ne"),
+    (CmpOp::Lt, "lt"),
+    (CmpOp::Le, "le"),
+    (CmpOp::Gt, "gt"),
+    (CmpOp::Ge, "ge"),
+];
+
+impl fmt::Display for VmConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "memory_size {:#x}", self.memory_size)?;
+        for device in &self.devices {
+            write!(f, "device ")?;
+            match device.kind {
+                DeviceKind::RtcSim { ns_per_instruction, epoch_ns } => writeln!(
+                    f,
+                    "rtc_sim {:#x} {:#x} ns_per_instruction={} epoch_ns={}",
+                    device.base, device.size, ns_per_instruction, epoch_ns
+                )?,
+                DeviceKind::Framebuffer { width, height, bpp } => writeln!(
+                    f,
+                    "framebuffer {:#x} {:#x} width={} height={} bpp={}",
+                    device.base, device.size, width, height, bpp
+                )?,
+                DeviceKind::Console { echo } => writeln!(
+                    f,
+                    "console {:#x} {:#x} echo={}",
+                    device.base, device.size, echo as u8
+                )?,
+                DeviceKind::Timer => writeln!(f, "timer {:#x} {:#x}", device.base, device.size)?,
+                DeviceKind::Rng => writeln!(f, "rng {:#x} {:#x}", device.base, device.size)?,
+                DeviceKind::Custom => {
+                    writeln!(f, "custom {:#x} {:#x}", device.base, device.size)?
+                }
+            }
+        }
+        for overlay in &self.readonly {
+            write!(f, "readonly {:#x} ", overlay.address)?;
+            for byte in overlay.data.iter() {
+                write!(f, "{:02x}", byte)?;
+            }
+            writeln!(f)?;
+        }
+        for region in &self.protections {
+            let protection = match region.protection {
+                Protection::None => "none",
+                Protection::Read => "read",
+                Protection::ReadWrite => "read_write",
+            };
+            writeln!(f, "protect {:#x} {:#x} {}", region.address, region.len, protection)?;
+        }
+        for breakpoint in &self.breakpoints {
+            write!(f, "breakpoint {:#x}", breakpoint.address)?;
+            match breakpoint.kind {
+                BreakpointKind::Always => writeln!(f)?,
+                BreakpointKind::Conditional(BreakCondition::Register { index, op, value }) => {
+                    let op = CMP_OPS.iter().find(|(candidate, _)| *candidate == op).unwrap().1;
+                    writeln!(f, " if r{} {} {:#x}", index, op, value)?
+                }
+                BreakpointKind::Counted { ignore_count } => writeln!(f, " ignore={}", ignore_count)?,
+                BreakpointKind::Temporary => writeln!(f, " temporary")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_error(line: usize, message: &str) -> Error {
+    Error {
+        status: Status::InvalidParameter,
+        message: format!("line {}: {}", line, message),
+    }
+}
+
+fn parse_u64(text: &str, line: usize) -> Result<u64> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| parse_error(line, &format!("invalid number '{}'", text)))
+}
+
+fn parse_hex(text: &str, line: usize) -> Result<Vec<u8>> {
+    let digits = text.as_bytes();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return Err(parse_error(line, "expected an even number of hex digits"));
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| parse_error(line, "invalid hex data"))
+        })
+        .collect()
+}
+
+fn parse_condition<'a>(mut words: impl Iterator<Item = &'a str>, line: usize) -> Result<BreakCondition> {
+    let index = words
+        .next()
+        .and_then(|reg| reg.strip_prefix('r'))
+        .and_then(|index| index.parse().ok())
+        .ok_or_else(|| parse_error(line, "expected a register such as r1"))?;
+    let op = words
+        .next()
+        .and_then(|name| CMP_OPS.iter().find(|(_, candidate)| *candidate == name))
+        .ok_or_else(|| parse_error(line, "expected eq, ne, lt, le, gt or ge"))?
+        .0;
+    let value = parse_u64(words.next().unwrap_or(""), line)?;
+    Ok(BreakCondition::Register { index, op, value })
+}
+
+fn parse_param(text: Option<&str>, key: &str, line: usize) -> Result<u64> {
+    let value = text
+        .and_then(|t| t.strip_prefix(key))
+        .and_then(|t| t.strip_prefix('='))
+        .ok_or_else(|| parse_error(line, &format!("expected {}=<value>", key)))?;
+    parse_u64(value, line)
+}
+
+impl FromStr for VmConfig {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut config = VmConfig {
+            memory_size: 0,
+            devices: Vec::new(),
+            readonly: Vec::new(),
+            protections: Vec::new(),
+            breakpoints: Vec::new(),
+        };
+
+        for (index, raw) in text.lines().enumerate() {
+            let line = index + 1;
+            let mut words = raw.split_whitespace();
+            match words.next() {
+                None => {}
+                Some("memory_size") => {
+                    let size = words.next().ok_or_else(|| parse_error(line, "missing size"))?;
+                    config.memory_size = parse_u64(size, line)?;
+                }
+                Some("protect") => {
+                    let address = parse_u64(words.next().unwrap_or(""), line)?;
+                    let len = parse_u64(words.next().unwrap_or(""), line)?;
+                    let protection = match words.next() {
+                        Some("none") => Protection::None,
+                        Some("read") => Protection::Read,
+                        Some("read_write") => Protection::ReadWrite,
+                        _ => return Err(parse_error(line, "expected none, read or read_write")),
+                    };
+                    config.protections.push(ProtectionConfig { address, len, protection });
+                }
+                Some("breakpoint") => {
+                    let address = words.next().ok_or_else(|| parse_error(line, "missing address"))?;
+                    let address = parse_u64(address, line)?;
+                    let kind = match words.next() {
+                        None => BreakpointKind::Always,
+                        Some("temporary") => BreakpointKind::Temporary,
+                        Some("if") => BreakpointKind::Conditional(parse_condition(&mut words, line)?),
+                        param => BreakpointKind::Counted {
+                            ignore_count: parse_param(param, "ignore", line)?,
+                        },
+                    };
+                    config.breakpoints.push(BreakpointConfig { address, kind });
+                }
+                Some("readonly") => {
+                    let address = parse_u64(words.next().unwrap_or(""), line)?;
+                    let data = parse_hex(words.next().unwrap_or(""), line)?;
+                    config.readonly.push(ReadonlyConfig {
+                        address,
+                        data: data.into(),
+                    });
+                }
+                Some("device") => {
+                    let kind = words.next().ok_or_else(|| parse_error(line, "missing device type"))?;
+                    let base = parse_u64(words.next().unwrap_or(""), line)?;
+                    let size = parse_u64(words.next().unwrap_or(""), line)?;
+                    let kind = match kind {
+                        "rtc_sim" => DeviceKind::RtcSim {
+                            ns_per_instruction: parse_param(words.next(), "ns_per_instruction", line)?,
+                            epoch_ns: parse_param(words.next(), "epoch_ns", line)?,
+                        },
+                        "framebuffer" => DeviceKind::Framebuffer {
+                            width: parse_param(words.next(), "width", line)? as u32,
+                            height: parse_param(words.next(), "height", line)? as u32,
+                            bpp: parse_param(words.next(), "bpp", line)? as u8,
+                        },
+                        "console" => DeviceKind::Console {
+                            echo: parse_param(words.next(), "echo", line)? != 0,
+                        },
+                        "timer" => DeviceKind::Timer,
+                        "rng" => DeviceKind::Rng,
+                        "custom" => DeviceKind::Custom,
+                        other => return Err(parse_error(line, &format!("unknown device type '{}'", other))),
+                    };
+                    config.devices.push(DeviceConfig { base, size, kind });
+                }
+                Some(other) => return Err(parse_error(line, &format!("unknown item '{}'", other))),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+impl VM {
+    /// Capture the machine structure: memory size, devices, overlays, protection and breakpoints
+    pub fn export_config(&self) -> VmConfig {
+        VmConfig {
+            memory_size: self.memory_size,
+            devices: self.devices.configs(),
+            readonly: self
+                .devices
+                .overlays()
+                .iter()
+                .map(|(address, data)| ReadonlyConfig {
+                    address: *address,
+                    data: data.clone(),
+                })
+                .collect(),
+            protections: self
+                .protection
+                .regions()
+                .iter()
+                .map(|&(start, end, protection)| ProtectionConfig {
+                    address: start,
+                    len: end - start,
+                    protection,
+                })
+                .collect(),
+            breakpoints: self
+                .breakpoints
+                .iter()
+                .map(|bp| BreakpointConfig {
+                    address: bp.address,
+                    kind: match (bp.condition, bp.ignore_count, bp.temporary) {
+                        (Some(condition), _, _) => BreakpointKind::Conditional(condition),
+                        (None, ignore_count @ 1.., _) => BreakpointKind::Counted { ignore_count },
+                        (None, 0, true) => BreakpointKind::Temporary,
+                        (None, 0, false) => BreakpointKind::Always,
+                    },
+                })
+                .collect(),
+        }
+    }
+
+    /// Build a fresh VM with the structure described by `config`
+    ///
+    /// Fails with `InvalidParameter` if the config contains a `Custom`
+    /// device, since those cannot be reconstructed from a descriptor.
+    pub fn from_config(config: &VmConfig) -> Result<VM> {
+        let mut vm = VM::new(config.memory_size)?;
+
+        for device in &config.devices {
+            match device.kind {
+                DeviceKind::RtcSim { ns_per_instruction, epoch_ns } => {
+                    let rtc = RtcSim::new(ns_per_instruction);
+                    rtc.handle().set_epoch(epoch_ns);
+                    vm.map_device(device.base, device.size, Box::new(rtc))?;
+                }
+                DeviceKind::Framebuffer { width, height, bpp } => {
+                    let framebuffer = Framebuffer::new(width, height, bpp);
+                    vm.map_device(device.base, device.size, Box::new(framebuffer))?;
+                }
+                DeviceKind::Console { echo } => {
+                    vm.attach_console_device(device.base, ConsoleDevice::new(echo))?;
+                }
+                DeviceKind::Timer => {
+                    vm.map_device(device.base, device.size, Box::new(TimerDevice::new()))?;
+                }
+                DeviceKind::Rng => {
+                    let rng = RngDevice::new(vm.entropy());
+                    vm.map_device(device.base, device.size, Box::new(rng))?;
+                }
+                DeviceKind::Custom => {
+                    return Err(Error {
+                        status: Status::InvalidParameter,
+                        message: format!("Cannot reconstruct custom device at {:#x}", device.base),
+                    });
+                }
+            }
+        }
+
+        for overlay in &config.readonly {
+            vm.map_readonly(overlay.address, overlay.data.clone())?;
+        }
+        for region in &config.protections {
+            vm.protect(region.address, region.len, region.protection)?;
+        }
+        for breakpoint in &config.breakpoints {
+            let address = breakpoint.address;
+            match breakpoint.kind {
+                BreakpointKind::Always => vm.set_breakpoint(address)?,
+                BreakpointKind::Conditional(condition) => vm.set_conditional_breakpoint(address, condition)?,
+                BreakpointKind::Counted { ignore_count } => vm.set_breakpoint_with_count(address, ignore_count)?,
+                BreakpointKind::Temporary => vm.set_temp_breakpoint(address)?,
+            }
+        }
+
+        Ok(vm)
+    }
+}
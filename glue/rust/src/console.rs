@@ -0,0 +1,87 @@
+//! Guest console streams
+//!
+//! The console is what the guest's semihosting console operations read from
+//! and write to, and where an attached `ConsoleDevice` echoes its output. It
+//! starts out connected to the process's stdin and stdout.
+
+use std::io::{self, Read, Write};
+
+use crate::VM;
+
+pub(crate) struct Console {
+    input: Option<Box<dyn Read + Send>>,
+    output: Option<Box<dyn Write + Send>>,
+    /// Copy of the output since `start_capture`
+    captured: Option<Vec<u8>>,
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Console {
+            input: Some(Box::new(io::stdin())),
+            output: Some(Box::new(io::stdout())),
+            captured: None,
+        }
+    }
+}
+
+impl Console {
+    /// Read guest input; a disconnected input is always at end of file
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.input.as_mut() {
+            Some(input) => input.read(buf),
+            None => Ok(0),
+        }
+    }
+
+    /// Write guest output; a disconnected output discards everything
+    pub fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if let Some(captured) = self.captured.as_mut() {
+            captured.extend_from_slice(data);
+        }
+        match self.output.as_mut() {
+            Some(output) => {
+                output.write_all(data)?;
+                output.flush()?;
+                Ok(data.len())
+            }
+            None => Ok(data.len()),
+        }
+    }
+
+    /// Add `data` to the capture without writing it out
+    pub fn record(&mut self, data: &[u8]) {
+        if let Some(captured) = self.captured.as_mut() {
+            captured.extend_from_slice(data);
+        }
+    }
+
+    /// Start keeping a copy of everything written
+    pub fn start_capture(&mut self) {
+        self.captured = Some(Vec::new());
+    }
+
+    /// Stop capturing and return what was written since `start_capture`
+    pub fn take_capture(&mut self) -> Vec<u8> {
+        self.captured.take().unwrap_or_default()
+    }
+}
+
+impl VM {
+    /// Route the guest console to `stdin` and `stdout`
+    ///
+    /// Any reader or writer works: the process's own streams, a pipe or an
+    /// in-memory buffer. `None` disconnects that direction, so guest reads
+    /// see end of file and guest output is dropped.
+    pub fn connect_stdio(
+        &mut self,
+        stdin: Option<Box<dyn Read + Send>>,
+        stdout: Option<Box<dyn Write + Send>>,
+    ) {
+        self.console = Console {
+            input: stdin,
+            output: stdout,
+            captured: None,
+        };
+    }
+}
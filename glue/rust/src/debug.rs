@@ -0,0 +1,443 @@
+//! Debugging primitives built on top of stepping and checkpoints
+
+use std::os::raw::c_int;
+
+use crate::exec::{CODE_BREAKPOINT, CODE_ERROR, CODE_OK, CODE_OPCODE_BREAK, CODE_WATCHPOINT};
+use crate::log::{log, Level};
+use crate::opcode::Opcode;
+use crate::{
+    check_status, ffi, Event, EventType, GuestBreakpointMode, RegDelta, Result, RunOutcome, Status,
+    StopCondition, StopReason, VmState, FLAGS_DELTA_INDEX, GUEST_BREAKPOINT_FLAG, VM,
+};
+
+/// Number of evenly spaced checkpoints taken on the coarse pass of `bisect`
+const BISECT_CHECKPOINTS: u64 = 16;
+/// Instructions `step_over` and `step_out` run before giving up
+const STEP_BUDGET: u64 = 10_000_000;
+
+/// How an instruction moves between stack frames, for `step_over` and `step_out`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameTransfer {
+    /// `CALL`, or `JMP` with a link register other than `R0`
+    Call,
+    /// `RET`, or `JMP R0, 0(R31)`
+    Return,
+}
+
+fn frame_transfer(raw: u32) -> Option<FrameTransfer> {
+    let rd = (raw >> 21) & 0x1F;
+    let rs1 = (raw >> 16) & 0x1F;
+    match Opcode::of(raw)? {
+        Opcode::Call => Some(FrameTransfer::Call),
+        Opcode::Jmp if rd != 0 => Some(FrameTransfer::Call),
+        Opcode::Ret => Some(FrameTransfer::Return),
+        Opcode::Jmp if rs1 == 31 && raw & 0xFFFF == 0 => Some(FrameTransfer::Return),
+        _ => None,
+    }
+}
+
+/// GPRs that differ between two states, in register order, then the flags
+pub(crate) fn reg_deltas(before: &ffi::VmState, after: &ffi::VmState) -> Vec<RegDelta> {
+    let mut deltas: Vec<RegDelta> = (0..32)
+        .filter(|&index| before.gprs[index] != after.gprs[index])
+        .map(|index| RegDelta {
+            index: index as u32,
+            old: before.gprs[index],
+            new: after.gprs[index],
+        })
+        .collect();
+    if before.flags != after.flags {
+        deltas.push(RegDelta {
+            index: FLAGS_DELTA_INDEX,
+            old: before.flags,
+            new: after.flags,
+        });
+    }
+    deltas
+}
+
+impl VM {
+    /// Why the most recent `run`, `run_outcome` or `step` stopped
+    ///
+    /// `None` until the VM has run, and again after `reset`.
+    pub fn last_stop_reason(&self) -> Option<StopReason> {
+        self.last_stop
+    }
+
+    /// Choose what the guest's `BRK` instruction does
+    ///
+    /// `Continue` needs to see every instruction, so in that mode the VM is
+    /// stepped from the host.
+    pub fn set_guest_breakpoint_mode(&mut self, mode: GuestBreakpointMode) -> Result<()> {
+        let core_mode = match mode {
+            GuestBreakpointMode::Stop => 0,
+            GuestBreakpointMode::Continue | GuestBreakpointMode::Ignore => 1,
+        };
+        let result = unsafe { ffi::nanocore_vm_set_guest_break_mode(self.handle, core_mode) };
+        check_status(result, "set guest breakpoint mode")?;
+        self.guest_breakpoint_mode = mode;
+        Ok(())
+    }
+
+    /// Raise `EXC_ZERO_REGISTER_WRITE` when an instruction names `R0` as its destination
+    ///
+    /// `R0` normally discards writes silently, which hides code that meant
+    /// to use a real register. The faulting instruction is not executed.
+    /// While enabled the VM is stepped from the host.
+    pub fn set_trap_zero_register_write(&mut self, enabled: bool) {
+        self.trap_zero_register_write = enabled;
+    }
+
+    /// Event for the last stop when the stop does not queue one itself
+    ///
+    /// Guest `BRK`, watchpoints and host-side exceptions queue their own
+    /// events; halts, host breakpoints and faults in the core do not.
+    pub(crate) fn stop_event(&self) -> Option<Event> {
+        let (event_type, data) = match self.last_stop? {
+            StopReason::Halted { exit_code } => (EventType::Halted, exit_code as i64 as u64),
+            StopReason::Breakpoint { address, .. } => (EventType::Breakpoint, address),
+            StopReason::Exception { code: None } => (EventType::Exception, 0),
+            _ => return None,
+        };
+        Some(Event {
+            event_type,
+            data,
+            fault_address: None,
+        })
+    }
+
+    /// Queue the event for a guest `BRK` at `address`
+    pub(crate) fn push_guest_breakpoint(&self, address: u64) {
+        self.push_event(Event {
+            event_type: EventType::Breakpoint,
+            data: address | GUEST_BREAKPOINT_FLAG,
+            fault_address: None,
+        });
+    }
+
+    /// Classify a core result code and remember it as the last stop
+    pub(crate) fn record_stop(&mut self, code: c_int) -> Result<()> {
+        let reason = match code {
+            CODE_BREAKPOINT => match self.guest_break()? {
+                Some(address) => {
+                    self.push_guest_breakpoint(address);
+                    StopReason::GuestBreakpoint { address }
+                }
+                None => {
+                    let address = self.raw_state()?.pc;
+                    let hit_count = self.breakpoint_hits.entry(address).or_insert(0);
+                    *hit_count += 1;
+                    let hit_count = *hit_count;
+                    self.remove_temp_breakpoint(address)?;
+                    StopReason::Breakpoint { address, hit_count }
+                }
+            },
+            CODE_WATCHPOINT => match self.watch_hit.take() {
+                Some(reason) => reason,
+                None => return Ok(()),
+            },
+            CODE_OPCODE_BREAK => match self.opcode_hit.take() {
+                Some((address, opcode)) => StopReason::OpcodeBreak { address, opcode },
+                None => return Ok(()),
+            },
+            CODE_ERROR => StopReason::Exception {
+                code: self.fault_code.take(),
+            },
+            CODE_OK => match self.exit_code()? {
+                Some(exit_code) => StopReason::Halted { exit_code },
+                None => StopReason::Limit,
+            },
+            // Argument errors never reached the guest
+            _ => return Ok(()),
+        };
+        #[cfg(feature = "tracing")]
+        match reason {
+            StopReason::Breakpoint { address, hit_count } => {
+                tracing::info!(handle = self.handle, address, hit_count, "breakpoint hit")
+            }
+            StopReason::GuestBreakpoint { address } => {
+                tracing::info!(handle = self.handle, address, "guest breakpoint")
+            }
+            StopReason::Watchpoint { address, kind, old_value, new_value } => {
+                tracing::info!(handle = self.handle, address, ?kind, old_value, new_value, "watchpoint hit")
+            }
+            StopReason::OpcodeBreak { address, opcode } => {
+                tracing::info!(handle = self.handle, address, %opcode, "opcode break")
+            }
+            StopReason::Exception { code } => {
+                tracing::warn!(handle = self.handle, code, "guest exception")
+            }
+            StopReason::Halted { exit_code } => {
+                tracing::debug!(handle = self.handle, exit_code, "guest halted")
+            }
+            _ => {}
+        }
+        match reason {
+            StopReason::Breakpoint { address, hit_count } => log(
+                Level::Info,
+                format_args!("VM {}: breakpoint hit at {:#x} (hit {})", self.handle, address, hit_count),
+            ),
+            StopReason::GuestBreakpoint { address } => log(
+                Level::Info,
+                format_args!("VM {}: guest breakpoint at {:#x}", self.handle, address),
+            ),
+            StopReason::Watchpoint { address, kind, old_value, new_value } => log(
+                Level::Info,
+                format_args!(
+                    "VM {}: {:?} watchpoint hit at {:#x} ({:#x} -> {:#x})",
+                    self.handle, kind, address, old_value, new_value
+                ),
+            ),
+            StopReason::OpcodeBreak { address, opcode } => log(
+                Level::Info,
+                format_args!("VM {}: {} at {:#x} matched an opcode break", self.handle, opcode, address),
+            ),
+            StopReason::Exception { code: Some(code) } => log(
+                Level::Warn,
+                format_args!("VM {}: guest exception {}", self.handle, code),
+            ),
+            StopReason::Exception { code: None } => {
+                log(Level::Warn, format_args!("VM {}: execution faulted in the core", self.handle))
+            }
+            StopReason::Halted { exit_code } => log(
+                Level::Debug,
+                format_args!("VM {}: guest halted with exit code {}", self.handle, exit_code),
+            ),
+            _ => {}
+        }
+        self.last_stop = Some(reason);
+        Ok(())
+    }
+
+    /// Whether a breakpoint at `pc` stops execution, given the registers
+    ///
+    /// A hit that a counted breakpoint lets pass is recorded here; hits that
+    /// stop are recorded by `record_stop`.
+    pub(crate) fn breakpoint_stops(&mut self, pc: u64, gprs: &[u64; 32]) -> bool {
+        let hits = self.breakpoint_hits.get(&pc).copied().unwrap_or(0);
+        let (mut stops, mut passes) = (false, false);
+        for bp in self.breakpoints.iter().filter(|bp| bp.address == pc) {
+            if bp.condition.is_some_and(|condition| !condition.holds(gprs)) {
+                continue;
+            }
+            if hits >= bp.ignore_count {
+                stops = true;
+            } else {
+                passes = true;
+            }
+        }
+        if passes && !stops {
+            *self.breakpoint_hits.entry(pc).or_insert(0) += 1;
+        }
+        stops
+    }
+    
+    /// Clear a temporary breakpoint at `address` once it has fired
+    fn remove_temp_breakpoint(&mut self, address: u64) -> Result<()> {
+        if let Some(index) = self.breakpoints.iter().position(|bp| bp.address == address && bp.temporary) {
+            self.breakpoints.remove(index);
+            let result = unsafe { ffi::nanocore_vm_clear_breakpoint(self.handle, address) };
+            check_status(result, "clear breakpoint")?;
+        }
+        Ok(())
+    }
+    
+    /// Find the first instruction count at which `predicate` becomes true
+    ///
+    /// Runs forward up to `max_instructions`, checkpointing at regular
+    /// intervals, then replays from the last checkpoint before the predicate
+    /// flipped and binary-searches for the exact instruction. The predicate is
+    /// assumed to stay true once it becomes true.
+    ///
+    /// On `Some(n)` the VM is left right after the `n`th instruction, so the
+    /// offending state can be inspected; `Some(0)` means the predicate already
+    /// held. On `None` the VM is left where the forward run stopped.
+    pub fn bisect(
+        &mut self,
+        predicate: impl Fn(&VmState) -> bool,
+        max_instructions: u64,
+    ) -> Result<Option<u64>> {
+        if predicate(&self.get_state()?) {
+            return Ok(Some(0));
+        }
+
+        let stride = (max_instructions / BISECT_CHECKPOINTS).max(1);
+        let mut base = self.checkpoint()?;
+        let mut base_count = 0;
+
+        // Coarse pass: find the chunk in which the predicate flips
+        let chunk_len = loop {
+            if base_count >= max_instructions {
+                return Ok(None);
+            }
+            let (retired, stopped) = self.run_exact(stride.min(max_instructions - base_count))?;
+            if predicate(&self.get_state()?) {
+                break retired;
+            }
+            if stopped {
+                return Ok(None);
+            }
+            base = self.checkpoint()?;
+            base_count += retired;
+        };
+
+        // Fine pass: predicate is false after `lo` and true after `hi`
+        let (mut lo, mut hi) = (0, chunk_len);
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            self.rewind(&base)?;
+            self.run_exact(mid)?;
+            if predicate(&self.get_state()?) {
+                hi = mid;
+            } else {
+                lo = mid;
+            }
+        }
+
+        self.rewind(&base)?;
+        self.run_exact(hi)?;
+        Ok(Some(base_count + hi))
+    }
+
+    /// Run until one of `conditions` holds, or execution stops on its own
+    ///
+    /// Conditions are checked after every instruction, in order, so the
+    /// first one that holds wins. Returns `RunOutcome::Condition` with the
+    /// index of that condition, or the reason execution stopped otherwise
+    /// (halt, breakpoint, exception or `max_instructions` running out) with
+    /// `None`. The VM is stepped one instruction at a time.
+    pub fn run_until_any(
+        &mut self,
+        conditions: &[StopCondition],
+        max_instructions: Option<u64>,
+    ) -> Result<(RunOutcome, Option<usize>)> {
+        let baselines = conditions
+            .iter()
+            .map(|condition| match *condition {
+                StopCondition::MemoryChanged { address, len } => self.read_memory(address, len).map(Some),
+                _ => Ok(None),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut executed = 0;
+        while max_instructions.is_none_or(|max| executed < max) {
+            let outcome = self.run_outcome(Some(1))?;
+            if outcome != RunOutcome::Limit {
+                return Ok((outcome, None));
+            }
+            executed += 1;
+
+            let state = self.get_state()?;
+            for (index, condition) in conditions.iter().enumerate() {
+                let fired = match condition {
+                    StopCondition::Address(address) => state.pc == *address,
+                    StopCondition::RegisterEquals { register, value } => {
+                        state.gprs.get(*register as usize) == Some(value)
+                    }
+                    StopCondition::MemoryChanged { address, len } => {
+                        baselines[index].as_deref() != Some(&self.read_memory(*address, *len)?[..])
+                    }
+                    StopCondition::InstructionCount(count) => executed >= *count,
+                    StopCondition::State(predicate) => predicate(&state),
+                };
+                if fired {
+                    self.last_stop = Some(StopReason::Condition { index });
+                    return Ok((RunOutcome::Condition, Some(index)));
+                }
+            }
+        }
+
+        Ok((RunOutcome::Limit, None))
+    }
+
+    /// Execute one instruction and report the registers it changed
+    ///
+    /// Deltas are listed in register order, with a change to the flags
+    /// last under `FLAGS_DELTA_INDEX`. Registers that were written with
+    /// their existing value are not listed.
+    pub fn step_with_diff(&mut self) -> Result<(Status, Vec<RegDelta>)> {
+        let before = self.raw_state()?;
+        let status = self.step()?;
+        let after = self.raw_state()?;
+        Ok((status, reg_deltas(&before, &after)))
+    }
+
+    /// Execute one instruction, running any call it makes to completion
+    ///
+    /// `CALL` and linking `JMP` (one whose destination register is not
+    /// `R0`) count as calls; `RET` and `JMP R0, 0(R31)` count as returns.
+    /// Nested and recursive calls are tracked, so execution stops only once
+    /// the call made here returns. Other instructions behave like `step`.
+    /// Breakpoints, faults and `HALT` inside the call stop early, with
+    /// `last_stop_reason` saying why. A call that has not returned after ten
+    /// million instructions is abandoned as by `step_over_within`.
+    pub fn step_over(&mut self) -> Result<Status> {
+        self.step_over_within(STEP_BUDGET)
+    }
+
+    /// Like `step_over`, but execute at most `max_instructions`
+    ///
+    /// Returns `Status::FuelExhausted`, with `last_stop_reason` `Limit`, if
+    /// the call has not returned by then, so a function that never returns
+    /// (one spinning on `JMP .`, say) cannot hang the debugger.
+    pub fn step_over_within(&mut self, max_instructions: u64) -> Result<Status> {
+        if max_instructions == 0 {
+            return Ok(Status::FuelExhausted);
+        }
+        let pc = self.raw_state()?.pc;
+        let transfer = self.fetch(pc).ok().and_then(frame_transfer);
+        let status = self.step()?;
+        if transfer != Some(FrameTransfer::Call) || self.last_stop != Some(StopReason::Limit) {
+            return Ok(status);
+        }
+        self.step_until_return(max_instructions - 1)
+    }
+
+    /// Run until the current function returns to its caller
+    ///
+    /// Stops just after the matching return, using the same notion of calls
+    /// and returns as `step_over`. The VM is stepped one instruction at a
+    /// time, giving up after ten million as `step_out_within` does.
+    pub fn step_out(&mut self) -> Result<Status> {
+        self.step_out_within(STEP_BUDGET)
+    }
+
+    /// Like `step_out`, but execute at most `max_instructions`
+    ///
+    /// Returns `Status::FuelExhausted`, with `last_stop_reason` `Limit`, if
+    /// the function has not returned by then.
+    pub fn step_out_within(&mut self, max_instructions: u64) -> Result<Status> {
+        self.step_until_return(max_instructions)
+    }
+
+    /// Step until a return executes at the current call depth, or `budget` runs out
+    fn step_until_return(&mut self, budget: u64) -> Result<Status> {
+        let mut depth = 0u64;
+        for _ in 0..budget {
+            let pc = self.raw_state()?.pc;
+            let transfer = self.fetch(pc).ok().and_then(frame_transfer);
+            let status = self.step()?;
+            if self.last_stop != Some(StopReason::Limit) {
+                return Ok(status);
+            }
+            match transfer {
+                Some(FrameTransfer::Call) => depth += 1,
+                Some(FrameTransfer::Return) if depth == 0 => return Ok(status),
+                Some(FrameTransfer::Return) => depth -= 1,
+                None => {}
+            }
+        }
+        Ok(Status::FuelExhausted)
+    }
+
+    /// Address of the `BRK` the last step stopped on, if it did
+    fn guest_break(&self) -> Result<Option<u64>> {
+        let mut address = 0;
+        let result = unsafe { ffi::nanocore_vm_get_guest_break(self.handle, &mut address) };
+        match result {
+            0 => Ok(Some(address)),
+            -1 => Ok(None),
+            _ => check_status(result, "get guest breakpoint").map(|_| None),
+        }
+    }
+}
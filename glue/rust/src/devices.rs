@@ -0,0 +1,885 @@
+//! MMIO devices attached to a VM from Rust
+//!
+//! Devices mirror the `Device` trait of the FFI layer. While any device is
+//! attached the VM is stepped from the host, and guest loads and stores that
+//! land in a device range are serviced here instead of touching memory.
+//! Read-only overlays added with `VM::map_readonly` go through the same
+//! dispatch and are checked before devices.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{DeviceConfig, DeviceKind};
+use crate::entropy::Entropy;
+use crate::{Error, Result, Status, VM, EXC_ALIGNMENT, EXC_MEMORY_FAULT};
+
+/// Trait for MMIO devices
+pub trait Device: Send + Sync {
+    fn read(&mut self, offset: u64) -> u64;
+    fn write(&mut self, offset: u64, value: u64);
+    fn reset(&mut self);
+
+    /// Access widths the device accepts; others raise `EXC_ALIGNMENT`
+    fn access_width(&self) -> AccessWidthPolicy {
+        AccessWidthPolicy::Any
+    }
+
+    /// Advance device time by `instructions` retired guest instructions
+    fn tick(&mut self, _instructions: u64) {}
+
+    /// Descriptor used to rebuild the device from a `VmConfig`
+    fn descriptor(&self) -> DeviceKind {
+        DeviceKind::Custom
+    }
+
+    /// Whether the device is raising an interrupt
+    ///
+    /// Checked after every instruction. While `INTERRUPT_ENABLE` is set a
+    /// pending interrupt is delivered as a `DeviceInterrupt` event and then
+    /// acknowledged; otherwise it stays pending.
+    fn interrupt_pending(&self) -> bool {
+        false
+    }
+
+    /// Called once a pending interrupt has been delivered
+    fn acknowledge_interrupt(&mut self) {}
+}
+
+/// Width/alignment constraint enforced on accesses to a device range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessWidthPolicy {
+    /// Any width at any offset
+    Any,
+    /// Naturally aligned 32-bit accesses only
+    Word32,
+    /// Naturally aligned 64-bit accesses only
+    Word64,
+    /// Naturally aligned 32- or 64-bit accesses
+    Word32Or64,
+}
+
+impl AccessWidthPolicy {
+    /// Check an access of `width` bytes at `offset` into the device range
+    pub fn permits(self, offset: u64, width: u64) -> bool {
+        let aligned = |w: u64| width == w && offset.is_multiple_of(w);
+        match self {
+            AccessWidthPolicy::Any => true,
+            AccessWidthPolicy::Word32 => aligned(4),
+            AccessWidthPolicy::Word64 => aligned(8),
+            AccessWidthPolicy::Word32Or64 => aligned(4) || aligned(8),
+        }
+    }
+}
+
+/// Identifies a device attached with `VM::attach_device`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DeviceId(usize);
+
+impl DeviceId {
+    /// Number carried in the data of the device's `DeviceInterrupt` events
+    pub fn index(self) -> u64 {
+        self.0 as u64
+    }
+}
+
+/// A `Device` built from read and write closures
+///
+/// The closures receive the offset into the device range. `reset` does
+/// nothing; capture shared state in the closures if the device needs any.
+pub struct FnDevice<R, W> {
+    read: R,
+    write: W,
+}
+
+impl<R, W> FnDevice<R, W>
+where
+    R: FnMut(u64) -> u64 + Send + Sync,
+    W: FnMut(u64, u64) + Send + Sync,
+{
+    pub fn new(read: R, write: W) -> Self {
+        Self { read, write }
+    }
+}
+
+impl<R, W> Device for FnDevice<R, W>
+where
+    R: FnMut(u64) -> u64 + Send + Sync,
+    W: FnMut(u64, u64) + Send + Sync,
+{
+    fn read(&mut self, offset: u64) -> u64 {
+        (self.read)(offset)
+    }
+
+    fn write(&mut self, offset: u64, value: u64) {
+        (self.write)(offset, value)
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// Device manager for MMIO devices
+#[derive(Default)]
+pub(crate) struct DeviceManager {
+    /// Indexed by `DeviceId`; detached devices leave a `None` so ids stay stable
+    devices: Vec<Option<Box<dyn Device>>>,
+    mmio_map: Vec<(u64, u64, usize)>, // (start, end, device_index)
+    /// Host bytes shown to the guest read-only, by start address
+    overlays: Vec<(u64, Arc<[u8]>)>,
+}
+
+impl DeviceManager {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.mmio_map.is_empty() && self.overlays.is_empty()
+    }
+
+    /// Whether `[start, end)` overlaps a device or an overlay
+    fn occupied(&self, start: u64, end: u64) -> bool {
+        self.mmio_map.iter().any(|&(s, e, _)| start < e && s < end)
+            || self.overlays.iter().any(|(s, data)| start < s + data.len() as u64 && *s < end)
+    }
+
+    /// Map `device` at `[start, end)`, failing if the range overlaps another
+    pub(crate) fn attach(&mut self, start: u64, end: u64, device: Box<dyn Device>) -> Option<usize> {
+        if start >= end || self.occupied(start, end) {
+            return None;
+        }
+        let index = self.devices.len();
+        self.devices.push(Some(device));
+        self.mmio_map.push((start, end, index));
+        Some(index)
+    }
+
+    /// Unmap the device at `index` and hand it back
+    pub(crate) fn detach(&mut self, index: usize) -> Option<Box<dyn Device>> {
+        let device = self.devices.get_mut(index)?.take()?;
+        self.mmio_map.retain(|&(_, _, mapped)| mapped != index);
+        Some(device)
+    }
+
+    /// Show `data` read-only at `start`, failing if it overlaps anything mapped
+    pub(crate) fn overlay(&mut self, start: u64, data: Arc<[u8]>) -> bool {
+        let end = start + data.len() as u64;
+        if data.is_empty() || self.occupied(start, end) {
+            return false;
+        }
+        let index = self.overlays.partition_point(|(s, _)| *s < start);
+        self.overlays.insert(index, (start, data));
+        true
+    }
+
+    /// The overlay containing `address` and the offset into it
+    pub(crate) fn overlay_at(&self, address: u64) -> Option<(&[u8], usize)> {
+        let index = self.overlays.partition_point(|(s, _)| *s <= address).checked_sub(1)?;
+        let (start, data) = &self.overlays[index];
+        let offset = (address - start) as usize;
+        (offset < data.len()).then_some((&data[..], offset))
+    }
+
+    /// Whether `[start, end)` overlaps an overlay
+    pub(crate) fn overlaps_overlay(&self, start: u64, end: u64) -> bool {
+        self.overlays.iter().any(|(s, data)| start < s + data.len() as u64 && *s < end)
+    }
+
+    /// Copy overlay contents over `buffer`, which holds memory from `address`
+    pub(crate) fn apply_overlays(&self, address: u64, buffer: &mut [u8]) {
+        let end = address + buffer.len() as u64;
+        for (start, data) in &self.overlays {
+            let from = address.max(*start);
+            let to = end.min(start + data.len() as u64);
+            if from < to {
+                buffer[(from - address) as usize..(to - address) as usize]
+                    .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+            }
+        }
+    }
+
+    /// The live device at `index`; mapped indices always have one
+    fn device(&mut self, index: usize) -> &mut Box<dyn Device> {
+        self.devices[index].as_mut().expect("mapped device was detached")
+    }
+
+    /// Find the device mapped at `address`, returning its index and offset
+    fn lookup(&self, address: u64) -> Option<(usize, u64)> {
+        self.mmio_map
+            .iter()
+            .find(|&&(start, end, _)| address >= start && address < end)
+            .map(|&(start, _, index)| (index, address - start))
+    }
+
+    pub(crate) fn maps(&self, address: u64) -> bool {
+        self.overlay_at(address).is_some() || self.lookup(address).is_some()
+    }
+
+    /// Dispatch a `width`-byte read at `address`
+    ///
+    /// Overlays are consulted first; a read running off the end of one
+    /// raises `EXC_MEMORY_FAULT`. Returns `Ok(None)` when nothing is mapped
+    /// there, or the exception code to raise when the device's width policy
+    /// rejects the access.
+    pub(crate) fn read(&mut self, address: u64, width: u64) -> std::result::Result<Option<u64>, u32> {
+        if let Some((data, offset)) = self.overlay_at(address) {
+            let bytes = data.get(offset..offset + width as usize).ok_or(EXC_MEMORY_FAULT)?;
+            return Ok(Some(bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64)));
+        }
+        let Some((index, offset)) = self.lookup(address) else {
+            return Ok(None);
+        };
+        let device = self.device(index);
+        if !device.access_width().permits(offset, width) {
+            return Err(EXC_ALIGNMENT);
+        }
+        Ok(Some(device.read(offset)))
+    }
+
+    /// Dispatch a `width`-byte write at `address`
+    ///
+    /// Writes to an overlay raise `EXC_MEMORY_FAULT`. Returns `Ok(false)`
+    /// when nothing is mapped there, or the exception code to raise when the
+    /// device's width policy rejects the access.
+    pub(crate) fn write(&mut self, address: u64, width: u64, value: u64) -> std::result::Result<bool, u32> {
+        if self.overlay_at(address).is_some() {
+            return Err(EXC_MEMORY_FAULT);
+        }
+        let Some((index, offset)) = self.lookup(address) else {
+            return Ok(false);
+        };
+        let device = self.device(index);
+        if !device.access_width().permits(offset, width) {
+            return Err(EXC_ALIGNMENT);
+        }
+        device.write(offset, value);
+        Ok(true)
+    }
+
+    pub(crate) fn tick(&mut self, instructions: u64) {
+        for device in self.devices.iter_mut().flatten() {
+            device.tick(instructions);
+        }
+    }
+
+    /// Indices of the devices with an interrupt pending
+    pub(crate) fn pending_interrupts(&self) -> Vec<usize> {
+        self.devices
+            .iter()
+            .enumerate()
+            .filter(|(_, device)| device.as_ref().is_some_and(|device| device.interrupt_pending()))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    pub(crate) fn acknowledge_interrupt(&mut self, index: usize) {
+        if let Some(device) = self.devices.get_mut(index).and_then(Option::as_mut) {
+            device.acknowledge_interrupt();
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        for device in self.devices.iter_mut().flatten() {
+            device.reset();
+        }
+    }
+
+    /// Read-only overlays in address order, as `(start, data)`
+    pub(crate) fn overlays(&self) -> &[(u64, Arc<[u8]>)] {
+        &self.overlays
+    }
+
+    pub(crate) fn configs(&self) -> Vec<DeviceConfig> {
+        self.mmio_map
+            .iter()
+            .map(|&(start, end, index)| DeviceConfig {
+                base: start,
+                size: end - start,
+                kind: self.devices[index].as_ref().map_or(DeviceKind::Custom, |device| device.descriptor()),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct RtcState {
+    epoch_ns: u64,
+    ns_per_instruction: u64,
+    instructions: u64,
+}
+
+impl RtcState {
+    fn now_ns(&self) -> u64 {
+        self.epoch_ns
+            .wrapping_add(self.instructions.wrapping_mul(self.ns_per_instruction))
+    }
+}
+
+/// Replay-safe clock whose time advances with retired instructions
+///
+/// The reported time is `epoch + instructions * ns_per_instruction`, so two
+/// runs of the same program observe identical timestamps.
+///
+/// Register layout, relative to the attach base:
+///
+/// | Offset | Access | Meaning                           |
+/// |--------|--------|-----------------------------------|
+/// | 0x0    | R      | Current time in ns                |
+/// | 0x4    | R      | Current time in ns (high 32 bits) |
+/// | 0x8    | R/W    | Epoch in ns                       |
+///
+/// Each register holds a 64-bit value truncated to the access width, so a
+/// 32-bit load from 0x0 sees the low half of the time.
+pub struct RtcSim {
+    state: Arc<Mutex<RtcState>>,
+}
+
+impl RtcSim {
+    /// Size of the MMIO range the device occupies
+    pub const SIZE: u64 = 0x10;
+
+    pub fn new(ns_per_instruction: u64) -> Self {
+        RtcSim {
+            state: Arc::new(Mutex::new(RtcState {
+                epoch_ns: 0,
+                ns_per_instruction,
+                instructions: 0,
+            })),
+        }
+    }
+
+    /// Handle for inspecting the clock from the host
+    pub fn handle(&self) -> RtcSimHandle {
+        RtcSimHandle {
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+impl Device for RtcSim {
+    fn read(&mut self, offset: u64) -> u64 {
+        let state = self.state.lock().unwrap();
+        match offset {
+            0x0 => state.now_ns(),
+            0x4 => state.now_ns() >> 32,
+            0x8 => state.epoch_ns,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u64, value: u64) {
+        if offset == 0x8 {
+            self.state.lock().unwrap().epoch_ns = value;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state.lock().unwrap().instructions = 0;
+    }
+
+    fn tick(&mut self, instructions: u64) {
+        self.state.lock().unwrap().instructions += instructions;
+    }
+
+    fn descriptor(&self) -> DeviceKind {
+        let state = self.state.lock().unwrap();
+        DeviceKind::RtcSim {
+            ns_per_instruction: state.ns_per_instruction,
+            epoch_ns: state.epoch_ns,
+        }
+    }
+}
+
+/// Host-side handle to an attached `RtcSim`
+#[derive(Clone)]
+pub struct RtcSimHandle {
+    state: Arc<Mutex<RtcState>>,
+}
+
+impl RtcSimHandle {
+    /// Current simulated time in nanoseconds
+    pub fn now_ns(&self) -> u64 {
+        self.state.lock().unwrap().now_ns()
+    }
+
+    /// Set the initial epoch the elapsed time is added to
+    pub fn set_epoch(&self, epoch_ns: u64) {
+        self.state.lock().unwrap().epoch_ns = epoch_ns;
+    }
+}
+
+#[derive(Debug, Default)]
+struct ConsoleState {
+    output: Vec<u8>,
+    /// Output not yet passed on to the VM's console
+    unforwarded: Vec<u8>,
+    input: VecDeque<u8>,
+    echo: bool,
+}
+
+/// Minimal UART for guest text I/O
+///
+/// | Offset | Access | Meaning |
+/// |--------|--------|---------|
+/// | `0x0`  | write  | Output the low byte of the value |
+/// | `0x4`  | read   | Next input byte, or all ones when there is none |
+/// | `0x8`  | read   | Number of input bytes waiting |
+///
+/// Output is kept until the host drains it through a `ConsoleHandle`. When
+/// attached with `VM::attach_console` it is also recorded in a
+/// `RunReport`'s console output and, with echo on, written to the VM's
+/// console (see `VM::connect_stdio`).
+pub struct ConsoleDevice {
+    state: Arc<Mutex<ConsoleState>>,
+}
+
+impl ConsoleDevice {
+    /// Size of the MMIO range the device occupies
+    pub const SIZE: u64 = 0x10;
+
+    pub fn new(echo: bool) -> Self {
+        ConsoleDevice {
+            state: Arc::new(Mutex::new(ConsoleState {
+                echo,
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Handle for exchanging text with the guest from the host
+    pub fn handle(&self) -> ConsoleHandle {
+        ConsoleHandle {
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+impl Device for ConsoleDevice {
+    fn read(&mut self, offset: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        match offset {
+            0x4 => state.input.pop_front().map_or(u64::MAX, u64::from),
+            0x8 => state.input.len() as u64,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u64, value: u64) {
+        if offset != 0x0 {
+            return;
+        }
+        let byte = value as u8;
+        let mut state = self.state.lock().unwrap();
+        state.output.push(byte);
+        state.unforwarded.push(byte);
+    }
+
+    fn reset(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.output.clear();
+        state.unforwarded.clear();
+        state.input.clear();
+    }
+
+    fn descriptor(&self) -> DeviceKind {
+        DeviceKind::Console {
+            echo: self.state.lock().unwrap().echo,
+        }
+    }
+}
+
+/// Host-side handle to an attached `ConsoleDevice`
+#[derive(Clone)]
+pub struct ConsoleHandle {
+    state: Arc<Mutex<ConsoleState>>,
+}
+
+impl ConsoleHandle {
+    /// Remove and return everything the guest has written so far
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.state.lock().unwrap().output)
+    }
+
+    /// Queue bytes for the guest to read
+    pub fn push_input(&self, data: &[u8]) {
+        self.state.lock().unwrap().input.extend(data);
+    }
+
+    /// Also write guest output to the VM's console
+    pub fn set_echo(&self, echo: bool) {
+        self.state.lock().unwrap().echo = echo;
+    }
+
+    /// Output written since the last call, and whether to echo it
+    fn take_unforwarded(&self) -> (Vec<u8>, bool) {
+        let mut state = self.state.lock().unwrap();
+        (std::mem::take(&mut state.unforwarded), state.echo)
+    }
+}
+
+#[derive(Debug, Default)]
+struct TimerState {
+    reload: u64,
+    current: u64,
+    control: u64,
+    pending: bool,
+}
+
+/// Countdown timer that raises a device interrupt when it expires
+///
+/// | Offset | Access | Meaning |
+/// |--------|--------|---------|
+/// | `0x0`  | read/write | Reload value |
+/// | `0x4`  | read   | Instructions left before the timer expires |
+/// | `0x8`  | read/write | Control: bit 0 enables, bit 1 reloads on expiry |
+///
+/// Setting the enable bit starts a countdown from the reload value, one
+/// tick per retired instruction starting with the store that set the bit.
+/// On reaching zero the timer raises an
+/// interrupt, then either restarts (bit 1 set) or clears its enable bit.
+#[derive(Debug, Default)]
+pub struct TimerDevice {
+    state: TimerState,
+}
+
+impl TimerDevice {
+    /// Size of the MMIO range the device occupies
+    pub const SIZE: u64 = 0x10;
+    /// Control bit that starts the countdown
+    pub const ENABLE: u64 = 1 << 0;
+    /// Control bit that restarts the countdown on expiry
+    pub const PERIODIC: u64 = 1 << 1;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Device for TimerDevice {
+    fn read(&mut self, offset: u64) -> u64 {
+        match offset {
+            0x0 => self.state.reload,
+            0x4 => self.state.current,
+            0x8 => self.state.control,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u64, value: u64) {
+        match offset {
+            0x0 => self.state.reload = value,
+            0x8 => {
+                let starting = value & Self::ENABLE != 0 && self.state.control & Self::ENABLE == 0;
+                self.state.control = value & (Self::ENABLE | Self::PERIODIC);
+                if starting {
+                    self.state.current = self.state.reload;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = TimerState::default();
+    }
+
+    fn tick(&mut self, instructions: u64) {
+        let state = &mut self.state;
+        if state.control & Self::ENABLE == 0 {
+            return;
+        }
+        state.current = state.current.saturating_sub(instructions);
+        if state.current == 0 {
+            state.pending = true;
+            if state.control & Self::PERIODIC != 0 && state.reload > 0 {
+                state.current = state.reload;
+            } else {
+                state.control &= !Self::ENABLE;
+            }
+        }
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.state.pending
+    }
+
+    fn acknowledge_interrupt(&mut self) {
+        self.state.pending = false;
+    }
+
+    fn descriptor(&self) -> DeviceKind {
+        DeviceKind::Timer
+    }
+}
+
+/// Source of random numbers for the guest
+///
+/// | Offset | Access | Meaning |
+/// |--------|--------|---------|
+/// | `0x0`  | read   | Next random value; every read draws a fresh one |
+///
+/// Values come from the `Entropy` passed in, normally the VM's own via
+/// `VM::attach_rng`, so `VM::set_seed` makes them reproducible.
+pub struct RngDevice {
+    entropy: Entropy,
+}
+
+impl RngDevice {
+    /// Size of the MMIO range the device occupies
+    pub const SIZE: u64 = 0x8;
+
+    pub fn new(entropy: Entropy) -> Self {
+        RngDevice { entropy }
+    }
+}
+
+impl Device for RngDevice {
+    fn read(&mut self, offset: u64) -> u64 {
+        match offset {
+            0x0 => self.entropy.next_u64(),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, _offset: u64, _value: u64) {}
+
+    fn reset(&mut self) {}
+
+    fn descriptor(&self) -> DeviceKind {
+        DeviceKind::Rng
+    }
+}
+
+struct FramebufferState {
+    width: u32,
+    height: u32,
+    bpp: u8,
+    pixels: Vec<u8>,
+}
+
+/// Linear framebuffer of `width * height` pixels, `bpp` bytes each
+///
+/// Pixels are stored row-major starting at offset 0. A guest write stores
+/// the low `bpp` bytes of the value (little-endian) at the written offset; a
+/// read returns the same bytes. The host retrieves the image through a
+/// `FramebufferHandle`.
+pub struct Framebuffer {
+    state: Arc<Mutex<FramebufferState>>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32, bpp: u8) -> Self {
+        let len = width as usize * height as usize * bpp as usize;
+        Framebuffer {
+            state: Arc::new(Mutex::new(FramebufferState {
+                width,
+                height,
+                bpp,
+                pixels: vec![0; len],
+            })),
+        }
+    }
+
+    /// Size in bytes of the MMIO range the framebuffer occupies
+    pub fn size(&self) -> u64 {
+        self.state.lock().unwrap().pixels.len() as u64
+    }
+
+    /// Handle for reading the image from the host
+    pub fn handle(&self) -> FramebufferHandle {
+        FramebufferHandle {
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn read(&mut self, offset: u64) -> u64 {
+        let state = self.state.lock().unwrap();
+        let start = offset as usize;
+        let end = (start + state.bpp as usize).min(state.pixels.len());
+        state.pixels[start..end]
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| (value << 8) | byte as u64)
+    }
+
+    fn write(&mut self, offset: u64, value: u64) {
+        let mut state = self.state.lock().unwrap();
+        let start = offset as usize;
+        let end = (start + state.bpp as usize).min(state.pixels.len());
+        for (i, byte) in state.pixels[start..end].iter_mut().enumerate() {
+            *byte = (value >> (8 * i)) as u8;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state.lock().unwrap().pixels.fill(0);
+    }
+
+    fn descriptor(&self) -> DeviceKind {
+        let state = self.state.lock().unwrap();
+        DeviceKind::Framebuffer {
+            width: state.width,
+            height: state.height,
+            bpp: state.bpp,
+        }
+    }
+}
+
+/// Host-side handle to an attached `Framebuffer`
+#[derive(Clone)]
+pub struct FramebufferHandle {
+    state: Arc<Mutex<FramebufferState>>,
+}
+
+impl FramebufferHandle {
+    /// Copy of the current image as `(width, height, pixels)`
+    pub fn snapshot(&self) -> (u32, u32, Vec<u8>) {
+        let state = self.state.lock().unwrap();
+        (state.width, state.height, state.pixels.clone())
+    }
+}
+
+impl VM {
+    /// Attach a `width` x `height` framebuffer with `bpp` bytes per pixel
+    pub fn attach_framebuffer(
+        &mut self,
+        base: u64,
+        width: u32,
+        height: u32,
+        bpp: u8,
+    ) -> Result<FramebufferHandle> {
+        let framebuffer = Framebuffer::new(width, height, bpp);
+        let handle = framebuffer.handle();
+        self.map_device(base, framebuffer.size(), Box::new(framebuffer))?;
+        Ok(handle)
+    }
+
+    /// Attach a replay-safe clock at `base` advancing `ns_per_instruction`
+    /// nanoseconds for every retired instruction
+    pub fn attach_rtc_sim(&mut self, base: u64, ns_per_instruction: u64) -> Result<RtcSimHandle> {
+        let rtc = RtcSim::new(ns_per_instruction);
+        let handle = rtc.handle();
+        self.map_device(base, RtcSim::SIZE, Box::new(rtc))?;
+        Ok(handle)
+    }
+
+    /// Map `device` at `[start, end)`
+    ///
+    /// Fails if the range is empty or overlaps an attached device. While
+    /// any device is attached the VM is stepped from the host.
+    pub fn attach_device(&mut self, start: u64, end: u64, device: Box<dyn Device>) -> Result<DeviceId> {
+        if start >= end {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Device range {:#x}..{:#x} is empty", start, end),
+            });
+        }
+        self.map_device(start, end - start, device).map(DeviceId)
+    }
+
+    /// Unmap a device attached with `attach_device` and return it
+    pub fn detach_device(&mut self, id: DeviceId) -> Result<Box<dyn Device>> {
+        self.devices.detach(id.0).ok_or_else(|| Error {
+            status: Status::InvalidParameter,
+            message: format!("No device attached as {:?}", id),
+        })
+    }
+
+    /// Attach a `TimerDevice` at `base`
+    ///
+    /// Its interrupts arrive as `DeviceInterrupt` events whose data is the
+    /// returned id's `index`.
+    pub fn attach_timer(&mut self, base: u64) -> Result<DeviceId> {
+        self.map_device(base, TimerDevice::SIZE, Box::new(TimerDevice::new()))
+            .map(DeviceId)
+    }
+
+    /// Attach an `RngDevice` at `base` drawing from the VM's `Entropy`
+    pub fn attach_rng(&mut self, base: u64) -> Result<DeviceId> {
+        let rng = RngDevice::new(self.entropy());
+        self.map_device(base, RngDevice::SIZE, Box::new(rng)).map(DeviceId)
+    }
+
+    /// Attach a `ConsoleDevice` at `base`
+    ///
+    /// Its output is collected for `console_output` and not echoed to the
+    /// VM's console unless enabled through the returned handle.
+    pub fn attach_console(&mut self, base: u64) -> Result<ConsoleHandle> {
+        self.attach_console_device(base, ConsoleDevice::new(false))
+    }
+
+    pub(crate) fn attach_console_device(&mut self, base: u64, console: ConsoleDevice) -> Result<ConsoleHandle> {
+        let handle = console.handle();
+        self.map_device(base, ConsoleDevice::SIZE, Box::new(console))?;
+        self.console_device = Some(handle.clone());
+        Ok(handle)
+    }
+
+    /// Pass console device output on to the VM's console
+    pub(crate) fn forward_console_output(&mut self) {
+        let Some((output, echo)) = self.console_device.as_ref().map(ConsoleHandle::take_unforwarded) else {
+            return;
+        };
+        if echo {
+            // A broken console must not fault the store that produced the output
+            let _ = self.console.write(&output);
+        } else {
+            self.console.record(&output);
+        }
+    }
+
+    /// Drain what the guest has written to the console device
+    ///
+    /// Invalid UTF-8 is replaced. Returns an empty string if no console is
+    /// attached.
+    pub fn console_output(&self) -> String {
+        self.console_device
+            .as_ref()
+            .map(|console| String::from_utf8_lossy(&console.take_output()).into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Show `data` to the guest at `address` without copying it into memory
+    ///
+    /// Guest loads and host reads of the range see `data` instead of the RAM
+    /// underneath. Guest stores into it raise a `MemoryFault` exception, and
+    /// host writes, fills and copies into it fail. The guest cannot execute
+    /// from an overlay; fetching there also raises `MemoryFault`. The range
+    /// must lie within memory and not overlap a device or another overlay.
+    /// Like devices, overlays make the VM step from the host.
+    pub fn map_readonly(&mut self, address: u64, data: Arc<[u8]>) -> Result<()> {
+        let end = address.checked_add(data.len() as u64).filter(|&end| end <= self.memory_size);
+        let Some(end) = end.filter(|_| !data.is_empty()) else {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Read-only range at {:#x} is empty or outside memory", address),
+            });
+        };
+        if !self.devices.overlay(address, data) {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Read-only range {:#x}..{:#x} overlaps a mapped range", address, end),
+            });
+        }
+        Ok(())
+    }
+
+    /// Fail if host writes to `[address, address + len)` would hit an overlay
+    pub(crate) fn check_writable(&self, address: u64, len: u64) -> Result<()> {
+        if self.devices.overlaps_overlay(address, address.saturating_add(len)) {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Range at {:#x} overlaps a read-only mapping", address),
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn map_device(&mut self, base: u64, size: u64, device: Box<dyn Device>) -> Result<usize> {
+        let end = base.checked_add(size).ok_or_else(|| Error {
+            status: Status::InvalidParameter,
+            message: format!("Device range at {:#x} overflows", base),
+        })?;
+        self.devices.attach(base, end, device).ok_or_else(|| Error {
+            status: Status::InvalidParameter,
+            message: format!("Device range {:#x}..{:#x} overlaps an attached device", base, end),
+        })
+    }
+}
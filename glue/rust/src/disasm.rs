@@ -0,0 +1,213 @@
+//! Instruction decoding and disassembly
+//!
+//! Decodes the 32-bit instruction words executed by the core. Words are
+//! stored most-significant byte first, matching the byte arrays used with
+//! `VM::load_program`.
+
+use std::fmt;
+
+pub use crate::opcode::Category;
+use crate::opcode::Opcode;
+use crate::{Result, VM};
+
+/// Decoded instruction operand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// General purpose register
+    Reg(u8),
+    /// Vector register
+    VReg(u8),
+    /// Immediate value
+    Imm(i64),
+    /// Memory reference `offset(base)`
+    Mem { base: u8, offset: i64 },
+    /// Absolute branch or call target
+    Target(u64),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Operand::Reg(r) => write!(f, "R{}", r),
+            Operand::VReg(v) => write!(f, "V{}", v),
+            Operand::Imm(imm) => write!(f, "{}", imm),
+            Operand::Mem { base, offset } => write!(f, "{}(R{})", offset, base),
+            Operand::Target(address) => write!(f, "{:#x}", address),
+        }
+    }
+}
+
+/// A decoded instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisasmInsn {
+    pub address: u64,
+    pub raw: u32,
+    pub mnemonic: String,
+    pub operands: Vec<Operand>,
+}
+
+impl fmt::Display for DisasmInsn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mnemonic)?;
+        for (i, operand) in self.operands.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " " } else { ", " }, operand)?;
+        }
+        Ok(())
+    }
+}
+
+/// Classify the instruction word `raw`
+pub fn category(raw: u32) -> Category {
+    Opcode::of(raw).map_or(Category::Unknown, Opcode::category)
+}
+
+/// Operand layout of an instruction, shared by the decoder and assembler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Shape {
+    /// No operands
+    None,
+    /// `rd`
+    Reg,
+    /// `rd, rs1`
+    RegReg,
+    /// `rd, rs1, rs2`
+    RegRegReg,
+    /// `rd, imm16`
+    RegImm,
+    /// `rd, imm16(rs1)`
+    RegMem,
+    /// `rd, rs1, target` with a 16-bit halfword offset
+    Branch,
+    /// `target` with a 26-bit word offset
+    Call,
+    /// Unsigned 26-bit immediate
+    Trap,
+    /// `imm16(rs1)`
+    Mem,
+    /// `imm16`
+    Imm,
+    /// `rd, 0(rs1)`
+    Reserved,
+    /// `rd, rs2, 0(rs1)`
+    Atomic,
+    /// `vd, vs1, vs2`
+    Vector,
+    /// `vd, imm16(rs1)`
+    VectorMem,
+    /// `vd, rs1`
+    Broadcast,
+}
+
+pub(crate) fn shape(opcode: Opcode) -> Shape {
+    use Opcode::*;
+    match opcode {
+        Not => Shape::RegReg,
+        Add | Sub | Mul | Mulh | Div | Mod | And | Or | Xor | Shl | Shr | Sar | Rol | Ror => Shape::RegRegReg,
+        Ld | Rdperf => Shape::RegImm,
+        Lw | Lh | Lb | St | Sw | Sh | Sb | Jmp => Shape::RegMem,
+        Beq | Bne | Blt | Bge | Bltu | Bgeu => Shape::Branch,
+        Call => Shape::Call,
+        Ret | Halt | Nop | Brk | Ei | Di => Shape::None,
+        Syscall => Shape::Trap,
+        Cpuid | Rdcycle => Shape::Reg,
+        Prefetch | Clflush => Shape::Mem,
+        Fence => Shape::Imm,
+        Lr => Shape::Reserved,
+        Sc | Amoswap | Amoadd | Amoand | Amoor | Amoxor => Shape::Atomic,
+        VaddF64 | VsubF64 | VmulF64 | VfmaF64 => Shape::Vector,
+        Vload | Vstore => Shape::VectorMem,
+        Vbroadcast => Shape::Broadcast,
+    }
+}
+
+/// Decode the instruction word `raw` located at `address`
+///
+/// Unknown opcodes decode to a `.word 0x........` pseudo-instruction.
+pub fn decode(address: u64, raw: u32) -> DisasmInsn {
+    let Some(opcode) = Opcode::of(raw) else {
+        return DisasmInsn {
+            address,
+            raw,
+            mnemonic: format!(".word {:#010x}", raw),
+            operands: Vec::new(),
+        };
+    };
+    let rd = ((raw >> 21) & 0x1F) as u8;
+    let rs1 = ((raw >> 16) & 0x1F) as u8;
+    let rs2 = ((raw >> 11) & 0x1F) as u8;
+    let imm = raw as u16 as i16 as i64;
+    let imm26 = ((raw << 6) as i32 >> 6) as i64;
+
+    use Operand::*;
+    let operands = match shape(opcode) {
+        Shape::None => vec![],
+        Shape::Reg => vec![Reg(rd)],
+        Shape::RegReg => vec![Reg(rd), Reg(rs1)],
+        Shape::RegRegReg => vec![Reg(rd), Reg(rs1), Reg(rs2)],
+        Shape::RegImm => vec![Reg(rd), Imm(imm)],
+        Shape::RegMem => vec![Reg(rd), Mem { base: rs1, offset: imm }],
+        Shape::Branch => vec![Reg(rd), Reg(rs1), Target(address.wrapping_add((imm * 2) as u64))],
+        Shape::Call => vec![Target(address.wrapping_add((imm26 * 4) as u64))],
+        Shape::Trap => vec![Imm(imm26 & 0x3FF_FFFF)],
+        Shape::Mem => vec![Mem { base: rs1, offset: imm }],
+        Shape::Imm => vec![Imm(imm)],
+        Shape::Reserved => vec![Reg(rd), Mem { base: rs1, offset: 0 }],
+        Shape::Atomic => vec![Reg(rd), Reg(rs2), Mem { base: rs1, offset: 0 }],
+        Shape::Vector => vec![VReg(rd & 0xF), VReg(rs1 & 0xF), VReg(rs2 & 0xF)],
+        Shape::VectorMem => vec![VReg(rd & 0xF), Mem { base: rs1, offset: imm }],
+        Shape::Broadcast => vec![VReg(rd & 0xF), Reg(rs1)],
+    };
+
+    DisasmInsn {
+        address,
+        raw,
+        mnemonic: opcode.mnemonic().to_string(),
+        operands,
+    }
+}
+
+impl VM {
+    /// Decode `count` consecutive instructions starting at `address`
+    ///
+    /// Words that are not valid instructions come back as `.word`
+    /// pseudo-instructions; only a range outside guest memory is an error.
+    pub fn disassemble(&self, address: u64, count: usize) -> Result<Vec<DisasmInsn>> {
+        let bytes = self.read_memory(address, 4 * count as u64)?;
+        Ok(bytes
+            .chunks_exact(4)
+            .zip((address..).step_by(4))
+            .map(|(word, pc)| decode(pc, u32::from_be_bytes(word.try_into().unwrap())))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_examples() {
+        assert_eq!(decode(0x10000, 0x3C20002A).to_string(), "LD R1, 42");
+        assert_eq!(decode(0x10000, 0x00611000).to_string(), "ADD R3, R1, R2");
+        assert_eq!(decode(0x10000, 0x84000000).to_string(), "HALT");
+        assert_eq!(decode(0x10000, 0xDC000000).to_string(), "BRK");
+        assert_eq!(decode(0x10000, 0x4C22FFF8).to_string(), "ST R1, -8(R2)");
+        assert_eq!(decode(0x10000, 0x5C220004).to_string(), "BEQ R1, R2, 0x10008");
+    }
+
+    #[test]
+    fn test_category() {
+        assert_eq!(category(0x3C20002A), Category::Alu);
+        assert_eq!(category(0x08611000), Category::MulDiv);
+        assert_eq!(category(0x4C22FFF8), Category::Store);
+        assert_eq!(category(0x5C220004), Category::Branch);
+        assert_eq!(category(0xFC000001), Category::Unknown);
+    }
+
+    #[test]
+    fn test_decode_unknown_opcode() {
+        let insn = decode(0, 0xFC000001);
+        assert_eq!(insn.mnemonic, ".word 0xfc000001");
+        assert!(insn.operands.is_empty());
+    }
+}
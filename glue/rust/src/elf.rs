@@ -0,0 +1,338 @@
+//! ELF program loading
+//!
+//! Only what is needed to place a statically linked executable in guest
+//! memory: the ELF64 header and its `PT_LOAD` program headers. Segments are
+//! loaded at their virtual address, since the core has no MMU. Both byte
+//! orders are accepted for the headers; segment contents are copied as-is.
+
+use crate::{Error, Flags, Result, RunOutcome, Status, VmState, VM};
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE: u8 = 1;
+const DATA_BIG: u8 = 2;
+const PT_LOAD: u32 = 1;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// A loadable segment of an ELF file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    address: u64,
+    /// Where the segment's contents start in the file
+    offset: usize,
+    file_size: usize,
+    /// Size in memory; the part past `file_size` is zero-filled
+    memory_size: u64,
+}
+
+fn invalid(message: &str) -> Error {
+    Error {
+        status: Status::InvalidParameter,
+        message: format!("Invalid ELF: {}", message),
+    }
+}
+
+/// Field reader honouring the file's byte order
+struct Reader<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn field<const N: usize>(&self, offset: usize) -> Result<[u8; N]> {
+        let mut field: [u8; N] = self
+            .bytes
+            .get(offset..offset.checked_add(N).ok_or_else(|| invalid("truncated header"))?)
+            .and_then(|slice| slice.try_into().ok())
+            .ok_or_else(|| invalid("truncated header"))?;
+        if !self.big_endian {
+            field.reverse();
+        }
+        Ok(field)
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16> {
+        self.field(offset).map(u16::from_be_bytes)
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32> {
+        self.field(offset).map(u32::from_be_bytes)
+    }
+
+    fn u64(&self, offset: usize) -> Result<u64> {
+        self.field(offset).map(u64::from_be_bytes)
+    }
+}
+
+/// Parse the entry point and loadable segments of `elf`
+fn parse(elf: &[u8]) -> Result<(u64, Vec<Segment>)> {
+    if elf.len() < HEADER_SIZE || &elf[..4] != MAGIC {
+        return Err(invalid("bad magic"));
+    }
+    if elf[4] != CLASS_64 {
+        return Err(invalid("only 64-bit files are supported"));
+    }
+    let big_endian = match elf[5] {
+        DATA_LITTLE => false,
+        DATA_BIG => true,
+        _ => return Err(invalid("unknown byte order")),
+    };
+    let reader = Reader { bytes: elf, big_endian };
+
+    let entry = reader.u64(24)?;
+    let table = reader.u64(32)? as usize;
+    let entry_size = reader.u16(54)? as usize;
+    let count = reader.u16(56)? as usize;
+    if count > 0 && entry_size < PROGRAM_HEADER_SIZE {
+        return Err(invalid("program header entries too small"));
+    }
+
+    let mut segments = Vec::new();
+    for index in 0..count {
+        let header = table
+            .checked_add(index * entry_size)
+            .ok_or_else(|| invalid("truncated header"))?;
+        if reader.u32(header)? != PT_LOAD {
+            continue;
+        }
+        let field = |offset: usize| header.checked_add(offset).ok_or_else(|| invalid("truncated header"));
+        let segment = Segment {
+            offset: reader.u64(field(8)?)? as usize,
+            address: reader.u64(field(16)?)?,
+            file_size: reader.u64(field(32)?)? as usize,
+            memory_size: reader.u64(field(40)?)?,
+        };
+        if segment.offset.checked_add(segment.file_size).is_none_or(|end| end > elf.len()) {
+            return Err(invalid("segment extends past end of file"));
+        }
+        if (segment.file_size as u64) > segment.memory_size {
+            return Err(invalid("segment file size exceeds its memory size"));
+        }
+        segments.push(segment);
+    }
+
+    Ok((entry, segments))
+}
+
+/// Everything observable about a finished `run_elf`
+#[derive(Debug, Clone)]
+pub struct RunReport {
+    pub outcome: RunOutcome,
+    /// Final registers, flags and performance counters
+    pub state: VmState,
+    /// Bytes the guest wrote to the console during the run
+    pub console_output: Vec<u8>,
+    /// `R1` at `HALT`, or `None` if the guest did not halt
+    pub exit_code: Option<i32>,
+}
+
+impl VM {
+    /// Copy the `PT_LOAD` segments of `elf` into memory and return its entry point
+    ///
+    /// Nothing is written unless every segment fits in guest memory.
+    pub fn load_elf(&mut self, elf: &[u8]) -> Result<u64> {
+        let (entry, segments) = parse(elf)?;
+        for segment in &segments {
+            if segment.address.checked_add(segment.memory_size).is_none_or(|end| end > self.memory_size) {
+                return Err(invalid(&format!("segment at {:#x} does not fit in guest memory", segment.address)));
+            }
+        }
+
+        for segment in &segments {
+            let mut data = elf[segment.offset..segment.offset + segment.file_size].to_vec();
+            data.resize(segment.memory_size as usize, 0);
+            self.write_memory(segment.address, &data)?;
+        }
+        self.flush_memory()?;
+        Ok(entry)
+    }
+
+    /// Load `elf`, start it at its entry point and run it to completion
+    ///
+    /// The stack pointer is set to the top of memory, as after `reset`. Other
+    /// VM configuration (devices, semihosting, breakpoints) is left as it is,
+    /// so attach what the program needs first. Console output is collected
+    /// for the report and still passed on to the connected console. Fails
+    /// without running anything if the entry point is not a valid PC.
+    pub fn run_elf(&mut self, elf: &[u8], max_instructions: Option<u64>) -> Result<RunReport> {
+        let entry = self.load_elf(elf)?;
+        if entry.checked_add(4).is_none_or(|end| end > self.memory_size) {
+            return Err(invalid(&format!("entry point {:#x} is outside guest memory", entry)));
+        }
+
+        let mut state = self.raw_state()?;
+        state.pc = entry;
+        state.sp = self.memory_size.saturating_sub(8);
+        state.gprs[30] = state.sp;
+        state.flags &= !Flags::HALTED;
+        self.set_raw_state(&state)?;
+
+        self.console.start_capture();
+        let outcome = self.run_outcome(max_instructions);
+        let console_output = self.console.take_capture();
+        let outcome = outcome?;
+
+        Ok(RunReport {
+            outcome,
+            state: self.get_state()?,
+            console_output,
+            exit_code: match outcome {
+                RunOutcome::Halted { exit_code } => Some(exit_code),
+                _ => None,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal big-endian ELF64 with one `PT_LOAD` segment holding `code`
+    fn build(entry: u64, address: u64, code: &[u8], bss: u64) -> Vec<u8> {
+        let mut elf = vec![0u8; HEADER_SIZE + PROGRAM_HEADER_SIZE];
+        elf[..4].copy_from_slice(MAGIC);
+        elf[4] = CLASS_64;
+        elf[5] = DATA_BIG;
+        elf[6] = 1;
+        elf[24..32].copy_from_slice(&entry.to_be_bytes());
+        elf[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_be_bytes());
+        elf[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_be_bytes());
+        elf[56..58].copy_from_slice(&1u16.to_be_bytes());
+
+        let header = &mut elf[HEADER_SIZE..];
+        header[..4].copy_from_slice(&PT_LOAD.to_be_bytes());
+        header[8..16].copy_from_slice(&((HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64).to_be_bytes());
+        header[16..24].copy_from_slice(&address.to_be_bytes());
+        header[32..40].copy_from_slice(&(code.len() as u64).to_be_bytes());
+        header[40..48].copy_from_slice(&(code.len() as u64 + bss).to_be_bytes());
+        elf.extend_from_slice(code);
+        elf
+    }
+
+    #[test]
+    fn test_parse_segments() {
+        let elf = build(0x20004, 0x20000, &[1, 2, 3, 4, 5, 6, 7, 8], 8);
+        let (entry, segments) = parse(&elf).unwrap();
+        assert_eq!(entry, 0x20004);
+        assert_eq!(
+            segments,
+            vec![Segment {
+                address: 0x20000,
+                offset: 120,
+                file_size: 8,
+                memory_size: 16,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_files() {
+        let elf = build(0, 0, &[0; 4], 0);
+        assert!(parse(&elf[..40]).is_err());
+        assert!(parse(&elf[..elf.len() - 1]).is_err());
+
+        let mut wrong_class = elf.clone();
+        wrong_class[4] = 1;
+        assert!(parse(&wrong_class).is_err());
+        let mut wrong_table = elf.clone();
+        wrong_table[32..40].copy_from_slice(&(u64::MAX - 2).to_be_bytes());
+        assert_eq!(parse(&wrong_table).unwrap_err().message, "Invalid ELF: truncated header");
+        let mut wrong_magic = elf;
+        wrong_magic[0] = 0;
+        assert_eq!(parse(&wrong_magic).unwrap_err().message, "Invalid ELF: bad magic");
+    }
+
+    #[test]
+    fn test_run_elf_reports_everything() {
+        use crate::semihosting::{SemihostingConfig, SEMIHOSTING_TRAP, SYS_WRITE0};
+        use crate::{init, PerfCounter};
+
+        let word = |opcode: u32, rd: u32, imm: u32| (opcode << 26 | rd << 21 | imm).to_be_bytes();
+        let code: Vec<u8> = [
+            *b"ok\n\0",
+            word(0x0F, 1, SYS_WRITE0 as u32),
+            word(0x0F, 2, 0x2000),
+            word(0x20, 0, SEMIHOSTING_TRAP),
+            word(0x0F, 1, 7),
+            word(0x21, 0, 0),
+        ]
+        .concat();
+
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        vm.connect_stdio(None, None);
+        vm.enable_semihosting(SemihostingConfig::default());
+        vm.write_memory(0x2004, &[0xFF; 4]).unwrap();
+
+        let report = vm.run_elf(&build(0x2004, 0x2000, &code, 4), Some(100)).unwrap();
+        assert_eq!(report.outcome, RunOutcome::Halted { exit_code: 7 });
+        assert_eq!(report.exit_code, Some(7));
+        assert_eq!(report.console_output, b"ok\n");
+        assert_eq!(report.state.sp, 1024 * 1024 - 8);
+        assert_eq!(report.state.perf_counters[PerfCounter::InstructionCount as usize], 5);
+        assert_eq!(vm.read_memory(0x2000 + code.len() as u64, 4).unwrap(), [0; 4]);
+    }
+
+    #[test]
+    fn test_run_elf_reports_console_device_output() {
+        use std::io::Write;
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for SharedOutput {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let word = |opcode: u32, rd: u32, rs1: u32, imm: u32| (opcode << 26 | rd << 21 | rs1 << 16 | imm).to_be_bytes();
+        let code: Vec<u8> = [
+            word(0x0F, 2, 0, 0x5000),
+            word(0x0F, 1, 0, b'o' as u32),
+            word(0x14, 1, 2, 0),
+            word(0x0F, 1, 0, b'k' as u32),
+            word(0x14, 1, 2, 0),
+            word(0x21, 0, 0, 0),
+        ]
+        .concat();
+        let elf = build(0x2000, 0x2000, &code, 0);
+
+        crate::init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let output = SharedOutput::default();
+        vm.connect_stdio(None, Some(Box::new(output.clone())));
+        let console = vm.attach_console(0x5000).unwrap();
+
+        // Without echo the output is reported but not written to the console
+        assert_eq!(vm.run_elf(&elf, Some(100)).unwrap().console_output, b"ok");
+        assert!(output.0.lock().unwrap().is_empty());
+
+        console.set_echo(true);
+        assert_eq!(vm.run_elf(&elf, Some(100)).unwrap().console_output, b"ok");
+        assert_eq!(*output.0.lock().unwrap(), b"ok");
+        assert_eq!(vm.console_output(), "okok");
+    }
+
+    #[test]
+    fn test_run_elf_rejects_bad_entry_and_handles_tiny_memory() {
+        crate::init().unwrap();
+        let halt = (0x21u32 << 26).to_be_bytes();
+        let mut vm = VM::new(1024).unwrap();
+        let error = vm.run_elf(&build(u64::MAX - 1, 0, &halt, 0), Some(10)).unwrap_err();
+        assert_eq!(error.message, "Invalid ELF: entry point 0xfffffffffffffffe is outside guest memory");
+        assert!(vm.run_elf(&build(1022, 0, &halt, 0), Some(10)).is_err());
+
+        let mut vm = VM::new(4).unwrap();
+        let report = vm.run_elf(&build(0, 0, &halt, 0), Some(10)).unwrap();
+        assert_eq!(report.outcome, RunOutcome::Halted { exit_code: 0 });
+        assert_eq!(report.state.sp, 0);
+    }
+}
@@ -0,0 +1,90 @@
+//! Energy estimation
+//!
+//! An `EnergyModel` assigns a cost in picojoules to each instruction
+//! category. While a non-zero model is installed every retired instruction is
+//! classified and charged, giving a rough energy profile of a guest workload.
+
+use std::collections::HashMap;
+
+use crate::disasm::{self, Category};
+use crate::VM;
+
+/// Picojoules per instruction, by category
+///
+/// Categories without a cost are free, so the default model is all zero.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EnergyModel {
+    costs: HashMap<Category, f64>,
+}
+
+impl EnergyModel {
+    /// Create a model in which every instruction is free
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the cost of one instruction in `category`, in picojoules
+    pub fn set_cost(&mut self, category: Category, picojoules: f64) {
+        self.costs.insert(category, picojoules);
+    }
+
+    /// Cost of one instruction in `category`, in picojoules
+    pub fn cost(&self, category: Category) -> f64 {
+        self.costs.get(&category).copied().unwrap_or(0.0)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.costs.values().all(|&cost| cost == 0.0)
+    }
+}
+
+/// Installed model plus the energy accumulated so far
+pub(crate) struct EnergyMeter {
+    model: EnergyModel,
+    /// Picojoules spent per category
+    totals: HashMap<Category, f64>,
+}
+
+impl EnergyMeter {
+    /// Charge one retired instruction
+    pub fn charge(&mut self, raw: u32) {
+        let category = disasm::category(raw);
+        let cost = self.model.cost(category);
+        if cost != 0.0 {
+            *self.totals.entry(category).or_insert(0.0) += cost;
+        }
+    }
+}
+
+const JOULES_PER_PICOJOULE: f64 = 1e-12;
+
+impl VM {
+    /// Install `model` and start accumulating from zero
+    ///
+    /// A model with all-zero costs turns estimation off. While a model is
+    /// installed the VM is stepped from the host.
+    pub fn set_energy_model(&mut self, model: EnergyModel) {
+        self.energy = if model.is_zero() {
+            None
+        } else {
+            Some(EnergyMeter {
+                model,
+                totals: HashMap::new(),
+            })
+        };
+    }
+
+    /// Total energy estimate in joules since the model was installed
+    pub fn estimated_energy(&self) -> f64 {
+        self.energy_breakdown().values().sum()
+    }
+
+    /// Energy estimate in joules per instruction category
+    pub fn energy_breakdown(&self) -> HashMap<Category, f64> {
+        self.energy
+            .iter()
+            .flat_map(|meter| meter.totals.iter())
+            .map(|(&category, &picojoules)| (category, picojoules * JOULES_PER_PICOJOULE))
+            .collect()
+    }
+}
@@ -0,0 +1,152 @@
+//! Randomness shared between a VM and its devices
+//!
+//! Each VM owns one `Entropy` source. Devices that need randomness, such as
+//! `devices::RngDevice`, draw from a clone of it instead of asking the OS,
+//! so seeding the VM with `VM::set_seed` makes every random value the guest
+//! sees reproducible. Without a seed the source starts from OS entropy and
+//! runs differ.
+//!
+//! The generator state is saved in every snapshot and restored with it, so
+//! a run resumed from a snapshot draws the same values it drew the first
+//! time.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+
+use crate::VM;
+
+/// Handle to a VM's random number generator
+///
+/// Clones share one generator. The sequence is SplitMix64: fast and well
+/// distributed, but not suitable for cryptography.
+#[derive(Debug, Clone)]
+pub struct Entropy {
+    state: Arc<Mutex<u64>>,
+}
+
+impl Entropy {
+    /// A generator producing the same sequence for the same `seed`
+    pub fn from_seed(seed: u64) -> Self {
+        Entropy {
+            state: Arc::new(Mutex::new(seed)),
+        }
+    }
+
+    /// A generator seeded from OS entropy
+    pub fn from_os() -> Self {
+        // `RandomState` keys are drawn from the OS on first use
+        Self::from_seed(RandomState::new().build_hasher().finish())
+    }
+
+    /// Restart the sequence from `seed` for every clone
+    pub fn reseed(&self, seed: u64) {
+        *self.state.lock().unwrap() = seed;
+    }
+
+    /// Current position in the sequence, which `reseed` returns to
+    pub(crate) fn state(&self) -> u64 {
+        *self.state.lock().unwrap()
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl VM {
+    /// Make every random value devices hand the guest reproducible
+    ///
+    /// Restarts the VM's generator from `seed`, so two VMs seeded alike and
+    /// given the same program and inputs see the same random values and
+    /// take the same path.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.entropy.reseed(seed);
+    }
+
+    /// The VM's random source, for custom devices to draw from
+    pub fn entropy(&self) -> Entropy {
+        self.entropy.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init;
+    use crate::snapshot::Snapshot;
+    use crate::trace::TraceEntry;
+
+    /// Run a loop that branches on random bits, returning the trace and R8
+    fn run_seeded(seed: u64) -> (Vec<TraceEntry>, u64) {
+        let word = |op: u32, rd: u32, rs1: u32, low: u32| op << 26 | rd << 21 | rs1 << 16 | low;
+        let program = [
+            word(0x10, 5, 2, 0),         // LW R5, 0(R2)
+            word(0x06, 6, 5, 7 << 11),   // AND R6, R5, R7
+            word(0x17, 6, 0, 4),         // BEQ R6, R0, +8
+            word(0x00, 8, 8, 9 << 11),   // ADD R8, R8, R9
+            word(0x01, 10, 10, 9 << 11), // SUB R10, R10, R9
+            word(0x18, 10, 0, 0xFFF6),   // BNE R10, R0, -20
+            word(0x21, 0, 0, 0),         // HALT
+        ];
+        let code: Vec<u8> = program.iter().flat_map(|word| word.to_be_bytes()).collect();
+
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        vm.attach_rng(0x4000).unwrap();
+        vm.set_seed(seed);
+        vm.load_program(&code, 0x10000).unwrap();
+        for (register, value) in [(2, 0x4000), (7, 1), (9, 1), (10, 64)] {
+            vm.set_register(register, value).unwrap();
+        }
+        vm.enable_trace(1024);
+        vm.run(Some(1000)).unwrap();
+        (vm.take_trace(), vm.get_register(8).unwrap())
+    }
+
+    #[test]
+    fn test_seeded_runs_replay_identically() {
+        let (trace, odd) = run_seeded(42);
+        assert_eq!(run_seeded(42), (trace.clone(), odd));
+        // Some draws were odd and some even, so the path really depended on them
+        assert!(odd > 0 && odd < 64);
+        assert_eq!(trace.len() as u64, 64 * 5 + odd + 1);
+        assert_ne!(run_seeded(43).0, trace);
+    }
+
+    #[test]
+    fn test_snapshots_restore_the_generator() {
+        let word = |op: u32, rd: u32, rs1: u32| (op << 26 | rd << 21 | rs1 << 16).to_be_bytes();
+        // LW R5, 0(R2); LW R6, 0(R2); HALT
+        let code = [word(0x10, 5, 2), word(0x10, 6, 2), word(0x21, 0, 0)].concat();
+
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        vm.attach_rng(0x4000).unwrap();
+        vm.load_program(&code, 0x10000).unwrap();
+        vm.set_register(2, 0x4000).unwrap();
+        vm.run(Some(1)).unwrap();
+
+        let snapshot = vm.snapshot().unwrap();
+        let cow = vm.snapshot_cow().unwrap();
+        let bytes = snapshot.to_bytes();
+        vm.run(Some(10)).unwrap();
+        let second = vm.get_register(6).unwrap();
+
+        // Each restore rewinds the draws made after the snapshot
+        vm.restore(&snapshot).unwrap();
+        vm.run(Some(10)).unwrap();
+        assert_eq!(vm.get_register(6).unwrap(), second);
+        vm.restore_cow(&cow).unwrap();
+        vm.run(Some(10)).unwrap();
+        assert_eq!(vm.get_register(6).unwrap(), second);
+        vm.restore(&Snapshot::from_bytes(&bytes).unwrap()).unwrap();
+        vm.run(Some(10)).unwrap();
+        assert_eq!(vm.get_register(6).unwrap(), second);
+    }
+}
@@ -0,0 +1,329 @@
+//! Host-driven execution
+//!
+//! The core runs whole batches on its own, but host-side features such as
+//! attached devices need to see every instruction. While any of them is
+//! active the VM is stepped one instruction at a time from here.
+
+use std::os::raw::c_int;
+
+use crate::opcode::Opcode;
+use crate::{disasm, semihosting};
+use crate::{
+    check_status, ffi, Event, EventType, Flags, GuestBreakpointMode, PerfCounter, Result, StopReason,
+    WatchKind, EXC_MEMORY_FAULT, EXC_ZERO_REGISTER_WRITE, VM, WATCHPOINT_WRITE_FLAG,
+};
+
+/// Core result code for a successful step or run
+pub(crate) const CODE_OK: c_int = 0;
+/// Core result code for a stop at a breakpoint
+pub(crate) const CODE_BREAKPOINT: c_int = 1;
+/// Core result code for a step that faulted
+pub(crate) const CODE_ERROR: c_int = -1;
+/// Host-side result code for a stop after a watched access
+pub(crate) const CODE_WATCHPOINT: c_int = 2;
+/// Host-side result code for a stop after an instruction in a watched class
+pub(crate) const CODE_OPCODE_BREAK: c_int = 3;
+
+/// Guest memory access made by a load or store instruction
+#[derive(Clone, Copy)]
+pub(crate) struct MemAccess {
+    pub store: bool,
+    pub address: u64,
+    pub width: u64,
+    /// Destination register for loads, source register for stores
+    pub reg: usize,
+}
+
+/// Decode the memory access an instruction would perform, if any
+pub(crate) fn decode_access(instruction: u32, gprs: &[u64; 32]) -> Option<MemAccess> {
+    let opcode = Opcode::of(instruction)?;
+    let reg = ((instruction >> 21) & 0x1F) as usize;
+    let rs1 = ((instruction >> 16) & 0x1F) as usize;
+    let imm = instruction as u16 as i16 as i64 as u64;
+
+    let (store, width) = match opcode {
+        Opcode::Lw => (false, 4),
+        Opcode::Lh => (false, 2),
+        Opcode::Lb => (false, 1),
+        Opcode::St => (true, 8),
+        Opcode::Sw => (true, 4),
+        Opcode::Sh => (true, 2),
+        Opcode::Sb => (true, 1),
+        _ => return None,
+    };
+
+    Some(MemAccess {
+        store,
+        address: gprs[rs1].wrapping_add(imm),
+        width,
+        reg,
+    })
+}
+
+/// General purpose register an instruction writes, if any
+pub(crate) fn destination(instruction: u32) -> Option<usize> {
+    use Opcode::*;
+    match Opcode::of(instruction)? {
+        Add | Sub | Mul | Mulh | Div | Mod | And | Or | Xor | Not | Shl | Shr | Sar | Rol | Ror
+        | Ld | Lw | Lh | Lb | Cpuid | Rdcycle | Rdperf | Lr | Sc | Amoswap | Amoadd | Amoand
+        | Amoor | Amoxor => Some(((instruction >> 21) & 0x1F) as usize),
+        _ => None,
+    }
+}
+
+fn width_mask(width: u64) -> u64 {
+    if width >= 8 {
+        u64::MAX
+    } else {
+        (1 << (8 * width)) - 1
+    }
+}
+
+pub(crate) fn sign_extend(value: u64, width: u64) -> u64 {
+    let shift = 64 - 8 * width;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+impl VM {
+    /// Whether any host-side feature requires stepping from the host
+    pub(crate) fn host_stepped(&self) -> bool {
+        !self.devices.is_empty()
+            || self.filtered_trace.is_some()
+            || self.trace_ring.is_some()
+            || !self.protection.is_empty()
+            || self.energy.is_some()
+            || self.bandwidth.is_some()
+            || self.read_fault_injector.is_some()
+            || self.semihosting.is_some()
+            || self.interrupt_flag_callback.is_some()
+            || self.guest_breakpoint_mode == GuestBreakpointMode::Continue
+            || self.trap_zero_register_write
+            || !self.watchpoints.is_empty()
+            || !self.opcode_breaks.is_empty()
+            || self.breakpoints.iter().any(|bp| bp.host_evaluated())
+    }
+
+    pub(crate) fn raw_state(&self) -> Result<ffi::VmState> {
+        let mut state = ffi::VmState::default();
+        let result = unsafe { ffi::nanocore_vm_get_state(self.handle, &mut state) };
+        check_status(result, "get VM state")?;
+        Ok(state)
+    }
+
+    pub(crate) fn set_raw_state(&mut self, state: &ffi::VmState) -> Result<()> {
+        let result = unsafe { ffi::nanocore_vm_set_state(self.handle, state) };
+        check_status(result, "set VM state")
+    }
+
+    /// Fetch the instruction word at `address` (stored most-significant byte first)
+    pub(crate) fn fetch(&self, address: u64) -> Result<u32> {
+        let bytes = self.read_memory(address, 4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Execute one instruction from the host
+    ///
+    /// Returns `None` once the instruction retired, or the core result code
+    /// when execution has to stop.
+    pub(crate) fn step_hosted(&mut self) -> Result<Option<c_int>> {
+        let mut state = self.raw_state()?;
+        if state.flags & Flags::HALTED != 0 {
+            return Ok(Some(CODE_OK));
+        }
+        if self.breakpoint_stops(state.pc, &state.gprs) {
+            return Ok(Some(CODE_BREAKPOINT));
+        }
+        let interrupts_were_enabled = state.flags & Flags::INTERRUPT_ENABLE != 0;
+        // Fetches from an overlay fault too, since the core would execute
+        // the RAM underneath it
+        if self.devices.overlay_at(state.pc).is_some()
+            || !self.protection.permits(state.pc, state.pc.saturating_add(4), false)
+        {
+            return Ok(Some(self.raise_exception(EXC_MEMORY_FAULT, Some(state.pc))));
+        }
+
+        let instruction = self.fetch(state.pc).ok();
+
+        if let (Some(ring), Some(raw)) = (self.trace_ring.as_mut(), instruction) {
+            ring.record(state.pc, raw);
+        }
+        if let (Some(trace), Some(raw)) = (self.filtered_trace.as_mut(), instruction) {
+            if (trace.filter)(state.pc, raw) {
+                (trace.sink)(disasm::decode(state.pc, raw));
+            }
+        }
+
+        if self.guest_breakpoint_mode == GuestBreakpointMode::Continue
+            && instruction.is_some_and(|raw| Opcode::of(raw) == Some(Opcode::Brk))
+        {
+            self.push_guest_breakpoint(state.pc);
+        }
+
+        if self.trap_zero_register_write && instruction.and_then(destination) == Some(0) {
+            return Ok(Some(self.raise_exception(EXC_ZERO_REGISTER_WRITE, None)));
+        }
+
+        let access = instruction.and_then(|raw| decode_access(raw, &state.gprs));
+        if let Some(access) = access.as_ref().filter(|access| {
+            !self.protection.permits(access.address, access.address.saturating_add(access.width), access.store)
+        }) {
+            return Ok(Some(self.raise_exception(EXC_MEMORY_FAULT, Some(access.address))));
+        }
+        let watch_hit = access.as_ref().and_then(|access| self.watched(access));
+        let mmio = access.as_ref().is_some_and(|access| self.devices.maps(access.address));
+        let watch_old = watch_hit.and(access).filter(|_| !mmio).map(|access| self.peek(access));
+        let injected = self.read_fault_injector.is_some()
+            && access.as_ref().is_some_and(|access| {
+                !access.store && access.address.saturating_add(access.width) <= self.memory_size
+            });
+
+        let semihost_call = self.semihosting.is_some() && instruction.is_some_and(semihosting::is_trap);
+        let opcode_hit = instruction
+            .and_then(Opcode::of)
+            .filter(|&opcode| self.opcode_breaks.iter().any(|class| class.contains(opcode)));
+
+        let code = match access {
+            Some(access) if mmio => self.emulate_mmio(&mut state, access)?,
+            Some(access) if injected => self.emulate_injected_load(&mut state, access)?,
+            _ if semihost_call => {
+                self.semihost_call(&mut state)?;
+                CODE_OK
+            }
+            _ => unsafe { ffi::nanocore_vm_step(self.handle) },
+        };
+        if code != CODE_OK {
+            return Ok(Some(code));
+        }
+
+        if let (Some(meter), Some(raw)) = (self.energy.as_mut(), instruction) {
+            meter.charge(raw);
+        }
+        if let Some(raw) = instruction.filter(|_| self.bandwidth.is_some()) {
+            self.charge_bandwidth(raw, &state.gprs)?;
+        }
+        self.devices.tick(1);
+        self.deliver_device_interrupts()?;
+        self.notify_interrupt_flag(interrupts_were_enabled)?;
+        
+        if let (Some((address, kind)), Some(access)) = (watch_hit, access) {
+            let (old_value, new_value) = match watch_old {
+                Some(old_value) => (old_value, self.peek(access)),
+                // A device register holds no value to compare, so report the one transferred
+                None => {
+                    let gprs = if access.store { state.gprs } else { self.raw_state()?.gprs };
+                    let value = gprs[access.reg] & width_mask(access.width);
+                    (value, value)
+                }
+            };
+            self.watch_hit = Some(StopReason::Watchpoint {
+                address,
+                kind,
+                old_value,
+                new_value,
+            });
+            self.push_event(Event {
+                event_type: EventType::Watchpoint,
+                data: if kind == WatchKind::Write { address | WATCHPOINT_WRITE_FLAG } else { address },
+                fault_address: None,
+            });
+            return Ok(Some(CODE_WATCHPOINT));
+        }
+        if let Some(opcode) = opcode_hit {
+            self.opcode_hit = Some((state.pc, opcode));
+            self.push_event(Event {
+                event_type: EventType::Breakpoint,
+                data: state.pc,
+                fault_address: None,
+            });
+            return Ok(Some(CODE_OPCODE_BREAK));
+        }
+        Ok(None)
+    }
+
+    /// Run up to `max_instructions` (0 for unlimited) from the host
+    pub(crate) fn run_hosted(&mut self, max_instructions: u64) -> Result<c_int> {
+        let mut executed = 0;
+        while max_instructions == 0 || executed < max_instructions {
+            if let Some(code) = self.step_hosted()? {
+                return Ok(code);
+            }
+            executed += 1;
+        }
+        Ok(CODE_OK)
+    }
+
+    /// Service a load or store that targets an attached device
+    fn emulate_mmio(&mut self, state: &mut ffi::VmState, access: MemAccess) -> Result<c_int> {
+        let result = if access.store {
+            let value = state.gprs[access.reg] & width_mask(access.width);
+            let written = self.devices.write(access.address, access.width, value).map(|_| ());
+            self.forward_console_output();
+            written
+        } else {
+            self.devices.read(access.address, access.width).map(|value| {
+                if access.reg != 0 {
+                    let value = value.unwrap_or(0) & width_mask(access.width);
+                    state.gprs[access.reg] = sign_extend(value, access.width);
+                }
+            })
+        };
+
+        if let Err(code) = result {
+            return Ok(self.raise_exception(code, Some(access.address)));
+        }
+
+        self.retire_memory_op(state)
+    }
+
+    /// The access start and kind if `access` touches a watched range
+    fn watched(&self, access: &MemAccess) -> Option<(u64, WatchKind)> {
+        let end = access.address.saturating_add(access.width);
+        self.watchpoints
+            .iter()
+            .any(|&(start, len, kind)| {
+                kind.matches(access.store) && access.address < start.saturating_add(len) && start < end
+            })
+            .then_some((access.address, if access.store { WatchKind::Write } else { WatchKind::Read }))
+    }
+
+    /// The bytes `access` covers as a little-endian value, or 0 outside memory
+    fn peek(&self, access: MemAccess) -> u64 {
+        self.read_memory(access.address, access.width)
+            .map(|bytes| bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64))
+            .unwrap_or(0)
+    }
+
+    /// Record a host-side exception and queue its event
+    fn raise_exception(&mut self, code: u32, fault_address: Option<u64>) -> c_int {
+        self.fault_code = Some(code);
+        self.push_event(Event {
+            event_type: EventType::Exception,
+            data: code as u64,
+            fault_address,
+        });
+        CODE_ERROR
+    }
+
+    /// Perform a guest load from RAM, passing the value through the read fault injector
+    fn emulate_injected_load(&mut self, state: &mut ffi::VmState, access: MemAccess) -> Result<c_int> {
+        let bytes = self.read_memory(access.address, access.width)?;
+        let mut value = bytes.iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+        if let Some(injector) = self.read_fault_injector.as_mut() {
+            value = injector(access.address, value) & width_mask(access.width);
+        }
+        if access.reg != 0 {
+            state.gprs[access.reg] = sign_extend(value, access.width);
+        }
+        self.retire_memory_op(state)
+    }
+
+    /// Retire an emulated load or store: advance PC and bump the counters
+    fn retire_memory_op(&mut self, state: &mut ffi::VmState) -> Result<c_int> {
+        state.pc += 4;
+        state.perf_counters[PerfCounter::InstructionCount as usize] += 1;
+        state.perf_counters[PerfCounter::CycleCount as usize] += 1;
+        state.perf_counters[PerfCounter::MemoryOps as usize] += 1;
+        self.set_raw_state(state)?;
+        Ok(CODE_OK)
+    }
+}
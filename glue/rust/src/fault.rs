@@ -0,0 +1,24 @@
+//! Fault injection hooks
+
+use crate::VM;
+
+/// Maps `(address, true value)` of a guest load to the value the guest sees
+pub type ReadFaultInjector = Box<dyn FnMut(u64, u64) -> u64 + Send>;
+
+impl VM {
+    /// Pass every guest load from RAM through `injector`
+    ///
+    /// The injector sees the zero-extended value actually stored in memory
+    /// and returns what the load should produce, which is truncated to the
+    /// access width and sign-extended as usual. Loads from devices and
+    /// host-side reads such as `read_memory` are not affected. While an
+    /// injector is installed the VM is stepped from the host.
+    pub fn set_read_fault_injector(&mut self, injector: ReadFaultInjector) {
+        self.read_fault_injector = Some(injector);
+    }
+
+    /// Remove the injector installed by `set_read_fault_injector`
+    pub fn clear_read_fault_injector(&mut self) {
+        self.read_fault_injector = None;
+    }
+}
@@ -0,0 +1,64 @@
+//! Canonical hex+ASCII dumps of guest memory
+
+use std::fmt::Write;
+
+use crate::{Result, VM};
+
+const BYTES_PER_LINE: usize = 16;
+
+impl VM {
+    /// Format `len` bytes at `address` like `hexdump -C`
+    ///
+    /// Each line holds 16 bytes: the guest address of the first one, the
+    /// bytes in hex split into two groups of eight, and the printable ASCII
+    /// characters between bars with `.` for the rest. Lines start at
+    /// `address` rather than at an aligned boundary, repeated lines are not
+    /// collapsed and there is no trailing line with the end address.
+    pub fn hexdump(&self, address: u64, len: u64) -> Result<String> {
+        let bytes = self.read_memory(address, len)?;
+        let mut out = String::new();
+        for (index, line) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+            write!(out, "{:08x} ", address + (index * BYTES_PER_LINE) as u64).unwrap();
+            for column in 0..BYTES_PER_LINE {
+                if column % 8 == 0 {
+                    out.push(' ');
+                }
+                match line.get(column) {
+                    Some(byte) => write!(out, "{:02x} ", byte).unwrap(),
+                    None => out.push_str("   "),
+                }
+            }
+            out.push_str(" |");
+            out.extend(line.iter().map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            }));
+            out.push_str("|\n");
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init;
+
+    #[test]
+    fn test_hexdump_format() {
+        init().unwrap();
+        let mut vm = VM::new(0x10000).unwrap();
+        vm.write_memory(0x1002, b"Hello, world!\n\x00\x7fAB").unwrap();
+
+        assert_eq!(
+            vm.hexdump(0x1000, 21).unwrap(),
+            "00001000  00 00 48 65 6c 6c 6f 2c  20 77 6f 72 6c 64 21 0a  |..Hello, world!.|\n\
+             00001010  00 7f 41 42 00                                    |..AB.|\n"
+        );
+        assert_eq!(vm.hexdump(0x1000, 0).unwrap(), "");
+        assert!(vm.hexdump(0xFFF8, 16).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! Multi-segment program images
+//!
+//! A `ProgramImage` places several blobs (code, data, tables) at their own
+//! addresses and names the entry point, for programs that do not fit the
+//! single blob `load_program` takes. Images can be shipped as one file in a
+//! small container format:
+//!
+//! | Offset | Size | Field                           |
+//! |--------|------|---------------------------------|
+//! | 0      | 4    | magic `NCIM`                    |
+//! | 4      | 4    | format version, currently 1     |
+//! | 8      | 8    | entry point                     |
+//! | 16     | 4    | number of segments              |
+//! | 20     | ...  | segments                        |
+//!
+//! Each segment is its load address (8 bytes), its length (8 bytes), then
+//! its contents. All integers are little-endian.
+
+use crate::{Error, Result, Status, VM};
+
+const MAGIC: &[u8; 4] = b"NCIM";
+const VERSION: u32 = 1;
+/// Magic, version, entry point and segment count
+const HEADER_SIZE: usize = 20;
+/// Address and length preceding each segment's contents
+const SEGMENT_HEADER_SIZE: usize = 16;
+
+fn invalid(message: &str) -> Error {
+    Error {
+        status: Status::InvalidParameter,
+        message: format!("Invalid program image: {}", message),
+    }
+}
+
+/// Segments to place in memory and the address to start executing at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramImage {
+    pub entry: u64,
+    /// `(address, bytes)` of each segment
+    pub segments: Vec<(u64, Vec<u8>)>,
+}
+
+impl ProgramImage {
+    /// Serialize into the container format described in the module docs
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.entry.to_le_bytes());
+        bytes.extend_from_slice(&(self.segments.len() as u32).to_le_bytes());
+        for (address, data) in &self.segments {
+            bytes.extend_from_slice(&address.to_le_bytes());
+            bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+
+    /// Parse the container format described in the module docs
+    ///
+    /// Fails on a bad magic, an unknown version, a truncated file or
+    /// trailing bytes. Overlap and fit are checked by `VM::load_image`.
+    pub fn from_bytes(data: &[u8]) -> Result<ProgramImage> {
+        if data.len() < HEADER_SIZE || &data[..4] != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let version = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+        let entry = u64::from_le_bytes(data[8..16].try_into().unwrap());
+        let count = u32::from_le_bytes(data[16..20].try_into().unwrap());
+
+        let mut rest = &data[HEADER_SIZE..];
+        let mut segments = Vec::new();
+        for _ in 0..count {
+            if rest.len() < SEGMENT_HEADER_SIZE {
+                return Err(invalid("truncated segment header"));
+            }
+            let address = u64::from_le_bytes(rest[..8].try_into().unwrap());
+            let len = u64::from_le_bytes(rest[8..16].try_into().unwrap());
+            rest = &rest[SEGMENT_HEADER_SIZE..];
+            if len > rest.len() as u64 {
+                return Err(invalid(&format!("segment at {:#x} extends past end of file", address)));
+            }
+            let (contents, tail) = rest.split_at(len as usize);
+            segments.push((address, contents.to_vec()));
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            return Err(invalid("trailing bytes after last segment"));
+        }
+
+        Ok(ProgramImage { entry, segments })
+    }
+}
+
+impl VM {
+    /// Copy every segment of `image` into memory and set PC to its entry point
+    ///
+    /// Nothing is written unless the segments fit in guest memory, no two
+    /// of them overlap and the entry point is a valid PC. Other registers
+    /// are left as they are.
+    pub fn load_image(&mut self, image: &ProgramImage) -> Result<()> {
+        if image.entry.checked_add(4).is_none_or(|end| end > self.memory_size) {
+            return Err(invalid(&format!("entry point {:#x} is outside guest memory", image.entry)));
+        }
+        let mut ranges = Vec::with_capacity(image.segments.len());
+        for (address, data) in &image.segments {
+            let end = address
+                .checked_add(data.len() as u64)
+                .filter(|&end| end <= self.memory_size)
+                .ok_or_else(|| invalid(&format!("segment at {:#x} does not fit in guest memory", address)))?;
+            ranges.push((*address, end));
+        }
+        ranges.sort_unstable();
+        if let Some(pair) = ranges.windows(2).find(|pair| pair[1].0 < pair[0].1) {
+            return Err(invalid(&format!(
+                "segments at {:#x} and {:#x} overlap",
+                pair[0].0, pair[1].0
+            )));
+        }
+
+        for (address, data) in &image.segments {
+            self.write_memory(*address, data)?;
+        }
+        self.flush_memory()?;
+        self.set_pc(image.entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init, RunOutcome};
+
+    fn image() -> ProgramImage {
+        // LW R1, 0(R2); HALT
+        let code = [0x10u32 << 26 | 1 << 21 | 2 << 16, 0x21 << 26]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect();
+        ProgramImage {
+            entry: 0x10000,
+            segments: vec![(0x20000, 42u32.to_le_bytes().to_vec()), (0x10000, code)],
+        }
+    }
+
+    #[test]
+    fn test_load_image_runs_from_entry() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let parsed = ProgramImage::from_bytes(&image().to_bytes()).unwrap();
+        assert_eq!(parsed, image());
+
+        vm.load_image(&parsed).unwrap();
+        assert_eq!(vm.get_state().unwrap().pc, 0x10000);
+        vm.set_register(2, 0x20000).unwrap();
+        assert_eq!(vm.run_outcome(Some(10)).unwrap(), RunOutcome::Halted { exit_code: 42 });
+    }
+
+    #[test]
+    fn test_load_image_rejects_overlap_and_overflow() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+
+        let mut overlapping = image();
+        overlapping.segments.push((0x10004, vec![0; 8]));
+        assert!(vm.load_image(&overlapping).is_err());
+        assert_eq!(vm.read_memory(0x10000, 4).unwrap(), [0; 4]);
+
+        let mut too_big = image();
+        too_big.segments.push((1024 * 1024 - 2, vec![0; 4]));
+        assert!(vm.load_image(&too_big).is_err());
+
+        let bad_entry = ProgramImage { entry: u64::MAX, ..image() };
+        assert!(vm.load_image(&bad_entry).is_err());
+        assert_eq!(vm.read_memory(0x10000, 4).unwrap(), [0; 4]);
+
+        let bytes = image().to_bytes();
+        assert!(ProgramImage::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(ProgramImage::from_bytes(&trailing).is_err());
+        assert!(ProgramImage::from_bytes(b"NCSN").is_err());
+    }
+}
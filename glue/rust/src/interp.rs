@@ -0,0 +1,1070 @@
+//! Pure-Rust interpreter, behind the `interp` feature
+//!
+//! `Interpreter` implements the instruction set of the reference C core in
+//! `glue/ffi/nanocore_ffi.c` over guest memory held in a `Vec` or handed over
+//! by `VM::new_with_allocator`, keeping the same `VmState` layout so registers
+//! and snapshots carry over unchanged.
+//!
+//! `build.rs` skips the C core when targeting wasm or when the
+//! `NANOCORE_NO_NATIVE` environment variable is set. The core entry points
+//! then resolve to `abi` below, so every `VM::new` runs on an interpreter.
+//! `NANOCORE_NO_NATIVE=1 cargo test --features interp` runs the crate's
+//! whole test suite that way; with the native core linked, the tests here
+//! run the same programs on both backends and compare the results.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::raw::c_int;
+use std::path::Path;
+
+use crate::allocator::GuestMemory;
+use crate::exec::{decode_access, sign_extend, CODE_BREAKPOINT, CODE_ERROR, CODE_OK};
+use crate::opcode::Opcode;
+use crate::snapshot::{dirty_words, PAGE_SIZE};
+use crate::{ffi, Error, Flags, Result, RunOutcome, Status, VmState};
+
+/// Register holding the guest exit code at HALT
+const EXIT_CODE_REGISTER: usize = 1;
+/// PC after creation and reset
+const ENTRY_POINT: u64 = 0x10000;
+/// Breakpoints tracked at once, as in the C core
+const MAX_BREAKPOINTS: usize = 64;
+
+/// A NanoCore core implemented in Rust
+pub struct Interpreter {
+    state: ffi::VmState,
+    memory: GuestMemory,
+    /// File the memory was loaded from, written back on flush
+    file: Option<File>,
+    halted: bool,
+    exit_code: i32,
+    /// `BRK` retires as a NOP instead of stopping
+    guest_break_nop: bool,
+    /// Address of the `BRK` the last step stopped on
+    guest_break: Option<u64>,
+    breakpoints: Vec<u64>,
+    /// One bit per `PAGE_SIZE` page written since `take_dirty_pages`
+    dirty: Vec<u64>,
+}
+
+impl Interpreter {
+    /// Create an interpreter with `memory_size` bytes of zeroed memory
+    pub fn new(memory_size: u64) -> Result<Interpreter> {
+        if memory_size == 0 {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: "Memory size must be nonzero".to_string(),
+            });
+        }
+        let mut memory = Vec::new();
+        usize::try_from(memory_size)
+            .ok()
+            .filter(|&size| memory.try_reserve_exact(size).is_ok())
+            .ok_or_else(|| Error {
+                status: Status::OutOfMemory,
+                message: format!("Cannot allocate {} bytes of guest memory", memory_size),
+            })?;
+        memory.resize(memory_size as usize, 0);
+        Ok(Interpreter::with_memory(Box::new(memory), None))
+    }
+
+    /// Run out of the first `memory_size` bytes of the file at `path`
+    ///
+    /// The file is created or extended as for `VM::new_with_file`. Guest
+    /// writes reach it through `flush_range`, and when the interpreter is
+    /// destroyed through the core entry points.
+    pub fn new_with_file(path: &Path, memory_size: u64) -> Result<Interpreter> {
+        let open = || -> io::Result<Interpreter> {
+            let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+            if file.metadata()?.len() < memory_size {
+                file.set_len(memory_size)?;
+            }
+            let size = usize::try_from(memory_size).map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+            let mut memory = vec![0; size];
+            file.read_exact(&mut memory)?;
+            Ok(Interpreter::with_memory(Box::new(memory), Some(file)))
+        };
+        let opened = if memory_size > 0 { open().ok() } else { None };
+        opened.ok_or_else(|| Error {
+            status: Status::InvalidParameter,
+            message: format!("Cannot use {} as guest memory", path.display()),
+        })
+    }
+
+    fn with_memory(memory: GuestMemory, file: Option<File>) -> Interpreter {
+        let mut interpreter = Interpreter {
+            state: ffi::VmState::default(),
+            memory,
+            file,
+            halted: false,
+            exit_code: 0,
+            guest_break_nop: false,
+            guest_break: None,
+            breakpoints: Vec::new(),
+            dirty: Vec::new(),
+        };
+        interpreter.dirty = vec![0; dirty_words(interpreter.memory.len())];
+        interpreter.reset();
+        interpreter
+    }
+
+    /// Clear registers, breakpoints and the halt, keeping memory
+    pub fn reset(&mut self) {
+        self.state = ffi::VmState {
+            sp: (self.memory.len() as u64).wrapping_sub(8),
+            pc: ENTRY_POINT,
+            ..ffi::VmState::default()
+        };
+        self.halted = false;
+        self.exit_code = 0;
+        self.guest_break = None;
+        self.breakpoints.clear();
+    }
+
+    /// Copy `program` to `address` and start executing there
+    pub fn load_program(&mut self, program: &[u8], address: u64) -> Result<()> {
+        let range = self.range(address, program.len() as u64).ok_or_else(|| Error {
+            status: Status::InvalidParameter,
+            message: format!("Program of {} bytes does not fit at {:#x}", program.len(), address),
+        })?;
+        self.memory[range.clone()].copy_from_slice(program);
+        self.mark_dirty(range);
+        self.state.pc = address;
+        Ok(())
+    }
+
+    /// Stop before executing the instruction at `address`
+    ///
+    /// At most 64 breakpoints can be set at once.
+    pub fn set_breakpoint(&mut self, address: u64) -> Result<()> {
+        if self.breakpoints.len() >= MAX_BREAKPOINTS {
+            return Err(Error {
+                status: Status::Error,
+                message: format!("Cannot set more than {} breakpoints", MAX_BREAKPOINTS),
+            });
+        }
+        self.breakpoints.push(address);
+        Ok(())
+    }
+
+    /// Remove one breakpoint at `address`
+    pub fn clear_breakpoint(&mut self, address: u64) -> Result<()> {
+        let index = self.breakpoints.iter().position(|&bp| bp == address).ok_or_else(|| Error {
+            status: Status::Error,
+            message: format!("No breakpoint at {:#x}", address),
+        })?;
+        self.breakpoints.remove(index);
+        Ok(())
+    }
+
+    /// Write `size` bytes of memory at `address` back to the backing file
+    ///
+    /// Does nothing beyond the range check for memory without a file.
+    pub fn flush_range(&mut self, address: u64, size: u64) -> Result<()> {
+        let range = self.range(address, size).ok_or_else(|| Error {
+            status: Status::InvalidParameter,
+            message: format!("Range {:#x}+{:#x} is outside guest memory", address, size),
+        })?;
+        self.write_back(range).map_err(|error| Error {
+            status: Status::Error,
+            message: format!("Failed to write back guest memory: {}", error),
+        })
+    }
+
+    /// Current registers
+    pub fn state(&self) -> VmState {
+        self.state.into()
+    }
+
+    /// Guest memory
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Guest memory, for the host to write
+    ///
+    /// Every page counts as written, since the caller may write anywhere.
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        self.mark_dirty(0..self.memory.len());
+        &mut self.memory
+    }
+
+    /// Which pages were written since the last call, then start over
+    ///
+    /// Bit `n % 64` of word `n / 64` is set if any byte of the `PAGE_SIZE`
+    /// page at `n * PAGE_SIZE` was written by the guest or the host.
+    pub fn take_dirty_pages(&mut self) -> Vec<u64> {
+        let words = self.dirty.len();
+        std::mem::replace(&mut self.dirty, vec![0; words])
+    }
+
+    fn mark_dirty(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        let page_size = PAGE_SIZE as usize;
+        for page in range.start / page_size..=(range.end - 1) / page_size {
+            self.dirty[page / 64] |= 1 << (page % 64);
+        }
+    }
+
+    /// Execute one instruction
+    pub fn step(&mut self) -> RunOutcome {
+        let code = self.step_raw();
+        self.outcome(code)
+    }
+
+    /// Run until HALT, a breakpoint, a fault or `max_instructions`
+    pub fn run(&mut self, max_instructions: Option<u64>) -> RunOutcome {
+        let code = self.run_raw(max_instructions.unwrap_or(0));
+        self.outcome(code)
+    }
+
+    fn outcome(&self, code: c_int) -> RunOutcome {
+        match code {
+            CODE_BREAKPOINT => RunOutcome::Breakpoint {
+                address: self.guest_break.unwrap_or(self.state.pc),
+            },
+            CODE_OK if self.state.flags & Flags::HALTED != 0 => RunOutcome::Halted {
+                exit_code: self.exit_code,
+            },
+            CODE_OK => RunOutcome::Limit,
+            _ => RunOutcome::Exception,
+        }
+    }
+
+    /// Indices of the `size` bytes at `address`, if they lie in memory
+    fn range(&self, address: u64, size: u64) -> Option<Range<usize>> {
+        let end = address.checked_add(size).filter(|&end| end <= self.memory.len() as u64)?;
+        Some(address as usize..end as usize)
+    }
+
+    /// Run like `nanocore_vm_run`; 0 means no instruction limit
+    fn run_raw(&mut self, max_instructions: u64) -> c_int {
+        let mut count = 0;
+        while !self.halted && (max_instructions == 0 || count < max_instructions) {
+            let result = self.step_raw();
+            if result != CODE_OK {
+                return result;
+            }
+            count += 1;
+        }
+        CODE_OK
+    }
+
+    fn step_raw(&mut self) -> c_int {
+        self.guest_break = None;
+        if self.halted {
+            return CODE_OK;
+        }
+        let pc = self.state.pc;
+        let Some(word) = self.range(pc, 4) else {
+            self.halted = true;
+            return CODE_ERROR;
+        };
+        if self.breakpoints.contains(&pc) {
+            return CODE_BREAKPOINT;
+        }
+
+        // Instructions are stored most-significant byte first
+        let raw = u32::from_be_bytes(self.memory[word].try_into().unwrap());
+        self.state.pc = pc.wrapping_add(4);
+        self.execute(raw)
+    }
+
+    fn execute(&mut self, raw: u32) -> c_int {
+        let rd = ((raw >> 21) & 0x1F) as usize;
+        let rs1 = ((raw >> 16) & 0x1F) as usize;
+        let rs2 = ((raw >> 11) & 0x1F) as usize;
+        let imm = raw as u16 as i16 as i64 as u64;
+        // Branch offsets count halfwords from the next instruction
+        let branch_offset = (imm << 1).wrapping_sub(4);
+
+        self.state.gprs[0] = 0;
+        let gprs = &self.state.gprs;
+        let (a, b) = (gprs[rs1], gprs[rs2]);
+        let result = match Opcode::of(raw) {
+            Some(Opcode::Add) => Some(a.wrapping_add(b)),
+            Some(Opcode::Sub) => Some(a.wrapping_sub(b)),
+            Some(Opcode::Mul) => Some(a.wrapping_mul(b)),
+            Some(Opcode::Div) => a.checked_div(b),
+            Some(Opcode::Mod) => a.checked_rem(b),
+            Some(Opcode::And) => Some(a & b),
+            Some(Opcode::Or) => Some(a | b),
+            Some(Opcode::Xor) => Some(a ^ b),
+            Some(Opcode::Shl) => Some(a << (b & 63)),
+            Some(Opcode::Shr) => Some(a >> (b & 63)),
+            Some(Opcode::Ld) => Some(imm),
+            Some(Opcode::Lw | Opcode::Lh | Opcode::Lb | Opcode::St | Opcode::Sw | Opcode::Sh | Opcode::Sb) => {
+                self.memory_op(raw);
+                None
+            }
+            Some(Opcode::Beq) => {
+                if gprs[rd] == a {
+                    self.state.pc = self.state.pc.wrapping_add(branch_offset);
+                }
+                None
+            }
+            Some(Opcode::Bne) => {
+                if gprs[rd] != a {
+                    self.state.pc = self.state.pc.wrapping_add(branch_offset);
+                }
+                None
+            }
+            Some(Opcode::Blt) => {
+                if (gprs[rd] as i64) < a as i64 {
+                    self.state.pc = self.state.pc.wrapping_add(branch_offset);
+                }
+                None
+            }
+            Some(Opcode::Jmp) => {
+                let link = self.state.pc;
+                self.state.pc = a.wrapping_add(imm);
+                Some(link)
+            }
+            Some(Opcode::Call) => {
+                // 26-bit signed word offset from the next instruction
+                let offset = ((raw << 6) as i32 >> 6) as i64 as u64;
+                self.state.gprs[31] = self.state.pc;
+                self.state.pc = self.state.pc.wrapping_add(offset.wrapping_mul(4)).wrapping_sub(4);
+                None
+            }
+            Some(Opcode::Ret) => {
+                self.state.pc = gprs[31];
+                None
+            }
+            Some(Opcode::Halt) => {
+                self.halted = true;
+                self.exit_code = gprs[EXIT_CODE_REGISTER] as i32;
+                self.state.flags |= Flags::HALTED;
+                None
+            }
+            Some(Opcode::Nop) => None,
+            Some(Opcode::Brk) => {
+                if !self.guest_break_nop {
+                    self.guest_break = Some(self.state.pc.wrapping_sub(4));
+                }
+                None
+            }
+            Some(Opcode::Ei) => {
+                self.state.flags |= Flags::INTERRUPT_ENABLE;
+                None
+            }
+            Some(Opcode::Di) => {
+                self.state.flags &= !Flags::INTERRUPT_ENABLE;
+                None
+            }
+            _ => {
+                self.halted = true;
+                return CODE_ERROR;
+            }
+        };
+        if let Some(value) = result.filter(|_| rd != 0) {
+            self.state.gprs[rd] = value;
+        }
+
+        let counters = &mut self.state.perf_counters;
+        counters[0] = counters[0].wrapping_add(1); // Instruction count
+        counters[1] = counters[1].wrapping_add(1); // Cycle count
+        if self.guest_break.is_some() {
+            CODE_BREAKPOINT
+        } else {
+            CODE_OK
+        }
+    }
+
+    /// Perform a load or store; accesses outside memory are dropped
+    fn memory_op(&mut self, raw: u32) {
+        if let Some(access) = decode_access(raw, &self.state.gprs) {
+            if let Some(range) = self.range(access.address, access.width) {
+                let bytes = &mut self.memory[range.clone()];
+                if access.store {
+                    let value = self.state.gprs[access.reg].to_le_bytes();
+                    bytes.copy_from_slice(&value[..bytes.len()]);
+                    self.mark_dirty(range);
+                } else if access.reg != 0 {
+                    let value = bytes.iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+                    self.state.gprs[access.reg] = sign_extend(value, access.width);
+                }
+            }
+        }
+        let memory_ops = &mut self.state.perf_counters[6];
+        *memory_ops = memory_ops.wrapping_add(1);
+    }
+
+    /// Write `range` of memory back to the backing file, if there is one
+    fn write_back(&mut self, range: Range<usize>) -> io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.seek(SeekFrom::Start(range.start as u64))?;
+            file.write_all(&self.memory[range])?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// The C core's entry points, implemented over a table of interpreters
+///
+/// Each function has the signature and result codes of its counterpart in
+/// `nanocore_ffi.c`, so the rest of the crate cannot tell them apart.
+#[cfg(not(nanocore_native))]
+pub(crate) mod abi {
+    use std::cell::RefCell;
+    use std::ffi::CStr;
+    use std::ops::{Deref, DerefMut};
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::path::Path;
+    use std::ptr;
+    use std::slice;
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Interpreter;
+    use crate::exec::{CODE_ERROR, CODE_OK};
+    use crate::{ffi, EventType, Flags, Result, Status};
+
+    const CODE_EINVAL: c_int = Status::InvalidParameter as c_int;
+    /// Handles available at once, as in the C core
+    const MAX_INSTANCES: usize = 256;
+    const GUEST_BREAK_STOP: c_int = 0;
+    const GUEST_BREAK_NOP: c_int = 1;
+
+    static INSTANCES: Mutex<Vec<Option<Interpreter>>> = Mutex::new(Vec::new());
+
+    thread_local! {
+        /// Why the last failed VM creation on this thread failed
+        static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+    }
+
+    /// Record `message` for `nanocore_last_error_message` and return `code`
+    fn fail(code: c_int, message: impl Into<String>) -> c_int {
+        LAST_ERROR.with(|last| *last.borrow_mut() = message.into());
+        code
+    }
+
+    fn instances() -> MutexGuard<'static, Vec<Option<Interpreter>>> {
+        INSTANCES.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Run `f` on the interpreter behind `vm_handle`, or fail with `CODE_EINVAL`
+    fn with_instance(vm_handle: c_int, f: impl FnOnce(&mut Interpreter) -> c_int) -> c_int {
+        let mut instances = instances();
+        let interpreter = usize::try_from(vm_handle)
+            .ok()
+            .and_then(|index| instances.get_mut(index)?.as_mut());
+        match interpreter {
+            Some(interpreter) => f(interpreter),
+            None => CODE_EINVAL,
+        }
+    }
+
+    fn status_code(result: Result<()>) -> c_int {
+        match result {
+            Ok(()) => CODE_OK,
+            Err(error) => error.status as c_int,
+        }
+    }
+
+    /// Guest memory owned by the embedder, released through its callback
+    struct ForeignMemory {
+        data: *mut u8,
+        len: usize,
+        release: unsafe extern "C" fn(*mut c_void),
+        context: *mut c_void,
+    }
+
+    // The region is exclusively ours until `release`, like a `Vec`'s buffer
+    unsafe impl Send for ForeignMemory {}
+
+    impl Deref for ForeignMemory {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            unsafe { slice::from_raw_parts(self.data, self.len) }
+        }
+    }
+
+    impl DerefMut for ForeignMemory {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            unsafe { slice::from_raw_parts_mut(self.data, self.len) }
+        }
+    }
+
+    impl Drop for ForeignMemory {
+        fn drop(&mut self) {
+            unsafe { (self.release)(self.context) }
+        }
+    }
+
+    /// Store `interpreter` in the first free slot and report its handle
+    unsafe fn register(interpreter: Interpreter, vm_handle: *mut c_int) -> c_int {
+        register_with(|| interpreter, vm_handle)
+    }
+
+    /// Like `register`, but only builds the interpreter once a slot is free
+    unsafe fn register_with(interpreter: impl FnOnce() -> Interpreter, vm_handle: *mut c_int) -> c_int {
+        let mut instances = instances();
+        let index = match instances.iter().position(Option::is_none) {
+            Some(index) => index,
+            None if instances.len() < MAX_INSTANCES => {
+                instances.push(None);
+                instances.len() - 1
+            }
+            None => return fail(CODE_ERROR, "all 256 VM slots are in use"),
+        };
+        instances[index] = Some(interpreter());
+        *vm_handle = index as c_int;
+        CODE_OK
+    }
+
+    pub unsafe fn nanocore_init() -> c_int {
+        CODE_OK
+    }
+
+    pub unsafe fn nanocore_last_error_message(buf: *mut c_char, len: usize) -> c_int {
+        LAST_ERROR.with(|last| {
+            let last = last.borrow();
+            if !buf.is_null() && len > 0 {
+                let copied = last.len().min(len - 1);
+                ptr::copy_nonoverlapping(last.as_ptr().cast(), buf, copied);
+                *buf.add(copied) = 0;
+            }
+            last.len() as c_int
+        })
+    }
+
+    pub unsafe fn nanocore_vm_create(memory_size: u64, vm_handle: *mut c_int) -> c_int {
+        if vm_handle.is_null() {
+            return fail(CODE_EINVAL, "no handle out-parameter");
+        }
+        match Interpreter::new(memory_size) {
+            Ok(interpreter) => register(interpreter, vm_handle),
+            Err(error) => fail(error.status as c_int, error.message),
+        }
+    }
+
+    pub unsafe fn nanocore_vm_create_with_memory(
+        memory: *mut u8,
+        memory_size: u64,
+        release: unsafe extern "C" fn(*mut c_void),
+        context: *mut c_void,
+        vm_handle: *mut c_int,
+    ) -> c_int {
+        if memory.is_null() || vm_handle.is_null() {
+            return fail(CODE_EINVAL, "no memory or handle out-parameter");
+        }
+        let Some(len) = usize::try_from(memory_size).ok().filter(|&len| len > 0) else {
+            return fail(CODE_EINVAL, "memory size must be nonzero");
+        };
+        // On failure the caller keeps the region, so it must not be wrapped yet
+        register_with(
+            || {
+                let memory = ForeignMemory { data: memory, len, release, context };
+                Interpreter::with_memory(Box::new(memory), None)
+            },
+            vm_handle,
+        )
+    }
+
+    pub unsafe fn nanocore_vm_create_file(path: *const c_char, memory_size: u64, vm_handle: *mut c_int) -> c_int {
+        if path.is_null() || vm_handle.is_null() {
+            return fail(CODE_EINVAL, "no path or handle out-parameter");
+        }
+        let Ok(path) = CStr::from_ptr(path).to_str() else {
+            return fail(CODE_EINVAL, "memory file path is not UTF-8");
+        };
+        match Interpreter::new_with_file(Path::new(path), memory_size) {
+            Ok(interpreter) => register(interpreter, vm_handle),
+            Err(error) => fail(error.status as c_int, error.message),
+        }
+    }
+
+    pub unsafe fn nanocore_vm_destroy(vm_handle: c_int) -> c_int {
+        let mut instances = instances();
+        let interpreter = usize::try_from(vm_handle)
+            .ok()
+            .and_then(|index| instances.get_mut(index)?.take());
+        match interpreter {
+            Some(mut interpreter) => {
+                let all = 0..interpreter.memory.len();
+                let _ = interpreter.write_back(all);
+                CODE_OK
+            }
+            None => CODE_EINVAL,
+        }
+    }
+
+    pub unsafe fn nanocore_vm_reset(vm_handle: c_int) -> c_int {
+        with_instance(vm_handle, |vm| {
+            vm.reset();
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_run(vm_handle: c_int, max_instructions: u64) -> c_int {
+        with_instance(vm_handle, |vm| vm.run_raw(max_instructions))
+    }
+
+    pub unsafe fn nanocore_vm_step(vm_handle: c_int) -> c_int {
+        with_instance(vm_handle, |vm| vm.step_raw())
+    }
+
+    pub unsafe fn nanocore_vm_set_guest_break_mode(vm_handle: c_int, mode: c_int) -> c_int {
+        if mode != GUEST_BREAK_STOP && mode != GUEST_BREAK_NOP {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            vm.guest_break_nop = mode == GUEST_BREAK_NOP;
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_get_guest_break(vm_handle: c_int, address: *mut u64) -> c_int {
+        if address.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| match vm.guest_break {
+            Some(pc) => {
+                *address = pc;
+                CODE_OK
+            }
+            None => CODE_ERROR,
+        })
+    }
+
+    pub unsafe fn nanocore_vm_get_exit_code(vm_handle: c_int, exit_code: *mut c_int) -> c_int {
+        if exit_code.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            if vm.state.flags & Flags::HALTED == 0 {
+                return CODE_ERROR;
+            }
+            *exit_code = vm.exit_code;
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_get_state(vm_handle: c_int, state: *mut ffi::VmState) -> c_int {
+        if state.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            *state = vm.state;
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_set_state(vm_handle: c_int, state: *const ffi::VmState) -> c_int {
+        if state.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            let was_halted = vm.state.flags & Flags::HALTED != 0;
+            vm.state = *state;
+            vm.state.gprs[0] = 0;
+            vm.halted = vm.state.flags & Flags::HALTED != 0;
+            if vm.halted && !was_halted {
+                // Halting from the host reports an exit code just like HALT
+                vm.exit_code = vm.state.gprs[super::EXIT_CODE_REGISTER] as i32;
+            }
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_get_register(vm_handle: c_int, reg_index: c_int, value: *mut u64) -> c_int {
+        if !(0..32).contains(&reg_index) || value.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            *value = vm.state.gprs[reg_index as usize];
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_set_register(vm_handle: c_int, reg_index: c_int, value: u64) -> c_int {
+        if !(0..32).contains(&reg_index) {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            // R0 is hardwired to zero
+            if reg_index != 0 {
+                vm.state.gprs[reg_index as usize] = value;
+            }
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_get_registers(vm_handle: c_int, values: *mut u64) -> c_int {
+        if values.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            slice::from_raw_parts_mut(values, 32).copy_from_slice(&vm.state.gprs);
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_set_registers(vm_handle: c_int, values: *const u64) -> c_int {
+        if values.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            // The value for R0 is ignored
+            vm.state.gprs[1..].copy_from_slice(&slice::from_raw_parts(values, 32)[1..]);
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_get_vreg(vm_handle: c_int, vreg_index: c_int, lanes: *mut u64) -> c_int {
+        if !(0..16).contains(&vreg_index) || lanes.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            slice::from_raw_parts_mut(lanes, 4).copy_from_slice(&vm.state.vregs[vreg_index as usize]);
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_set_vreg(vm_handle: c_int, vreg_index: c_int, lanes: *const u64) -> c_int {
+        if !(0..16).contains(&vreg_index) || lanes.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            vm.state.vregs[vreg_index as usize].copy_from_slice(slice::from_raw_parts(lanes, 4));
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_load_program(vm_handle: c_int, data: *const u8, size: u64, address: u64) -> c_int {
+        if data.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            let Some(range) = vm.range(address, size) else {
+                return CODE_EINVAL;
+            };
+            vm.memory[range.clone()].copy_from_slice(slice::from_raw_parts(data, size as usize));
+            vm.mark_dirty(range);
+            vm.state.pc = address;
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_read_memory(vm_handle: c_int, address: u64, buffer: *mut u8, size: u64) -> c_int {
+        if buffer.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| match vm.range(address, size) {
+            Some(range) => {
+                slice::from_raw_parts_mut(buffer, size as usize).copy_from_slice(&vm.memory[range]);
+                CODE_OK
+            }
+            None => CODE_EINVAL,
+        })
+    }
+
+    pub unsafe fn nanocore_vm_write_memory(vm_handle: c_int, address: u64, data: *const u8, size: u64) -> c_int {
+        if data.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| match vm.range(address, size) {
+            Some(range) => {
+                vm.memory[range.clone()].copy_from_slice(slice::from_raw_parts(data, size as usize));
+                vm.mark_dirty(range);
+                CODE_OK
+            }
+            None => CODE_EINVAL,
+        })
+    }
+
+    pub unsafe fn nanocore_vm_fill_memory(vm_handle: c_int, address: u64, value: u8, size: u64) -> c_int {
+        with_instance(vm_handle, |vm| match vm.range(address, size) {
+            Some(range) => {
+                vm.memory[range.clone()].fill(value);
+                vm.mark_dirty(range);
+                CODE_OK
+            }
+            None => CODE_EINVAL,
+        })
+    }
+
+    pub unsafe fn nanocore_vm_copy_memory(vm_handle: c_int, dst: u64, src: u64, size: u64) -> c_int {
+        with_instance(vm_handle, |vm| match (vm.range(dst, size), vm.range(src, size)) {
+            (Some(dst), Some(src)) => {
+                vm.memory.copy_within(src, dst.start);
+                vm.mark_dirty(dst);
+                CODE_OK
+            }
+            _ => CODE_EINVAL,
+        })
+    }
+
+    pub unsafe fn nanocore_vm_get_memory(vm_handle: c_int, data: *mut *const u8, size: *mut u64) -> c_int {
+        if data.is_null() || size.is_null() {
+            return CODE_EINVAL;
+        }
+        // The buffer lives on the heap, so the pointer survives the lock
+        with_instance(vm_handle, |vm| {
+            *data = vm.memory.as_ptr();
+            *size = vm.memory.len() as u64;
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_load_memory_image(vm_handle: c_int, image: *const u8, size: u64) -> c_int {
+        if image.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            if size != vm.memory.len() as u64 {
+                return CODE_EINVAL;
+            }
+            vm.memory.copy_from_slice(slice::from_raw_parts(image, size as usize));
+            vm.mark_dirty(0..vm.memory.len());
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_take_dirty_pages(vm_handle: c_int, bitmap: *mut u64, words: u64) -> c_int {
+        if bitmap.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            if words != vm.dirty.len() as u64 {
+                return CODE_EINVAL;
+            }
+            slice::from_raw_parts_mut(bitmap, words as usize).copy_from_slice(&vm.take_dirty_pages());
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_save_memory_image(vm_handle: c_int, buffer: *mut u8, size: u64) -> c_int {
+        if buffer.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            if size != vm.memory.len() as u64 {
+                return CODE_EINVAL;
+            }
+            slice::from_raw_parts_mut(buffer, size as usize).copy_from_slice(&vm.memory);
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_flush_memory(vm_handle: c_int) -> c_int {
+        with_instance(vm_handle, |vm| {
+            let size = vm.memory.len() as u64;
+            status_code(vm.flush_range(0, size))
+        })
+    }
+
+    pub unsafe fn nanocore_vm_flush_range(vm_handle: c_int, address: u64, size: u64) -> c_int {
+        with_instance(vm_handle, |vm| status_code(vm.flush_range(address, size)))
+    }
+
+    pub unsafe fn nanocore_vm_set_breakpoint(vm_handle: c_int, address: u64) -> c_int {
+        with_instance(vm_handle, |vm| status_code(vm.set_breakpoint(address)))
+    }
+
+    pub unsafe fn nanocore_vm_clear_breakpoint(vm_handle: c_int, address: u64) -> c_int {
+        with_instance(vm_handle, |vm| status_code(vm.clear_breakpoint(address)))
+    }
+
+    pub unsafe fn nanocore_vm_get_perf_counter(vm_handle: c_int, counter_index: c_int, value: *mut u64) -> c_int {
+        if !(0..8).contains(&counter_index) || value.is_null() {
+            return CODE_EINVAL;
+        }
+        with_instance(vm_handle, |vm| {
+            *value = vm.state.perf_counters[counter_index as usize];
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_reset_perf_counters(vm_handle: c_int) -> c_int {
+        with_instance(vm_handle, |vm| {
+            vm.state.perf_counters = [0; 8];
+            CODE_OK
+        })
+    }
+
+    /// Report a halt, the only event the core raises itself
+    unsafe fn poll_event(vm_handle: c_int, event_type: *mut c_int, event_data: *mut u64) -> c_int {
+        with_instance(vm_handle, |vm| {
+            if !vm.halted {
+                return CODE_ERROR;
+            }
+            *event_type = EventType::Halted as c_int;
+            *event_data = vm.exit_code as i64 as u64;
+            CODE_OK
+        })
+    }
+
+    pub unsafe fn nanocore_vm_wait_event(
+        vm_handle: c_int,
+        timeout_ms: u64,
+        event_type: *mut c_int,
+        event_data: *mut u64,
+    ) -> c_int {
+        if event_type.is_null() || event_data.is_null() {
+            return CODE_EINVAL;
+        }
+        let mut waited_ms = 0;
+        loop {
+            let result = poll_event(vm_handle, event_type, event_data);
+            if result != CODE_ERROR || waited_ms >= timeout_ms {
+                return result;
+            }
+            thread::sleep(Duration::from_millis(1));
+            if timeout_ms != u64::MAX {
+                waited_ms += 1;
+            }
+        }
+    }
+}
+
+#[cfg(all(test, nanocore_native))]
+mod tests {
+    use super::*;
+    use crate::{init, VM};
+
+    const MEMORY_SIZE: u64 = 1024 * 1024;
+
+    fn insn(opcode: u32, rd: u32, rs1: u32, imm: u16) -> u32 {
+        (opcode << 26) | (rd << 21) | (rs1 << 16) | imm as u32
+    }
+
+    fn rtype(opcode: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+        (opcode << 26) | (rd << 21) | (rs1 << 16) | (rs2 << 11)
+    }
+
+    /// Programs loaded at 0x10000 and run on both backends
+    fn programs() -> Vec<(&'static str, Vec<u32>)> {
+        vec![
+            (
+                "arithmetic",
+                vec![
+                    insn(0x0F, 1, 0, 100),
+                    insn(0x0F, 2, 0, 0xFFF9), // -7
+                    rtype(0x00, 3, 1, 2),
+                    rtype(0x01, 4, 1, 2),
+                    rtype(0x02, 5, 1, 2),
+                    rtype(0x04, 6, 1, 2),
+                    rtype(0x05, 7, 1, 2),
+                    rtype(0x04, 8, 1, 0), // divide by zero leaves R8 alone
+                    rtype(0x06, 9, 1, 2),
+                    rtype(0x07, 10, 1, 2),
+                    rtype(0x08, 11, 1, 2),
+                    rtype(0x0A, 12, 1, 1),
+                    rtype(0x0B, 13, 2, 1),
+                    rtype(0x00, 0, 1, 1), // writes to R0 are dropped
+                    insn(0x21, 0, 0, 0),
+                ],
+            ),
+            (
+                "memory",
+                vec![
+                    insn(0x0F, 1, 0, 0x4000),
+                    insn(0x0F, 2, 0, 0xFF80),
+                    insn(0x13, 2, 1, 0),
+                    insn(0x14, 1, 1, 8),
+                    insn(0x15, 1, 1, 12),
+                    insn(0x16, 2, 1, 14),
+                    insn(0x10, 3, 1, 0),
+                    insn(0x11, 4, 1, 12),
+                    insn(0x12, 5, 1, 14),
+                    insn(0x13, 2, 2, 0), // out of range, ignored
+                    insn(0x21, 0, 0, 0),
+                ],
+            ),
+            (
+                "loop",
+                vec![
+                    insn(0x0F, 1, 0, 10),
+                    insn(0x0F, 2, 0, 1),
+                    rtype(0x00, 3, 3, 1),
+                    rtype(0x01, 1, 1, 2),
+                    insn(0x18, 1, 0, 0xFFFC), // BNE R1, R0, back two words
+                    insn(0x19, 0, 2, 2),      // BLT R0, R2, skip one word
+                    insn(0x0F, 4, 0, 1),
+                    insn(0x21, 0, 0, 0),
+                ],
+            ),
+            (
+                "calls",
+                vec![
+                    insn(0x1E, 0, 0, 4),    // 0x10000: CALL 0x10010
+                    insn(0x0F, 1, 0, 5),    // 0x10004: LD R1, 5
+                    insn(0x21, 0, 0, 0),    // 0x10008: HALT
+                    insn(0x22, 0, 0, 0),    // 0x1000C: NOP
+                    insn(0x0F, 2, 0, 7),    // 0x10010: LD R2, 7
+                    insn(0x0F, 5, 0, 0x4000),
+                    rtype(0x00, 5, 5, 5),
+                    rtype(0x00, 5, 5, 5),   // R5 = 0x10000
+                    insn(0x1D, 7, 5, 0x2C), // 0x10020: JMP R7, 0x2C(R5)
+                    insn(0x22, 0, 0, 0),
+                    insn(0x22, 0, 0, 0),
+                    insn(0x1F, 0, 0, 0),    // 0x1002C: RET
+                ],
+            ),
+            ("guest breakpoint", vec![insn(0x0F, 1, 0, 3), insn(0x37, 0, 0, 0), insn(0x21, 0, 0, 0)]),
+            ("invalid opcode", vec![insn(0x0F, 1, 0, 3), insn(0x03, 1, 1, 0)]),
+            ("budget", vec![insn(0x1D, 0, 0, 0)]),
+        ]
+    }
+
+    fn assert_same(name: &str, vm: &VM, interpreter: &Interpreter) {
+        let (native, interp) = (vm.get_state().unwrap(), interpreter.state());
+        assert_eq!(native.pc, interp.pc, "{}: pc", name);
+        assert_eq!(native.sp, interp.sp, "{}: sp", name);
+        assert_eq!(native.flags, interp.flags, "{}: flags", name);
+        assert_eq!(native.gprs, interp.gprs, "{}: registers", name);
+        assert_eq!(native.perf_counters, interp.perf_counters, "{}: perf counters", name);
+        assert!(vm.read_memory(0, MEMORY_SIZE).unwrap() == interpreter.memory(), "{}: memory", name);
+    }
+
+    #[test]
+    fn test_backends_agree() {
+        init().unwrap();
+        for (name, program) in programs() {
+            let bytes: Vec<u8> = program.iter().flat_map(|word| word.to_be_bytes()).collect();
+            let mut vm = VM::new(MEMORY_SIZE).unwrap();
+            let mut interpreter = Interpreter::new(MEMORY_SIZE).unwrap();
+            vm.load_program(&bytes, 0x10000).unwrap();
+            interpreter.load_program(&bytes, 0x10000).unwrap();
+            vm.set_breakpoint(0x10008).unwrap();
+            interpreter.set_breakpoint(0x10008).unwrap();
+
+            vm.step().unwrap();
+            interpreter.step();
+            assert_same(name, &vm, &interpreter);
+
+            // Run to the breakpoint, then on from it and from the next stop
+            for round in 0..3 {
+                let native = vm.run_outcome(Some(1000)).unwrap();
+                assert_eq!(interpreter.run(Some(1000)), native, "{}: outcome", name);
+                assert_same(name, &vm, &interpreter);
+                if round == 0 {
+                    vm.clear_breakpoint(0x10008).unwrap();
+                    interpreter.clear_breakpoint(0x10008).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_reset_and_bounds() {
+        let mut interpreter = Interpreter::new(MEMORY_SIZE).unwrap();
+        assert!(interpreter.load_program(&[0; 8], MEMORY_SIZE - 4).is_err());
+        interpreter.memory_mut()[0x10000..0x10004].copy_from_slice(&insn(0x0F, 1, 0, 9).to_be_bytes());
+        assert_eq!(interpreter.run(Some(1)), RunOutcome::Limit);
+        assert_eq!(interpreter.state().gprs[1], 9);
+
+        interpreter.reset();
+        let state = interpreter.state();
+        assert_eq!((state.pc, state.sp, state.gprs[1]), (0x10000, MEMORY_SIZE - 8, 0));
+        assert_eq!(Interpreter::new(0).err().unwrap().status, Status::InvalidParameter);
+    }
+}
@@ -0,0 +1,72 @@
+//! Interrupt-enable flag observation and host-side masking
+
+use crate::{Event, EventType, Flags, Result, VM};
+
+/// Called with the new value of `Flags::INTERRUPT_ENABLE` when it changes
+pub type InterruptFlagCallback = Box<dyn FnMut(bool) + Send>;
+
+impl VM {
+    /// Call `callback` whenever an instruction changes `INTERRUPT_ENABLE`
+    ///
+    /// The guest sets the flag with `EI` and clears it with `DI`. The flag
+    /// is compared before and after every instruction, so the VM is stepped
+    /// from the host while a callback is installed. Changes made by the
+    /// host, including `with_interrupts_masked`, are not reported.
+    pub fn set_interrupt_flag_callback(&mut self, callback: InterruptFlagCallback) {
+        self.interrupt_flag_callback = Some(callback);
+    }
+
+    /// Remove the callback installed by `set_interrupt_flag_callback`
+    pub fn clear_interrupt_flag_callback(&mut self) {
+        self.interrupt_flag_callback = None;
+    }
+
+    /// Run `f` with `INTERRUPT_ENABLE` cleared, then restore it
+    ///
+    /// The flag is set again afterwards only if it was set on entry, so
+    /// nested critical sections behave as expected.
+    pub fn with_interrupts_masked<R>(&mut self, f: impl FnOnce(&mut VM) -> R) -> Result<R> {
+        let mut state = self.raw_state()?;
+        let was_enabled = state.flags & Flags::INTERRUPT_ENABLE != 0;
+        state.flags &= !Flags::INTERRUPT_ENABLE;
+        self.set_raw_state(&state)?;
+
+        let result = f(self);
+
+        if was_enabled {
+            let mut state = self.raw_state()?;
+            state.flags |= Flags::INTERRUPT_ENABLE;
+            self.set_raw_state(&state)?;
+        }
+        Ok(result)
+    }
+
+    /// Report a change of `INTERRUPT_ENABLE` made by the instruction just retired
+    pub(crate) fn notify_interrupt_flag(&mut self, was_enabled: bool) -> Result<()> {
+        if self.interrupt_flag_callback.is_none() {
+            return Ok(());
+        }
+        let enabled = self.raw_state()?.flags & Flags::INTERRUPT_ENABLE != 0;
+        if let Some(callback) = self.interrupt_flag_callback.as_mut().filter(|_| enabled != was_enabled) {
+            callback(enabled);
+        }
+        Ok(())
+    }
+
+    /// Deliver pending device interrupts if `INTERRUPT_ENABLE` is set
+    pub(crate) fn deliver_device_interrupts(&mut self) -> Result<()> {
+        let pending = self.devices.pending_interrupts();
+        if pending.is_empty() || self.raw_state()?.flags & Flags::INTERRUPT_ENABLE == 0 {
+            return Ok(());
+        }
+        for index in pending {
+            self.push_event(Event {
+                event_type: EventType::DeviceInterrupt,
+                data: index as u64,
+                fault_address: None,
+            });
+            self.devices.acknowledge_interrupt(index);
+        }
+        Ok(())
+    }
+}
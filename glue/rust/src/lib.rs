@@ -27,7 +27,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     // Run the program
     match vm.run(Some(1000))? {
-        Status::Halted => {
+        Status::Ok => {
             println!("Program completed successfully");
             println!("R1 = {}", vm.get_register(1)?);
             println!("R2 = {}", vm.get_register(2)?);
@@ -43,14 +43,65 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 ```
 */
 
-use std::ffi::CStr;
-use std::os::raw::{c_int, c_uint, c_void};
-use std::ptr;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CStr, CString};
+use std::os::raw::c_int;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+pub mod allocator;
+pub mod asm;
+pub mod background;
+pub mod builder;
+mod bandwidth;
+pub mod config;
+mod console;
+mod debug;
+pub mod devices;
+pub mod disasm;
+pub mod elf;
+pub mod energy;
+pub mod entropy;
+pub mod fault;
+mod hexdump;
+pub mod image;
+#[cfg(feature = "interp")]
+pub mod interp;
+pub mod interrupts;
+pub mod log;
+pub mod opcode;
+pub mod protection;
+mod exec;
+#[cfg(feature = "bytemuck")]
+mod pod;
+#[cfg(feature = "python")]
+mod python;
+pub mod scheduler;
+mod search;
+pub mod semihosting;
+pub mod shared;
+pub mod snapshot;
+pub mod source_map;
+#[cfg(feature = "async")]
+pub mod stream;
+#[cfg(feature = "test-utils")]
+pub mod testing;
+pub mod trace;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+use devices::DeviceManager;
+
+#[cfg(not(any(nanocore_native, feature = "interp")))]
+compile_error!("the native core is not built for this target; enable the `interp` feature");
 
 mod ffi {
-    use super::*;
+    #[cfg(nanocore_native)]
+    use std::os::raw::{c_char, c_int, c_void};
     
     #[repr(C)]
+    #[derive(Clone, Copy, Default)]
     pub struct VmState {
         pub pc: u64,
         pub sp: u64,
@@ -62,24 +113,55 @@ mod ffi {
         pub vbase: u64,
     }
     
+    #[cfg(nanocore_native)]
     extern "C" {
         pub fn nanocore_init() -> c_int;
+        pub fn nanocore_last_error_message(buf: *mut c_char, len: usize) -> c_int;
         pub fn nanocore_vm_create(memory_size: u64, vm_handle: *mut c_int) -> c_int;
+        pub fn nanocore_vm_create_file(path: *const c_char, memory_size: u64, vm_handle: *mut c_int) -> c_int;
+        pub fn nanocore_vm_create_with_memory(
+            memory: *mut u8,
+            memory_size: u64,
+            release: unsafe extern "C" fn(*mut c_void),
+            context: *mut c_void,
+            vm_handle: *mut c_int,
+        ) -> c_int;
         pub fn nanocore_vm_destroy(vm_handle: c_int) -> c_int;
         pub fn nanocore_vm_reset(vm_handle: c_int) -> c_int;
         pub fn nanocore_vm_run(vm_handle: c_int, max_instructions: u64) -> c_int;
         pub fn nanocore_vm_step(vm_handle: c_int) -> c_int;
+        pub fn nanocore_vm_set_guest_break_mode(vm_handle: c_int, mode: c_int) -> c_int;
+        pub fn nanocore_vm_get_guest_break(vm_handle: c_int, address: *mut u64) -> c_int;
+        pub fn nanocore_vm_get_exit_code(vm_handle: c_int, exit_code: *mut c_int) -> c_int;
         pub fn nanocore_vm_get_state(vm_handle: c_int, state: *mut VmState) -> c_int;
+        pub fn nanocore_vm_set_state(vm_handle: c_int, state: *const VmState) -> c_int;
         pub fn nanocore_vm_get_register(vm_handle: c_int, reg_index: c_int, value: *mut u64) -> c_int;
         pub fn nanocore_vm_set_register(vm_handle: c_int, reg_index: c_int, value: u64) -> c_int;
+        pub fn nanocore_vm_get_registers(vm_handle: c_int, values: *mut u64) -> c_int;
+        pub fn nanocore_vm_set_registers(vm_handle: c_int, values: *const u64) -> c_int;
+        pub fn nanocore_vm_get_vreg(vm_handle: c_int, vreg_index: c_int, lanes: *mut u64) -> c_int;
+        pub fn nanocore_vm_set_vreg(vm_handle: c_int, vreg_index: c_int, lanes: *const u64) -> c_int;
         pub fn nanocore_vm_load_program(vm_handle: c_int, data: *const u8, size: u64, address: u64) -> c_int;
         pub fn nanocore_vm_read_memory(vm_handle: c_int, address: u64, buffer: *mut u8, size: u64) -> c_int;
         pub fn nanocore_vm_write_memory(vm_handle: c_int, address: u64, data: *const u8, size: u64) -> c_int;
+        pub fn nanocore_vm_fill_memory(vm_handle: c_int, address: u64, value: u8, size: u64) -> c_int;
+        pub fn nanocore_vm_copy_memory(vm_handle: c_int, dst: u64, src: u64, size: u64) -> c_int;
+        pub fn nanocore_vm_get_memory(vm_handle: c_int, data: *mut *const u8, size: *mut u64) -> c_int;
+        pub fn nanocore_vm_load_memory_image(vm_handle: c_int, image: *const u8, size: u64) -> c_int;
+        pub fn nanocore_vm_take_dirty_pages(vm_handle: c_int, bitmap: *mut u64, words: u64) -> c_int;
+        pub fn nanocore_vm_save_memory_image(vm_handle: c_int, buffer: *mut u8, size: u64) -> c_int;
+        pub fn nanocore_vm_flush_memory(vm_handle: c_int) -> c_int;
+        pub fn nanocore_vm_flush_range(vm_handle: c_int, address: u64, size: u64) -> c_int;
         pub fn nanocore_vm_set_breakpoint(vm_handle: c_int, address: u64) -> c_int;
         pub fn nanocore_vm_clear_breakpoint(vm_handle: c_int, address: u64) -> c_int;
         pub fn nanocore_vm_get_perf_counter(vm_handle: c_int, counter_index: c_int, value: *mut u64) -> c_int;
-        pub fn nanocore_vm_poll_event(vm_handle: c_int, event_type: *mut c_int, event_data: *mut u64) -> c_int;
+        pub fn nanocore_vm_reset_perf_counters(vm_handle: c_int) -> c_int;
+        pub fn nanocore_vm_wait_event(vm_handle: c_int, timeout_ms: u64, event_type: *mut c_int, event_data: *mut u64) -> c_int;
     }
+    
+    // Without the native core the same entry points come from the interpreter
+    #[cfg(not(nanocore_native))]
+    pub use crate::interp::abi::*;
 }
 
 /// VM execution status codes
@@ -95,6 +177,10 @@ pub enum Status {
     InvalidParameter = -3,
     /// Initialization error
     InitializationError = -4,
+    /// `VM::run`, `step_over` or `step_out` used up its instruction budget
+    /// before the guest halted or hit a breakpoint; never returned by the
+    /// core itself
+    FuelExhausted = 2,
 }
 
 impl Status {
@@ -113,14 +199,17 @@ impl Status {
 /// VM event types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EventType {
-    /// Program halted normally
+    /// Program halted normally; `data` carries the exit code
     Halted = 0,
-    /// Hit a breakpoint
+    /// Hit a breakpoint; `GUEST_BREAKPOINT_FLAG` is set in the data for `BRK`
     Breakpoint = 1,
     /// Exception occurred
     Exception = 2,
     /// Device interrupt
     DeviceInterrupt = 3,
+    /// Guest access hit a watchpoint; `data` is the accessed address, with
+    /// `WATCHPOINT_WRITE_FLAG` set for stores
+    Watchpoint = 4,
 }
 
 impl EventType {
@@ -130,11 +219,360 @@ impl EventType {
             1 => Some(EventType::Breakpoint),
             2 => Some(EventType::Exception),
             3 => Some(EventType::DeviceInterrupt),
+            4 => Some(EventType::Watchpoint),
             _ => None,
         }
     }
 }
 
+/// Exception code for an instruction word with no defined opcode
+pub const EXC_INVALID_OPCODE: u32 = 1;
+
+/// Exception code for an access outside guest memory
+pub const EXC_MEMORY_FAULT: u32 = 2;
+
+/// Exception code raised when an MMIO access violates a device's width policy
+pub const EXC_ALIGNMENT: u32 = 3;
+
+/// Exception code raised when the guest targets `R0` with trapping enabled
+pub const EXC_ZERO_REGISTER_WRITE: u32 = 4;
+
+/// Exception code for an integer `DIV` or `MOD` by zero
+pub const EXC_DIVIDE_BY_ZERO: u32 = 5;
+
+/// Decoded cause of an `Exception` event
+///
+/// The code carried in the event's `data` maps to a kind as follows:
+///
+/// | Code | Constant                  | Kind                |
+/// |------|---------------------------|---------------------|
+/// | 1    | `EXC_INVALID_OPCODE`      | `InvalidOpcode`     |
+/// | 2    | `EXC_MEMORY_FAULT`        | `MemoryFault`       |
+/// | 3    | `EXC_ALIGNMENT`           | `AlignmentFault`    |
+/// | 4    | `EXC_ZERO_REGISTER_WRITE` | `ZeroRegisterWrite` |
+/// | 5    | `EXC_DIVIDE_BY_ZERO`      | `DivideByZero`      |
+///
+/// Codes 1 and 2 follow the ISA's interrupt vector numbers. Any other code
+/// decodes as `Unknown`. The reference core stops on an invalid opcode
+/// without raising an event, so today only codes 2 to 4 are raised, all
+/// from the host side: 2 by memory protection and read-only overlays, 3 by
+/// devices and 4 by `set_trap_zero_register_write`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionKind {
+    InvalidOpcode,
+    /// `address` is the guest address the access started at
+    MemoryFault { address: u64 },
+    /// `address` is the guest address the access started at
+    AlignmentFault { address: u64 },
+    ZeroRegisterWrite,
+    DivideByZero,
+    Unknown(u32),
+}
+
+impl ExceptionKind {
+    /// Decode an exception code, with the faulting address if one was recorded
+    pub fn from_code(code: u32, address: Option<u64>) -> Self {
+        let address = address.unwrap_or(0);
+        match code {
+            EXC_INVALID_OPCODE => ExceptionKind::InvalidOpcode,
+            EXC_MEMORY_FAULT => ExceptionKind::MemoryFault { address },
+            EXC_ALIGNMENT => ExceptionKind::AlignmentFault { address },
+            EXC_ZERO_REGISTER_WRITE => ExceptionKind::ZeroRegisterWrite,
+            EXC_DIVIDE_BY_ZERO => ExceptionKind::DivideByZero,
+            code => ExceptionKind::Unknown(code),
+        }
+    }
+}
+
+/// Set in a `Breakpoint` event's data when the guest executed `BRK`
+pub const GUEST_BREAKPOINT_FLAG: u64 = 1 << 63;
+
+/// Set in a `Watchpoint` event's data when the access was a store
+pub const WATCHPOINT_WRITE_FLAG: u64 = 1 << 63;
+
+/// Which guest accesses a watchpoint fires on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    /// Whether a load (`store == false`) or store fires this kind of watchpoint
+    pub fn matches(self, store: bool) -> bool {
+        match self {
+            WatchKind::Read => !store,
+            WatchKind::Write => store,
+            WatchKind::ReadWrite => true,
+        }
+    }
+}
+
+/// Comparison used by `BreakCondition`, on unsigned register values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    pub fn compare(self, lhs: u64, rhs: u64) -> bool {
+        match self {
+            CmpOp::Eq => lhs == rhs,
+            CmpOp::Ne => lhs != rhs,
+            CmpOp::Lt => lhs < rhs,
+            CmpOp::Le => lhs <= rhs,
+            CmpOp::Gt => lhs > rhs,
+            CmpOp::Ge => lhs >= rhs,
+        }
+    }
+}
+
+/// When a conditional breakpoint stops execution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakCondition {
+    /// `R[index] op value`
+    Register { index: u32, op: CmpOp, value: u64 },
+}
+
+impl BreakCondition {
+    /// Whether the condition holds for these general-purpose registers
+    pub fn holds(&self, gprs: &[u64; 32]) -> bool {
+        match *self {
+            BreakCondition::Register { index, op, value } => {
+                gprs.get(index as usize).is_some_and(|&reg| op.compare(reg, value))
+            }
+        }
+    }
+}
+
+/// A breakpoint and the rules for when it stops execution
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+    /// Only stop when this holds
+    condition: Option<BreakCondition>,
+    /// Hits to let pass before the first stop
+    ignore_count: u64,
+    /// Removed after it first stops execution
+    temporary: bool,
+}
+
+impl Breakpoint {
+    fn new(address: u64) -> Self {
+        Breakpoint {
+            address,
+            condition: None,
+            ignore_count: 0,
+            temporary: false,
+        }
+    }
+    
+    /// Whether the host decides if this breakpoint stops, rather than the core
+    fn host_evaluated(&self) -> bool {
+        self.condition.is_some() || self.ignore_count > 0
+    }
+}
+
+/// What happens when the guest executes `BRK`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GuestBreakpointMode {
+    /// Stop right after the `BRK`, so running again resumes past it
+    #[default]
+    Stop,
+    /// Queue a `Breakpoint` event and keep running
+    Continue,
+    /// Execute `BRK` as a `NOP`
+    Ignore,
+}
+
+/// Register holding the guest's exit code when it executes HALT
+pub const EXIT_CODE_REGISTER: u32 = 1;
+
+/// Why a run stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// Guest executed HALT; `exit_code` is `R1` at that point
+    Halted { exit_code: i32 },
+    /// Stopped at a breakpoint
+    Breakpoint { address: u64 },
+    /// Stopped after an access to a watched range
+    Watchpoint { address: u64 },
+    /// Execution faulted
+    Exception,
+    /// Instruction budget ran out
+    Limit,
+    /// A `StopCondition` passed to `VM::run_until_any` fired
+    Condition,
+}
+
+/// Result of `VM::run_counted`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunCount {
+    /// Same status `VM::run` would have returned
+    pub status: Status,
+    /// Instructions retired by this run
+    pub instructions_retired: u64,
+}
+
+/// Result of `VM::run_cycles`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleRun {
+    /// Why the run stopped; `Limit` when the cycle budget ran out
+    pub outcome: RunOutcome,
+    /// Change in `PerfCounter::CycleCount` across the run
+    pub cycles: u64,
+}
+
+/// Instructions `VM::run_cycles` runs between budget checks by default
+pub const DEFAULT_CYCLE_BATCH: u64 = 1000;
+
+/// `RegDelta::index` used for the flags register
+pub const FLAGS_DELTA_INDEX: u32 = 32;
+
+/// A register changed by `VM::step_with_diff`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegDelta {
+    /// GPR number, or `FLAGS_DELTA_INDEX` for the flags
+    pub index: u32,
+    pub old: u64,
+    pub new: u64,
+}
+
+/// Condition checked after every instruction by `VM::run_until_any`
+pub enum StopCondition {
+    /// PC reached `address`
+    Address(u64),
+    /// General-purpose register `register` holds `value`
+    RegisterEquals { register: u32, value: u64 },
+    /// Any of the `len` bytes at `address` differ from when the run started
+    MemoryChanged { address: u64, len: u64 },
+    /// This many instructions have executed since the run started
+    InstructionCount(u64),
+    /// Arbitrary predicate over the register state
+    State(Box<dyn Fn(&VmState) -> bool>),
+}
+
+/// Why the VM last stopped, as reported by `VM::last_stop_reason`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StopReason {
+    /// Guest executed HALT; `exit_code` is `R1` at that point
+    Halted { exit_code: i32 },
+    /// Stopped at the breakpoint at `address`; `hit_count` includes this stop
+    Breakpoint { address: u64, hit_count: u64 },
+    /// Guest executed the `BRK` at `address`
+    GuestBreakpoint { address: u64 },
+    /// A load or store touched a watched range; `address` is where the
+    /// access started and `kind` is `Read` or `Write`
+    ///
+    /// `old_value` and `new_value` are the accessed bytes, read as a
+    /// little-endian value, before and after the access; they are equal for
+    /// a read. For a device register both are the value transferred.
+    Watchpoint {
+        address: u64,
+        kind: WatchKind,
+        old_value: u64,
+        new_value: u64,
+    },
+    /// Execution faulted; `code` is set for exceptions raised host-side
+    Exception { code: Option<u32> },
+    /// Instruction budget ran out
+    Limit,
+    /// The `StopCondition` at `index` passed to `VM::run_until_any` fired
+    Condition { index: usize },
+    /// The instruction at `address` matched a class passed to
+    /// `VM::break_on_opcode`; it has already executed
+    OpcodeBreak { address: u64, opcode: opcode::Opcode },
+}
+
+/// A general purpose register, `R0` to `R31`
+///
+/// Only the constants and `Reg::new` create one, so a `Reg` is always in
+/// range and register accessors taking it cannot fail on the index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Reg(u8);
+
+impl Reg {
+    pub const R0: Reg = Reg(0);
+    pub const R1: Reg = Reg(1);
+    pub const R2: Reg = Reg(2);
+    pub const R3: Reg = Reg(3);
+    pub const R4: Reg = Reg(4);
+    pub const R5: Reg = Reg(5);
+    pub const R6: Reg = Reg(6);
+    pub const R7: Reg = Reg(7);
+    pub const R8: Reg = Reg(8);
+    pub const R9: Reg = Reg(9);
+    pub const R10: Reg = Reg(10);
+    pub const R11: Reg = Reg(11);
+    pub const R12: Reg = Reg(12);
+    pub const R13: Reg = Reg(13);
+    pub const R14: Reg = Reg(14);
+    pub const R15: Reg = Reg(15);
+    pub const R16: Reg = Reg(16);
+    pub const R17: Reg = Reg(17);
+    pub const R18: Reg = Reg(18);
+    pub const R19: Reg = Reg(19);
+    pub const R20: Reg = Reg(20);
+    pub const R21: Reg = Reg(21);
+    pub const R22: Reg = Reg(22);
+    pub const R23: Reg = Reg(23);
+    pub const R24: Reg = Reg(24);
+    pub const R25: Reg = Reg(25);
+    pub const R26: Reg = Reg(26);
+    pub const R27: Reg = Reg(27);
+    pub const R28: Reg = Reg(28);
+    pub const R29: Reg = Reg(29);
+    pub const R30: Reg = Reg(30);
+    pub const R31: Reg = Reg(31);
+    
+    /// Hardwired to zero
+    pub const ZERO: Reg = Reg::R0;
+    /// Stack pointer, kept in step with the core's SP by `VM::set_sp`
+    pub const SP: Reg = Reg::R30;
+    /// Link register `CALL` writes and `RET` returns through
+    pub const LR: Reg = Reg::R31;
+    
+    /// The register numbered `index`, if there is one
+    pub fn new(index: u32) -> Option<Reg> {
+        (index < 32).then_some(Reg(index as u8))
+    }
+    
+    pub fn index(self) -> u32 {
+        self.0 as u32
+    }
+}
+
+impl std::fmt::Display for Reg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "R{}", self.0)
+    }
+}
+
+/// Anything naming a general purpose register: a `Reg`, or a raw `u32`
+/// index that is checked when used
+pub trait RegisterIndex {
+    fn register_index(self) -> u32;
+}
+
+impl RegisterIndex for Reg {
+    fn register_index(self) -> u32 {
+        self.index()
+    }
+}
+
+impl RegisterIndex for u32 {
+    fn register_index(self) -> u32 {
+        self
+    }
+}
+
 /// CPU flags
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Flags(pub u64);
@@ -151,6 +589,112 @@ impl Flags {
     pub fn is_set(&self, flag: u64) -> bool {
         self.0 & flag != 0
     }
+    
+    /// Whether every bit of `flags` is set
+    pub fn contains(&self, flags: u64) -> bool {
+        self.0 & flags == flags
+    }
+    
+    pub fn zero(&self) -> bool {
+        self.is_set(Self::ZERO)
+    }
+    
+    pub fn carry(&self) -> bool {
+        self.is_set(Self::CARRY)
+    }
+    
+    pub fn overflow(&self) -> bool {
+        self.is_set(Self::OVERFLOW)
+    }
+    
+    pub fn negative(&self) -> bool {
+        self.is_set(Self::NEGATIVE)
+    }
+    
+    pub fn interrupt_enable(&self) -> bool {
+        self.is_set(Self::INTERRUPT_ENABLE)
+    }
+    
+    pub fn user_mode(&self) -> bool {
+        self.is_set(Self::USER_MODE)
+    }
+    
+    pub fn halted(&self) -> bool {
+        self.is_set(Self::HALTED)
+    }
+}
+
+/// Cache control register
+///
+/// The core stores this register and snapshots carry it, but the bundled
+/// cores do not model caches; the bits are for cache models built on top.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheCtrl(pub u64);
+
+impl CacheCtrl {
+    /// Caches are enabled
+    pub const ENABLE: u64 = 1 << 0;
+    /// Write back and invalidate every line; a cache model clears it when done
+    pub const FLUSH: u64 = 1 << 1;
+    
+    pub fn is_set(&self, bit: u64) -> bool {
+        self.0 & bit != 0
+    }
+    
+    pub fn enabled(&self) -> bool {
+        self.is_set(Self::ENABLE)
+    }
+    
+    pub fn flush_pending(&self) -> bool {
+        self.is_set(Self::FLUSH)
+    }
+}
+
+/// Named flag updates collected by `VM::modify_flags`
+///
+/// Flags that are not touched keep their current value. `HALTED` is not
+/// exposed; use `VM::reset` or run the guest to change it.
+#[derive(Debug, Clone, Copy)]
+pub struct FlagsBuilder(Flags);
+
+impl FlagsBuilder {
+    fn assign(&mut self, flag: u64, value: bool) -> &mut Self {
+        if value {
+            self.0 .0 |= flag;
+        } else {
+            self.0 .0 &= !flag;
+        }
+        self
+    }
+    
+    pub fn set_zero(&mut self, value: bool) -> &mut Self {
+        self.assign(Flags::ZERO, value)
+    }
+    
+    pub fn set_carry(&mut self, value: bool) -> &mut Self {
+        self.assign(Flags::CARRY, value)
+    }
+    
+    pub fn set_overflow(&mut self, value: bool) -> &mut Self {
+        self.assign(Flags::OVERFLOW, value)
+    }
+    
+    pub fn set_negative(&mut self, value: bool) -> &mut Self {
+        self.assign(Flags::NEGATIVE, value)
+    }
+    
+    pub fn set_interrupt_enable(&mut self, value: bool) -> &mut Self {
+        self.assign(Flags::INTERRUPT_ENABLE, value)
+    }
+    
+    pub fn set_user_mode(&mut self, value: bool) -> &mut Self {
+        self.assign(Flags::USER_MODE, value)
+    }
+    
+    /// The flags as they will be written
+    pub fn flags(&self) -> Flags {
+        self.0
+    }
 }
 
 /// Performance counter indices
@@ -166,6 +710,79 @@ pub enum PerfCounter {
     SIMDOps = 7,
 }
 
+/// All performance counters, by name
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PerfCounters {
+    pub instruction_count: u64,
+    pub cycle_count: u64,
+    pub l1_miss: u64,
+    pub l2_miss: u64,
+    pub branch_miss: u64,
+    pub pipeline_stall: u64,
+    pub memory_ops: u64,
+    pub simd_ops: u64,
+}
+
+impl PerfCounters {
+    /// Instructions retired per cycle
+    ///
+    /// `0.0` when no cycles have been counted, so an idle VM plots as zero
+    /// rather than as a gap.
+    pub fn ipc(&self) -> f64 {
+        ratio(self.instruction_count, self.cycle_count)
+    }
+    
+    /// L1 plus L2 misses per memory operation
+    ///
+    /// `0.0` when there have been no memory operations, like `ipc`.
+    pub fn cache_miss_rate(&self) -> f64 {
+        ratio(self.l1_miss.saturating_add(self.l2_miss), self.memory_ops)
+    }
+}
+
+/// Result of `VM::profile_region`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionProfile {
+    /// Change in every counter across the instructions run inside the region
+    pub counters: PerfCounters,
+    /// Why execution stopped inside the region, or `None` if PC left it
+    pub stopped: Option<RunOutcome>,
+}
+
+/// `numerator / denominator`, or `0.0` when the denominator is zero
+fn ratio(numerator: u64, denominator: u64) -> f64 {
+    if denominator == 0 {
+        0.0
+    } else {
+        numerator as f64 / denominator as f64
+    }
+}
+
+impl From<[u64; 8]> for PerfCounters {
+    fn from(counters: [u64; 8]) -> Self {
+        let [
+            instruction_count,
+            cycle_count,
+            l1_miss,
+            l2_miss,
+            branch_miss,
+            pipeline_stall,
+            memory_ops,
+            simd_ops,
+        ] = counters;
+        PerfCounters {
+            instruction_count,
+            cycle_count,
+            l1_miss,
+            l2_miss,
+            branch_miss,
+            pipeline_stall,
+            memory_ops,
+            simd_ops,
+        }
+    }
+}
+
 /// VM state snapshot
 #[derive(Debug, Clone)]
 pub struct VmState {
@@ -194,11 +811,82 @@ impl From<ffi::VmState> for VmState {
     }
 }
 
+/// Short names of the `Flags` bits, in bit order, for `VmState`'s `Display`
+const FLAG_NAMES: [(u64, &str); 7] = [
+    (Flags::ZERO, "Z"),
+    (Flags::CARRY, "C"),
+    (Flags::OVERFLOW, "V"),
+    (Flags::NEGATIVE, "N"),
+    (Flags::INTERRUPT_ENABLE, "IE"),
+    (Flags::USER_MODE, "U"),
+    (Flags::HALTED, "H"),
+];
+
+/// Names of the performance counters, indexed like `PerfCounter`
+const PERF_COUNTER_NAMES: [&str; 8] = [
+    "instructions",
+    "cycles",
+    "l1_miss",
+    "l2_miss",
+    "branch_miss",
+    "stalls",
+    "memory_ops",
+    "simd_ops",
+];
+
+/// One item per line: PC, SP, decoded flags, nonzero GPRs, then the
+/// performance counters
+impl std::fmt::Display for VmState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "pc    {:#018x}", self.pc)?;
+        writeln!(f, "sp    {:#018x}", self.sp)?;
+        let names: Vec<&str> = FLAG_NAMES
+            .iter()
+            .filter(|&&(bit, _)| self.flags.is_set(bit))
+            .map(|&(_, name)| name)
+            .collect();
+        writeln!(f, "flags {:#018x} [{}]", self.flags.0, names.join(" "))?;
+        for (index, value) in self.gprs.iter().enumerate().filter(|&(_, &value)| value != 0) {
+            writeln!(f, "{:<5} {:#018x}", format!("r{}", index), value)?;
+        }
+        write!(f, "perf ")?;
+        for (name, value) in PERF_COUNTER_NAMES.iter().zip(&self.perf_counters) {
+            write!(f, " {}={}", name, value)?;
+        }
+        writeln!(f)
+    }
+}
+
 /// VM event with type and data
 #[derive(Debug, Clone)]
 pub struct Event {
     pub event_type: EventType,
     pub data: u64,
+    /// Guest address that faulted, for `Exception` events that have one
+    pub fault_address: Option<u64>,
+}
+
+impl Event {
+    /// Decode the cause of an `Exception` event; `None` for other events
+    pub fn exception_kind(&self) -> Option<ExceptionKind> {
+        (self.event_type == EventType::Exception)
+            .then(|| ExceptionKind::from_code(self.data as u32, self.fault_address))
+    }
+}
+
+/// How often `VM::wait_event` checks the core's own queue while it waits
+const CORE_EVENT_POLL: Duration = Duration::from_millis(1);
+
+/// Events raised on the host side, waiting to be polled
+#[derive(Default)]
+struct EventQueue {
+    events: VecDeque<Event>,
+    /// Tasks waiting in an `EventStream` for the next event
+    #[cfg(feature = "async")]
+    wakers: Vec<std::task::Waker>,
+    /// Set when the VM is dropped, ending every `EventStream`
+    #[cfg(feature = "async")]
+    closed: bool,
 }
 
 /// Error type for NanoCore operations
@@ -230,16 +918,125 @@ fn check_status(status: c_int, operation: &str) -> Result<()> {
     }
 }
 
+/// `check_status` for VM creation, adding why the core says it failed
+fn check_create(status: c_int, operation: &str) -> Result<()> {
+    check_status(status, operation).map_err(|mut error| {
+        let mut buf = [0u8; 256];
+        let len = unsafe { ffi::nanocore_last_error_message(buf.as_mut_ptr().cast(), buf.len()) };
+        if len > 0 {
+            let detail = CStr::from_bytes_until_nul(&buf).unwrap_or_default();
+            error.message = format!("{}: {}", error.message, detail.to_string_lossy());
+        }
+        error
+    })
+}
+
 /// Initialize the NanoCore library
+///
+/// Only the first call initializes the core; later and concurrent calls
+/// wait for it and return its result.
 pub fn init() -> Result<()> {
-    let result = unsafe { ffi::nanocore_init() };
+    static INIT: OnceLock<c_int> = OnceLock::new();
+    let result = *INIT.get_or_init(|| unsafe { ffi::nanocore_init() });
     check_status(result, "initialize NanoCore")
 }
 
+/// Version of this crate as `(major, minor, patch)`
+///
+/// Bindings can compare it with the version they were written against.
+pub fn version() -> (u32, u32, u32) {
+    let part = |text: &str| text.parse().unwrap_or(0);
+    (
+        part(env!("CARGO_PKG_VERSION_MAJOR")),
+        part(env!("CARGO_PKG_VERSION_MINOR")),
+        part(env!("CARGO_PKG_VERSION_PATCH")),
+    )
+}
+
+/// One-line description of this build: crate version, target triple and
+/// whether the native C core or the Rust interpreter executes guests
+pub fn build_info() -> &'static str {
+    if cfg!(nanocore_native) {
+        concat!("nanocore ", env!("CARGO_PKG_VERSION"), " (", env!("NANOCORE_TARGET"), ", native core)")
+    } else {
+        concat!("nanocore ", env!("CARGO_PKG_VERSION"), " (", env!("NANOCORE_TARGET"), ", interpreter)")
+    }
+}
+
+/// Features the active backend supports, as reported by `capabilities`
+///
+/// The bit values match `nanocore_capabilities` in the FFI library, so
+/// bindings can test them the same way whichever library they load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(pub u64);
+
+impl Capabilities {
+    /// Vector instructions execute
+    pub const SIMD: u64 = 1 << 0;
+    /// MMIO devices can be attached
+    pub const DEVICES: u64 = 1 << 1;
+    /// `VM::set_watchpoint`
+    pub const WATCHPOINTS: u64 = 1 << 2;
+    /// `VM::snapshot` and `VM::restore`
+    pub const SNAPSHOT: u64 = 1 << 3;
+    /// `VM::enable_trace`
+    pub const TRACE: u64 = 1 << 4;
+    
+    /// Whether every bit of `capabilities` is set
+    pub fn contains(&self, capabilities: u64) -> bool {
+        self.0 & capabilities == capabilities
+    }
+}
+
+/// Features of this build, so callers can skip what it does not support
+///
+/// Devices, watchpoints, snapshots and tracing are implemented on the host
+/// and work with either backend. Neither the native core nor the
+/// interpreter executes vector instructions yet, so `SIMD` is clear. A
+/// feature sets its bit when it lands.
+pub fn capabilities() -> Capabilities {
+    Capabilities(
+        Capabilities::DEVICES | Capabilities::WATCHPOINTS | Capabilities::SNAPSHOT | Capabilities::TRACE,
+    )
+}
+
 /// NanoCore Virtual Machine
+///
+/// A `VM` can move to another thread but is not `Sync`: its callbacks are
+/// only `Send`, and the core is not safe to drive from two threads at once.
+/// Share one through `shared::SharedVm`.
 pub struct VM {
     handle: c_int,
     memory_size: u64,
+    devices: DeviceManager,
+    events: Arc<Mutex<EventQueue>>,
+    /// Signalled whenever an event is added to `events`
+    event_arrived: Arc<Condvar>,
+    breakpoints: Vec<Breakpoint>,
+    /// Times each breakpoint address has been hit, including ignored hits
+    breakpoint_hits: HashMap<u64, u64>,
+    watchpoints: Vec<(u64, u64, WatchKind)>,
+    watch_hit: Option<StopReason>,
+    opcode_breaks: Vec<opcode::OpcodeClass>,
+    opcode_hit: Option<(u64, opcode::Opcode)>,
+    last_stop: Option<StopReason>,
+    fault_code: Option<u32>,
+    energy: Option<energy::EnergyMeter>,
+    bandwidth: Option<bandwidth::BandwidthMeter>,
+    read_fault_injector: Option<fault::ReadFaultInjector>,
+    interrupt_flag_callback: Option<interrupts::InterruptFlagCallback>,
+    semihosting: Option<semihosting::Semihost>,
+    console: console::Console,
+    console_device: Option<devices::ConsoleHandle>,
+    guest_breakpoint_mode: GuestBreakpointMode,
+    trap_zero_register_write: bool,
+    filtered_trace: Option<trace::FilteredTrace>,
+    trace_ring: Option<trace::TraceRing>,
+    protection: protection::ProtectionMap,
+    entropy: entropy::Entropy,
+    cycle_batch: u64,
+    /// Pages of the latest copy-on-write snapshot, shared by the next one
+    cow_pages: Option<Vec<std::sync::Arc<[u8]>>>,
 }
 
 impl VM {
@@ -247,33 +1044,222 @@ impl VM {
     pub fn new(memory_size: u64) -> Result<Self> {
         let mut handle = 0;
         let result = unsafe { ffi::nanocore_vm_create(memory_size, &mut handle) };
-        check_status(result, "create VM")?;
+        check_create(result, "create VM")?;
+        
+        Ok(VM::with_handle(handle, memory_size))
+    }
+    
+    /// Create a VM whose memory is a shared mapping of the file at `path`
+    ///
+    /// The file is created if it does not exist and extended with zeros if it
+    /// is shorter than `memory_size`. Guest writes go straight to the file and
+    /// are synced to disk by `flush_memory` and when the VM is dropped.
+    pub fn new_with_file(path: &Path, memory_size: u64) -> Result<Self> {
+        let path = path
+            .to_str()
+            .and_then(|path| CString::new(path).ok())
+            .ok_or_else(|| Error {
+                status: Status::InvalidParameter,
+                message: format!("Unsupported memory file path {}", path.display()),
+            })?;
+        let mut handle = 0;
+        let result = unsafe { ffi::nanocore_vm_create_file(path.as_ptr(), memory_size, &mut handle) };
+        check_create(result, "create file-backed VM")?;
         
-        Ok(VM { handle, memory_size })
+        Ok(VM::with_handle(handle, memory_size))
     }
     
-    /// Reset VM to initial state
+    fn with_handle(handle: c_int, memory_size: u64) -> Self {
+        VM {
+            handle,
+            memory_size,
+            devices: DeviceManager::default(),
+            events: Arc::default(),
+            event_arrived: Arc::default(),
+            breakpoints: Vec::new(),
+            breakpoint_hits: HashMap::new(),
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            opcode_breaks: Vec::new(),
+            opcode_hit: None,
+            last_stop: None,
+            fault_code: None,
+            energy: None,
+            bandwidth: None,
+            read_fault_injector: None,
+            interrupt_flag_callback: None,
+            semihosting: None,
+            console: console::Console::default(),
+            console_device: None,
+            guest_breakpoint_mode: GuestBreakpointMode::Stop,
+            trap_zero_register_write: false,
+            filtered_trace: None,
+            trace_ring: None,
+            protection: Default::default(),
+            entropy: entropy::Entropy::from_os(),
+            cycle_batch: DEFAULT_CYCLE_BATCH,
+            cow_pages: None,
+        }
+    }
+    
+    /// Reset the CPU, devices and breakpoints, keeping memory
+    ///
+    /// Registers, flags and performance counters return to their initial
+    /// values, but whatever the previous program left in guest memory stays
+    /// there. Use `reset_full` to start from zeroed memory as well.
     pub fn reset(&mut self) -> Result<()> {
         let result = unsafe { ffi::nanocore_vm_reset(self.handle) };
-        check_status(result, "reset VM")
+        check_status(result, "reset VM")?;
+        self.devices.reset();
+        self.breakpoints.clear();
+        self.breakpoint_hits.clear();
+        self.watchpoints.clear();
+        self.opcode_breaks.clear();
+        self.last_stop = None;
+        Ok(())
+    }
+    
+    /// `reset`, then zero all of guest memory
+    ///
+    /// Leaves the VM as `VM::new` created it, apart from attached devices
+    /// and read-only mappings, which are kept. For file-backed memory the
+    /// zeros are written through to the file.
+    pub fn reset_full(&mut self) -> Result<()> {
+        self.reset()?;
+        let result = unsafe { ffi::nanocore_vm_fill_memory(self.handle, 0, 0, self.memory_size) };
+        check_status(result, "zero memory")
     }
     
     /// Run VM for a specified number of instructions
+    ///
+    /// Returns `Status::FuelExhausted` when `max_instructions` ran out
+    /// before the guest halted or stopped at a breakpoint.
     pub fn run(&mut self, max_instructions: Option<u64>) -> Result<Status> {
-        let max_instructions = max_instructions.unwrap_or(0);
-        let result = unsafe { ffi::nanocore_vm_run(self.handle, max_instructions) };
+        let result = self.run_raw(max_instructions)?;
         
         // For run, the return value is the exit status, not an error code
         match result {
+            0 if self.last_stop == Some(StopReason::Limit) => Ok(Status::FuelExhausted),
             0 => Ok(Status::Ok),
             1 => Ok(Status::Error), // Halted with error
             _ => Ok(Status::from_code(result)),
         }
     }
     
+    /// Like `run`, but also report how many instructions were retired
+    ///
+    /// The count is the change in `PerfCounter::InstructionCount` across the
+    /// run, so it is exact for both core and host-stepped execution.
+    pub fn run_counted(&mut self, max_instructions: Option<u64>) -> Result<RunCount> {
+        let counter = PerfCounter::InstructionCount as usize;
+        let before = self.raw_state()?.perf_counters[counter];
+        let status = self.run(max_instructions)?;
+        let after = self.raw_state()?.perf_counters[counter];
+        Ok(RunCount {
+            status,
+            instructions_retired: after.wrapping_sub(before),
+        })
+    }
+    
+    /// Run until `max_cycles` cycles have elapsed or execution stops
+    ///
+    /// Cycles are counted by `PerfCounter::CycleCount`, so stalls charged by
+    /// the bandwidth model count too. The budget is checked between batches
+    /// of instructions (see `set_cycle_batch`), so a run that hits it may
+    /// overshoot by up to one batch; `CycleRun::cycles` reports how many
+    /// cycles were actually consumed.
+    pub fn run_cycles(&mut self, max_cycles: u64) -> Result<CycleRun> {
+        let counter = PerfCounter::CycleCount as usize;
+        let before = self.raw_state()?.perf_counters[counter];
+        let mut cycles = 0;
+        let mut outcome = RunOutcome::Limit;
+        while cycles < max_cycles {
+            outcome = self.run_outcome(Some(self.cycle_batch))?;
+            cycles = self.raw_state()?.perf_counters[counter].wrapping_sub(before);
+            if outcome != RunOutcome::Limit {
+                break;
+            }
+        }
+        Ok(CycleRun { outcome, cycles })
+    }
+    
+    /// Set how many instructions `run_cycles` runs between budget checks
+    ///
+    /// Smaller batches overshoot the budget less but run slower. Defaults to
+    /// `DEFAULT_CYCLE_BATCH`; must not be zero.
+    pub fn set_cycle_batch(&mut self, instructions: u64) -> Result<()> {
+        if instructions == 0 {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: "Cycle batch must be at least one instruction".to_string(),
+            });
+        }
+        self.cycle_batch = instructions;
+        Ok(())
+    }
+    
+    /// Run VM and report why it stopped
+    pub fn run_outcome(&mut self, max_instructions: Option<u64>) -> Result<RunOutcome> {
+        self.run_raw(max_instructions)?;
+        
+        Ok(match self.last_stop {
+            Some(StopReason::Halted { exit_code }) => RunOutcome::Halted { exit_code },
+            Some(StopReason::Breakpoint { address, .. }) => RunOutcome::Breakpoint { address },
+            Some(StopReason::GuestBreakpoint { address }) => RunOutcome::Breakpoint { address },
+            Some(StopReason::Watchpoint { address, .. }) => RunOutcome::Watchpoint { address },
+            Some(StopReason::OpcodeBreak { address, .. }) => RunOutcome::Breakpoint { address },
+            Some(StopReason::Limit) => RunOutcome::Limit,
+            _ => RunOutcome::Exception,
+        })
+    }
+    
+    /// Run until the VM raises an event or `max_instructions` run out
+    ///
+    /// Returns the status `run` would and the oldest pending event, so one
+    /// call replaces `run` followed by `poll_event`. Halts, host breakpoints
+    /// and faults in the core are reported as events too. Events beyond the
+    /// first stay queued for `poll_event`; the event is `None` only when
+    /// the budget ran out with nothing pending.
+    pub fn run_until_event(&mut self, max_instructions: Option<u64>) -> Result<(Status, Option<Event>)> {
+        let status = self.run(max_instructions)?;
+        let queued = self.events.lock().unwrap().events.pop_front();
+        Ok((status, queued.or_else(|| self.stop_event())))
+    }
+    
+    /// Exit code reported by the guest's HALT, or `None` if it hasn't halted
+    pub fn exit_code(&self) -> Result<Option<i32>> {
+        let mut exit_code = 0;
+        let result = unsafe { ffi::nanocore_vm_get_exit_code(self.handle, &mut exit_code) };
+        match Status::from_code(result) {
+            Status::Ok => Ok(Some(exit_code)),
+            Status::Error => Ok(None),
+            _ => check_status(result, "get exit code").map(|_| None),
+        }
+    }
+    
+    fn run_raw(&mut self, max_instructions: Option<u64>) -> Result<c_int> {
+        let max_instructions = max_instructions.unwrap_or(0);
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("nanocore.run", handle = self.handle, max_instructions).entered();
+        let result = if self.host_stepped() {
+            self.run_hosted(max_instructions)?
+        } else {
+            unsafe { ffi::nanocore_vm_run(self.handle, max_instructions) }
+        };
+        self.record_stop(result)?;
+        Ok(result)
+    }
+    
     /// Execute a single instruction
     pub fn step(&mut self) -> Result<Status> {
-        let result = unsafe { ffi::nanocore_vm_step(self.handle) };
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("nanocore.step", handle = self.handle).entered();
+        let result = if self.host_stepped() {
+            self.run_hosted(1)?
+        } else {
+            unsafe { ffi::nanocore_vm_step(self.handle) }
+        };
+        self.record_stop(result)?;
         
         // For step, the return value is the exit status, not an error code
         match result {
@@ -302,8 +1288,94 @@ impl VM {
         Ok(state.into())
     }
     
+    /// Render the architectural state as readable text
+    ///
+    /// Lists PC, SP, the flags word with its set bits named, every nonzero
+    /// general-purpose register and the performance counters, one item per
+    /// line. Unlike the core's `vm_dump_state`, which prints to stderr, the
+    /// result can be logged or shown in a UI.
+    pub fn format_state(&self) -> String {
+        // Only fails for an invalid handle, which a live VM never has
+        self.get_state().map(|state| state.to_string()).unwrap_or_default()
+    }
+    
+    /// The whole vector register file, one row of four lanes per register
+    pub fn vregs_as_matrix(&self) -> Result<[[u64; 4]; 16]> {
+        Ok(self.raw_state()?.vregs)
+    }
+    
+    /// The vector register file with every lane reinterpreted as an `f64`
+    pub fn vregs_as_f64_matrix(&self) -> Result<[[f64; 4]; 16]> {
+        Ok(self.vregs_as_matrix()?.map(|lanes| lanes.map(f64::from_bits)))
+    }
+    
+    /// Change named flags and write the result back in one state update
+    ///
+    /// Useful for forcing the other side of a conditional branch:
+    /// `vm.modify_flags(|f| { f.set_zero(true).set_carry(false); })`.
+    pub fn modify_flags(&mut self, f: impl FnOnce(&mut FlagsBuilder)) -> Result<()> {
+        let mut state = self.raw_state()?;
+        let mut builder = FlagsBuilder(Flags(state.flags));
+        f(&mut builder);
+        state.flags = builder.0 .0;
+        self.set_raw_state(&state)
+    }
+    
+    /// Get the CPU flags
+    pub fn get_flags(&self) -> Result<Flags> {
+        Ok(Flags(self.raw_state()?.flags))
+    }
+    
+    /// Get the cache control register
+    pub fn get_cache_ctrl(&self) -> Result<CacheCtrl> {
+        Ok(CacheCtrl(self.raw_state()?.cache_ctrl))
+    }
+    
+    /// Replace the cache control register
+    pub fn set_cache_ctrl(&mut self, cache_ctrl: CacheCtrl) -> Result<()> {
+        let mut state = self.raw_state()?;
+        state.cache_ctrl = cache_ctrl.0;
+        self.set_raw_state(&state)
+    }
+    
+    /// Get the interrupt vector base
+    ///
+    /// The guest address of the vectored interrupt table, whose entry for
+    /// vector `n` is the 8-byte handler address at `vbase + 8 * n`.
+    pub fn get_vbase(&self) -> Result<u64> {
+        Ok(self.raw_state()?.vbase)
+    }
+    
+    /// Set the interrupt vector base
+    ///
+    /// `address` must lie inside guest memory.
+    pub fn set_vbase(&mut self, address: u64) -> Result<()> {
+        if address >= self.memory_size {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Vector base {:#x} is outside guest memory", address),
+            });
+        }
+        let mut state = self.raw_state()?;
+        state.vbase = address;
+        self.set_raw_state(&state)
+    }
+    
+    /// Replace the CPU flags, including `HALTED`
+    ///
+    /// Meant for test setup; `modify_flags` changes individual flags.
+    pub fn set_flags(&mut self, flags: Flags) -> Result<()> {
+        let mut state = self.raw_state()?;
+        state.flags = flags.0;
+        self.set_raw_state(&state)
+    }
+    
     /// Get a register value
-    pub fn get_register(&self, index: u32) -> Result<u64> {
+    ///
+    /// Takes a `Reg` such as `Reg::R5` or `Reg::SP`, or a raw index, which
+    /// fails with `InvalidParameter` unless it is below 32.
+    pub fn get_register(&self, index: impl RegisterIndex) -> Result<u64> {
+        let index = index.register_index();
         if index >= 32 {
             return Err(Error {
                 status: Status::InvalidParameter,
@@ -319,7 +1391,11 @@ impl VM {
     }
     
     /// Set a register value
-    pub fn set_register(&mut self, index: u32, value: u64) -> Result<()> {
+    ///
+    /// R0 is hardwired to zero, so writing it succeeds but has no effect.
+    /// Registers are named as for `get_register`.
+    pub fn set_register(&mut self, index: impl RegisterIndex, value: u64) -> Result<()> {
+        let index = index.register_index();
         if index >= 32 {
             return Err(Error {
                 status: Status::InvalidParameter,
@@ -331,8 +1407,117 @@ impl VM {
         check_status(result, "set register")
     }
     
-    /// Load a program into memory
-    pub fn load_program(&mut self, data: &[u8], address: u64) -> Result<()> {
+    /// Get the program counter
+    pub fn get_pc(&self) -> Result<u64> {
+        Ok(self.raw_state()?.pc)
+    }
+    
+    /// Set where execution continues on the next `run` or `step`
+    ///
+    /// `address` must leave room for a whole instruction inside guest
+    /// memory. Other registers and flags are left as they are.
+    pub fn set_pc(&mut self, address: u64) -> Result<()> {
+        if address.checked_add(4).is_none_or(|end| end > self.memory_size) {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("PC {:#x} is outside guest memory", address),
+            });
+        }
+        let mut state = self.raw_state()?;
+        state.pc = address;
+        self.set_raw_state(&state)
+    }
+    
+    /// Get the stack pointer
+    ///
+    /// SP is an alias for `R30`, which is what guest code adjusts, so this
+    /// reads `R30`.
+    pub fn get_sp(&self) -> Result<u64> {
+        Ok(self.raw_state()?.gprs[30])
+    }
+    
+    /// Set the stack pointer, both `R30` and the core's SP field
+    ///
+    /// `value` may be anywhere up to and including `memory_size`, the
+    /// position of an empty stack that starts at the very top of memory.
+    pub fn set_sp(&mut self, value: u64) -> Result<()> {
+        if value > self.memory_size {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("SP {:#x} is outside guest memory", value),
+            });
+        }
+        let mut state = self.raw_state()?;
+        state.sp = value;
+        state.gprs[30] = value;
+        self.set_raw_state(&state)
+    }
+    
+    /// Point SP at `top`, the highest slot of a fresh stack
+    ///
+    /// The stack grows down, towards lower addresses, so everything below
+    /// `top` should be free for it. `top` must be 8-byte aligned and leave
+    /// room for one 64-bit word; `memory_size - 8`, where `reset` puts the
+    /// core's SP, uses all of memory above the program.
+    pub fn init_stack(&mut self, top: u64) -> Result<()> {
+        if !top.is_multiple_of(8) || top.checked_add(8).is_none_or(|end| end > self.memory_size) {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Stack top {:#x} is not an aligned word inside guest memory", top),
+            });
+        }
+        self.set_sp(top)
+    }
+    
+    /// Get all 32 general purpose registers in one call
+    pub fn get_registers(&self) -> Result<[u64; 32]> {
+        let mut values = [0u64; 32];
+        let result = unsafe { ffi::nanocore_vm_get_registers(self.handle, values.as_mut_ptr()) };
+        check_status(result, "get registers")?;
+        
+        Ok(values)
+    }
+    
+    /// Set all 32 general purpose registers in one call
+    ///
+    /// `regs[0]` is ignored, since R0 is hardwired to zero.
+    pub fn set_registers(&mut self, regs: &[u64; 32]) -> Result<()> {
+        let result = unsafe { ffi::nanocore_vm_set_registers(self.handle, regs.as_ptr()) };
+        check_status(result, "set registers")
+    }
+    
+    /// Get the four lanes of a vector register
+    pub fn get_vector_register(&self, index: u32) -> Result<[u64; 4]> {
+        if index >= 16 {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Vector register index {} out of range", index),
+            });
+        }
+        
+        let mut lanes = [0u64; 4];
+        let result = unsafe { ffi::nanocore_vm_get_vreg(self.handle, index as c_int, lanes.as_mut_ptr()) };
+        check_status(result, "get vector register")?;
+        
+        Ok(lanes)
+    }
+    
+    /// Set the four lanes of a vector register
+    pub fn set_vector_register(&mut self, index: u32, value: [u64; 4]) -> Result<()> {
+        if index >= 16 {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Vector register index {} out of range", index),
+            });
+        }
+        
+        let result = unsafe { ffi::nanocore_vm_set_vreg(self.handle, index as c_int, value.as_ptr()) };
+        check_status(result, "set vector register")
+    }
+    
+    /// Load a program into memory
+    pub fn load_program(&mut self, data: &[u8], address: u64) -> Result<()> {
+        self.check_writable(address, data.len() as u64)?;
         let result = unsafe {
             ffi::nanocore_vm_load_program(
                 self.handle,
@@ -345,7 +1530,16 @@ impl VM {
     }
     
     /// Read memory from VM
+    ///
+    /// Ranges mapped with `map_readonly` read as the mapped bytes.
     pub fn read_memory(&self, address: u64, size: u64) -> Result<Vec<u8>> {
+        // Check before allocating, so a huge size fails instead of aborting
+        if address.checked_add(size).is_none_or(|end| end > self.memory_size) {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Read of {} bytes at {:#x} is outside guest memory", size, address),
+            });
+        }
         let mut buffer = vec![0u8; size as usize];
         let result = unsafe {
             ffi::nanocore_vm_read_memory(
@@ -356,12 +1550,63 @@ impl VM {
             )
         };
         check_status(result, "read memory")?;
+        self.devices.apply_overlays(address, &mut buffer);
         
         Ok(buffer)
     }
     
+    /// Borrow all of guest memory for the duration of `f`, without copying
+    ///
+    /// The slice is the core's own memory, indexed by guest address. The
+    /// `&self` borrow keeps anything from writing to it while `f` runs, and
+    /// the slice must not escape the closure. Unlike `read_memory` it shows
+    /// RAM only: ranges mapped with `map_readonly` read as whatever RAM lies
+    /// underneath, and device registers are not read.
+    pub fn with_memory<R>(&self, f: impl FnOnce(&[u8]) -> R) -> Result<R> {
+        let mut data = std::ptr::null();
+        let mut size = 0;
+        let result = unsafe { ffi::nanocore_vm_get_memory(self.handle, &mut data, &mut size) };
+        check_status(result, "borrow memory")?;
+        // The core's memory stays put until the VM is dropped, which the
+        // borrow of `self` rules out while `f` runs
+        let memory = unsafe { std::slice::from_raw_parts(data, size as usize) };
+        Ok(f(memory))
+    }
+    
+    /// Read a NUL-terminated string from VM memory
+    ///
+    /// Stops at the first NUL, after `max_len` bytes or at the end of
+    /// memory, whichever comes first; a string cut short is returned as far
+    /// as it goes. Invalid UTF-8 is replaced with U+FFFD. Use
+    /// `read_cstring_bytes` to find out whether the terminator was reached.
+    pub fn read_cstring(&self, address: u64, max_len: usize) -> Result<String> {
+        let (bytes, _) = self.read_cstring_bytes(address, max_len)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+    
+    /// Bytes of a NUL-terminated string, and whether the NUL was found
+    ///
+    /// The terminator itself is not included. `false` means no NUL appeared
+    /// within `max_len` bytes or before the end of memory, so the bytes are
+    /// only a prefix of the string.
+    pub fn read_cstring_bytes(&self, address: u64, max_len: usize) -> Result<(Vec<u8>, bool)> {
+        let available = self.memory_size.checked_sub(address).ok_or_else(|| Error {
+            status: Status::InvalidParameter,
+            message: format!("String address {:#x} is outside memory", address),
+        })?;
+        let mut bytes = self.read_memory(address, available.min(max_len as u64))?;
+        match bytes.iter().position(|&b| b == 0) {
+            Some(end) => {
+                bytes.truncate(end);
+                Ok((bytes, true))
+            }
+            None => Ok((bytes, false)),
+        }
+    }
+    
     /// Write memory to VM
     pub fn write_memory(&mut self, address: u64, data: &[u8]) -> Result<()> {
+        self.check_writable(address, data.len() as u64)?;
         let result = unsafe {
             ffi::nanocore_vm_write_memory(
                 self.handle,
@@ -373,16 +1618,262 @@ impl VM {
         check_status(result, "write memory")
     }
     
+    /// Set `len` bytes starting at `address` to `value`
+    ///
+    /// Fails without writing anything unless the whole range is in memory.
+    pub fn fill_memory(&mut self, address: u64, value: u8, len: u64) -> Result<()> {
+        self.check_writable(address, len)?;
+        let result = unsafe { ffi::nanocore_vm_fill_memory(self.handle, address, value, len) };
+        check_status(result, "fill memory")
+    }
+    
+    /// Copy `len` bytes from `src` to `dst` without a host buffer
+    ///
+    /// The ranges may overlap; the result is as if `src` were copied out
+    /// first. Fails without writing anything unless both ranges are in memory.
+    pub fn copy_memory(&mut self, dst: u64, src: u64, len: u64) -> Result<()> {
+        self.check_writable(dst, len)?;
+        if self.devices.overlaps_overlay(src, src.saturating_add(len)) {
+            // The core only sees the RAM underneath a read-only mapping
+            let bytes = self.read_memory(src, len)?;
+            return self.write_memory(dst, &bytes);
+        }
+        let result = unsafe { ffi::nanocore_vm_copy_memory(self.handle, dst, src, len) };
+        check_status(result, "copy memory")
+    }
+    
+    /// Read the little-endian `u64` at `address`
+    ///
+    /// Guest data memory is little-endian, the same byte order the load
+    /// and store instructions use. Memory is byte-addressable and `address`
+    /// need not be aligned.
+    pub fn read_u64(&self, address: u64) -> Result<u64> {
+        let bytes = self.read_memory(address, 8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+    
+    /// Write `value` at `address` as a little-endian `u64`
+    ///
+    /// `address` need not be aligned.
+    pub fn write_u64(&mut self, address: u64, value: u64) -> Result<()> {
+        self.write_memory(address, &value.to_le_bytes())
+    }
+    
+    /// Read the little-endian `u32` at `address`
+    ///
+    /// This is the value a guest `LW` loads, before sign extension.
+    /// `address` need not be aligned. Instruction words are encoded
+    /// big-endian, so this does not return an instruction's encoding; use
+    /// `disassemble` for that.
+    pub fn read_u32(&self, address: u64) -> Result<u32> {
+        let bytes = self.read_memory(address, 4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+    
+    /// Write `value` at `address` as a little-endian `u32`
+    ///
+    /// `address` need not be aligned.
+    pub fn write_u32(&mut self, address: u64, value: u32) -> Result<()> {
+        self.write_memory(address, &value.to_le_bytes())
+    }
+    
+    /// Replace the entire memory contents in one operation
+    ///
+    /// `image` must be exactly `memory_size` bytes long.
+    pub fn load_memory_image(&mut self, image: &[u8]) -> Result<()> {
+        if image.len() as u64 != self.memory_size {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!(
+                    "Memory image is {} bytes, expected {}",
+                    image.len(),
+                    self.memory_size
+                ),
+            });
+        }
+        
+        let result = unsafe {
+            ffi::nanocore_vm_load_memory_image(self.handle, image.as_ptr(), self.memory_size)
+        };
+        check_status(result, "load memory image")
+    }
+    
+    /// Copy out the entire memory contents
+    ///
+    /// This is the RAM itself; ranges mapped with `map_readonly` hold
+    /// whatever was underneath them.
+    pub fn save_memory_image(&self) -> Vec<u8> {
+        let mut image = vec![0u8; self.memory_size as usize];
+        let result = unsafe {
+            ffi::nanocore_vm_save_memory_image(self.handle, image.as_mut_ptr(), self.memory_size)
+        };
+        // Only fails for an invalid handle or size, neither of which a live VM has
+        debug_assert_eq!(result, 0);
+        image
+    }
+    
+    /// Write modified memory back to its backing store
+    ///
+    /// When memory is backed by a file, everything written before this call
+    /// is durable once it returns `Ok`. Memory without a backing file (the
+    /// default) has nothing to persist, so this is then a no-op.
+    pub fn flush_memory(&self) -> Result<()> {
+        let result = unsafe { ffi::nanocore_vm_flush_memory(self.handle) };
+        check_status(result, "flush memory")
+    }
+    
+    /// Like `flush_memory`, limited to `size` bytes starting at `address`
+    pub fn flush_range(&self, address: u64, size: u64) -> Result<()> {
+        let result = unsafe { ffi::nanocore_vm_flush_range(self.handle, address, size) };
+        check_status(result, "flush memory range")
+    }
+    
     /// Set a breakpoint
     pub fn set_breakpoint(&mut self, address: u64) -> Result<()> {
-        let result = unsafe { ffi::nanocore_vm_set_breakpoint(self.handle, address) };
-        check_status(result, "set breakpoint")
+        self.add_breakpoint(Breakpoint::new(address))
+    }
+    
+    /// Set a breakpoint that only stops when `cond` holds
+    ///
+    /// The condition is evaluated on the host each time execution reaches
+    /// `address`, before the instruction there runs; when it does not hold
+    /// the instruction executes and the run continues. Replaces any earlier
+    /// conditional or counted breakpoint at the same address. While any
+    /// conditional breakpoint is set the VM is stepped from the host.
+    pub fn set_conditional_breakpoint(&mut self, address: u64, cond: BreakCondition) -> Result<()> {
+        let BreakCondition::Register { index, .. } = cond;
+        if index >= 32 {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Invalid register index {} in breakpoint condition", index),
+            });
+        }
+        self.add_breakpoint(Breakpoint {
+            condition: Some(cond),
+            ..Breakpoint::new(address)
+        })
+    }
+    
+    /// Set a breakpoint that lets the first `ignore_count` hits pass
+    ///
+    /// Execution stops on hit `ignore_count + 1` and every hit after it.
+    /// The hit count for `address` starts again from zero, and any earlier
+    /// conditional or counted breakpoint there is replaced. With a nonzero
+    /// `ignore_count` the VM is stepped from the host while the breakpoint
+    /// is set.
+    pub fn set_breakpoint_with_count(&mut self, address: u64, ignore_count: u64) -> Result<()> {
+        self.breakpoint_hits.remove(&address);
+        self.add_breakpoint(Breakpoint {
+            ignore_count,
+            ..Breakpoint::new(address)
+        })
+    }
+    
+    /// Set a breakpoint that is cleared the first time it stops execution
+    pub fn set_temp_breakpoint(&mut self, address: u64) -> Result<()> {
+        self.add_breakpoint(Breakpoint {
+            temporary: true,
+            ..Breakpoint::new(address)
+        })
+    }
+    
+    /// How many times execution has reached the breakpoint at `address`
+    ///
+    /// Counts hits let pass by `set_breakpoint_with_count` as well as
+    /// stops, but not visits where a condition did not hold. `None` if no
+    /// breakpoint is set at `address`.
+    pub fn breakpoint_hit_count(&self, address: u64) -> Option<u64> {
+        self.breakpoints
+            .iter()
+            .any(|bp| bp.address == address)
+            .then(|| self.breakpoint_hits.get(&address).copied().unwrap_or(0))
+    }
+    
+    /// Addresses of all breakpoints, in the order they were set
+    ///
+    /// Includes conditional, counted and temporary breakpoints.
+    pub fn list_breakpoints(&self) -> Result<Vec<u64>> {
+        Ok(self.breakpoints.iter().map(|bp| bp.address).collect())
+    }
+    
+    /// Register `breakpoint`, replacing any host-evaluated one at the same address
+    fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> Result<()> {
+        if breakpoint.host_evaluated() {
+            self.breakpoints
+                .retain(|bp| bp.address != breakpoint.address || !bp.host_evaluated());
+        } else {
+            let result = unsafe { ffi::nanocore_vm_set_breakpoint(self.handle, breakpoint.address) };
+            check_status(result, "set breakpoint")?;
+        }
+        self.breakpoints.push(breakpoint);
+        Ok(())
     }
     
     /// Clear a breakpoint
+    ///
+    /// Removes a conditional or counted breakpoint at `address` if there is
+    /// one, and a plain breakpoint otherwise.
     pub fn clear_breakpoint(&mut self, address: u64) -> Result<()> {
-        let result = unsafe { ffi::nanocore_vm_clear_breakpoint(self.handle, address) };
-        check_status(result, "clear breakpoint")
+        let host_evaluated = self
+            .breakpoints
+            .iter()
+            .position(|bp| bp.address == address && bp.host_evaluated());
+        if let Some(index) = host_evaluated {
+            // These never reach the core
+            self.breakpoints.remove(index);
+        } else {
+            let result = unsafe { ffi::nanocore_vm_clear_breakpoint(self.handle, address) };
+            check_status(result, "clear breakpoint")?;
+            if let Some(index) = self.breakpoints.iter().position(|bp| bp.address == address) {
+                self.breakpoints.remove(index);
+            }
+        }
+        self.breakpoint_hits.remove(&address);
+        Ok(())
+    }
+    
+    /// Stop after any guest load or store of `kind` that touches `[address, address + len)`
+    ///
+    /// A `Watchpoint` event is queued for each hit. Watchpoints need to see
+    /// every access, so while any is set the VM is stepped from the host.
+    pub fn set_watchpoint(&mut self, address: u64, len: u64, kind: WatchKind) -> Result<()> {
+        if len == 0 {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: "Watchpoint length must be non-zero".to_string(),
+            });
+        }
+        self.watchpoints.retain(|&(start, _, _)| start != address);
+        self.watchpoints.push((address, len, kind));
+        Ok(())
+    }
+    
+    /// Clear the watchpoint starting at `address`
+    pub fn clear_watchpoint(&mut self, address: u64) {
+        self.watchpoints.retain(|&(start, _, _)| start != address);
+    }
+    
+    /// Stop after any instruction in `class` executes
+    ///
+    /// Each instruction is decoded just before it runs; on a match it is
+    /// allowed to finish, a `Breakpoint` event carrying its address is
+    /// queued, and the run stops with `StopReason::OpcodeBreak`, so running
+    /// again carries on past it. Classes accumulate until
+    /// `clear_opcode_breaks` or `reset`.
+    ///
+    /// The core cannot match on opcodes, so while any class is set the VM is
+    /// stepped from the host. That costs a state read and an instruction
+    /// fetch per instruction on top of the step itself, which makes runs
+    /// one to two orders of magnitude slower than in the core.
+    pub fn break_on_opcode(&mut self, class: opcode::OpcodeClass) {
+        if !self.opcode_breaks.contains(&class) {
+            self.opcode_breaks.push(class);
+        }
+    }
+    
+    /// Stop breaking on the classes set by `break_on_opcode`
+    pub fn clear_opcode_breaks(&mut self) {
+        self.opcode_breaks.clear();
     }
     
     /// Get performance counter value
@@ -396,46 +1887,142 @@ impl VM {
         Ok(value)
     }
     
+    /// Get all eight performance counters in one call
+    ///
+    /// Indexed like `PerfCounter`; convert with `PerfCounters::from` for
+    /// named fields.
+    pub fn get_perf_counters(&self) -> Result<[u64; 8]> {
+        Ok(self.raw_state()?.perf_counters)
+    }
+    
+    /// Zero all performance counters without resetting the VM
+    ///
+    /// Counting resumes from zero on the next `run` or `step`, so a region
+    /// of interest can be measured on its own.
+    pub fn reset_perf_counters(&mut self) -> Result<()> {
+        let result = unsafe { ffi::nanocore_vm_reset_perf_counters(self.handle) };
+        check_status(result, "reset performance counters")
+    }
+    
+    /// Run while PC stays in `[start, end)` and report what the region cost
+    ///
+    /// Execution stops on the first instruction outside the region, which
+    /// is left unexecuted, or earlier on a halt, breakpoint or fault. Since
+    /// only instructions inside the region run, the counter deltas are
+    /// exactly theirs; calls out of the region end the profile. If PC starts
+    /// outside the region nothing runs and every delta is zero. There is no
+    /// instruction budget, so a region that never exits never returns. The
+    /// VM is stepped one instruction at a time.
+    pub fn profile_region(&mut self, start: u64, end: u64) -> Result<RegionProfile> {
+        if start >= end {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Empty profile region [{:#x}, {:#x})", start, end),
+            });
+        }
+        
+        let before = self.raw_state()?.perf_counters;
+        let mut stopped = None;
+        while (start..end).contains(&self.raw_state()?.pc) {
+            let outcome = self.run_outcome(Some(1))?;
+            if outcome != RunOutcome::Limit {
+                stopped = Some(outcome);
+                break;
+            }
+        }
+        let after = self.raw_state()?.perf_counters;
+        Ok(RegionProfile {
+            counters: PerfCounters::from(std::array::from_fn(|index| after[index].wrapping_sub(before[index]))),
+            stopped,
+        })
+    }
+    
     /// Poll for VM events (non-blocking)
     pub fn poll_event(&self) -> Result<Option<Event>> {
+        self.wait_event(Some(Duration::ZERO))
+    }
+    
+    /// Wait for the next event
+    ///
+    /// `None` blocks until an event arrives and a zero timeout behaves like
+    /// `poll_event`. Returns `Ok(None)` if the timeout elapsed first.
+    ///
+    /// Host-side events wake the wait as soon as they are queued. Events
+    /// from the core are only queued while it runs, which cannot happen
+    /// during the wait, so the core's queue is checked every millisecond
+    /// rather than blocked on. With nothing else feeding the VM, a wait
+    /// without a timeout on a VM that is not running never returns.
+    pub fn wait_event(&self, timeout: Option<Duration>) -> Result<Option<Event>> {
+        // A timeout too long to represent is as good as none
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        let mut queue = self.events.lock().unwrap();
+        loop {
+            if let Some(event) = queue.events.pop_front() {
+                return Ok(Some(event));
+            }
+            if let Some(event) = self.poll_core_event() {
+                return Ok(Some(event));
+            }
+            
+            let mut wait = CORE_EVENT_POLL;
+            if let Some(deadline) = deadline {
+                match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) if !left.is_zero() => wait = wait.min(left),
+                    _ => return Ok(None),
+                }
+            }
+            queue = self.event_arrived.wait_timeout(queue, wait).unwrap().0;
+        }
+    }
+    
+    /// Take the oldest event from the core's own queue, if there is one
+    fn poll_core_event(&self) -> Option<Event> {
         let mut event_type = 0;
         let mut event_data = 0;
-        let result = unsafe {
-            ffi::nanocore_vm_poll_event(self.handle, &mut event_type, &mut event_data)
-        };
-        
-        if result == 0 {
-            if let Some(event_type) = EventType::from_code(event_type) {
-                Ok(Some(Event {
-                    event_type,
-                    data: event_data,
-                }))
-            } else {
-                Ok(None)
-            }
-        } else {
-            Ok(None)
+        let result = unsafe { ffi::nanocore_vm_wait_event(self.handle, 0, &mut event_type, &mut event_data) };
+        if result != 0 {
+            return None;
         }
+        EventType::from_code(event_type).map(|event_type| Event {
+            event_type,
+            data: event_data,
+            fault_address: None,
+        })
     }
     
     /// Get memory size
     pub fn memory_size(&self) -> u64 {
         self.memory_size
     }
+    
+    /// Queue an event raised on the host side
+    pub(crate) fn push_event(&self, event: Event) {
+        #[cfg(feature = "tracing")]
+        if event.event_type == EventType::DeviceInterrupt {
+            tracing::info!(handle = self.handle, irq = event.data, "device interrupt");
+        }
+        let mut queue = self.events.lock().unwrap();
+        queue.events.push_back(event);
+        #[cfg(feature = "async")]
+        queue.wake();
+        self.event_arrived.notify_all();
+    }
 }
 
 impl Drop for VM {
     fn drop(&mut self) {
+        #[cfg(feature = "async")]
+        {
+            let mut queue = self.events.lock().unwrap();
+            queue.closed = true;
+            queue.wake();
+        }
         unsafe {
             ffi::nanocore_vm_destroy(self.handle);
         }
     }
 }
 
-// Ensure VM is Send and Sync safe
-unsafe impl Send for VM {}
-unsafe impl Sync for VM {}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -447,6 +2034,12 @@ mod tests {
         assert_eq!(vm.memory_size(), 1024 * 1024);
     }
     
+    #[test]
+    fn test_zero_size_memory_is_rejected() {
+        init().unwrap();
+        assert!(matches!(VM::new(0), Err(Error { status: Status::InvalidParameter, .. })));
+    }
+    
     #[test]
     fn test_register_access() {
         init().unwrap();
@@ -495,4 +2088,1918 @@ mod tests {
             status => panic!("Expected Ok, got {:?}", status),
         }
     }
-}
\ No newline at end of file
+    
+    /// Encode an I-type instruction word in load order
+    fn insn(opcode: u32, rd: u32, rs1: u32, imm: u16) -> [u8; 4] {
+        ((opcode << 26) | (rd << 21) | (rs1 << 16) | imm as u32).to_be_bytes()
+    }
+    
+    /// Encode an R-type instruction word in load order
+    fn rtype(opcode: u32, rd: u32, rs1: u32, rs2: u32) -> [u8; 4] {
+        ((opcode << 26) | (rd << 21) | (rs1 << 16) | (rs2 << 11)).to_be_bytes()
+    }
+    
+    #[test]
+    fn test_rtc_sim_is_deterministic() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let rtc = vm.attach_rtc_sim(0x7000, 10).unwrap();
+        rtc.set_epoch(1000);
+        
+        // NOP; NOP; LD R2, 0x7000; LW R1, 0(R2); HALT
+        let program: Vec<u8> = [
+            insn(0x22, 0, 0, 0),
+            insn(0x22, 0, 0, 0),
+            insn(0x0F, 2, 0, 0x7000),
+            insn(0x10, 1, 2, 0),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        vm.run(Some(100)).unwrap();
+        
+        // Three instructions retired before the clock was read
+        assert_eq!(vm.get_register(1).unwrap(), 1030);
+        assert_eq!(rtc.now_ns(), 1050);
+        
+        // Overlapping ranges are rejected
+        assert!(vm.attach_rtc_sim(0x7008, 1).is_err());
+    }
+    
+    #[test]
+    fn test_config_round_trip() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        vm.attach_rtc_sim(0x7000, 10).unwrap().set_epoch(500);
+        vm.protect(0x2000, 0x1000, protection::Protection::Read).unwrap();
+        vm.protect(0x4000, 0x100, protection::Protection::None).unwrap();
+        vm.map_readonly(0x3000, Arc::from(&b"rom"[..])).unwrap();
+        vm.set_breakpoint(0x10008).unwrap();
+        vm.set_temp_breakpoint(0x1000C).unwrap();
+        vm.set_breakpoint_with_count(0x10010, 3).unwrap();
+        let condition = BreakCondition::Register { index: 1, op: CmpOp::Ge, value: 5 };
+        vm.set_conditional_breakpoint(0x10014, condition).unwrap();
+        
+        let config = vm.export_config();
+        assert_eq!(config.protections.len(), 2);
+        assert_eq!(config.breakpoints.len(), 4);
+        let text = config.to_string();
+        assert!(text.contains("protect 0x2000 0x1000 read\n"));
+        assert!(text.contains("readonly 0x3000 726f6d\n"));
+        assert!(text.contains("breakpoint 0x10014 if r1 ge 0x5\n"));
+        let parsed: config::VmConfig = text.parse().unwrap();
+        assert_eq!(parsed, config);
+        
+        let rebuilt = VM::from_config(&parsed).unwrap();
+        assert_eq!(rebuilt.export_config(), config);
+        assert_eq!(rebuilt.memory_size(), 1024 * 1024);
+    }
+    
+    #[test]
+    fn test_filtered_trace_only_reports_matches() {
+        use std::sync::{Arc, Mutex};
+        
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        
+        // LD R2, 0x100; NOP; ST R1, 0(R2); HALT
+        let program: Vec<u8> = [
+            insn(0x0F, 2, 0, 0x100),
+            insn(0x22, 0, 0, 0),
+            insn(0x13, 1, 2, 0),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink_seen = Arc::clone(&seen);
+        vm.set_filtered_trace(
+            Box::new(|_pc, raw| (0x13..=0x16).contains(&(raw >> 26))),
+            Box::new(move |insn| sink_seen.lock().unwrap().push(insn)),
+        );
+        vm.run(Some(100)).unwrap();
+        
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].address, 0x10008);
+        assert_eq!(seen[0].to_string(), "ST R1, 0(R2)");
+    }
+    
+    #[test]
+    fn test_halt_reports_exit_code() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        
+        // LD R1, -3; HALT
+        let program: Vec<u8> = [insn(0x0F, 1, 0, -3i16 as u16), insn(0x21, 0, 0, 0)].concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        assert_eq!(vm.exit_code().unwrap(), None);
+        
+        assert_eq!(
+            vm.run_outcome(Some(100)).unwrap(),
+            RunOutcome::Halted { exit_code: -3 }
+        );
+        assert_eq!(vm.exit_code().unwrap(), Some(-3));
+        
+        let event = vm.poll_event().unwrap().unwrap();
+        assert_eq!(event.event_type, EventType::Halted);
+        assert_eq!(event.data as i64, -3);
+    }
+    
+    #[test]
+    fn test_last_stop_reason() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        
+        // NOP; NOP; LD R1, 7; HALT
+        let program: Vec<u8> = [
+            insn(0x22, 0, 0, 0),
+            insn(0x22, 0, 0, 0),
+            insn(0x0F, 1, 0, 7),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        assert_eq!(vm.last_stop_reason(), None);
+        
+        vm.step().unwrap();
+        assert_eq!(vm.last_stop_reason(), Some(StopReason::Limit));
+        
+        vm.set_breakpoint(0x10004).unwrap();
+        vm.set_breakpoint(0x10008).unwrap();
+        vm.run(Some(100)).unwrap();
+        assert_eq!(
+            vm.last_stop_reason(),
+            Some(StopReason::Breakpoint { address: 0x10004, hit_count: 1 })
+        );
+        
+        // The core stays parked on the breakpoint until it is cleared
+        vm.run(Some(100)).unwrap();
+        assert_eq!(
+            vm.last_stop_reason(),
+            Some(StopReason::Breakpoint { address: 0x10004, hit_count: 2 })
+        );
+        
+        vm.clear_breakpoint(0x10004).unwrap();
+        vm.run(Some(100)).unwrap();
+        assert_eq!(
+            vm.last_stop_reason(),
+            Some(StopReason::Breakpoint { address: 0x10008, hit_count: 1 })
+        );
+        
+        vm.clear_breakpoint(0x10008).unwrap();
+        vm.run(Some(100)).unwrap();
+        assert_eq!(vm.last_stop_reason(), Some(StopReason::Halted { exit_code: 7 }));
+        
+        vm.reset().unwrap();
+        assert_eq!(vm.last_stop_reason(), None);
+        vm.load_program(&[0xFC, 0, 0, 0], 0x10000).unwrap();
+        vm.run(Some(100)).unwrap();
+        assert_eq!(vm.last_stop_reason(), Some(StopReason::Exception { code: None }));
+    }
+    
+    #[test]
+    fn test_run_until_any_reports_first_condition() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        
+        // LD R2, 1; 20 x ADD R1, R1, R2; HALT
+        let mut program = insn(0x0F, 2, 0, 1).to_vec();
+        for _ in 0..20 {
+            program.extend_from_slice(&rtype(0x00, 1, 1, 2));
+        }
+        program.extend_from_slice(&insn(0x21, 0, 0, 0));
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        let conditions = [
+            StopCondition::Address(0x10040),
+            StopCondition::RegisterEquals { register: 1, value: 3 },
+            StopCondition::InstructionCount(2),
+        ];
+        assert_eq!(
+            vm.run_until_any(&conditions, None).unwrap(),
+            (RunOutcome::Condition, Some(2))
+        );
+        assert_eq!(
+            vm.run_until_any(&conditions[..2], None).unwrap(),
+            (RunOutcome::Condition, Some(1))
+        );
+        assert_eq!(vm.get_register(1).unwrap(), 3);
+        assert_eq!(vm.last_stop_reason(), Some(StopReason::Condition { index: 1 }));
+        
+        let negative = [StopCondition::State(Box::new(|state| (state.gprs[1] as i64) < 0))];
+        assert_eq!(
+            vm.run_until_any(&negative, Some(5)).unwrap(),
+            (RunOutcome::Limit, None)
+        );
+        assert_eq!(
+            vm.run_until_any(&negative, None).unwrap(),
+            (RunOutcome::Halted { exit_code: 20 }, None)
+        );
+    }
+    
+    #[test]
+    fn test_energy_estimate() {
+        use crate::disasm::Category;
+        use crate::energy::EnergyModel;
+        
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        
+        // LD R1, 6; LD R2, 7; MUL R3, R1, R2; HALT
+        let program: Vec<u8> = [
+            insn(0x0F, 1, 0, 6),
+            insn(0x0F, 2, 0, 7),
+            rtype(0x02, 3, 1, 2),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        let mut model = EnergyModel::new();
+        model.set_cost(Category::Alu, 2.0);
+        model.set_cost(Category::MulDiv, 10.0);
+        vm.set_energy_model(model);
+        vm.run(Some(100)).unwrap();
+        
+        assert!((vm.estimated_energy() - 14e-12).abs() < 1e-18);
+        let breakdown = vm.energy_breakdown();
+        assert!((breakdown[&Category::Alu] - 4e-12).abs() < 1e-18);
+        assert!((breakdown[&Category::MulDiv] - 10e-12).abs() < 1e-18);
+        assert!(!breakdown.contains_key(&Category::System));
+        
+        vm.set_energy_model(EnergyModel::new());
+        assert_eq!(vm.estimated_energy(), 0.0);
+    }
+    
+    #[test]
+    fn test_memory_image_round_trip() {
+        init().unwrap();
+        let mut vm = VM::new(0x1000).unwrap();
+        
+        let image: Vec<u8> = (0..0x1000).map(|i| i as u8).collect();
+        vm.load_memory_image(&image).unwrap();
+        assert_eq!(vm.read_memory(0xFF0, 4).unwrap(), vec![0xF0, 0xF1, 0xF2, 0xF3]);
+        assert_eq!(vm.save_memory_image(), image);
+        
+        let err = vm.load_memory_image(&image[..0x800]).unwrap_err();
+        assert_eq!(err.status, Status::InvalidParameter);
+    }
+    
+    #[test]
+    fn test_semihosting_console_files_and_exit() {
+        use crate::semihosting::*;
+        use std::io::Write;
+        use std::sync::Arc;
+        
+        #[derive(Clone, Default)]
+        struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+        
+        impl Write for SharedOutput {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        
+        fn words(values: &[u64]) -> Vec<u8> {
+            values.iter().flat_map(|v| v.to_le_bytes()).collect()
+        }
+        
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let root = std::env::temp_dir().join(format!("nanocore-semihost-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        
+        vm.write_memory(0x1000, b"hi\n\0").unwrap();
+        vm.write_memory(0x2000, &words(&[0x2100, 4, 7])).unwrap();
+        vm.write_memory(0x2100, b"out.txt").unwrap();
+        vm.write_memory(0x2200, &words(&[3, 0x1000, 2])).unwrap();
+        vm.write_memory(0x2300, &words(&[0x2400, 4, 6])).unwrap();
+        vm.write_memory(0x2400, b"../bad").unwrap();
+        
+        let call = |op: u16, param: u16| {
+            [insn(0x0F, 1, 0, op), insn(0x0F, 2, 0, param), insn(0x20, 0, 0, SEMIHOSTING_TRAP as u16)]
+        };
+        let program: Vec<u8> = [
+            call(SYS_WRITE0 as u16, 0x1000),
+            call(SYS_OPEN as u16, 0x2000),
+            call(SYS_WRITE as u16, 0x2200),
+            call(SYS_OPEN as u16, 0x2300),
+            call(SYS_EXIT as u16, 9),
+        ]
+        .concat()
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        let output = SharedOutput::default();
+        vm.connect_stdio(None, Some(Box::new(output.clone())));
+        vm.enable_semihosting(SemihostingConfig {
+            file_root: Some(root.clone()),
+            ..SemihostingConfig::default()
+        });
+        
+        assert_eq!(vm.run_outcome(Some(6)).unwrap(), RunOutcome::Limit);
+        assert_eq!(vm.get_register(1).unwrap(), 3);
+        vm.run(Some(3)).unwrap();
+        assert_eq!(vm.get_register(1).unwrap(), 0);
+        vm.run(Some(3)).unwrap();
+        assert_eq!(vm.get_register(1).unwrap(), u64::MAX);
+        
+        assert_eq!(vm.run_outcome(Some(100)).unwrap(), RunOutcome::Halted { exit_code: 9 });
+        assert_eq!(output.0.lock().unwrap().as_slice(), b"hi\n");
+        assert_eq!(std::fs::read(root.join("out.txt")).unwrap(), b"hi");
+        assert!(!root.parent().unwrap().join("bad").exists());
+        
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+    
+    #[test]
+    fn test_semihosting_read_bounds_guest_lengths() {
+        use crate::semihosting::*;
+        use std::io::Cursor;
+        
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let block = |len: u64| [0, 0x1000, len].iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>();
+        vm.write_memory(0x2000, &block(u64::MAX / 2)).unwrap();
+        vm.write_memory(0x2100, &block(1024 * 1024 - 0x1000)).unwrap();
+        let call = |param: u16| {
+            [insn(0x0F, 1, 0, SYS_READ as u16), insn(0x0F, 2, 0, param), insn(0x20, 0, 0, SEMIHOSTING_TRAP as u16)]
+        };
+        vm.load_program(&[call(0x2000), call(0x2100)].concat().concat(), 0x80000).unwrap();
+        vm.connect_stdio(Some(Box::new(Cursor::new(vec![b'x'; 100 * 1024]))), None);
+        vm.enable_semihosting(SemihostingConfig::default());
+        
+        // A length past the end of memory is refused rather than allocated
+        vm.run(Some(3)).unwrap();
+        assert_eq!(vm.get_register(1).unwrap(), u64::MAX);
+        
+        // A valid but large read is capped at 64 KiB
+        vm.run(Some(3)).unwrap();
+        assert_eq!(vm.get_register(1).unwrap(), 1024 * 1024 - 0x1000 - 64 * 1024);
+        assert_eq!(vm.read_memory(0x1000 + 64 * 1024 - 1, 2).unwrap(), [b'x', 0]);
+    }
+    
+    #[test]
+    fn test_semihosting_rejects_huge_writes_and_paths() {
+        use crate::semihosting::*;
+        
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let words = |values: &[u64]| values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>();
+        vm.write_memory(0x2000, &words(&[1, 0x1000, u64::MAX / 4])).unwrap();
+        vm.write_memory(0x2100, &words(&[0x1000, 0, 1024 * 1024 - 0x1000])).unwrap();
+        vm.write_memory(0x2200, &words(&[1, 0x1000, 3])).unwrap();
+        vm.write_memory(0x1000, b"ok!").unwrap();
+        let call = |op: u64, param: u16| {
+            [insn(0x0F, 1, 0, op as u16), insn(0x0F, 2, 0, param), insn(0x20, 0, 0, SEMIHOSTING_TRAP as u16)]
+        };
+        let program = [call(SYS_WRITE, 0x2000), call(SYS_OPEN, 0x2100), call(SYS_WRITE, 0x2200)];
+        vm.load_program(&program.concat().concat(), 0x80000).unwrap();
+        vm.connect_stdio(None, None);
+        vm.enable_semihosting(SemihostingConfig {
+            file_root: Some(std::env::temp_dir()),
+            ..SemihostingConfig::default()
+        });
+        
+        // Lengths past the end of memory, and overlong paths, fail cleanly
+        vm.run(Some(3)).unwrap();
+        assert_eq!(vm.get_register(1).unwrap(), u64::MAX);
+        vm.run(Some(3)).unwrap();
+        assert_eq!(vm.get_register(1).unwrap(), u64::MAX);
+        vm.run(Some(3)).unwrap();
+        assert_eq!(vm.get_register(1).unwrap(), 0);
+        assert_eq!(vm.read_memory(0, u64::MAX / 4).unwrap_err().status, Status::InvalidParameter);
+    }
+    
+    #[cfg(feature = "bytemuck")]
+    #[test]
+    fn test_read_typed_slice() {
+        init().unwrap();
+        let mut vm = VM::new(0x1000).unwrap();
+        
+        let data: Vec<u8> = [1u32, 2, 0xDEADBEEF].iter().flat_map(|v| v.to_le_bytes()).collect();
+        vm.write_memory(0x100, &data).unwrap();
+        
+        assert_eq!(vm.read_slice::<u32>(0x100, 3).unwrap(), vec![1, 2, 0xDEADBEEF]);
+        assert_eq!(vm.with_slice(0x100, 3, |v: &[u32]| v.iter().sum::<u32>()).unwrap(), 0xDEADBEF2);
+        
+        assert_eq!(vm.read_slice::<u32>(0x102, 1).unwrap_err().status, Status::InvalidParameter);
+        assert_eq!(vm.read_slice::<u64>(0xFF8, 2).unwrap_err().status, Status::InvalidParameter);
+        assert_eq!(vm.read_slice::<u64>(0, usize::MAX).unwrap_err().status, Status::InvalidParameter);
+    }
+    
+    #[test]
+    fn test_flush_memory() {
+        init().unwrap();
+        let vm = VM::new(0x1000).unwrap();
+        
+        vm.flush_memory().unwrap();
+        vm.flush_range(0x800, 0x800).unwrap();
+        assert_eq!(vm.flush_range(0x800, 0x801).unwrap_err().status, Status::InvalidParameter);
+    }
+    
+    #[test]
+    fn test_guest_breakpoint_modes() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        
+        // LD R1, 1; BRK; LD R1, 2; HALT
+        let program: Vec<u8> = [
+            insn(0x0F, 1, 0, 1),
+            insn(opcode::Opcode::Brk as u32, 0, 0, 0),
+            insn(0x0F, 1, 0, 2),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        
+        vm.load_program(&program, 0x10000).unwrap();
+        assert_eq!(vm.run_outcome(None).unwrap(), RunOutcome::Breakpoint { address: 0x10004 });
+        assert_eq!(vm.last_stop_reason(), Some(StopReason::GuestBreakpoint { address: 0x10004 }));
+        assert_eq!(vm.get_register(1).unwrap(), 1);
+        let event = vm.poll_event().unwrap().unwrap();
+        assert_eq!(event.event_type, EventType::Breakpoint);
+        assert_eq!(event.data, 0x10004 | GUEST_BREAKPOINT_FLAG);
+        assert_eq!(vm.run_outcome(None).unwrap(), RunOutcome::Halted { exit_code: 2 });
+        
+        vm.reset().unwrap();
+        vm.set_guest_breakpoint_mode(GuestBreakpointMode::Continue).unwrap();
+        vm.load_program(&program, 0x10000).unwrap();
+        assert_eq!(vm.run_outcome(None).unwrap(), RunOutcome::Halted { exit_code: 2 });
+        assert_eq!(vm.poll_event().unwrap().unwrap().data, 0x10004 | GUEST_BREAKPOINT_FLAG);
+        
+        vm.reset().unwrap();
+        vm.set_guest_breakpoint_mode(GuestBreakpointMode::Ignore).unwrap();
+        vm.load_program(&program, 0x10000).unwrap();
+        assert_eq!(vm.run_outcome(None).unwrap(), RunOutcome::Halted { exit_code: 2 });
+        assert_eq!(vm.poll_event().unwrap().unwrap().event_type, EventType::Halted);
+    }
+    
+    #[test]
+    fn test_memory_bandwidth_cap_adds_stalls() {
+        init().unwrap();
+        
+        // LD R2, 0x1000; 200 x SW R1, 0(R2); HALT
+        let mut program = insn(0x0F, 2, 0, 0x1000).to_vec();
+        for _ in 0..200 {
+            program.extend_from_slice(&insn(0x14, 1, 2, 0));
+        }
+        program.extend_from_slice(&insn(0x21, 0, 0, 0));
+        
+        let mut uncapped = VM::new(1024 * 1024).unwrap();
+        uncapped.load_program(&program, 0x10000).unwrap();
+        uncapped.run(None).unwrap();
+        
+        let mut capped = VM::new(1024 * 1024).unwrap();
+        capped.load_program(&program, 0x10000).unwrap();
+        capped.set_memory_bandwidth(1.0).unwrap();
+        capped.run(None).unwrap();
+        
+        let cycles = |vm: &VM| vm.get_perf_counter(PerfCounter::CycleCount).unwrap();
+        let instructions = capped.get_perf_counter(PerfCounter::InstructionCount).unwrap();
+        assert_eq!(instructions, uncapped.get_perf_counter(PerfCounter::InstructionCount).unwrap());
+        assert_eq!(cycles(&uncapped), instructions);
+        
+        // 800 bytes at 1 byte/cycle after a 64-byte burst allowance
+        let stalls = capped.get_perf_counter(PerfCounter::PipelineStall).unwrap();
+        assert!(stalls > 500, "only {} stall cycles", stalls);
+        assert_eq!(cycles(&capped), instructions + stalls);
+        
+        for rate in [f64::NAN, f64::INFINITY, 1e-300] {
+            assert_eq!(capped.set_memory_bandwidth(rate).unwrap_err().status, Status::InvalidParameter);
+        }
+        capped.set_memory_bandwidth(0.0).unwrap();
+    }
+    
+    #[test]
+    fn test_read_fault_injector_corrupts_loads() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        
+        // LD R2, 0x1000; LW R1, 0(R2); LB R3, 4(R2); HALT
+        let program: Vec<u8> = [
+            insn(0x0F, 2, 0, 0x1000),
+            insn(0x10, 1, 2, 0),
+            insn(0x12, 3, 2, 4),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        vm.write_memory(0x1000, &[0x78, 0x56, 0x34, 0x12, 0x01]).unwrap();
+        
+        // Flip bit 7 of every byte read from 0x1004
+        vm.set_read_fault_injector(Box::new(|address, value| {
+            if address == 0x1004 { value ^ 0x80 } else { value }
+        }));
+        assert_eq!(vm.run_outcome(None).unwrap(), RunOutcome::Halted { exit_code: 0x12345678 });
+        assert_eq!(vm.get_register(3).unwrap(), 0xFFFF_FFFF_FFFF_FF81);
+        assert_eq!(vm.get_perf_counter(PerfCounter::MemoryOps).unwrap(), 2);
+        
+        // Memory itself is untouched
+        assert_eq!(vm.read_memory(0x1004, 1).unwrap(), vec![0x01]);
+    }
+    
+    #[test]
+    fn test_connect_stdio_echoes_console() {
+        use crate::semihosting::*;
+        use std::io::{Cursor, Write};
+        use std::sync::Arc;
+        
+        struct Sink(Arc<Mutex<Vec<u8>>>);
+        
+        impl Write for Sink {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        
+        // Echo console input until READC reports end of file
+        let trap = insn(0x20, 0, 0, SEMIHOSTING_TRAP as u16);
+        let program: Vec<u8> = [
+            insn(0x0F, 3, 0, 0x1000),            // LD R3, 0x1000
+            insn(0x0F, 1, 0, SYS_READC as u16),  // LD R1, SYS_READC
+            trap,
+            insn(0x19, 1, 0, 12),                // BLT R1, R0, +24
+            insn(0x16, 1, 3, 0),                 // SB R1, 0(R3)
+            insn(0x0F, 1, 0, SYS_WRITEC as u16), // LD R1, SYS_WRITEC
+            insn(0x0F, 2, 0, 0x1000),            // LD R2, 0x1000
+            trap,
+            insn(0x17, 0, 0, -14i16 as u16),     // BEQ R0, R0, -28
+            insn(0x21, 0, 0, 0),                 // HALT
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        let output = Arc::new(Mutex::new(Vec::new()));
+        vm.connect_stdio(
+            Some(Box::new(Cursor::new(b"echo".to_vec()))),
+            Some(Box::new(Sink(output.clone()))),
+        );
+        vm.enable_semihosting(SemihostingConfig::default());
+        
+        assert_eq!(vm.run_outcome(Some(1000)).unwrap(), RunOutcome::Halted { exit_code: -1 });
+        assert_eq!(output.lock().unwrap().as_slice(), b"echo");
+    }
+    
+    #[test]
+    fn test_scheduler_round_robin_is_deterministic() {
+        use crate::scheduler::Scheduler;
+        
+        init().unwrap();
+        
+        // LD R2, 1; 8 x ADD R1, R1, R2; HALT
+        let mut program = insn(0x0F, 2, 0, 1).to_vec();
+        for _ in 0..8 {
+            program.extend_from_slice(&rtype(0x00, 1, 1, 2));
+        }
+        program.extend_from_slice(&insn(0x21, 0, 0, 0));
+        
+        let make_vm = || {
+            let mut vm = VM::new(1024 * 1024).unwrap();
+            vm.load_program(&program, 0x10000).unwrap();
+            vm
+        };
+        let mut scheduler = Scheduler::new().add(make_vm()).add(make_vm()).quantum(3);
+        
+        // Turns: A 3, B 3, A 3, B 1
+        assert_eq!(scheduler.run(10).unwrap(), 10);
+        let counts: Vec<u64> = scheduler.vms().iter().map(|vm| vm.get_register(1).unwrap()).collect();
+        assert_eq!(counts, vec![5, 3]);
+        assert!(!scheduler.is_finished());
+        
+        // Both programs are 10 instructions long; the rest of the budget goes unused
+        assert_eq!(scheduler.run(100).unwrap(), 10);
+        assert!(scheduler.is_finished());
+        for vm in scheduler.into_vms() {
+            assert_eq!(vm.exit_code().unwrap(), Some(8));
+        }
+    }
+    
+    #[test]
+    fn test_disassemble_with_source() {
+        use crate::source_map::SourceMap;
+        
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let program: Vec<u8> = [insn(0x0F, 1, 0, 42), insn(0x0F, 2, 0, 1), rtype(0x00, 3, 1, 2), insn(0x21, 0, 0, 0)].concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        let source = std::env::temp_dir().join(format!("nanocore-source-{}.s", std::process::id()));
+        std::fs::write(&source, "; demo\n    LD R1, 42\n    LD R2, 1\n    ADD R3, R1, R2\n").unwrap();
+        let mut map = SourceMap::new();
+        map.add(0x10000, 0x10004, &source, 2);
+        map.add(0x10004, 0x10008, &source, 3);
+        map.add(0x10008, 0x1000C, &source, 4);
+        map.add(0x1000C, 0x10010, "missing.s", 7);
+        
+        let listing = vm.disassemble_with_source(0x10000, 4, &map).unwrap();
+        std::fs::remove_file(&source).unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], ";     LD R1, 42");
+        assert_eq!(lines[1], "0x010000: 3c20002a  LD R1, 42");
+        assert_eq!(lines[4], ";     ADD R3, R1, R2");
+        assert_eq!(lines[6], "; missing.s:7");
+        assert_eq!(lines[7], "0x01000c: 84000000  HALT");
+    }
+    
+    #[test]
+    fn test_modify_flags() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        vm.modify_flags(|f| {
+            f.set_zero(true).set_carry(true).set_negative(true);
+        })
+        .unwrap();
+        vm.modify_flags(|f| {
+            f.set_carry(false);
+        })
+        .unwrap();
+        
+        let flags = vm.get_state().unwrap().flags;
+        assert!(flags.is_set(Flags::ZERO));
+        assert!(!flags.is_set(Flags::CARRY));
+        assert!(flags.is_set(Flags::NEGATIVE));
+        assert!(!flags.is_set(Flags::HALTED));
+    }
+    
+    #[test]
+    fn test_patch_rewrites_code() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let program: Vec<u8> = [insn(0x0F, 1, 0, 1), insn(0x0F, 2, 0, 2), insn(0x21, 0, 0, 0)].concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        assert_eq!(vm.patch(0x10004, "LD R2, 40 ; was 2").unwrap(), 4);
+        let error = vm.patch_within(0x10000, "LD R1, 5\nNOP", 4).unwrap_err();
+        assert_eq!(error.status, Status::InvalidParameter);
+        assert_eq!(vm.patch(0x10002, "NOP").unwrap_err().status, Status::InvalidParameter);
+        assert!(vm.patch(1024 * 1024 - 4, "NOP\nNOP").is_err());
+        
+        vm.run(Some(10)).unwrap();
+        assert_eq!(vm.get_register(1).unwrap(), 1);
+        assert_eq!(vm.get_register(2).unwrap(), 40);
+    }
+    
+    #[test]
+    fn test_interrupt_flag_callback_and_masking() {
+        use std::sync::{Arc, Mutex};
+        
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        // LD R1, 1; DI; NOP; EI; HALT
+        let program: Vec<u8> = [
+            insn(0x0F, 1, 0, 1),
+            insn(0x39, 0, 0, 0),
+            insn(0x22, 0, 0, 0),
+            insn(0x38, 0, 0, 0),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        vm.modify_flags(|f| {
+            f.set_interrupt_enable(true);
+        })
+        .unwrap();
+        
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        vm.set_interrupt_flag_callback(Box::new(move |enabled| sink.lock().unwrap().push(enabled)));
+        
+        // Host-side masking is not a guest transition and is undone afterwards
+        let inside = vm
+            .with_interrupts_masked(|vm| {
+                vm.step().unwrap();
+                vm.get_state().unwrap().flags.is_set(Flags::INTERRUPT_ENABLE)
+            })
+            .unwrap();
+        assert!(!inside);
+        assert!(vm.get_state().unwrap().flags.is_set(Flags::INTERRUPT_ENABLE));
+        
+        assert!(seen.lock().unwrap().is_empty());
+        
+        // The guest's DI and EI are reported in order
+        vm.run(Some(10)).unwrap();
+        assert_eq!(vm.get_register(1).unwrap(), 1);
+        assert_eq!(*seen.lock().unwrap(), [false, true]);
+        assert!(vm.get_state().unwrap().flags.is_set(Flags::INTERRUPT_ENABLE));
+    }
+    
+    #[test]
+    fn test_vregs_as_matrix() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let mut state = vm.raw_state().unwrap();
+        state.vregs[3] = [1.5f64, -2.0, 0.25, 8.0].map(f64::to_bits);
+        state.vregs[15] = [1, 2, 3, 4];
+        vm.set_raw_state(&state).unwrap();
+        
+        let raw = vm.vregs_as_matrix().unwrap();
+        assert_eq!(raw[15], [1, 2, 3, 4]);
+        assert_eq!(raw[0], [0; 4]);
+        let doubles = vm.vregs_as_f64_matrix().unwrap();
+        assert_eq!(doubles[3], [1.5, -2.0, 0.25, 8.0]);
+    }
+    
+    #[test]
+    fn test_trap_zero_register_write() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let program: Vec<u8> = [
+            insn(0x0F, 1, 0, 5),
+            insn(0x13, 0, 0, 0x100), // ST R0, 0x100(R0): R0 is a source
+            rtype(0x00, 0, 1, 1),    // ADD R0, R1, R1
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        vm.set_trap_zero_register_write(true);
+        
+        assert_eq!(vm.run_outcome(Some(10)).unwrap(), RunOutcome::Exception);
+        assert_eq!(vm.get_state().unwrap().pc, 0x10008);
+        assert_eq!(
+            vm.last_stop_reason(),
+            Some(StopReason::Exception { code: Some(EXC_ZERO_REGISTER_WRITE) })
+        );
+        let event = vm.poll_event().unwrap().unwrap();
+        assert_eq!(event.event_type, EventType::Exception);
+        assert_eq!(event.data, EXC_ZERO_REGISTER_WRITE as u64);
+        assert_eq!(event.exception_kind(), Some(ExceptionKind::ZeroRegisterWrite));
+        
+        vm.set_trap_zero_register_write(false);
+        assert_eq!(vm.run_outcome(Some(10)).unwrap(), RunOutcome::Halted { exit_code: 5 });
+    }
+    
+    #[test]
+    fn test_vector_register_access() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let lanes = [0x0123_4567_89AB_CDEF, u64::MAX, 0, 0x8000_0000_0000_0001];
+        
+        vm.set_vector_register(15, lanes).unwrap();
+        assert_eq!(vm.get_vector_register(15).unwrap(), lanes);
+        assert_eq!(vm.get_vector_register(0).unwrap(), [0; 4]);
+        assert_eq!(vm.vregs_as_matrix().unwrap()[15], lanes);
+        
+        assert_eq!(vm.get_vector_register(16).unwrap_err().status, Status::InvalidParameter);
+        assert_eq!(vm.set_vector_register(16, lanes).unwrap_err().status, Status::InvalidParameter);
+    }
+    
+    #[test]
+    fn test_wait_event_timeout() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        
+        let start = std::time::Instant::now();
+        assert!(vm.wait_event(Some(Duration::from_millis(20))).unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(vm.wait_event(Some(Duration::ZERO)).unwrap().is_none());
+        
+        vm.load_program(&insn(0x21, 0, 0, 0), 0x10000).unwrap();
+        vm.run(Some(10)).unwrap();
+        let event = vm.wait_event(None).unwrap().unwrap();
+        assert_eq!(event.event_type, EventType::Halted);
+    }
+    
+    #[test]
+    fn test_wait_event_wakes_for_host_events() {
+        init().unwrap();
+        let vm = VM::new(1024 * 1024).unwrap();
+        let (events, arrived) = (vm.events.clone(), vm.event_arrived.clone());
+        
+        // Stands in for a host-side source queuing an event mid-wait
+        let start = std::time::Instant::now();
+        let source = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            let event = Event { event_type: EventType::DeviceInterrupt, data: 3, fault_address: None };
+            events.lock().unwrap().events.push_back(event);
+            arrived.notify_all();
+        });
+        let event = vm.wait_event(Some(Duration::MAX)).unwrap().unwrap();
+        assert_eq!((event.event_type, event.data), (EventType::DeviceInterrupt, 3));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        source.join().unwrap();
+    }
+    
+    #[test]
+    fn test_file_backed_memory() {
+        init().unwrap();
+        let path = std::env::temp_dir().join(format!("nanocore-memory-{}.img", std::process::id()));
+        std::fs::write(&path, [0x3C, 0x20, 0x00, 0x2A]).unwrap();
+        
+        {
+            let mut vm = VM::new_with_file(&path, 0x20000).unwrap();
+            assert_eq!(vm.read_memory(0, 4).unwrap(), [0x3C, 0x20, 0x00, 0x2A]);
+            vm.write_memory(0x10000, &[1, 2, 3, 4]).unwrap();
+            vm.flush_range(0x10000, 4).unwrap();
+        }
+        
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents.len(), 0x20000);
+        assert_eq!(contents[0x10000..0x10004], [1, 2, 3, 4]);
+        
+        let error = VM::new_with_file(Path::new("/nonexistent-dir/memory.img"), 0x1000).err().unwrap();
+        assert_eq!(error.status, Status::InvalidParameter);
+    }
+    
+    #[test]
+    fn test_watchpoints() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let program: Vec<u8> = [
+            insn(0x0F, 1, 0, 7),
+            insn(0x14, 1, 0, 0x2000), // SW R1, 0x2000(R0)
+            insn(0x10, 2, 0, 0x2004), // LW R2, 0x2004(R0)
+            insn(0x10, 3, 0, 0x2000), // LW R3, 0x2000(R0)
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        vm.set_watchpoint(0x2000, 4, WatchKind::Read).unwrap();
+        
+        // The store does not match a read watchpoint; the load of 0x2004 is outside it
+        assert_eq!(vm.run_outcome(Some(10)).unwrap(), RunOutcome::Watchpoint { address: 0x2000 });
+        assert_eq!(vm.get_state().unwrap().pc, 0x10010);
+        assert_eq!(vm.get_register(3).unwrap(), 7);
+        assert_eq!(
+            vm.last_stop_reason(),
+            Some(StopReason::Watchpoint {
+                address: 0x2000,
+                kind: WatchKind::Read,
+                old_value: 7,
+                new_value: 7,
+            })
+        );
+        let event = vm.poll_event().unwrap().unwrap();
+        assert_eq!((event.event_type, event.data), (EventType::Watchpoint, 0x2000));
+        
+        vm.reset().unwrap();
+        vm.load_program(&program, 0x10000).unwrap();
+        vm.write_memory(0x2000, &[9, 0, 0, 0]).unwrap();
+        vm.set_watchpoint(0x1FFE, 4, WatchKind::Write).unwrap();
+        assert_eq!(vm.run_outcome(Some(10)).unwrap(), RunOutcome::Watchpoint { address: 0x2000 });
+        assert_eq!(vm.get_state().unwrap().pc, 0x10008);
+        assert_eq!(
+            vm.last_stop_reason(),
+            Some(StopReason::Watchpoint {
+                address: 0x2000,
+                kind: WatchKind::Write,
+                old_value: 9,
+                new_value: 7,
+            })
+        );
+        let event = vm.poll_event().unwrap().unwrap();
+        assert_eq!(event.data, 0x2000 | WATCHPOINT_WRITE_FLAG);
+        
+        vm.clear_watchpoint(0x1FFE);
+        assert_eq!(vm.run_outcome(Some(10)).unwrap(), RunOutcome::Halted { exit_code: 7 });
+        assert!(vm.set_watchpoint(0x2000, 0, WatchKind::Read).is_err());
+    }
+    
+    #[test]
+    fn test_snapshot_and_restore() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let program: Vec<u8> = [
+            insn(0x0F, 1, 0, 5),
+            insn(0x14, 1, 0, 0x2000), // SW R1, 0x2000(R0)
+            insn(0x0F, 2, 0, 9),
+            insn(0x14, 2, 0, 0x2000),
+            rtype(0x00, 1, 1, 2),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        vm.run(Some(2)).unwrap();
+        
+        let snapshot = vm.snapshot().unwrap();
+        assert_eq!(snapshot.state().pc, 0x10008);
+        assert_eq!(snapshot.memory()[0x2000..0x2004], 5u32.to_le_bytes());
+        
+        assert_eq!(vm.run_outcome(None).unwrap(), RunOutcome::Halted { exit_code: 14 });
+        vm.restore(&snapshot).unwrap();
+        let state = vm.get_state().unwrap();
+        assert_eq!(state.pc, 0x10008);
+        assert_eq!(state.gprs, snapshot.state().gprs);
+        assert!(!state.flags.is_set(Flags::HALTED));
+        assert_eq!(vm.read_memory(0x2000, 4).unwrap(), 5u32.to_le_bytes());
+        
+        // Running on from the restored point repeats the same ending
+        assert_eq!(vm.run_outcome(None).unwrap(), RunOutcome::Halted { exit_code: 14 });
+        assert!(VM::new(4096).unwrap().restore(&snapshot).is_err());
+    }
+    
+    #[test]
+    fn test_disassemble_range() {
+        use crate::disasm::Operand;
+        
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let program: Vec<u8> = [
+            insn(0x0F, 1, 0, 42),
+            rtype(0x00, 3, 1, 2),
+            0xFFFF_FFFFu32.to_be_bytes(),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        let listing = vm.disassemble(0x10000, 4).unwrap();
+        assert_eq!(listing.len(), 4);
+        assert_eq!(listing[0].mnemonic, "LD");
+        assert_eq!(listing[0].operands, [Operand::Reg(1), Operand::Imm(42)]);
+        assert_eq!((listing[1].address, listing[1].to_string()), (0x10004, "ADD R3, R1, R2".to_string()));
+        assert_eq!((listing[2].raw, listing[2].mnemonic.as_str()), (0xFFFF_FFFF, ".word 0xffffffff"));
+        assert_eq!(listing[3].mnemonic, "HALT");
+        assert!(vm.disassemble(1024 * 1024 - 4, 2).is_err());
+    }
+    
+    #[test]
+    fn test_assembled_program_runs() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let source = "
+            ; sum 0x10 and 26 into R1
+            LD R1, 0x10
+            LD R2, 26
+            ADD R1, R1, R2
+            HALT
+        ";
+        let program = asm::assemble(source).unwrap();
+        assert_eq!(program[..4], [0x3C, 0x20, 0x00, 0x10]);
+        vm.load_program(&program, 0x10000).unwrap();
+        assert_eq!(vm.run_outcome(Some(10)).unwrap(), RunOutcome::Halted { exit_code: 42 });
+        
+        let error = asm::assemble("LD R1, 1\nLD R2, 2x").unwrap_err();
+        assert!(error.message.starts_with("line 2:"), "{}", error.message);
+    }
+    
+    #[test]
+    fn test_batch_register_access() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let regs: [u64; 32] = std::array::from_fn(|i| 0x1000 + i as u64);
+        vm.set_registers(&regs).unwrap();
+        
+        let read = vm.get_registers().unwrap();
+        assert_eq!(read[0], 0);
+        assert_eq!(read[1..], regs[1..]);
+        assert_eq!(vm.get_register(31).unwrap(), 0x101F);
+    }
+    
+    #[test]
+    fn test_memory_bounds_do_not_wrap() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        assert_eq!(vm.read_memory(u64::MAX, 16).unwrap_err().status, Status::InvalidParameter);
+        assert!(vm.write_memory(u64::MAX, &[0; 16]).is_err());
+        assert!(vm.load_program(&[0; 16], u64::MAX - 8).is_err());
+        
+        // A load whose address wraps past the end of memory leaves R2 untouched
+        vm.set_register(1, u64::MAX - 1).unwrap();
+        vm.set_register(2, 7).unwrap();
+        vm.load_program(&[insn(0x10, 2, 1, 0), insn(0x21, 0, 0, 0)].concat(), 0x10000).unwrap();
+        vm.run(Some(10)).unwrap();
+        assert_eq!(vm.get_register(2).unwrap(), 7);
+    }
+    
+    #[test]
+    fn test_tiny_memory_accesses_stay_in_bounds() {
+        init().unwrap();
+        // An 8-byte store cannot fit in 4 bytes of memory, wherever it points
+        let mut vm = VM::new(4).unwrap();
+        vm.set_register(1, u64::MAX).unwrap();
+        vm.load_program(&insn(0x13, 1, 0, 0), 0).unwrap();
+        vm.set_pc(0).unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.read_memory(0, 4).unwrap(), insn(0x13, 1, 0, 0));
+        
+        let mut vm = VM::new(4).unwrap();
+        vm.set_register(2, 7).unwrap();
+        vm.load_program(&insn(0x10, 2, 0, 1), 0).unwrap();
+        vm.set_pc(0).unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.get_register(2).unwrap(), 7);
+    }
+    
+    #[test]
+    fn test_run_counted() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        vm.load_program(&[insn(0x0F, 1, 0, 42), insn(0x21, 0, 0, 0)].concat(), 0x10000).unwrap();
+        
+        let count = vm.run_counted(Some(100)).unwrap();
+        assert_eq!(count, RunCount { status: Status::Ok, instructions_retired: 2 });
+        // Already halted, so nothing more retires
+        assert_eq!(vm.run_counted(Some(100)).unwrap().instructions_retired, 0);
+    }
+    
+    #[test]
+    fn test_step_over_and_out() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let program: Vec<u8> = [
+            insn(0x1E, 0, 0, 4),      // 0x10000: CALL 0x10010
+            insn(0x0F, 1, 0, 5),      // 0x10004: LD R1, 5
+            insn(0x21, 0, 0, 0),      // 0x10008: HALT
+            insn(0x22, 0, 0, 0),      // 0x1000C: NOP
+            rtype(0x00, 29, 31, 0),   // 0x10010: ADD R29, R31, R0 -- save return address
+            insn(0x1D, 31, 10, 0x28), // 0x10014: JMP R31, 0x28(R10) -- nested call
+            insn(0x0F, 2, 0, 7),      // 0x10018: LD R2, 7
+            rtype(0x00, 31, 29, 0),   // 0x1001C: ADD R31, R29, R0
+            insn(0x1F, 0, 0, 0),      // 0x10020: RET
+            insn(0x22, 0, 0, 0),      // 0x10024: NOP
+            insn(0x0F, 3, 0, 9),      // 0x10028: LD R3, 9
+            insn(0x1D, 0, 31, 0),     // 0x1002C: JMP R0, 0(R31)
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        vm.set_register(10, 0x10000).unwrap();
+        
+        assert_eq!(vm.step_over().unwrap(), Status::Ok);
+        let state = vm.get_state().unwrap();
+        assert_eq!(state.pc, 0x10004);
+        assert_eq!((state.gprs[2], state.gprs[3]), (7, 9));
+        
+        // Non-call instructions step normally
+        vm.step_over().unwrap();
+        assert_eq!(vm.get_state().unwrap().pc, 0x10008);
+        
+        vm.reset().unwrap();
+        vm.load_program(&program, 0x10000).unwrap();
+        vm.set_register(10, 0x10000).unwrap();
+        vm.step().unwrap();
+        vm.step().unwrap();
+        vm.step_over().unwrap(); // over the nested call
+        assert_eq!(vm.get_state().unwrap().pc, 0x10018);
+        assert_eq!(vm.step_out().unwrap(), Status::Ok);
+        assert_eq!(vm.get_state().unwrap().pc, 0x10004);
+        
+        // With nothing left to return from, step_out runs to HALT
+        vm.step_out().unwrap();
+        assert_eq!(vm.last_stop_reason(), Some(StopReason::Halted { exit_code: 5 }));
+    }
+    
+    #[test]
+    fn test_step_over_and_out_give_up_on_endless_calls() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let program: Vec<u8> = [
+            insn(0x1E, 0, 0, 2),  // 0x10000: CALL 0x10008
+            insn(0x21, 0, 0, 0),  // 0x10004: HALT
+            insn(0x1D, 0, 10, 0), // 0x10008: JMP R0, 0(R10) -- spins in place
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        vm.set_register(10, 0x10008).unwrap();
+        
+        assert_eq!(vm.step_over_within(100).unwrap(), Status::FuelExhausted);
+        assert_eq!(vm.last_stop_reason(), Some(StopReason::Limit));
+        assert_eq!(vm.get_state().unwrap().pc, 0x10008);
+        assert_eq!(vm.step_out_within(100).unwrap(), Status::FuelExhausted);
+        assert_eq!(vm.get_state().unwrap().pc, 0x10008);
+        assert_eq!(vm.step_out_within(0).unwrap(), Status::FuelExhausted);
+    }
+    
+    #[test]
+    fn test_step_with_diff() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let program: Vec<u8> = [insn(0x0F, 1, 0, 42), insn(0x0F, 1, 0, 42), insn(0x21, 0, 0, 0)].concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        let (status, deltas) = vm.step_with_diff().unwrap();
+        assert_eq!(status, Status::Ok);
+        assert_eq!(deltas, [RegDelta { index: 1, old: 0, new: 42 }]);
+        
+        // Rewriting the same value is not a change
+        assert!(vm.step_with_diff().unwrap().1.is_empty());
+        
+        let (_, deltas) = vm.step_with_diff().unwrap();
+        assert_eq!(
+            deltas,
+            [RegDelta { index: FLAGS_DELTA_INDEX, old: 0, new: Flags::HALTED }]
+        );
+    }
+    
+    #[test]
+    fn test_typed_flags() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        assert_eq!(vm.get_flags().unwrap(), Flags(0));
+        
+        vm.set_flags(Flags(Flags::CARRY | Flags::NEGATIVE | Flags::USER_MODE)).unwrap();
+        let flags = vm.get_flags().unwrap();
+        assert!(flags.carry() && flags.negative() && flags.user_mode());
+        assert!(!flags.zero() && !flags.overflow() && !flags.interrupt_enable() && !flags.halted());
+        assert!(flags.contains(Flags::CARRY | Flags::USER_MODE));
+        assert!(!flags.contains(Flags::CARRY | Flags::ZERO));
+        
+        vm.set_flags(Flags(Flags::HALTED | Flags::INTERRUPT_ENABLE)).unwrap();
+        let flags = vm.get_flags().unwrap();
+        assert!(flags.halted() && flags.interrupt_enable());
+    }
+    
+    #[test]
+    fn test_attach_fn_device() {
+        use crate::devices::FnDevice;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+        
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let written = Arc::new(AtomicU64::new(0));
+        let sink = written.clone();
+        let device = FnDevice::new(|offset| 0x100 + offset, move |_, value| sink.store(value, Ordering::SeqCst));
+        let id = vm.attach_device(0x4000, 0x4010, Box::new(device)).unwrap();
+        
+        assert!(vm.attach_device(0x400C, 0x4020, Box::new(FnDevice::new(|_| 0, |_, _| ()))).is_err());
+        assert!(vm.attach_device(0x5000, 0x5000, Box::new(FnDevice::new(|_| 0, |_, _| ()))).is_err());
+        
+        // LD R2, 0x4000; LW R1, 8(R2); SW R1, 0(R2); HALT
+        let program: Vec<u8> = [
+            insn(0x0F, 2, 0, 0x4000),
+            insn(0x10, 1, 2, 8),
+            insn(0x14, 1, 2, 0),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        assert_eq!(vm.run_outcome(Some(10)).unwrap(), RunOutcome::Halted { exit_code: 0x108 });
+        assert_eq!(written.load(Ordering::SeqCst), 0x108);
+        
+        vm.detach_device(id).unwrap();
+        assert!(vm.detach_device(id).is_err());
+        vm.attach_device(0x400C, 0x4020, Box::new(FnDevice::new(|_| 0, |_, _| ()))).unwrap();
+    }
+    
+    #[test]
+    fn test_console_device() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let console = vm.attach_console(0x5000).unwrap();
+        console.push_input(b"!");
+        
+        // Echo the input byte after "hi", then read again to see no input left
+        let program: Vec<u8> = [
+            insn(0x0F, 2, 0, 0x5000),
+            insn(0x0F, 1, 0, b'h' as u16),
+            insn(0x14, 1, 2, 0),
+            insn(0x0F, 1, 0, b'i' as u16),
+            insn(0x14, 1, 2, 0),
+            insn(0x10, 1, 2, 4),
+            insn(0x14, 1, 2, 0),
+            insn(0x10, 1, 2, 4),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        assert_eq!(vm.run_outcome(Some(100)).unwrap(), RunOutcome::Halted { exit_code: -1 });
+        assert_eq!(vm.console_output(), "hi!");
+        assert_eq!(vm.console_output(), "");
+        
+        let config = vm.export_config();
+        assert!(config.to_string().contains("console 0x5000 0x10 echo=0"));
+        assert!(VM::from_config(&config).is_ok());
+    }
+    
+    #[test]
+    fn test_timer_raises_device_interrupt() {
+        use crate::devices::TimerDevice;
+        
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let timer = vm.attach_timer(0x6000).unwrap();
+        vm.modify_flags(|flags| {
+            flags.set_interrupt_enable(true);
+        })
+        .unwrap();
+        
+        // Program a reload of 10 and enable the timer, then spin on NOPs
+        let mut program: Vec<u8> = [
+            insn(0x0F, 2, 0, 0x6000),
+            insn(0x0F, 1, 0, 10),
+            insn(0x14, 1, 2, 0),
+            insn(0x0F, 1, 0, TimerDevice::ENABLE as u16),
+            insn(0x14, 1, 2, 8),
+        ]
+        .concat();
+        program.extend((0..20).flat_map(|_| insn(0x22, 0, 0, 0)));
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        // The enabling store is the first of the 10 ticks
+        vm.run(Some(13)).unwrap();
+        assert!(vm.poll_event().unwrap().is_none());
+        vm.run(Some(1)).unwrap();
+        let event = vm.poll_event().unwrap().unwrap();
+        assert_eq!((event.event_type, event.data), (EventType::DeviceInterrupt, timer.index()));
+        
+        // One-shot, so no further interrupts
+        vm.run(Some(10)).unwrap();
+        assert!(vm.poll_event().unwrap().is_none());
+        assert!(vm.export_config().to_string().contains("timer 0x6000 0x10"));
+    }
+    
+    #[test]
+    fn test_format_state_lists_nonzero_registers() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        vm.load_program(&insn(0x0F, 5, 0, 0x2A), 0x10000).unwrap();
+        vm.step().unwrap();
+        vm.modify_flags(|flags| {
+            flags.set_zero(true).set_interrupt_enable(true);
+        })
+        .unwrap();
+        
+        let text = vm.format_state();
+        assert!(text.contains("pc    0x0000000000010004\n"));
+        assert!(text.contains("flags 0x0000000000000011 [Z IE]\n"));
+        assert!(text.contains("r5    0x000000000000002a\n"));
+        assert!(!text.contains("r6 "));
+        assert!(text.contains("instructions=1 "));
+    }
+    
+    #[test]
+    fn test_read_cstring() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        vm.write_memory(0x2000, b"hello\0world").unwrap();
+        
+        assert_eq!(vm.read_cstring(0x2000, 64).unwrap(), "hello");
+        assert_eq!(vm.read_cstring_bytes(0x2000, 64).unwrap(), (b"hello".to_vec(), true));
+        
+        // No NUL within the limit: the prefix comes back, flagged as cut short
+        assert_eq!(vm.read_cstring(0x2000, 3).unwrap(), "hel");
+        assert_eq!(vm.read_cstring_bytes(0x2000, 3).unwrap(), (b"hel".to_vec(), false));
+        
+        // The end of memory also cuts the string short
+        let end = vm.memory_size();
+        vm.write_memory(end - 2, &[0xFF, b'!']).unwrap();
+        assert_eq!(vm.read_cstring_bytes(end - 2, 64).unwrap(), (vec![0xFF, b'!'], false));
+        assert_eq!(vm.read_cstring(end - 2, 64).unwrap(), "\u{FFFD}!");
+        assert!(vm.read_cstring(end + 1, 64).is_err());
+    }
+    
+    #[test]
+    fn test_typed_word_accessors_are_little_endian() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        
+        vm.write_u64(0x2001, 0x0102_0304_0506_0708).unwrap();
+        assert_eq!(vm.read_memory(0x2001, 8).unwrap(), [8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(vm.read_u64(0x2001).unwrap(), 0x0102_0304_0506_0708);
+        assert_eq!(vm.read_u32(0x2001).unwrap(), 0x0506_0708);
+        
+        vm.write_u32(0x2003, 0xAABB_CCDD).unwrap();
+        assert_eq!(vm.read_u64(0x2001).unwrap(), 0x0102_AABB_CCDD_0708);
+        
+        // A guest LW sees the same value
+        vm.write_u32(0x3000, 0x1234_5678).unwrap();
+        vm.load_program(&insn(0x10, 3, 0, 0x3000), 0x10000).unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.get_register(3).unwrap(), 0x1234_5678);
+        
+        let end = vm.memory_size();
+        assert!(vm.read_u64(end - 4).is_err());
+        assert!(vm.write_u32(end - 2, 0).is_err());
+    }
+    
+    #[test]
+    fn test_perf_counters_snapshot() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let program = [insn(0x0F, 1, 0, 0x2000), insn(0x14, 1, 1, 0), insn(0x22, 0, 0, 0)].concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        vm.run(Some(3)).unwrap();
+        
+        let counters = vm.get_perf_counters().unwrap();
+        for counter in [PerfCounter::InstructionCount, PerfCounter::CycleCount, PerfCounter::MemoryOps] {
+            assert_eq!(counters[counter as usize], vm.get_perf_counter(counter).unwrap());
+        }
+        
+        let named = PerfCounters::from(counters);
+        assert_eq!(named.instruction_count, 3);
+        assert_eq!(named.memory_ops, 1);
+        assert_eq!(named.simd_ops, 0);
+    }
+    
+    #[test]
+    fn test_reset_perf_counters_mid_run() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let program: Vec<u8> = (0..10).flat_map(|_| insn(0x22, 0, 0, 0)).collect();
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        vm.run(Some(6)).unwrap();
+        assert_eq!(vm.get_perf_counter(PerfCounter::InstructionCount).unwrap(), 6);
+        vm.reset_perf_counters().unwrap();
+        assert_eq!(vm.get_perf_counters().unwrap(), [0; 8]);
+        assert_eq!(vm.get_state().unwrap().pc, 0x10018);
+        
+        vm.run(Some(3)).unwrap();
+        assert_eq!(vm.get_perf_counter(PerfCounter::InstructionCount).unwrap(), 3);
+    }
+    
+    #[test]
+    fn test_profile_region_counts_only_the_region() {
+        init().unwrap();
+        let mut vm = VM::new(64 * 1024).unwrap();
+        let program = [
+            insn(0x0F, 1, 0, 0x4000),
+            insn(0x1D, 0, 0, 0x100C),
+            insn(0x22, 0, 0, 0),
+            insn(0x14, 1, 1, 0),
+            insn(0x10, 2, 1, 0),
+            insn(0x22, 0, 0, 0),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x1000).unwrap();
+        vm.set_pc(0x1000).unwrap();
+        vm.run(Some(2)).unwrap();
+        assert_eq!(vm.get_state().unwrap().pc, 0x100C);
+        
+        let profile = vm.profile_region(0x100C, 0x1018).unwrap();
+        assert_eq!(profile.stopped, None);
+        assert_eq!(profile.counters.instruction_count, 3);
+        assert_eq!(profile.counters.memory_ops, 2);
+        assert_eq!(profile.counters.simd_ops, 0);
+        assert_eq!(vm.get_state().unwrap().pc, 0x1018);
+        
+        // Already outside the region
+        assert_eq!(vm.profile_region(0x1000, 0x1018).unwrap().counters, PerfCounters::default());
+        assert_eq!(
+            vm.profile_region(0x1018, 0x2000).unwrap().stopped,
+            Some(RunOutcome::Halted { exit_code: 0x4000 })
+        );
+        assert!(vm.profile_region(0x2000, 0x2000).is_err());
+    }
+    
+    #[test]
+    fn test_derived_perf_metrics() {
+        let counters = PerfCounters {
+            instruction_count: 300,
+            cycle_count: 400,
+            l1_miss: 6,
+            l2_miss: 2,
+            memory_ops: 32,
+            ..PerfCounters::default()
+        };
+        assert_eq!(counters.ipc(), 0.75);
+        assert_eq!(counters.cache_miss_rate(), 0.25);
+        
+        // Zero denominators give zero rather than NaN
+        let idle = PerfCounters::default();
+        assert_eq!(idle.ipc(), 0.0);
+        assert_eq!(idle.cache_miss_rate(), 0.0);
+    }
+    
+    #[test]
+    fn test_conditional_breakpoint_checks_registers() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        
+        // R1 counts up by one each time round the loop at 0x10004
+        let program = [
+            insn(0x0F, 1, 0, 0),
+            rtype(0x00, 1, 1, 3),
+            insn(0x1D, 0, 2, 0),
+        ]
+        .concat();
+        vm.set_register(2, 0x10004).unwrap();
+        vm.set_register(3, 1).unwrap();
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        let condition = BreakCondition::Register { index: 1, op: CmpOp::Ge, value: 5 };
+        vm.set_conditional_breakpoint(0x10004, condition).unwrap();
+        let outcome = vm.run_outcome(Some(100)).unwrap();
+        assert_eq!(outcome, RunOutcome::Breakpoint { address: 0x10004 });
+        assert_eq!(vm.get_register(1).unwrap(), 5);
+        
+        // Conditional breakpoints keep their condition in the exported config
+        assert_eq!(
+            vm.export_config().breakpoints,
+            [config::BreakpointConfig {
+                address: 0x10004,
+                kind: config::BreakpointKind::Conditional(condition),
+            }]
+        );
+        vm.clear_breakpoint(0x10004).unwrap();
+        assert_eq!(vm.run_outcome(Some(4)).unwrap(), RunOutcome::Limit);
+        
+        let invalid = BreakCondition::Register { index: 32, op: CmpOp::Eq, value: 0 };
+        assert!(vm.set_conditional_breakpoint(0x10004, invalid).is_err());
+    }
+    
+    #[test]
+    fn test_counted_and_temporary_breakpoints() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        
+        // R1 counts up by one each time round the loop at 0x10004
+        let program = [insn(0x0F, 1, 0, 0), rtype(0x00, 1, 1, 3), insn(0x1D, 0, 2, 0)].concat();
+        vm.set_register(2, 0x10004).unwrap();
+        vm.set_register(3, 1).unwrap();
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        assert_eq!(vm.breakpoint_hit_count(0x10004), None);
+        vm.set_breakpoint_with_count(0x10004, 3).unwrap();
+        assert_eq!(vm.breakpoint_hit_count(0x10004), Some(0));
+        let outcome = vm.run_outcome(Some(100)).unwrap();
+        assert_eq!(outcome, RunOutcome::Breakpoint { address: 0x10004 });
+        assert_eq!(vm.get_register(1).unwrap(), 3);
+        assert_eq!(vm.breakpoint_hit_count(0x10004), Some(4));
+        vm.clear_breakpoint(0x10004).unwrap();
+        
+        // A temporary breakpoint fires once, then is gone
+        vm.set_temp_breakpoint(0x10008).unwrap();
+        let outcome = vm.run_outcome(Some(100)).unwrap();
+        assert_eq!(outcome, RunOutcome::Breakpoint { address: 0x10008 });
+        assert_eq!(vm.breakpoint_hit_count(0x10008), None);
+        assert_eq!(vm.run_outcome(Some(10)).unwrap(), RunOutcome::Limit);
+    }
+    
+    #[test]
+    fn test_list_breakpoints() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        assert!(vm.list_breakpoints().unwrap().is_empty());
+        
+        vm.set_breakpoint(0x10000).unwrap();
+        vm.set_temp_breakpoint(0x10008).unwrap();
+        vm.set_breakpoint_with_count(0x10010, 2).unwrap();
+        assert_eq!(vm.list_breakpoints().unwrap(), [0x10000, 0x10008, 0x10010]);
+        
+        vm.clear_breakpoint(0x10008).unwrap();
+        assert_eq!(vm.list_breakpoints().unwrap(), [0x10000, 0x10010]);
+    }
+    
+    #[test]
+    fn test_trace_ring_keeps_most_recent_instructions() {
+        use crate::trace::TraceEntry;
+        
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let program = [
+            insn(0x0F, 1, 0, 1),
+            insn(0x22, 0, 0, 0),
+            insn(0x22, 0, 0, 0),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        vm.enable_trace(3);
+        vm.run(None).unwrap();
+        let trace = vm.take_trace();
+        assert_eq!(
+            trace,
+            [
+                TraceEntry { pc: 0x10004, opcode: 0x22 },
+                TraceEntry { pc: 0x10008, opcode: 0x22 },
+                TraceEntry { pc: 0x1000C, opcode: 0x21 },
+            ]
+        );
+        assert!(vm.take_trace().is_empty());
+        
+        vm.disable_trace();
+        vm.reset().unwrap();
+        vm.run(None).unwrap();
+        assert!(vm.take_trace().is_empty());
+    }
+    
+    #[test]
+    fn test_set_pc_starts_execution_there() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        vm.write_memory(0x20000, &[insn(0x0F, 1, 0, 5), insn(0x21, 0, 0, 0)].concat()).unwrap();
+        
+        vm.set_pc(0x20000).unwrap();
+        assert_eq!(vm.get_pc().unwrap(), 0x20000);
+        assert_eq!(vm.run_outcome(Some(10)).unwrap(), RunOutcome::Halted { exit_code: 5 });
+        assert_eq!(vm.get_pc().unwrap(), 0x20008);
+        
+        assert!(vm.set_pc(vm.memory_size() - 2).is_err());
+        assert!(vm.set_pc(u64::MAX).is_err());
+        assert_eq!(vm.get_pc().unwrap(), 0x20008);
+    }
+    
+    #[test]
+    fn test_stack_pointer_is_r30() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let top = vm.memory_size() - 8;
+        
+        vm.init_stack(top).unwrap();
+        assert_eq!(vm.get_sp().unwrap(), top);
+        assert_eq!(vm.get_register(30).unwrap(), top);
+        assert_eq!(vm.get_state().unwrap().sp, top);
+        
+        // A guest store through SP lands just below the top
+        vm.load_program(&insn(0x14, 30, 30, (-8i16) as u16), 0x10000).unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.read_u32(top - 8).unwrap(), top as u32);
+        
+        assert!(vm.init_stack(top + 4).is_err());
+        assert!(vm.init_stack(vm.memory_size()).is_err());
+        assert!(vm.set_sp(vm.memory_size() + 1).is_err());
+        vm.set_sp(vm.memory_size()).unwrap();
+        assert_eq!(vm.get_sp().unwrap(), vm.memory_size());
+    }
+    
+    #[test]
+    fn test_run_reports_fuel_exhausted() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        // JMP R0, 0(R2) back to itself forever
+        vm.set_register(2, 0x10000).unwrap();
+        vm.load_program(&insn(0x1D, 0, 2, 0), 0x10000).unwrap();
+        
+        assert_eq!(vm.run(Some(100)).unwrap(), Status::FuelExhausted);
+        assert_eq!(vm.run_counted(Some(100)).unwrap().status, Status::FuelExhausted);
+        
+        vm.load_program(&insn(0x21, 0, 0, 0), 0x10000).unwrap();
+        assert_eq!(vm.run(Some(100)).unwrap(), Status::Ok);
+    }
+    
+    #[test]
+    fn test_exception_kind_carries_fault_address() {
+        use crate::devices::{AccessWidthPolicy, Device};
+        
+        struct WordRegister;
+        
+        impl Device for WordRegister {
+            fn read(&mut self, _offset: u64) -> u64 {
+                0
+            }
+            fn write(&mut self, _offset: u64, _value: u64) {}
+            fn reset(&mut self) {}
+            fn access_width(&self) -> AccessWidthPolicy {
+                AccessWidthPolicy::Word32
+            }
+        }
+        
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        vm.attach_device(0x4000, 0x4010, Box::new(WordRegister)).unwrap();
+        // LD R2, 0x4000; LW R1, 2(R2)
+        vm.load_program(&[insn(0x0F, 2, 0, 0x4000), insn(0x10, 1, 2, 2)].concat(), 0x10000).unwrap();
+        
+        assert_eq!(vm.run_outcome(Some(10)).unwrap(), RunOutcome::Exception);
+        let event = vm.poll_event().unwrap().unwrap();
+        assert_eq!(event.exception_kind(), Some(ExceptionKind::AlignmentFault { address: 0x4002 }));
+        
+        let interrupt = Event { event_type: EventType::DeviceInterrupt, data: 3, fault_address: None };
+        assert_eq!(interrupt.exception_kind(), None);
+        assert_eq!(ExceptionKind::from_code(EXC_DIVIDE_BY_ZERO, None), ExceptionKind::DivideByZero);
+        assert_eq!(ExceptionKind::from_code(99, None), ExceptionKind::Unknown(99));
+    }
+    
+    #[test]
+    fn test_run_until_event() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        vm.set_guest_breakpoint_mode(GuestBreakpointMode::Continue).unwrap();
+        let program: Vec<u8> = [
+            insn(0x37, 0, 0, 0),  // 0x10000: BRK
+            insn(0x37, 0, 0, 0),  // 0x10004: BRK
+            insn(0x0F, 1, 0, 3),  // 0x10008: LD R1, 3
+            insn(0x21, 0, 0, 0),  // 0x1000C: HALT
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        vm.set_breakpoint(0x10008).unwrap();
+        
+        // Both BRKs fire in one run; the second stays queued
+        let (status, event) = vm.run_until_event(None).unwrap();
+        assert_eq!(status, Status::Error);
+        let event = event.unwrap();
+        assert_eq!((event.event_type, event.data), (EventType::Breakpoint, 0x10000 | GUEST_BREAKPOINT_FLAG));
+        assert_eq!(vm.poll_event().unwrap().unwrap().data, 0x10004 | GUEST_BREAKPOINT_FLAG);
+        
+        // Host breakpoints and halts queue nothing, but are still reported
+        let (_, event) = vm.run_until_event(None).unwrap();
+        let event = event.unwrap();
+        assert_eq!((event.event_type, event.data), (EventType::Breakpoint, 0x10008));
+        vm.clear_breakpoint(0x10008).unwrap();
+        let (status, event) = vm.run_until_event(None).unwrap();
+        assert_eq!(status, Status::Ok);
+        let event = event.unwrap();
+        assert_eq!((event.event_type, event.data), (EventType::Halted, 3));
+        
+        vm.reset().unwrap();
+        vm.set_register(2, 0x10000).unwrap();
+        vm.load_program(&insn(0x1D, 0, 2, 0), 0x10000).unwrap();
+        let (status, event) = vm.run_until_event(Some(100)).unwrap();
+        assert_eq!(status, Status::FuelExhausted);
+        assert!(event.is_none());
+    }
+    
+    #[test]
+    fn test_init_is_idempotent_across_threads() {
+        let threads: Vec<_> = (0..8).map(|_| std::thread::spawn(init)).collect();
+        for thread in threads {
+            assert!(thread.join().unwrap().is_ok());
+        }
+        assert!(init().is_ok());
+    }
+    
+    #[test]
+    fn test_fill_and_copy_memory() {
+        init().unwrap();
+        let mut vm = VM::new(0x10000).unwrap();
+        vm.write_memory(0x100, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        
+        // Overlapping in both directions
+        vm.copy_memory(0x102, 0x100, 6).unwrap();
+        assert_eq!(vm.read_memory(0x100, 8).unwrap(), [1, 2, 1, 2, 3, 4, 5, 6]);
+        vm.copy_memory(0x100, 0x103, 5).unwrap();
+        assert_eq!(vm.read_memory(0x100, 8).unwrap(), [2, 3, 4, 5, 6, 4, 5, 6]);
+        
+        vm.fill_memory(0x101, 0xAA, 3).unwrap();
+        assert_eq!(vm.read_memory(0x100, 8).unwrap(), [2, 0xAA, 0xAA, 0xAA, 6, 4, 5, 6]);
+        vm.fill_memory(0xFFF8, 0x55, 8).unwrap();
+        assert_eq!(vm.read_u64(0xFFF8).unwrap(), 0x5555_5555_5555_5555);
+        
+        assert!(vm.fill_memory(0xFFF9, 0, 8).is_err());
+        assert!(vm.fill_memory(u64::MAX, 0, 2).is_err());
+        assert!(vm.copy_memory(0x100, 0xFFF9, 8).is_err());
+        assert!(vm.copy_memory(0xFFF9, 0x100, 8).is_err());
+        assert!(vm.copy_memory(0x100, u64::MAX, 2).is_err());
+        assert_eq!(vm.read_memory(0x100, 8).unwrap(), [2, 0xAA, 0xAA, 0xAA, 6, 4, 5, 6]);
+    }
+    
+    #[test]
+    fn test_map_readonly_overlays_memory() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        vm.fill_memory(0x1FFFC, 0xEE, 16).unwrap();
+        let data: Arc<[u8]> = Arc::from(&[0x11, 0x22, 0x33, 0x44, 0x85, 0x66, 0x77, 0x88][..]);
+        vm.map_readonly(0x20000, data.clone()).unwrap();
+        
+        // Reads see the mapping, with RAM either side
+        assert_eq!(
+            vm.read_memory(0x1FFFE, 12).unwrap(),
+            [0xEE, 0xEE, 0x11, 0x22, 0x33, 0x44, 0x85, 0x66, 0x77, 0x88, 0xEE, 0xEE]
+        );
+        assert!(vm.write_memory(0x1FFFE, &[0; 4]).is_err());
+        assert!(vm.fill_memory(0x20007, 0, 1).is_err());
+        assert!(vm.copy_memory(0x20000, 0x30000, 4).is_err());
+        vm.copy_memory(0x30000, 0x20002, 4).unwrap();
+        assert_eq!(vm.read_memory(0x30000, 4).unwrap(), [0x33, 0x44, 0x85, 0x66]);
+        
+        assert!(vm.map_readonly(0x20004, data.clone()).is_err());
+        assert!(vm.map_readonly(1024 * 1024 - 4, data.clone()).is_err());
+        assert!(vm.attach_device(0x20006, 0x20010, Box::new(devices::TimerDevice::new())).is_err());
+        
+        // LD R2, 0x2000; SHL R2, R2, R4 (R4 = 4); LW R1, 0(R2); LB R3, 4(R2); SW R1, 0(R2)
+        let program = [
+            insn(0x0F, 2, 0, 0x2000),
+            rtype(0x0A, 2, 2, 4),
+            insn(0x10, 1, 2, 0),
+            insn(0x12, 3, 2, 4),
+            insn(0x14, 1, 2, 0),
+        ];
+        vm.load_program(&program.concat(), 0x10000).unwrap();
+        vm.set_register(4, 4).unwrap();
+        assert_eq!(vm.run_outcome(Some(10)).unwrap(), RunOutcome::Exception);
+        assert_eq!(vm.get_register(1).unwrap(), 0x44332211);
+        assert_eq!(vm.get_register(3).unwrap(), 0xFFFF_FFFF_FFFF_FF85);
+        let event = vm.poll_event().unwrap().unwrap();
+        assert_eq!(event.exception_kind(), Some(ExceptionKind::MemoryFault { address: 0x20000 }));
+        
+        // The mapped bytes were never copied into RAM
+        assert_eq!(vm.save_memory_image()[0x20000..0x20004], [0xEE; 4]);
+    }
+    
+    #[test]
+    fn test_cache_ctrl_and_vbase_accessors() {
+        init().unwrap();
+        let mut vm = VM::new(0x10000).unwrap();
+        assert_eq!(vm.get_cache_ctrl().unwrap(), CacheCtrl::default());
+        
+        vm.set_cache_ctrl(CacheCtrl(CacheCtrl::ENABLE | CacheCtrl::FLUSH)).unwrap();
+        let cache_ctrl = vm.get_cache_ctrl().unwrap();
+        assert!(cache_ctrl.enabled() && cache_ctrl.flush_pending());
+        assert_eq!(vm.get_state().unwrap().cache_ctrl, 0b11);
+        
+        vm.set_vbase(0x8000).unwrap();
+        assert_eq!(vm.get_vbase().unwrap(), 0x8000);
+        assert!(vm.set_vbase(0x10000).is_err());
+        assert_eq!(vm.get_vbase().unwrap(), 0x8000);
+    }
+    
+    #[test]
+    fn test_reg_constants_and_raw_indices() {
+        init().unwrap();
+        let mut vm = VM::new(0x10000).unwrap();
+        vm.set_register(Reg::R5, 0x55).unwrap();
+        vm.set_register(Reg::LR, 0x1234).unwrap();
+        assert_eq!(vm.get_register(5).unwrap(), 0x55);
+        assert_eq!(vm.get_register(Reg::R31).unwrap(), 0x1234);
+        
+        vm.set_sp(0x8000).unwrap();
+        assert_eq!(vm.get_register(Reg::SP).unwrap(), 0x8000);
+        vm.set_register(Reg::ZERO, 1).unwrap();
+        assert_eq!(vm.get_register(Reg::R0).unwrap(), 0);
+        
+        assert_eq!(Reg::new(7), Some(Reg::R7));
+        assert_eq!(Reg::new(32), None);
+        assert_eq!(Reg::SP.to_string(), "R30");
+        assert!(vm.get_register(37).is_err());
+    }
+    
+    #[test]
+    fn test_run_cycles_stops_between_batches() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        // loop: ADD R1, R1, R2; JMP R4, 0(R3)
+        vm.load_program(&[rtype(0x00, 1, 1, 2), insn(0x1D, 4, 3, 0)].concat(), 0x10000).unwrap();
+        vm.set_register(2, 1).unwrap();
+        vm.set_register(3, 0x10000).unwrap();
+        
+        vm.set_cycle_batch(100).unwrap();
+        let run = vm.run_cycles(250).unwrap();
+        assert_eq!(run, CycleRun { outcome: RunOutcome::Limit, cycles: 300 });
+        assert_eq!(vm.get_register(1).unwrap(), 150);
+        
+        vm.set_cycle_batch(1).unwrap();
+        assert_eq!(vm.run_cycles(7).unwrap().cycles, 7);
+        assert_eq!(vm.run_cycles(0).unwrap().cycles, 0);
+        assert!(vm.set_cycle_batch(0).is_err());
+        
+        // A halt ends the run early and still reports what was used
+        vm.load_program(&insn(0x21, 0, 0, 0), 0x10000).unwrap();
+        vm.reset().unwrap();
+        vm.set_pc(0x10000).unwrap();
+        vm.set_cycle_batch(DEFAULT_CYCLE_BATCH).unwrap();
+        let run = vm.run_cycles(1_000_000).unwrap();
+        assert!(matches!(run.outcome, RunOutcome::Halted { .. }));
+        assert_eq!(run.cycles, 1);
+    }
+    
+    #[test]
+    fn test_with_memory_borrows_guest_ram() {
+        init().unwrap();
+        let mut vm = VM::new(0x10000).unwrap();
+        vm.write_memory(0x1000, &0xDEADBEEFu32.to_le_bytes()).unwrap();
+        vm.map_readonly(0x2000, Arc::from(&b"rom"[..])).unwrap();
+        
+        let (len, word, under_rom) = vm
+            .with_memory(|memory| {
+                let word = u32::from_le_bytes(memory[0x1000..0x1004].try_into().unwrap());
+                (memory.len(), word, memory[0x2000..0x2003].to_vec())
+            })
+            .unwrap();
+        assert_eq!(len, 0x10000);
+        assert_eq!(word, 0xDEADBEEF);
+        assert_eq!(under_rom, [0, 0, 0]);
+        assert_eq!(vm.read_memory(0x2000, 3).unwrap(), b"rom");
+    }
+    
+    #[test]
+    fn test_failed_create_explains_why() {
+        init().unwrap();
+        let error = VM::new(u64::MAX).err().unwrap();
+        assert_eq!(error.status, Status::OutOfMemory);
+        assert!(error.message.starts_with("Failed to create VM: "));
+        assert!(error.message.contains("memory"));
+        
+        let error = VM::new(0).err().unwrap();
+        assert_eq!(error.status, Status::InvalidParameter);
+        assert!(error.message.contains("nonzero"));
+    }
+    
+    #[test]
+    fn test_capabilities_match_host_features() {
+        let capabilities = capabilities();
+        assert!(capabilities.contains(Capabilities::DEVICES | Capabilities::TRACE));
+        assert!(!capabilities.contains(Capabilities::SIMD));
+        
+        // What the bits promise actually works
+        init().unwrap();
+        let mut vm = VM::new(0x20000).unwrap();
+        vm.enable_trace(4);
+        vm.set_watchpoint(0x1000, 8, WatchKind::Write).unwrap();
+        let snapshot = vm.snapshot().unwrap();
+        vm.restore(&snapshot).unwrap();
+    }
+    
+    #[test]
+    fn test_version_matches_build_info() {
+        let (major, minor, patch) = version();
+        assert_eq!(format!("{}.{}.{}", major, minor, patch), env!("CARGO_PKG_VERSION"));
+        
+        let info = build_info();
+        assert!(info.starts_with(&format!("nanocore {} (", env!("CARGO_PKG_VERSION"))));
+        assert!(info.contains(std::env::consts::ARCH));
+        let backend = if cfg!(nanocore_native) { "native core" } else { "interpreter" };
+        assert!(info.ends_with(&format!(", {})", backend)));
+    }
+    
+    #[test]
+    fn test_reset_full_zeroes_memory() {
+        init().unwrap();
+        let mut vm = VM::new(0x20000).unwrap();
+        let pattern: Vec<u8> = (0..=255).cycle().take(0x1000).collect();
+        vm.write_memory(0x8000, &pattern).unwrap();
+        vm.set_register(5, 42).unwrap();
+        
+        vm.reset().unwrap();
+        assert_eq!(vm.read_memory(0x8000, 0x1000).unwrap(), pattern);
+        
+        vm.set_register(5, 42).unwrap();
+        vm.reset_full().unwrap();
+        assert_eq!(vm.read_memory(0x8000, 0x1000).unwrap(), vec![0; 0x1000]);
+        assert_eq!(vm.find_first(&[1, 2, 3], 0, 0x20000).unwrap(), None);
+        assert_eq!(vm.get_register(5).unwrap(), 0);
+    }
+    
+    #[test]
+    fn test_break_on_opcode_stops_after_matching_instruction() {
+        init().unwrap();
+        let mut vm = VM::new(64 * 1024).unwrap();
+        let program = [
+            insn(0x0F, 1, 0, 0x4000),
+            insn(0x0F, 2, 0, 7),
+            insn(0x10, 3, 1, 0),
+            insn(0x14, 2, 1, 0),
+            insn(0x13, 2, 1, 8),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x1000).unwrap();
+        vm.set_pc(0x1000).unwrap();
+        vm.break_on_opcode(opcode::OpcodeClass::Store);
+        
+        assert_eq!(vm.run_outcome(None).unwrap(), RunOutcome::Breakpoint { address: 0x100C });
+        assert_eq!(
+            vm.last_stop_reason(),
+            Some(StopReason::OpcodeBreak { address: 0x100C, opcode: opcode::Opcode::Sw })
+        );
+        assert_eq!(vm.read_memory(0x4000, 4).unwrap(), 7u32.to_le_bytes());
+        assert_eq!(vm.poll_event().unwrap().map(|event| event.data), Some(0x100C));
+        
+        assert_eq!(vm.run_outcome(None).unwrap(), RunOutcome::Breakpoint { address: 0x1010 });
+        vm.clear_opcode_breaks();
+        assert_eq!(vm.run_outcome(None).unwrap(), RunOutcome::Halted { exit_code: 0x4000 });
+    }
+    
+    #[test]
+    fn test_bisect_finds_first_change() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        
+        // LD R2, 1; 50 x ADD R1, R1, R2; HALT
+        let mut program = insn(0x0F, 2, 0, 1).to_vec();
+        for _ in 0..50 {
+            program.extend_from_slice(&rtype(0x00, 1, 1, 2));
+        }
+        program.extend_from_slice(&insn(0x21, 0, 0, 0));
+        vm.load_program(&program, 0x10000).unwrap();
+        
+        let found = vm.bisect(|state| state.gprs[1] >= 20, 1000).unwrap();
+        assert_eq!(found, Some(21));
+        assert_eq!(vm.get_register(1).unwrap(), 20);
+        
+        vm.reset().unwrap();
+        vm.load_program(&program, 0x10000).unwrap();
+        assert_eq!(vm.bisect(|state| state.gprs[1] >= 100, 1000).unwrap(), None);
+    }
+    
+    #[test]
+    fn test_framebuffer_captures_guest_writes() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let fb = vm.attach_framebuffer(0x4000, 4, 2, 4).unwrap();
+        
+        // LD R2, 0x4000; LD R1, 0x1234; SW R1, 4(R2); HALT
+        let program: Vec<u8> = [
+            insn(0x0F, 2, 0, 0x4000),
+            insn(0x0F, 1, 0, 0x1234),
+            insn(0x14, 1, 2, 4),
+            insn(0x21, 0, 0, 0),
+        ]
+        .concat();
+        vm.load_program(&program, 0x10000).unwrap();
+        vm.run(Some(100)).unwrap();
+        
+        let (width, height, pixels) = fb.snapshot();
+        assert_eq!((width, height), (4, 2));
+        assert_eq!(pixels.len(), 32);
+        assert_eq!(&pixels[4..8], &[0x34, 0x12, 0x00, 0x00]);
+        assert!(pixels[..4].iter().chain(&pixels[8..]).all(|&b| b == 0));
+    }
+}
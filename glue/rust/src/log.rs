@@ -0,0 +1,97 @@
+//! Routing VM diagnostics into the host's logging
+//!
+//! The crate reports why runs stop, errors on background workers and panics
+//! it swallows through a single process-wide handler. Until
+//! `set_log_handler` is called, errors are printed to stderr and everything
+//! else is dropped. The `tracing` feature is independent of this and emits
+//! its own events either way.
+
+use std::fmt;
+use std::sync::{PoisonError, RwLock};
+
+/// Severity of a diagnostic message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+type Handler = Box<dyn Fn(Level, &str) + Send + Sync>;
+
+static HANDLER: RwLock<Option<Handler>> = RwLock::new(None);
+
+/// Send every diagnostic to `handler` instead of stderr
+///
+/// Replaces any handler set before. The handler may be called from any
+/// thread running a VM, and must not itself set or clear the handler.
+pub fn set_log_handler(handler: impl Fn(Level, &str) + Send + Sync + 'static) {
+    *HANDLER.write().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(handler));
+}
+
+/// Go back to printing errors to stderr
+pub fn clear_log_handler() {
+    *HANDLER.write().unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+/// Report a diagnostic; the message is only formatted if someone will see it
+pub(crate) fn log(level: Level, args: fmt::Arguments<'_>) {
+    let handler = HANDLER.read().unwrap_or_else(PoisonError::into_inner);
+    match handler.as_ref() {
+        Some(handler) => handler(level, &args.to_string()),
+        None if level == Level::Error => eprintln!("NanoCore: {}", args),
+        None => {}
+    }
+}
+
+/// Best-effort text of a panic payload
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init, VM};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_handler_receives_stop_reasons() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        // NOP; HALT
+        vm.load_program(&[0x88, 0, 0, 0, 0x84, 0, 0, 0], 0x10000).unwrap();
+        vm.set_breakpoint(0x10004).unwrap();
+
+        // Other tests run VMs concurrently, so only keep this VM's messages
+        let prefix = format!("VM {}: ", vm.handle);
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        set_log_handler({
+            let messages = messages.clone();
+            move |level, message| {
+                if let Some(message) = message.strip_prefix(&prefix) {
+                    messages.lock().unwrap().push((level, message.to_string()));
+                }
+            }
+        });
+        vm.run(Some(10)).unwrap();
+        vm.clear_breakpoint(0x10004).unwrap();
+        vm.run(Some(10)).unwrap();
+        clear_log_handler();
+        vm.reset().unwrap();
+        vm.run(Some(10)).unwrap();
+
+        assert_eq!(
+            *messages.lock().unwrap(),
+            [
+                (Level::Info, "breakpoint hit at 0x10004 (hit 1)".to_string()),
+                (Level::Debug, "guest halted with exit code 0".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! The NanoCore opcode space
+//!
+//! The opcode is the top six bits of every instruction word. This module is
+//! the single place that maps opcode numbers to names and instruction
+//! classes; the disassembler and cost models build on it.
+
+use std::fmt;
+
+/// Broad instruction class, used by cost models
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Category {
+    /// Integer arithmetic, logic, shifts and load-immediate
+    Alu,
+    /// Integer multiply and divide
+    MulDiv,
+    /// Memory loads, including load-reserved
+    Load,
+    /// Memory stores, including store-conditional
+    Store,
+    /// Conditional branches
+    Branch,
+    /// Jumps, calls and returns
+    Jump,
+    /// System, counter, cache and fence instructions
+    System,
+    /// Atomic read-modify-write
+    Atomic,
+    /// SIMD operations
+    Vector,
+    /// Opcodes the core does not define
+    Unknown,
+}
+
+impl Category {
+    /// Whether instructions in this class access data memory
+    pub fn is_memory(self) -> bool {
+        matches!(self, Category::Load | Category::Store | Category::Atomic)
+    }
+}
+
+/// Instruction class `VM::break_on_opcode` stops on
+///
+/// Classes overlap where an instruction does more than one thing: atomics
+/// are both loads and stores, and `VLOAD` and `VSTORE` are SIMD as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OpcodeClass {
+    /// Anything that reads data memory
+    Load,
+    /// Anything that writes data memory
+    Store,
+    /// Conditional branches, jumps, calls and returns
+    Branch,
+    /// SIMD operations
+    Simd,
+    /// System, counter, cache and fence instructions, including `HALT` and `BRK`
+    System,
+}
+
+impl OpcodeClass {
+    /// Whether `opcode` belongs to this class
+    pub fn contains(self, opcode: Opcode) -> bool {
+        let category = opcode.category();
+        match self {
+            OpcodeClass::Load => {
+                matches!(category, Category::Load | Category::Atomic) || opcode == Opcode::Vload
+            }
+            OpcodeClass::Store => {
+                matches!(category, Category::Store | Category::Atomic) || opcode == Opcode::Vstore
+            }
+            OpcodeClass::Branch => matches!(category, Category::Branch | Category::Jump),
+            OpcodeClass::Simd => category == Category::Vector,
+            OpcodeClass::System => category == Category::System,
+        }
+    }
+}
+
+macro_rules! opcodes {
+    ($($variant:ident = $value:literal, $mnemonic:literal, $category:ident;)*) => {
+        /// A documented NanoCore opcode
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #[repr(u8)]
+        pub enum Opcode {
+            $(
+                #[doc = concat!("`", $mnemonic, "`")]
+                $variant = $value,
+            )*
+        }
+
+        impl Opcode {
+            /// Every opcode, in numeric order
+            pub const ALL: &'static [Opcode] = &[$(Opcode::$variant),*];
+
+            /// The opcode with number `byte`, if one is defined
+            pub fn from_u8(byte: u8) -> Option<Opcode> {
+                match byte {
+                    $($value => Some(Opcode::$variant),)*
+                    _ => None,
+                }
+            }
+
+            /// Assembler mnemonic
+            pub fn mnemonic(self) -> &'static str {
+                match self {
+                    $(Opcode::$variant => $mnemonic,)*
+                }
+            }
+
+            /// Instruction class
+            pub fn category(self) -> Category {
+                match self {
+                    $(Opcode::$variant => Category::$category,)*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    Add = 0x00, "ADD", Alu;
+    Sub = 0x01, "SUB", Alu;
+    Mul = 0x02, "MUL", MulDiv;
+    Mulh = 0x03, "MULH", MulDiv;
+    Div = 0x04, "DIV", MulDiv;
+    Mod = 0x05, "MOD", MulDiv;
+    And = 0x06, "AND", Alu;
+    Or = 0x07, "OR", Alu;
+    Xor = 0x08, "XOR", Alu;
+    Not = 0x09, "NOT", Alu;
+    Shl = 0x0A, "SHL", Alu;
+    Shr = 0x0B, "SHR", Alu;
+    Sar = 0x0C, "SAR", Alu;
+    Rol = 0x0D, "ROL", Alu;
+    Ror = 0x0E, "ROR", Alu;
+    Ld = 0x0F, "LD", Alu;
+    Lw = 0x10, "LW", Load;
+    Lh = 0x11, "LH", Load;
+    Lb = 0x12, "LB", Load;
+    St = 0x13, "ST", Store;
+    Sw = 0x14, "SW", Store;
+    Sh = 0x15, "SH", Store;
+    Sb = 0x16, "SB", Store;
+    Beq = 0x17, "BEQ", Branch;
+    Bne = 0x18, "BNE", Branch;
+    Blt = 0x19, "BLT", Branch;
+    Bge = 0x1A, "BGE", Branch;
+    Bltu = 0x1B, "BLTU", Branch;
+    Bgeu = 0x1C, "BGEU", Branch;
+    Jmp = 0x1D, "JMP", Jump;
+    Call = 0x1E, "CALL", Jump;
+    Ret = 0x1F, "RET", Jump;
+    Syscall = 0x20, "SYSCALL", System;
+    Halt = 0x21, "HALT", System;
+    Nop = 0x22, "NOP", System;
+    Cpuid = 0x23, "CPUID", System;
+    Rdcycle = 0x24, "RDCYCLE", System;
+    Rdperf = 0x25, "RDPERF", System;
+    Prefetch = 0x26, "PREFETCH", System;
+    Clflush = 0x27, "CLFLUSH", System;
+    Fence = 0x28, "FENCE", System;
+    Lr = 0x29, "LR", Load;
+    Sc = 0x2A, "SC", Store;
+    Amoswap = 0x2B, "AMOSWAP", Atomic;
+    Amoadd = 0x2C, "AMOADD", Atomic;
+    Amoand = 0x2D, "AMOAND", Atomic;
+    Amoor = 0x2E, "AMOOR", Atomic;
+    Amoxor = 0x2F, "AMOXOR", Atomic;
+    VaddF64 = 0x30, "VADD.F64", Vector;
+    VsubF64 = 0x31, "VSUB.F64", Vector;
+    VmulF64 = 0x32, "VMUL.F64", Vector;
+    VfmaF64 = 0x33, "VFMA.F64", Vector;
+    Vload = 0x34, "VLOAD", Vector;
+    Vstore = 0x35, "VSTORE", Vector;
+    Vbroadcast = 0x36, "VBROADCAST", Vector;
+    Brk = 0x37, "BRK", System;
+    Ei = 0x38, "EI", System;
+    Di = 0x39, "DI", System;
+}
+
+impl Opcode {
+    /// Opcode number
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    /// Opcode of the instruction word `raw`, if it is defined
+    pub fn of(raw: u32) -> Option<Opcode> {
+        Opcode::from_u8((raw >> 26) as u8)
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.mnemonic())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opcode_round_trip() {
+        for (index, &opcode) in Opcode::ALL.iter().enumerate() {
+            assert_eq!(opcode.as_u8() as usize, index);
+            assert_eq!(Opcode::from_u8(opcode.as_u8()), Some(opcode));
+        }
+        assert_eq!(Opcode::from_u8(0x3A), None);
+        assert_eq!(Opcode::from_u8(0x3F), None);
+    }
+
+    #[test]
+    fn test_opcode_names_and_categories() {
+        assert_eq!(Opcode::Ld.mnemonic(), "LD");
+        assert_eq!(Opcode::VfmaF64.to_string(), "VFMA.F64");
+        assert_eq!(Opcode::Halt.category(), Category::System);
+        assert_eq!(Opcode::Mulh.category(), Category::MulDiv);
+        assert_eq!(Opcode::Sc.category(), Category::Store);
+        assert!(Opcode::Amoadd.category().is_memory());
+        assert!(!Opcode::Vbroadcast.category().is_memory());
+        assert_eq!(Opcode::of(0x84000000), Some(Opcode::Halt));
+    }
+
+    #[test]
+    fn test_opcode_classes() {
+        assert!(OpcodeClass::Store.contains(Opcode::Sb));
+        assert!(OpcodeClass::Store.contains(Opcode::Amoswap));
+        assert!(OpcodeClass::Load.contains(Opcode::Amoswap));
+        assert!(OpcodeClass::Store.contains(Opcode::Vstore));
+        assert!(OpcodeClass::Simd.contains(Opcode::Vstore));
+        assert!(!OpcodeClass::Load.contains(Opcode::Ld));
+        assert!(OpcodeClass::Branch.contains(Opcode::Ret));
+        assert!(OpcodeClass::System.contains(Opcode::Brk));
+        assert!(OpcodeClass::System.contains(Opcode::Di));
+        assert!(!OpcodeClass::Simd.contains(Opcode::Mul));
+    }
+}
@@ -0,0 +1,54 @@
+//! Typed views of guest memory for plain-old-data types
+
+use std::mem;
+
+use bytemuck::Pod;
+
+use crate::{check_status, ffi, Error, Result, Status, VM};
+
+impl VM {
+    /// Read `count` values of `T` starting at `address`
+    ///
+    /// `address` must be aligned for `T` and the whole range must lie inside
+    /// guest memory. Values are reinterpreted from the guest's bytes as-is,
+    /// so multi-byte fields are little-endian on little-endian hosts.
+    pub fn read_slice<T: Pod>(&self, address: u64, count: usize) -> Result<Vec<T>> {
+        self.with_slice(address, count, |values: &[T]| values.to_vec())
+    }
+
+    /// Run `f` over `count` values of `T` starting at `address`
+    ///
+    /// Same requirements as `read_slice`; the values are copied into an
+    /// aligned host buffer before `f` sees them.
+    pub fn with_slice<T: Pod, R>(
+        &self,
+        address: u64,
+        count: usize,
+        f: impl FnOnce(&[T]) -> R,
+    ) -> Result<R> {
+        let align = mem::align_of::<T>() as u64;
+        if !address.is_multiple_of(align) {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Address {:#x} is not {}-byte aligned", address, align),
+            });
+        }
+
+        let size = (count as u64)
+            .checked_mul(mem::size_of::<T>() as u64)
+            .filter(|&size| address.checked_add(size).is_some_and(|end| end <= self.memory_size))
+            .ok_or_else(|| Error {
+                status: Status::InvalidParameter,
+                message: format!("{} values at {:#x} exceed guest memory", count, address),
+            })?;
+
+        let mut values = vec![T::zeroed(); count];
+        let bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut values);
+        let result = unsafe {
+            ffi::nanocore_vm_read_memory(self.handle, address, bytes.as_mut_ptr(), size)
+        };
+        check_status(result, "read memory")?;
+
+        Ok(f(&values))
+    }
+}
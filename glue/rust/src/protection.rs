@@ -0,0 +1,185 @@
+//! Guest memory protection
+//!
+//! `VM::protect` marks ranges of guest memory unmapped or read-only. Memory
+//! never protected is readable and writable. Guest loads, stores and
+//! instruction fetches that violate a range's protection raise a
+//! `MemoryFault` exception carrying the address the access started at; host
+//! reads and writes through the `VM` API are not affected. While any range
+//! is protected the VM is stepped from the host.
+
+use crate::{Error, Result, Status, VM};
+
+/// What the guest may do with a range of memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    /// Unmapped; every access faults
+    None,
+    /// Loads and instruction fetches only
+    Read,
+    /// Any access, the default for memory never protected
+    ReadWrite,
+}
+
+impl Protection {
+    fn permits(self, store: bool) -> bool {
+        match self {
+            Protection::None => false,
+            Protection::Read => !store,
+            Protection::ReadWrite => true,
+        }
+    }
+}
+
+/// Protected ranges as sorted, non-overlapping `(start, end, protection)`
+///
+/// `ReadWrite` ranges are not stored, so an empty map protects nothing.
+#[derive(Debug, Default)]
+pub(crate) struct ProtectionMap {
+    regions: Vec<(u64, u64, Protection)>,
+}
+
+impl ProtectionMap {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// Set the protection of `[start, end)`, replacing whatever covered it
+    fn set(&mut self, start: u64, end: u64, protection: Protection) {
+        let mut regions = Vec::with_capacity(self.regions.len() + 2);
+        for &(s, e, p) in &self.regions {
+            if e <= start || end <= s {
+                regions.push((s, e, p));
+                continue;
+            }
+            if s < start {
+                regions.push((s, start, p));
+            }
+            if end < e {
+                regions.push((end, e, p));
+            }
+        }
+        if protection != Protection::ReadWrite {
+            regions.push((start, end, protection));
+        }
+        regions.sort_unstable_by_key(|&(s, _, _)| s);
+
+        // Merge touching ranges with the same protection to keep the list short
+        self.regions.clear();
+        for region in regions {
+            match self.regions.last_mut() {
+                Some(last) if last.1 == region.0 && last.2 == region.2 => last.1 = region.1,
+                _ => self.regions.push(region),
+            }
+        }
+    }
+
+    /// Protected ranges in address order, as `(start, end, protection)`
+    pub(crate) fn regions(&self) -> &[(u64, u64, Protection)] {
+        &self.regions
+    }
+
+    /// Whether a load (or fetch) or store touching `[start, end)` is allowed
+    pub(crate) fn permits(&self, start: u64, end: u64, store: bool) -> bool {
+        let first = self.regions.partition_point(|&(_, e, _)| e <= start);
+        self.regions[first..]
+            .iter()
+            .take_while(|&&(s, _, _)| s < end)
+            .all(|&(_, _, protection)| protection.permits(store))
+    }
+}
+
+impl VM {
+    /// Set the guest's access to `len` bytes at `address`
+    ///
+    /// Replaces the protection previously set on any part of the range;
+    /// `Protection::ReadWrite` lifts it. The range must lie within memory.
+    pub fn protect(&mut self, address: u64, len: u64, protection: Protection) -> Result<()> {
+        let end = address
+            .checked_add(len)
+            .filter(|&end| len > 0 && end <= self.memory_size)
+            .ok_or_else(|| Error {
+                status: Status::InvalidParameter,
+                message: format!("Cannot protect {} bytes at {:#x}", len, address),
+            })?;
+        self.protection.set(address, end, protection);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init, Event, ExceptionKind, RunOutcome};
+
+    #[test]
+    fn test_protection_map_splits_and_merges() {
+        let mut map = ProtectionMap::default();
+        map.set(0x1000, 0x3000, Protection::Read);
+        map.set(0x1800, 0x2000, Protection::None);
+        assert_eq!(
+            map.regions,
+            [
+                (0x1000, 0x1800, Protection::Read),
+                (0x1800, 0x2000, Protection::None),
+                (0x2000, 0x3000, Protection::Read),
+            ]
+        );
+        assert!(map.permits(0x17FC, 0x1800, false));
+        assert!(!map.permits(0x17FE, 0x1802, false));
+        assert!(!map.permits(0x1000, 0x1004, true));
+        assert!(map.permits(0x3000, 0x3008, true));
+
+        map.set(0x1800, 0x2000, Protection::Read);
+        assert_eq!(map.regions, [(0x1000, 0x3000, Protection::Read)]);
+        map.set(0, 0x4000, Protection::ReadWrite);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_protected_accesses_fault() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        // LW R1, 0(R2); SW R1, 0(R3); LW R1, 0(R4)
+        let code: Vec<u8> = [
+            0x10u32 << 26 | 1 << 21 | 2 << 16,
+            0x14 << 26 | 1 << 21 | 3 << 16,
+            0x10 << 26 | 1 << 21 | 4 << 16,
+        ]
+        .iter()
+        .flat_map(|word| word.to_be_bytes())
+        .collect();
+        vm.load_program(&code, 0x10000).unwrap();
+        vm.write_memory(0x20000, &7u32.to_le_bytes()).unwrap();
+        vm.set_register(2, 0x20000).unwrap();
+        vm.set_register(3, 0x20004).unwrap();
+        vm.set_register(4, 0x30000).unwrap();
+        vm.protect(0x20000, 0x1000, Protection::Read).unwrap();
+        vm.protect(0x30000, 0x1000, Protection::None).unwrap();
+
+        let fault = |vm: &mut VM| {
+            assert_eq!(vm.run_outcome(Some(10)).unwrap(), RunOutcome::Exception);
+            vm.poll_event().unwrap().as_ref().and_then(Event::exception_kind)
+        };
+        assert_eq!(fault(&mut vm), Some(ExceptionKind::MemoryFault { address: 0x20004 }));
+        assert_eq!(vm.get_register(1).unwrap(), 7);
+        assert_eq!(vm.get_state().unwrap().pc, 0x10004);
+
+        vm.protect(0x20000, 0x1000, Protection::ReadWrite).unwrap();
+        vm.reset().unwrap();
+        vm.set_pc(0x10004).unwrap();
+        for (register, value) in [(1, 7), (3, 0x20004), (4, 0x30000)] {
+            vm.set_register(register, value).unwrap();
+        }
+        assert_eq!(fault(&mut vm), Some(ExceptionKind::MemoryFault { address: 0x30000 }));
+        assert_eq!(vm.read_memory(0x20004, 4).unwrap(), 7u32.to_le_bytes());
+
+        // Fetching from unmapped memory faults too
+        vm.reset().unwrap();
+        vm.set_pc(0x10000).unwrap();
+        vm.protect(0x10000, 4, Protection::None).unwrap();
+        assert_eq!(fault(&mut vm), Some(ExceptionKind::MemoryFault { address: 0x10000 }));
+
+        assert!(vm.protect(0, 0, Protection::None).is_err());
+        assert!(vm.protect(1024 * 1024 - 4, 8, Protection::None).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! Python bindings, behind the `python` feature
+//!
+//! `maturin develop` builds this crate as an extension module named
+//! `nanocore` (see `pyproject.toml`) exposing a `Vm` class, plus `version()`
+//! and `build_info()` for checking which native library was loaded:
+//!
+//! ```python
+//! import nanocore
+//!
+//! assert nanocore.version() >= (0, 1, 0)
+//! vm = nanocore.Vm(1024 * 1024)
+//! vm.load_program(program, 0x10000)
+//! vm.run(1000)
+//! print(vm.get_register(1), vm.get_state()["pc"])
+//! ```
+
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use crate::{init, Error, Status, VM};
+
+impl From<Error> for PyErr {
+    fn from(error: Error) -> PyErr {
+        match error.status {
+            Status::InvalidParameter => PyValueError::new_err(error.to_string()),
+            _ => PyRuntimeError::new_err(error.to_string()),
+        }
+    }
+}
+
+/// A NanoCore VM; methods mirror those of the Rust `VM`
+#[pyclass(name = "Vm", module = "nanocore", unsendable)]
+struct PyVm {
+    vm: VM,
+}
+
+#[pymethods]
+impl PyVm {
+    #[new]
+    #[pyo3(signature = (memory_size = 64 * 1024 * 1024))]
+    fn new(memory_size: u64) -> PyResult<Self> {
+        init()?;
+        Ok(PyVm {
+            vm: VM::new(memory_size)?,
+        })
+    }
+
+    #[pyo3(signature = (program, address = 0x10000))]
+    fn load_program(&mut self, program: &[u8], address: u64) -> PyResult<()> {
+        Ok(self.vm.load_program(program, address)?)
+    }
+
+    /// Run until halt, breakpoint or `max_instructions`; returns the status code
+    #[pyo3(signature = (max_instructions = None))]
+    fn run(&mut self, max_instructions: Option<u64>) -> PyResult<i32> {
+        Ok(self.vm.run(max_instructions)? as i32)
+    }
+
+    fn get_register(&self, index: u32) -> PyResult<u64> {
+        Ok(self.vm.get_register(index)?)
+    }
+
+    fn read_memory<'py>(&self, py: Python<'py>, address: u64, size: u64) -> PyResult<Bound<'py, PyBytes>> {
+        Ok(PyBytes::new(py, &self.vm.read_memory(address, size)?))
+    }
+
+    /// Registers as a dict keyed by `VmState` field name
+    fn get_state<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let state = self.vm.get_state()?;
+        let dict = PyDict::new(py);
+        dict.set_item("pc", state.pc)?;
+        dict.set_item("sp", state.sp)?;
+        dict.set_item("flags", state.flags.0)?;
+        dict.set_item("gprs", state.gprs.to_vec())?;
+        dict.set_item("vregs", state.vregs.iter().map(|lanes| lanes.to_vec()).collect::<Vec<_>>())?;
+        dict.set_item("perf_counters", state.perf_counters.to_vec())?;
+        dict.set_item("cache_ctrl", state.cache_ctrl)?;
+        dict.set_item("vbase", state.vbase)?;
+        Ok(dict)
+    }
+}
+
+/// `nanocore::version`
+#[pyfunction]
+fn version() -> (u32, u32, u32) {
+    crate::version()
+}
+
+/// `nanocore::build_info`
+#[pyfunction]
+fn build_info() -> &'static str {
+    crate::build_info()
+}
+
+/// `nanocore::capabilities` as its raw bits
+#[pyfunction]
+fn capabilities() -> u64 {
+    crate::capabilities().0
+}
+
+#[pymodule]
+fn nanocore(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(version, module)?)?;
+    module.add_function(wrap_pyfunction!(build_info, module)?)?;
+    module.add_function(wrap_pyfunction!(capabilities, module)?)?;
+    module.add_class::<PyVm>()
+}
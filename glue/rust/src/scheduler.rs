@@ -0,0 +1,96 @@
+//! Deterministic round-robin execution of several VMs
+//!
+//! The scheduler runs each VM for a fixed instruction quantum in turn, so a
+//! given set of VMs, quantum and budget always produces the same
+//! interleaving. VMs are driven one at a time from the calling thread, so
+//! there is never more than one VM executing and no lock ordering to get
+//! wrong.
+
+use crate::{Result, VM};
+
+/// Instructions each VM runs per turn unless `quantum` is called
+const DEFAULT_QUANTUM: u64 = 1000;
+
+/// Round-robin scheduler over a set of VMs
+pub struct Scheduler {
+    vms: Vec<VM>,
+    /// VMs that halted, faulted or hit a breakpoint leave the rotation
+    stopped: Vec<bool>,
+    quantum: u64,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            vms: Vec::new(),
+            stopped: Vec::new(),
+            quantum: DEFAULT_QUANTUM,
+        }
+    }
+
+    /// Add `vm` to the end of the rotation
+    #[allow(clippy::should_implement_trait)]
+    pub fn add(mut self, vm: VM) -> Self {
+        self.vms.push(vm);
+        self.stopped.push(false);
+        self
+    }
+
+    /// Instructions each VM runs per turn (at least one)
+    pub fn quantum(mut self, instructions: u64) -> Self {
+        self.quantum = instructions.max(1);
+        self
+    }
+
+    /// Run up to `total_instructions` across all VMs
+    ///
+    /// VMs take turns in the order they were added, each running a full
+    /// quantum (or whatever is left of the budget). A VM that stops before
+    /// its quantum is up drops out of the rotation. Returns the number of
+    /// instructions retired, which is less than the budget only if every VM
+    /// stopped.
+    pub fn run(&mut self, total_instructions: u64) -> Result<u64> {
+        let mut retired_total = 0;
+
+        while retired_total < total_instructions && !self.is_finished() {
+            for (vm, stopped) in self.vms.iter_mut().zip(self.stopped.iter_mut()) {
+                if *stopped {
+                    continue;
+                }
+                let budget = self.quantum.min(total_instructions - retired_total);
+                if budget == 0 {
+                    break;
+                }
+                let (retired, stopped_early) = vm.run_exact(budget)?;
+                retired_total += retired;
+                *stopped = stopped_early;
+            }
+        }
+
+        Ok(retired_total)
+    }
+
+    /// Whether every VM has left the rotation
+    pub fn is_finished(&self) -> bool {
+        self.stopped.iter().all(|&stopped| stopped)
+    }
+
+    pub fn vms(&self) -> &[VM] {
+        &self.vms
+    }
+
+    pub fn vms_mut(&mut self) -> &mut [VM] {
+        &mut self.vms
+    }
+
+    /// Take the VMs back, in the order they were added
+    pub fn into_vms(self) -> Vec<VM> {
+        self.vms
+    }
+}
@@ -0,0 +1,102 @@
+//! Scanning guest memory for byte patterns
+
+use crate::{Error, Result, Status, VM};
+
+/// Bytes read from the VM per pass of the scan
+const CHUNK: u64 = 64 * 1024;
+
+impl VM {
+    /// Guest addresses in `start..end` where `pattern` occurs
+    ///
+    /// A match must lie entirely within the range. Overlapping matches are
+    /// all reported, in ascending order. Results are addresses in VM space,
+    /// not offsets from `start`.
+    pub fn find_pattern(&self, pattern: &[u8], start: u64, end: u64) -> Result<Vec<u64>> {
+        self.scan(pattern, None, start, end, usize::MAX)
+    }
+
+    /// Like `find_pattern`, but a `wildcard` byte in `pattern` matches any byte
+    pub fn find_pattern_with_wildcard(
+        &self,
+        pattern: &[u8],
+        wildcard: u8,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<u64>> {
+        self.scan(pattern, Some(wildcard), start, end, usize::MAX)
+    }
+
+    /// Guest address of the first occurrence of `pattern` in `start..end`
+    pub fn find_first(&self, pattern: &[u8], start: u64, end: u64) -> Result<Option<u64>> {
+        Ok(self.scan(pattern, None, start, end, 1)?.first().copied())
+    }
+
+    fn scan(&self, pattern: &[u8], wildcard: Option<u8>, start: u64, end: u64, limit: usize) -> Result<Vec<u64>> {
+        if pattern.is_empty() || start > end || end > self.memory_size {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Invalid search of {:#x}..{:#x} for {} bytes", start, end, pattern.len()),
+            });
+        }
+        let matches_at = |window: &[u8]| {
+            window
+                .iter()
+                .zip(pattern)
+                .all(|(&byte, &expected)| byte == expected || Some(expected) == wildcard)
+        };
+
+        // Consecutive chunks overlap by one byte less than the pattern, so
+        // every candidate start is tried exactly once
+        let overlap = pattern.len() as u64 - 1;
+        let mut found = Vec::new();
+        let mut chunk_start = start;
+        while chunk_start + overlap < end && found.len() < limit {
+            let chunk_end = chunk_start.saturating_add(CHUNK + overlap).min(end);
+            let bytes = self.read_memory(chunk_start, chunk_end - chunk_start)?;
+            found.extend(
+                bytes
+                    .windows(pattern.len())
+                    .enumerate()
+                    .filter(|(_, window)| matches_at(window))
+                    .map(|(offset, _)| chunk_start + offset as u64),
+            );
+            chunk_start += CHUNK;
+        }
+        found.truncate(limit);
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init;
+
+    #[test]
+    fn test_find_pattern_across_chunks() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        let boundary = 0x1000 + CHUNK;
+        vm.write_memory(0x1000, b"abab").unwrap();
+        vm.write_memory(boundary - 2, b"abcd").unwrap();
+        vm.write_memory(0x40000, b"aXcd").unwrap();
+
+        assert_eq!(
+            vm.find_pattern(b"ab", 0x1000, 0x80000).unwrap(),
+            [0x1000, 0x1002, boundary - 2]
+        );
+        assert_eq!(vm.find_pattern(b"abcd", 0x1000, boundary + 2).unwrap(), [boundary - 2]);
+        // A match must fit inside the range
+        assert_eq!(vm.find_pattern(b"abcd", 0x1000, boundary + 1).unwrap(), Vec::<u64>::new());
+        assert_eq!(
+            vm.find_pattern_with_wildcard(b"a?cd", b'?', 0, 0x80000).unwrap(),
+            [boundary - 2, 0x40000]
+        );
+        assert_eq!(vm.find_first(b"cd", 0x2000, 0x80000).unwrap(), Some(boundary));
+        assert_eq!(vm.find_first(b"zz", 0, 1024 * 1024).unwrap(), None);
+
+        assert!(vm.find_pattern(b"", 0, 0x100).is_err());
+        assert!(vm.find_pattern(b"ab", 0x100, 0).is_err());
+        assert!(vm.find_pattern(b"ab", 0, 1024 * 1024 + 1).is_err());
+    }
+}
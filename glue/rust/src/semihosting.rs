@@ -0,0 +1,269 @@
+//! Semihosting: host services requested by the guest
+//!
+//! A guest requests a service by executing `SYSCALL 0xAB` with the operation
+//! number in `R1` and a parameter in `R2`. For most operations `R2` points to
+//! a block of 64-bit little-endian words in guest memory. The result is
+//! returned in `R1`; `-1` means the operation failed or is not permitted.
+//!
+//! | Op   | Name          | `R2`                 | Result                    |
+//! |------|---------------|----------------------|---------------------------|
+//! | 0x01 | `SYS_OPEN`    | `[path, mode, len]`  | file descriptor           |
+//! | 0x02 | `SYS_CLOSE`   | `[fd]`               | 0                         |
+//! | 0x03 | `SYS_WRITEC`  | pointer to a byte    | unchanged                 |
+//! | 0x04 | `SYS_WRITE0`  | pointer to a C string| unchanged                 |
+//! | 0x05 | `SYS_WRITE`   | `[fd, buf, len]`     | bytes *not* written       |
+//! | 0x06 | `SYS_READ`    | `[fd, buf, len]`     | bytes *not* read          |
+//! | 0x07 | `SYS_READC`   | unused               | byte read, -1 at EOF      |
+//! | 0x15 | `SYS_GET_CMDLINE` | `[buf, len]`     | 0, `len` updated          |
+//! | 0x18 | `SYS_EXIT`    | exit code            | does not return           |
+//!
+//! `SYS_READ` and `SYS_WRITE` fail if the buffer does not fit in guest
+//! memory. `SYS_READ` reads at most 64 KiB per call, reporting the rest as
+//! not read, and `SYS_WRITE` copies out 64 KiB at a time. `SYS_OPEN` paths
+//! are limited to 4096 bytes.
+//!
+//! `mode` follows the C `fopen` numbering: 0–3 read, 4–7 write (truncate),
+//! 8–11 append, with bit 1 adding update (`+`) access. Descriptor 0 reads
+//! from and descriptors 1 and 2 write to the VM console (see
+//! `VM::connect_stdio`).
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::opcode::Opcode;
+use crate::{ffi, Flags, PerfCounter, Result, EXIT_CODE_REGISTER, VM};
+
+/// Immediate operand of the `SYSCALL` that requests a semihosting operation
+pub const SEMIHOSTING_TRAP: u32 = 0xAB;
+
+pub const SYS_OPEN: u64 = 0x01;
+pub const SYS_CLOSE: u64 = 0x02;
+pub const SYS_WRITEC: u64 = 0x03;
+pub const SYS_WRITE0: u64 = 0x04;
+pub const SYS_WRITE: u64 = 0x05;
+pub const SYS_READ: u64 = 0x06;
+pub const SYS_READC: u64 = 0x07;
+pub const SYS_GET_CMDLINE: u64 = 0x15;
+pub const SYS_EXIT: u64 = 0x18;
+
+/// Register holding the operation number and, afterwards, the result
+const OP_REGISTER: usize = 1;
+/// Register holding the parameter
+const PARAM_REGISTER: usize = 2;
+/// Result reported for failed or refused operations
+const FAILED: u64 = u64::MAX;
+/// Upper bound on strings read by `SYS_WRITE0`
+const MAX_STRING: u64 = 4096;
+/// Most bytes one `SYS_READ` transfers, and the chunk size for `SYS_WRITE`
+const MAX_TRANSFER: u64 = 64 * 1024;
+/// Longest path `SYS_OPEN` accepts
+const MAX_PATH: u64 = 4096;
+/// First descriptor handed out by `SYS_OPEN`; 0–2 are the console
+const FIRST_FD: u64 = 3;
+
+/// Which semihosting operations a guest may use
+pub struct SemihostingConfig {
+    /// Allow console I/O: `SYS_WRITEC`, `SYS_WRITE0`, `SYS_READC` and
+    /// descriptors 0–2
+    pub console: bool,
+    /// Directory that file operations are confined to; `None` disables them
+    pub file_root: Option<PathBuf>,
+    /// Returned by `SYS_GET_CMDLINE`; `None` disables it
+    pub command_line: Option<String>,
+    /// Allow `SYS_EXIT` to halt the VM
+    pub exit: bool,
+}
+
+impl Default for SemihostingConfig {
+    fn default() -> Self {
+        SemihostingConfig {
+            console: true,
+            file_root: None,
+            command_line: None,
+            exit: true,
+        }
+    }
+}
+
+pub(crate) struct Semihost {
+    config: SemihostingConfig,
+    files: HashMap<u64, File>,
+    next_fd: u64,
+}
+
+/// Whether `instruction` is the semihosting `SYSCALL`
+pub(crate) fn is_trap(instruction: u32) -> bool {
+    Opcode::of(instruction) == Some(Opcode::Syscall) && instruction & 0x3FF_FFFF == SEMIHOSTING_TRAP
+}
+
+/// Resolve a guest path inside `root`, refusing anything that could escape it
+fn sandboxed(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    if path.components().all(|c| matches!(c, Component::Normal(_))) {
+        Some(root.join(path))
+    } else {
+        None
+    }
+}
+
+fn open_options(mode: u64) -> Option<OpenOptions> {
+    let mut options = OpenOptions::new();
+    let update = mode & 2 != 0;
+    match mode >> 2 {
+        0 => options.read(true).write(update),
+        1 => options.write(true).create(true).truncate(true).read(update),
+        2 => options.append(true).create(true).read(update),
+        _ => return None,
+    };
+    Some(options)
+}
+
+impl VM {
+    /// Let the guest request host services through `SYSCALL 0xAB`
+    ///
+    /// Replaces any previous configuration and closes files it opened. While
+    /// semihosting is enabled the VM is stepped from the host.
+    pub fn enable_semihosting(&mut self, config: SemihostingConfig) {
+        self.semihosting = Some(Semihost {
+            config,
+            files: HashMap::new(),
+            next_fd: FIRST_FD,
+        });
+    }
+
+    /// Turn semihosting off, closing any files the guest left open
+    pub fn disable_semihosting(&mut self) {
+        self.semihosting = None;
+    }
+
+    /// Service the semihosting call described by `state` and retire it
+    pub(crate) fn semihost_call(&mut self, state: &mut ffi::VmState) -> Result<()> {
+        let Some(mut host) = self.semihosting.take() else {
+            return Ok(());
+        };
+        let op = state.gprs[OP_REGISTER];
+        let param = state.gprs[PARAM_REGISTER];
+
+        if op == SYS_EXIT && host.config.exit {
+            state.gprs[EXIT_CODE_REGISTER as usize] = param;
+            state.flags |= Flags::HALTED;
+        } else {
+            state.gprs[OP_REGISTER] = self.semihost_op(&mut host, op, param).unwrap_or(FAILED);
+        }
+        self.semihosting = Some(host);
+
+        state.pc += 4;
+        state.perf_counters[PerfCounter::InstructionCount as usize] += 1;
+        state.perf_counters[PerfCounter::CycleCount as usize] += 1;
+        self.set_raw_state(state)
+    }
+
+    /// Perform one operation, or `None` if it failed or is not permitted
+    fn semihost_op(&mut self, host: &mut Semihost, op: u64, param: u64) -> Option<u64> {
+        match op {
+            SYS_WRITEC if host.config.console => {
+                let byte = self.read_memory(param, 1).ok()?;
+                self.console.write(&byte).ok()?;
+                Some(param)
+            }
+            SYS_WRITE0 if host.config.console => {
+                let text = self.read_c_string(param)?;
+                self.console.write(&text).ok()?;
+                Some(param)
+            }
+            SYS_READC if host.config.console => {
+                let mut byte = [0];
+                match self.console.read(&mut byte).ok()? {
+                    0 => None,
+                    _ => Some(byte[0] as u64),
+                }
+            }
+            SYS_OPEN => {
+                let [path, mode, len] = self.param_block(param)?;
+                let root = host.config.file_root.as_ref()?;
+                if len > MAX_PATH || !self.in_memory(path, len) {
+                    return None;
+                }
+                let path = String::from_utf8(self.read_memory(path, len).ok()?).ok()?;
+                let file = open_options(mode)?.open(sandboxed(root, &path)?).ok()?;
+                let fd = host.next_fd;
+                host.next_fd += 1;
+                host.files.insert(fd, file);
+                Some(fd)
+            }
+            SYS_CLOSE => {
+                let [fd] = self.param_block(param)?;
+                host.files.remove(&fd).map(|_| 0)
+            }
+            SYS_WRITE => {
+                let [fd, buffer, len] = self.param_block(param)?;
+                if !self.in_memory(buffer, len) {
+                    return None;
+                }
+                let mut written = 0;
+                while written < len {
+                    let data = self.read_memory(buffer + written, (len - written).min(MAX_TRANSFER)).ok()?;
+                    let count = match fd {
+                        1 | 2 if host.config.console => self.console.write(&data).ok()?,
+                        _ => host.files.get_mut(&fd)?.write(&data).ok()?,
+                    };
+                    written += count as u64;
+                    if count < data.len() {
+                        break;
+                    }
+                }
+                Some(len - written)
+            }
+            SYS_READ => {
+                let [fd, buffer, len] = self.param_block(param)?;
+                if !self.in_memory(buffer, len) {
+                    return None;
+                }
+                let mut data = vec![0; len.min(MAX_TRANSFER) as usize];
+                let read = match fd {
+                    0 if host.config.console => self.console.read(&mut data).ok()?,
+                    _ => host.files.get_mut(&fd)?.read(&mut data).ok()?,
+                };
+                self.write_memory(buffer, &data[..read]).ok()?;
+                Some(len - read as u64)
+            }
+            SYS_GET_CMDLINE => {
+                let [buffer, len] = self.param_block(param)?;
+                let mut text = host.config.command_line.clone()?.into_bytes();
+                text.push(0);
+                if text.len() as u64 > len {
+                    return None;
+                }
+                self.write_memory(buffer, &text).ok()?;
+                self.write_memory(param + 8, &(text.len() as u64 - 1).to_le_bytes()).ok()?;
+                Some(0)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `[address, address + len)` lies inside guest memory
+    fn in_memory(&self, address: u64, len: u64) -> bool {
+        address.checked_add(len).is_some_and(|end| end <= self.memory_size)
+    }
+
+    /// Read `N` little-endian words starting at `address`
+    fn param_block<const N: usize>(&self, address: u64) -> Option<[u64; N]> {
+        let bytes = self.read_memory(address, 8 * N as u64).ok()?;
+        let mut words = [0; N];
+        for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(8)) {
+            *word = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        Some(words)
+    }
+
+    /// Read a NUL-terminated string of at most `MAX_STRING` bytes
+    fn read_c_string(&self, address: u64) -> Option<Vec<u8>> {
+        match self.read_cstring_bytes(address, MAX_STRING as usize).ok()? {
+            (bytes, true) => Some(bytes),
+            (_, false) => None,
+        }
+    }
+}
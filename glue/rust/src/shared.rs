@@ -0,0 +1,137 @@
+//! A VM shared between threads
+//!
+//! `VM` is `Send` but not `Sync`: its methods take `&mut self` or read core
+//! state that another call may be halfway through updating, and some of its
+//! callbacks are only `Send`. `SharedVm` puts the VM behind a mutex so clones
+//! can be handed to several threads, for example one running the guest while
+//! others sample its registers.
+//!
+//! Every method locks the VM for the duration of the call, so each one sees
+//! the VM between instructions, never mid-step. A long `run` holds the lock
+//! until it returns; callers that want readers to make progress should run
+//! in bounded batches. Use `lock` or `with` to make several calls atomically.
+
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::{RegisterIndex, Result, Status, VmState, VM};
+
+/// Cloneable, thread-safe handle to a single VM
+#[derive(Clone)]
+pub struct SharedVm {
+    inner: Arc<Mutex<VM>>,
+}
+
+impl SharedVm {
+    pub fn new(vm: VM) -> Self {
+        SharedVm {
+            inner: Arc::new(Mutex::new(vm)),
+        }
+    }
+
+    /// Lock the VM until the guard is dropped
+    ///
+    /// A panic while another clone held the lock does not poison it for
+    /// the rest; the VM is left in whatever state the last completed call
+    /// put it in.
+    pub fn lock(&self) -> MutexGuard<'_, VM> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Run `f` with the VM locked
+    pub fn with<R>(&self, f: impl FnOnce(&mut VM) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    /// `VM::run` under the lock
+    pub fn run(&self, max_instructions: Option<u64>) -> Result<Status> {
+        self.lock().run(max_instructions)
+    }
+
+    /// `VM::step` under the lock
+    pub fn step(&self) -> Result<Status> {
+        self.lock().step()
+    }
+
+    /// `VM::get_state` under the lock
+    pub fn get_state(&self) -> Result<VmState> {
+        self.lock().get_state()
+    }
+
+    /// `VM::get_register` under the lock
+    pub fn get_register(&self, index: impl RegisterIndex) -> Result<u64> {
+        self.lock().get_register(index)
+    }
+
+    /// `VM::read_memory` under the lock
+    pub fn read_memory(&self, address: u64, size: u64) -> Result<Vec<u8>> {
+        self.lock().read_memory(address, size)
+    }
+
+    /// Take the VM back out if this is the last clone
+    pub fn into_inner(self) -> std::result::Result<VM, SharedVm> {
+        Arc::try_unwrap(self.inner)
+            .map(|mutex| mutex.into_inner().unwrap_or_else(PoisonError::into_inner))
+            .map_err(|inner| SharedVm { inner })
+    }
+}
+
+impl From<VM> for SharedVm {
+    fn from(vm: VM) -> Self {
+        SharedVm::new(vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init;
+    use std::thread;
+
+    #[test]
+    fn test_concurrent_readers_see_consistent_state() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        // loop: ADD R1, R1, R2; JMP R4, 0(R3)
+        let code: Vec<u8> = [1u32 << 21 | 1 << 16 | 2 << 11, 0x1D << 26 | 4 << 21 | 3 << 16]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect();
+        vm.load_program(&code, 0x10000).unwrap();
+        vm.set_register(2, 1).unwrap();
+        vm.set_register(3, 0x10000).unwrap();
+        let shared = SharedVm::from(vm);
+
+        let runner = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for _ in 0..200 {
+                    assert_eq!(shared.run(Some(1000)).unwrap(), Status::FuelExhausted);
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let mut last = 0;
+                    for _ in 0..500 {
+                        let state = shared.get_state().unwrap();
+                        assert!(state.pc == 0x10000 || state.pc == 0x10004);
+                        assert!(state.gprs[1] >= last);
+                        last = state.gprs[1];
+                        assert_eq!(shared.get_register(2).unwrap(), 1);
+                    }
+                })
+            })
+            .collect();
+
+        runner.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        let vm = shared.into_inner().ok().unwrap();
+        // Each 1000-instruction batch ends on a loop boundary, 500 ADDs in
+        assert_eq!(vm.get_register(1).unwrap(), 200 * 500);
+    }
+}
@@ -0,0 +1,540 @@
+//! Capturing and restoring execution points
+//!
+//! `VM::snapshot` copies all of guest memory, which is simple but costs
+//! `memory_size` bytes and a full copy every time. `VM::snapshot_cow`
+//! instead shares pages with the previous copy-on-write snapshot and only
+//! copies the `PAGE_SIZE` pages written since then, so frequent snapshots of
+//! a large VM cost time and memory in proportion to what the guest touched.
+//! The price is that the first one is a full copy, each snapshot also holds
+//! a pointer per page (16 bytes per 4 KiB), and `load_memory_image` or
+//! `restore` dirty every page, making the next one a full copy again.
+
+use std::sync::Arc;
+
+use crate::debug::reg_deltas;
+use crate::{check_status, ffi, Error, RegDelta, Result, Status, VmState, VM};
+
+/// Granularity at which `VM::snapshot_cow` tracks and shares memory
+pub const PAGE_SIZE: u64 = 4096;
+
+const MAGIC: &[u8; 4] = b"NCSN";
+const VERSION: u32 = 2;
+/// Magic, version and memory size
+const HEADER_SIZE: usize = 16;
+/// `pc`, `sp`, `flags`, 32 GPRs, 16 x 4 vector lanes, 8 counters, `cache_ctrl`, `vbase`
+const STATE_WORDS: usize = 3 + 32 + 64 + 8 + 2;
+/// Memory compared at a time by `Snapshot::diff` before looking at single bytes
+const DIFF_BLOCK: usize = 64;
+
+/// Register state, the entropy generator and a full copy of memory
+///
+/// Attached devices keep their own state and are not rewound.
+#[derive(Clone)]
+pub(crate) struct Checkpoint {
+    pub state: ffi::VmState,
+    pub entropy: u64,
+    pub memory: Vec<u8>,
+}
+
+/// An owned execution point taken by `VM::snapshot`
+///
+/// Holds a full copy of guest memory, so each snapshot costs `memory_size`
+/// bytes; `CowSnapshot` shares unchanged pages between snapshots instead.
+#[derive(Clone)]
+pub struct Snapshot(Checkpoint);
+
+impl std::fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("pc", &self.0.state.pc)
+            .field("memory_size", &self.0.memory.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A run of contiguous bytes that differ between two snapshots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemRegion {
+    pub address: u64,
+    pub len: u64,
+}
+
+/// What changed between two snapshots, from `Snapshot::diff`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotDiff {
+    /// Changed GPRs in register order, then the flags under `FLAGS_DELTA_INDEX`
+    pub registers: Vec<RegDelta>,
+    /// Changed memory, in address order
+    pub regions: Vec<MemRegion>,
+    /// Whether more regions differ than `regions` lists
+    pub truncated: bool,
+}
+
+impl Snapshot {
+    /// Registers, flags and counters at the time of the snapshot
+    pub fn state(&self) -> VmState {
+        self.0.state.into()
+    }
+
+    /// Guest memory at the time of the snapshot
+    pub fn memory(&self) -> &[u8] {
+        &self.0.memory
+    }
+
+    /// Compare with a later snapshot
+    ///
+    /// `old` values come from `self` and `new` values from `other`. Adjacent
+    /// changed bytes are merged into one region, and at most `max_regions`
+    /// regions are returned. If the memory sizes differ, the bytes past the
+    /// end of the smaller memory count as one changed region.
+    pub fn diff(&self, other: &Snapshot, max_regions: usize) -> SnapshotDiff {
+        let (old, new) = (&self.0.memory, &other.0.memory);
+        let common = old.len().min(new.len());
+        let mut regions: Vec<MemRegion> = Vec::new();
+        let mut truncated = false;
+        let mut add = |address: usize, len: usize| {
+            let (address, len) = (address as u64, len as u64);
+            if let Some(last) = regions.last_mut().filter(|last| last.address + last.len == address) {
+                last.len += len;
+            } else if regions.len() == max_regions {
+                truncated = true;
+            } else {
+                regions.push(MemRegion { address, len });
+            }
+        };
+
+        let blocks = old[..common].chunks(DIFF_BLOCK).zip(new[..common].chunks(DIFF_BLOCK));
+        for (block, (a, b)) in blocks.enumerate().filter(|(_, (a, b))| a != b) {
+            let base = block * DIFF_BLOCK;
+            for offset in (0..a.len()).filter(|&offset| a[offset] != b[offset]) {
+                add(base + offset, 1);
+            }
+        }
+        if old.len() != new.len() {
+            add(common, old.len().max(new.len()) - common);
+        }
+
+        SnapshotDiff {
+            registers: reg_deltas(&self.0.state, &other.0.state),
+            regions,
+            truncated,
+        }
+    }
+
+    /// Serialize for storage or transfer to another process
+    ///
+    /// The layout is a header (`NCSN` magic, `u32` version, `u64` memory
+    /// size), the register block as `u64` words in `VmState` field order,
+    /// the `u64` entropy generator state, then memory. All integers are
+    /// little-endian, so equal snapshots always produce equal bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let state = &self.0.state;
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + (STATE_WORDS + 1) * 8 + self.0.memory.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.0.memory.len() as u64).to_le_bytes());
+
+        let words = [state.pc, state.sp, state.flags]
+            .into_iter()
+            .chain(state.gprs)
+            .chain(state.vregs.into_iter().flatten())
+            .chain(state.perf_counters)
+            .chain([state.cache_ctrl, state.vbase, self.0.entropy]);
+        for word in words {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&self.0.memory);
+        bytes
+    }
+
+    /// Parse bytes produced by `to_bytes`
+    ///
+    /// Fails on a bad magic, an unknown version, a length that does not
+    /// match the memory size recorded in the header, or a PC, SP or vector
+    /// base past the end of that memory.
+    pub fn from_bytes(data: &[u8]) -> Result<Snapshot> {
+        let invalid = |message: String| Error {
+            status: Status::InvalidParameter,
+            message: format!("Invalid snapshot: {}", message),
+        };
+        if data.len() < HEADER_SIZE || &data[..4] != MAGIC {
+            return Err(invalid("bad magic".to_string()));
+        }
+        let version = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid(format!("unsupported version {}", version)));
+        }
+        let memory_size = u64::from_le_bytes(data[8..16].try_into().unwrap());
+        let expected = ((HEADER_SIZE + (STATE_WORDS + 1) * 8) as u64).checked_add(memory_size);
+        if expected != Some(data.len() as u64) {
+            return Err(invalid(match expected {
+                Some(expected) => {
+                    format!("{} bytes for a {} byte memory, expected {}", data.len(), memory_size, expected)
+                }
+                None => format!("memory size {} is too large", memory_size),
+            }));
+        }
+
+        let (registers, memory) = data[HEADER_SIZE..].split_at((STATE_WORDS + 1) * 8);
+        let mut words = registers
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()));
+        let mut next = || words.next().unwrap();
+
+        let mut state = ffi::VmState {
+            pc: next(),
+            sp: next(),
+            flags: next(),
+            ..Default::default()
+        };
+        state.gprs.fill_with(&mut next);
+        for lanes in &mut state.vregs {
+            lanes.fill_with(&mut next);
+        }
+        state.perf_counters.fill_with(&mut next);
+        state.cache_ctrl = next();
+        state.vbase = next();
+        let entropy = next();
+
+        for (name, address) in [("PC", state.pc), ("SP", state.sp), ("vector base", state.vbase)] {
+            if address > memory_size {
+                return Err(invalid(format!("{} {:#x} is past the end of memory", name, address)));
+            }
+        }
+
+        Ok(Snapshot(Checkpoint {
+            state,
+            entropy,
+            memory: memory.to_vec(),
+        }))
+    }
+}
+
+/// Words in a dirty page bitmap covering `memory_size` bytes
+pub(crate) fn dirty_words(memory_size: usize) -> usize {
+    (memory_size as u64).div_ceil(PAGE_SIZE).div_ceil(64) as usize
+}
+
+/// An execution point taken by `VM::snapshot_cow`
+///
+/// Memory is held as shared `PAGE_SIZE` pages, so cloning one is cheap and
+/// snapshots taken in a row share every page the guest did not write in
+/// between.
+#[derive(Clone)]
+pub struct CowSnapshot {
+    state: ffi::VmState,
+    entropy: u64,
+    pages: Vec<Arc<[u8]>>,
+}
+
+impl std::fmt::Debug for CowSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CowSnapshot")
+            .field("pc", &self.state.pc)
+            .field("pages", &self.pages.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl CowSnapshot {
+    /// Registers, flags and counters at the time of the snapshot
+    pub fn state(&self) -> VmState {
+        self.state.into()
+    }
+
+    /// Pages this snapshot holds that `other` does not share
+    ///
+    /// For consecutive snapshots this is how many pages the later one had
+    /// to copy.
+    pub fn unshared_pages(&self, other: &CowSnapshot) -> usize {
+        self.pages
+            .iter()
+            .zip(&other.pages)
+            .filter(|(a, b)| !Arc::ptr_eq(a, b))
+            .count()
+            + self.pages.len().saturating_sub(other.pages.len())
+    }
+
+    /// Copy out guest memory at the time of the snapshot
+    pub fn to_memory(&self) -> Vec<u8> {
+        self.pages.concat()
+    }
+}
+
+impl VM {
+    /// Capture the registers, entropy and guest memory, sharing unchanged pages
+    ///
+    /// Only pages written since the previous `snapshot_cow` or `restore_cow`
+    /// are copied; the rest are shared with that snapshot. See the module
+    /// docs for how this compares with `snapshot`. Attached devices keep
+    /// their own state and are not captured.
+    pub fn snapshot_cow(&mut self) -> Result<CowSnapshot> {
+        let dirty = self.take_dirty_pages()?;
+        let page_size = PAGE_SIZE as usize;
+        let pages = self.with_memory(|memory| {
+            let copy = |page: usize| -> Arc<[u8]> {
+                let start = page * page_size;
+                Arc::from(&memory[start..memory.len().min(start + page_size)])
+            };
+            match &self.cow_pages {
+                Some(base) => (0..base.len())
+                    .map(|page| match dirty[page / 64] & (1 << (page % 64)) {
+                        0 => base[page].clone(),
+                        _ => copy(page),
+                    })
+                    .collect(),
+                None => (0..memory.len().div_ceil(page_size)).map(copy).collect::<Vec<_>>(),
+            }
+        })?;
+        self.cow_pages = Some(pages.clone());
+        Ok(CowSnapshot {
+            state: self.raw_state()?,
+            entropy: self.entropy.state(),
+            pages,
+        })
+    }
+
+    /// Return to the execution point captured by `snapshot_cow`
+    ///
+    /// Only pages that differ from memory as it stands are written back.
+    /// Fails if the snapshot was taken from a VM with a different memory size.
+    pub fn restore_cow(&mut self, snapshot: &CowSnapshot) -> Result<()> {
+        let size: usize = snapshot.pages.iter().map(|page| page.len()).sum();
+        if size as u64 != self.memory_size {
+            return Err(Error {
+                status: Status::InvalidParameter,
+                message: format!("Snapshot holds {} bytes of memory, expected {}", size, self.memory_size),
+            });
+        }
+
+        let dirty = self.take_dirty_pages()?;
+        for (page, data) in snapshot.pages.iter().enumerate() {
+            let unchanged = dirty[page / 64] & (1 << (page % 64)) == 0
+                && self.cow_pages.as_ref().is_some_and(|base| Arc::ptr_eq(&base[page], data));
+            if !unchanged {
+                let address = page as u64 * PAGE_SIZE;
+                let result = unsafe {
+                    ffi::nanocore_vm_write_memory(self.handle, address, data.as_ptr(), data.len() as u64)
+                };
+                check_status(result, "restore memory")?;
+            }
+        }
+        // Memory now matches the snapshot, so what was just written is clean
+        self.take_dirty_pages()?;
+        self.cow_pages = Some(snapshot.pages.clone());
+        self.set_raw_state(&snapshot.state)?;
+        self.entropy.reseed(snapshot.entropy);
+        self.flush_memory()
+    }
+
+    /// Which pages were written since the last call, one bit per page
+    fn take_dirty_pages(&self) -> Result<Vec<u64>> {
+        let mut bitmap = vec![0; dirty_words(self.memory_size as usize)];
+        let result = unsafe {
+            ffi::nanocore_vm_take_dirty_pages(self.handle, bitmap.as_mut_ptr(), bitmap.len() as u64)
+        };
+        check_status(result, "take dirty pages")?;
+        Ok(bitmap)
+    }
+
+    /// Capture the registers, entropy and the whole of guest memory
+    ///
+    /// Attached devices keep their own state and are not captured.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        self.checkpoint().map(Snapshot)
+    }
+
+    /// Return to the execution point captured by `snapshot`
+    ///
+    /// Fails if the snapshot was taken from a VM with a different memory size.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<()> {
+        self.rewind(&snapshot.0)?;
+        self.flush_memory()
+    }
+
+    pub(crate) fn checkpoint(&self) -> Result<Checkpoint> {
+        Ok(Checkpoint {
+            state: self.raw_state()?,
+            entropy: self.entropy.state(),
+            memory: self.save_memory_image(),
+        })
+    }
+
+    pub(crate) fn rewind(&mut self, checkpoint: &Checkpoint) -> Result<()> {
+        self.load_memory_image(&checkpoint.memory)?;
+        self.set_raw_state(&checkpoint.state)?;
+        self.entropy.reseed(checkpoint.entropy);
+        Ok(())
+    }
+
+    /// Run exactly `count` instructions unless execution stops first
+    ///
+    /// Returns the number of instructions retired and whether execution
+    /// stopped early (halt, breakpoint or fault).
+    pub(crate) fn run_exact(&mut self, count: u64) -> Result<(u64, bool)> {
+        if count == 0 {
+            return Ok((0, false));
+        }
+        let retired = self.run_counted(Some(count))?.instructions_retired;
+        Ok((retired, retired < count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init, Flags, RunOutcome, FLAGS_DELTA_INDEX};
+
+    #[test]
+    fn test_bytes_round_trip() {
+        init().unwrap();
+        let mut vm = VM::new(64 * 1024).unwrap();
+        vm.set_register(3, 0x0123_4567_89AB_CDEF).unwrap();
+        vm.set_vector_register(15, [1, 2, 3, u64::MAX]).unwrap();
+        vm.write_memory(0x100, b"snapshot").unwrap();
+
+        let snapshot = vm.snapshot().unwrap();
+        let bytes = snapshot.to_bytes();
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(bytes, snapshot.to_bytes());
+
+        let parsed = Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.to_bytes(), bytes);
+        assert_eq!(parsed.memory(), snapshot.memory());
+        let (state, original) = (parsed.state(), snapshot.state());
+        assert_eq!((state.pc, state.sp, state.flags.0), (original.pc, original.sp, original.flags.0));
+        assert_eq!(state.gprs, original.gprs);
+        assert_eq!(state.vregs, original.vregs);
+        assert_eq!(state.perf_counters, original.perf_counters);
+        assert_eq!((state.cache_ctrl, state.vbase), (original.cache_ctrl, original.vbase));
+
+        vm.set_register(3, 0).unwrap();
+        vm.restore(&parsed).unwrap();
+        assert_eq!(vm.get_register(3).unwrap(), 0x0123_4567_89AB_CDEF);
+    }
+
+    #[test]
+    fn test_from_bytes_rejects_mismatches() {
+        init().unwrap();
+        let bytes = VM::new(4096).unwrap().snapshot().unwrap().to_bytes();
+        assert!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut wrong_size = bytes.clone();
+        wrong_size[8..16].copy_from_slice(&8192u64.to_le_bytes());
+        assert!(Snapshot::from_bytes(&wrong_size).is_err());
+
+        let mut wrong_version = bytes;
+        wrong_version[4] = 1;
+        assert_eq!(
+            Snapshot::from_bytes(&wrong_version).unwrap_err().message,
+            "Invalid snapshot: unsupported version 1"
+        );
+    }
+
+    #[test]
+    fn test_from_bytes_rejects_hostile_headers() {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(
+            Snapshot::from_bytes(&header).unwrap_err().message,
+            format!("Invalid snapshot: memory size {} is too large", u64::MAX)
+        );
+
+        init().unwrap();
+        let mut vm = VM::new(0x20000).unwrap();
+        let bytes = vm.snapshot().unwrap().to_bytes();
+        for (word, value) in [(0, u64::MAX - 1), (1, 0x20008), (STATE_WORDS - 1, u64::MAX)] {
+            let mut bad = bytes.clone();
+            let offset = HEADER_SIZE + word * 8;
+            bad[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+            assert_eq!(Snapshot::from_bytes(&bad).unwrap_err().status, Status::InvalidParameter);
+        }
+
+        // The core refuses to fetch from a wrapped PC, however it got there
+        let mut state = vm.raw_state().unwrap();
+        state.pc = u64::MAX - 1;
+        vm.set_raw_state(&state).unwrap();
+        assert_eq!(vm.run_outcome(Some(1)).unwrap(), RunOutcome::Exception);
+    }
+
+    #[test]
+    fn test_diff_coalesces_changed_bytes() {
+        init().unwrap();
+        let mut vm = VM::new(64 * 1024).unwrap();
+        let before = vm.snapshot().unwrap();
+
+        vm.write_memory(0x3E, b"abcd").unwrap();
+        vm.write_memory(0x100, b"x").unwrap();
+        vm.write_memory(0x200, b"yz").unwrap();
+        vm.set_register(4, 9).unwrap();
+        vm.set_flags(Flags(Flags::CARRY)).unwrap();
+        let after = vm.snapshot().unwrap();
+
+        let diff = before.diff(&after, 8);
+        assert_eq!(
+            diff.regions,
+            [
+                MemRegion { address: 0x3E, len: 4 },
+                MemRegion { address: 0x100, len: 1 },
+                MemRegion { address: 0x200, len: 2 },
+            ]
+        );
+        assert!(!diff.truncated);
+        assert_eq!(
+            diff.registers,
+            [
+                RegDelta { index: 4, old: 0, new: 9 },
+                RegDelta { index: FLAGS_DELTA_INDEX, old: 0, new: Flags::CARRY },
+            ]
+        );
+
+        let capped = before.diff(&after, 2);
+        assert_eq!(capped.regions.len(), 2);
+        assert!(capped.truncated);
+        assert_eq!(after.diff(&after, 8), SnapshotDiff { registers: vec![], regions: vec![], truncated: false });
+    }
+
+    #[test]
+    fn test_cow_snapshots_share_clean_pages() {
+        init().unwrap();
+        let mut vm = VM::new(16 * PAGE_SIZE).unwrap();
+        vm.write_memory(0x10, b"first").unwrap();
+        let first = vm.snapshot_cow().unwrap();
+        assert_eq!(&first.to_memory()[0x10..0x15], b"first");
+
+        // A guest store dirties its page just like a host write does
+        let program = [(0x13u32 << 26) | (2 << 21) | (1 << 16), 0x21 << 26];
+        let program: Vec<u8> = program.iter().flat_map(|word| word.to_be_bytes()).collect();
+        vm.load_program(&program, 0).unwrap();
+        vm.set_register(1, 5 * PAGE_SIZE).unwrap();
+        vm.set_register(2, 0x1122_3344_5566_7788).unwrap();
+        vm.run(Some(10)).unwrap();
+        let second = vm.snapshot_cow().unwrap();
+        assert_eq!(second.unshared_pages(&first), 2);
+        assert_eq!(second.unshared_pages(&vm.snapshot_cow().unwrap()), 0);
+
+        vm.write_memory(0x10, b"later").unwrap();
+        vm.write_memory(9 * PAGE_SIZE, b"later").unwrap();
+        vm.set_register(3, 7).unwrap();
+        vm.restore_cow(&first).unwrap();
+        assert_eq!(vm.read_memory(0x10, 5).unwrap(), b"first");
+        assert_eq!(vm.read_memory(5 * PAGE_SIZE, 8).unwrap(), [0; 8]);
+        assert_eq!(vm.read_memory(9 * PAGE_SIZE, 5).unwrap(), [0; 5]);
+        assert_eq!(vm.get_register(3).unwrap(), 0);
+        assert_eq!(vm.snapshot_cow().unwrap().unshared_pages(&first), 0);
+
+        vm.restore_cow(&second).unwrap();
+        assert_eq!(vm.read_memory(5 * PAGE_SIZE, 8).unwrap(), 0x1122_3344_5566_7788u64.to_le_bytes());
+        assert_eq!(vm.read_memory(0, 16 * PAGE_SIZE).unwrap(), second.to_memory());
+    }
+
+    #[test]
+    fn test_restore_cow_rejects_other_memory_sizes() {
+        init().unwrap();
+        let snapshot = VM::new(PAGE_SIZE + 100).unwrap().snapshot_cow().unwrap();
+        assert_eq!(snapshot.to_memory().len() as u64, PAGE_SIZE + 100);
+        assert!(VM::new(2 * PAGE_SIZE).unwrap().restore_cow(&snapshot).is_err());
+    }
+}
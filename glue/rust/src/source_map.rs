@@ -0,0 +1,123 @@
+//! Address-to-source line mapping and annotated disassembly
+//!
+//! A `SourceMap` records which source line each range of guest addresses
+//! was assembled from. `VM::disassemble_with_source` uses it to produce an
+//! `objdump -S` style listing with the source lines interleaved with the
+//! instructions they generated.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{disasm, Result, VM};
+
+#[derive(Debug, Clone)]
+struct Entry {
+    start: u64,
+    end: u64,
+    file: PathBuf,
+    line: u32,
+}
+
+/// Maps guest address ranges to `(file, line)` locations
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    /// Kept sorted by start address
+    entries: Vec<Entry>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that addresses `start..end` came from `line` (1-based) of `file`
+    ///
+    /// Where ranges overlap, the one starting closest to the address wins.
+    pub fn add(&mut self, start: u64, end: u64, file: impl Into<PathBuf>, line: u32) {
+        let index = self.entries.partition_point(|entry| entry.start <= start);
+        self.entries.insert(
+            index,
+            Entry {
+                start,
+                end,
+                file: file.into(),
+                line,
+            },
+        );
+    }
+
+    /// Source location of the instruction at `address`
+    pub fn lookup(&self, address: u64) -> Option<(&Path, u32)> {
+        let end = self.entries.partition_point(|entry| entry.start <= address);
+        self.entries[..end]
+            .iter()
+            .rev()
+            .find(|entry| address < entry.end)
+            .map(|entry| (entry.file.as_path(), entry.line))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl VM {
+    /// Disassemble `count` instructions from `address`, interleaving source
+    ///
+    /// Each time the source location changes, the corresponding line of the
+    /// source file is printed as a `;` comment ahead of the instructions it
+    /// produced. Files that cannot be read are shown as `; file:line` only.
+    pub fn disassemble_with_source(&self, address: u64, count: usize, source_map: &SourceMap) -> Result<String> {
+        let mut files: HashMap<&Path, Option<Vec<String>>> = HashMap::new();
+        let mut current = None;
+        let mut out = String::new();
+
+        for index in 0..count as u64 {
+            let pc = address + 4 * index;
+            let raw = self.fetch(pc)?;
+
+            let location = source_map.lookup(pc);
+            if let Some((file, line)) = location.filter(|_| location != current) {
+                let lines = files.entry(file).or_insert_with(|| {
+                    fs::read_to_string(file)
+                        .ok()
+                        .map(|text| text.lines().map(str::to_string).collect())
+                });
+                match lines.as_ref().and_then(|lines| lines.get(line.checked_sub(1)? as usize)) {
+                    Some(text) => {
+                        let _ = writeln!(out, "; {}", text.trim_end());
+                    }
+                    None => {
+                        let _ = writeln!(out, "; {}:{}", file.display(), line);
+                    }
+                }
+            }
+            current = location;
+
+            let _ = writeln!(out, "{:#08x}: {:08x}  {}", pc, raw, disasm::decode(pc, raw));
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let mut map = SourceMap::new();
+        map.add(0x1000, 0x1008, "main.s", 3);
+        map.add(0x1008, 0x100C, "main.s", 4);
+        map.add(0x1004, 0x1008, "macro.s", 10);
+
+        assert_eq!(map.lookup(0x1000), Some((Path::new("main.s"), 3)));
+        assert_eq!(map.lookup(0x1004), Some((Path::new("macro.s"), 10)));
+        assert_eq!(map.lookup(0x1008), Some((Path::new("main.s"), 4)));
+        assert_eq!(map.lookup(0x100C), None);
+        assert_eq!(map.lookup(0xFFC), None);
+    }
+}
@@ -0,0 +1,120 @@
+//! Asynchronous event delivery, behind the `async` feature
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+
+use crate::{Event, EventQueue, VM};
+
+/// Stream of the events a VM raises on the host side
+///
+/// Created by `VM::event_stream`. The stream does not borrow the VM, so it
+/// can be polled on one task while another runs the VM. It ends once the VM
+/// is dropped and every queued event has been yielded.
+pub struct EventStream {
+    queue: Arc<Mutex<EventQueue>>,
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        // Registering under the queue lock means a concurrent push cannot
+        // slip in between the check and the registration
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(event) = queue.events.pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        if !queue.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            queue.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl EventQueue {
+    /// Wake every task waiting for an event
+    pub(crate) fn wake(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl VM {
+    /// Receive events as a `futures_core::Stream`
+    ///
+    /// Yields guest breakpoints, watchpoint hits, exceptions and device
+    /// interrupts as they are queued; a waiting task is woken by the event
+    /// itself rather than by polling. The stream shares the queue read by
+    /// `poll_event`, so each event goes to whichever consumer takes it
+    /// first. `Halted` is reported by the core as state rather than queued,
+    /// so it only comes from `poll_event`; use `last_stop_reason` to learn
+    /// why a run ended.
+    pub fn event_stream(&self) -> EventStream {
+        EventStream {
+            queue: self.events.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::{Wake, Waker};
+    use std::thread::{self, Thread};
+
+    use super::*;
+    use crate::{init, EventType, GuestBreakpointMode, GUEST_BREAKPOINT_FLAG};
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Block the current thread until the stream yields
+    fn next(stream: &mut EventStream) -> Option<Event> {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match Pin::new(&mut *stream).poll_next(&mut cx) {
+                Poll::Ready(event) => return event,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_event_stream_follows_a_running_vm() {
+        init().unwrap();
+        let mut vm = VM::new(1024 * 1024).unwrap();
+        vm.set_guest_breakpoint_mode(GuestBreakpointMode::Continue).unwrap();
+        // BRK; BRK; HALT
+        let program = [0x37u32 << 26, 0x37 << 26, 0x21 << 26]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect::<Vec<u8>>();
+        vm.load_program(&program, 0x10000).unwrap();
+
+        let mut stream = vm.event_stream();
+        let runner = thread::spawn(move || {
+            vm.run(None).unwrap();
+        });
+
+        for address in [0x10000, 0x10004] {
+            let event = next(&mut stream).unwrap();
+            assert_eq!(event.event_type, EventType::Breakpoint);
+            assert_eq!(event.data, address | GUEST_BREAKPOINT_FLAG);
+        }
+        // Dropping the VM at the end of the thread ends the stream
+        assert!(next(&mut stream).is_none());
+        runner.join().unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+//! Test harness for guest programs
+//!
+//! `TestVm` wraps the usual init → create → load → run → assert sequence:
+//!
+//! ```
+//! use nanocore::testing::TestVm;
+//!
+//! let program = [
+//!     0x3C, 0x20, 0x00, 0x2A, // LD R1, 42
+//!     0x84, 0x00, 0x00, 0x00, // HALT
+//! ];
+//! TestVm::new().memory_mb(1).program(&program).entry(0x10000).expect_register(1, 42).run();
+//! ```
+//!
+//! On a mismatch it panics with the expected and actual values, the stop
+//! reason, the non-zero registers and a disassembly around the PC.
+
+use std::fmt::Write;
+
+use crate::{disasm, init, RunOutcome, VM};
+
+/// Instructions shown on each side of the PC in a failure report
+const CONTEXT_INSTRUCTIONS: u64 = 4;
+
+enum Expectation {
+    Register(u32, u64),
+    Memory(u64, Vec<u8>),
+    Outcome(RunOutcome),
+}
+
+/// Builder for a single-program test run
+pub struct TestVm {
+    memory_size: u64,
+    program: Vec<u8>,
+    entry: u64,
+    max_instructions: u64,
+    expectations: Vec<Expectation>,
+}
+
+impl Default for TestVm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestVm {
+    /// 1 MiB of memory, entry at `0x10000`, 100 000 instruction budget
+    pub fn new() -> Self {
+        TestVm {
+            memory_size: 1024 * 1024,
+            program: Vec::new(),
+            entry: 0x10000,
+            max_instructions: 100_000,
+            expectations: Vec::new(),
+        }
+    }
+
+    pub fn memory_mb(mut self, megabytes: u64) -> Self {
+        self.memory_size = megabytes * 1024 * 1024;
+        self
+    }
+
+    pub fn program(mut self, bytes: &[u8]) -> Self {
+        self.program = bytes.to_vec();
+        self
+    }
+
+    /// Load address of the program, which is also where execution starts
+    pub fn entry(mut self, address: u64) -> Self {
+        self.entry = address;
+        self
+    }
+
+    pub fn max_instructions(mut self, count: u64) -> Self {
+        self.max_instructions = count;
+        self
+    }
+
+    pub fn expect_register(mut self, index: u32, value: u64) -> Self {
+        self.expectations.push(Expectation::Register(index, value));
+        self
+    }
+
+    pub fn expect_memory(mut self, address: u64, bytes: &[u8]) -> Self {
+        self.expectations.push(Expectation::Memory(address, bytes.to_vec()));
+        self
+    }
+
+    pub fn expect_outcome(mut self, outcome: RunOutcome) -> Self {
+        self.expectations.push(Expectation::Outcome(outcome));
+        self
+    }
+
+    /// Run the program and check every expectation
+    ///
+    /// Returns the VM for further inspection. Panics on setup errors and on
+    /// the first failed expectation.
+    pub fn run(self) -> VM {
+        init().expect("initialize NanoCore");
+        let mut vm = VM::new(self.memory_size).expect("create VM");
+        vm.load_program(&self.program, self.entry).expect("load program");
+        let outcome = vm.run_outcome(Some(self.max_instructions)).expect("run VM");
+
+        for expectation in &self.expectations {
+            let mismatch = match expectation {
+                Expectation::Register(index, expected) => {
+                    let actual = vm.get_register(*index).expect("read register");
+                    (actual != *expected).then(|| {
+                        format!("R{}: expected {:#x}, got {:#x}", index, expected, actual)
+                    })
+                }
+                Expectation::Memory(address, expected) => {
+                    let actual = vm.read_memory(*address, expected.len() as u64).expect("read memory");
+                    (actual != *expected).then(|| {
+                        format!("memory at {:#x}: expected {:02x?}, got {:02x?}", address, expected, actual)
+                    })
+                }
+                Expectation::Outcome(expected) => (outcome != *expected)
+                    .then(|| format!("outcome: expected {:?}, got {:?}", expected, outcome)),
+            };
+            if let Some(mismatch) = mismatch {
+                panic!("{}\n{}", mismatch, report(&vm, outcome));
+            }
+        }
+
+        vm
+    }
+}
+
+/// Describe where the VM stopped: outcome, registers and nearby code
+fn report(vm: &VM, outcome: RunOutcome) -> String {
+    let mut out = String::new();
+    let Ok(state) = vm.get_state() else {
+        return "(VM state unavailable)".to_string();
+    };
+
+    let _ = writeln!(out, "stopped: {:?}", outcome);
+    let _ = writeln!(out, "pc={:#x} sp={:#x} flags={:#x}", state.pc, state.sp, state.flags.0);
+    for (index, value) in state.gprs.iter().enumerate().filter(|(_, &v)| v != 0) {
+        let _ = writeln!(out, "  R{:<2} = {:#x}", index, value);
+    }
+
+    let start = state.pc.saturating_sub(4 * CONTEXT_INSTRUCTIONS);
+    for address in (start..=state.pc + 4 * CONTEXT_INSTRUCTIONS).step_by(4) {
+        let Ok(raw) = vm.fetch(address) else {
+            break;
+        };
+        let marker = if address == state.pc { "=>" } else { "  " };
+        let _ = writeln!(out, "{} {:#08x}: {}", marker, address, disasm::decode(address, raw));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: [u8; 8] = [
+        0x3C, 0x20, 0x00, 0x2A, // LD R1, 42
+        0x84, 0x00, 0x00, 0x00, // HALT
+    ];
+
+    #[test]
+    fn test_passing_expectations() {
+        let vm = TestVm::new()
+            .program(&PROGRAM)
+            .expect_register(1, 42)
+            .expect_outcome(RunOutcome::Halted { exit_code: 42 })
+            .expect_memory(0x10000, &PROGRAM[..4])
+            .run();
+        assert_eq!(vm.get_register(1).unwrap(), 42);
+    }
+
+    #[test]
+    #[should_panic(expected = "R1: expected 0x2b, got 0x2a")]
+    fn test_register_mismatch_panics() {
+        TestVm::new().program(&PROGRAM).expect_register(1, 43).run();
+    }
+
+    #[test]
+    fn test_report_shows_disassembly() {
+        let vm = TestVm::new().program(&PROGRAM).run();
+        let text = report(&vm, RunOutcome::Halted { exit_code: 42 });
+        assert!(text.contains("R1  = 0x2a"));
+        assert!(text.contains("LD R1, 42"));
+        assert!(text.contains("=> 0x010008"));
+    }
+}
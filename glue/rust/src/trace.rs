@@ -0,0 +1,88 @@
+//! Instruction tracing hooks
+
+use std::collections::VecDeque;
+
+use crate::disasm::DisasmInsn;
+use crate::VM;
+
+/// Predicate over `(pc, raw instruction)` deciding what gets traced
+pub type TraceFilter = Box<dyn Fn(u64, u32) -> bool + Send>;
+
+/// Receiver for decoded instructions that passed the filter
+pub type TraceSink = Box<dyn FnMut(DisasmInsn) + Send>;
+
+pub(crate) struct FilteredTrace {
+    pub filter: TraceFilter,
+    pub sink: TraceSink,
+}
+
+/// One executed instruction, as recorded by `VM::enable_trace`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub pc: u64,
+    /// Primary opcode field, bits 31..26 of the instruction word
+    pub opcode: u8,
+}
+
+/// Ring buffer holding the most recent `capacity` trace entries
+pub(crate) struct TraceRing {
+    entries: VecDeque<TraceEntry>,
+    capacity: usize,
+}
+
+impl TraceRing {
+    pub(crate) fn record(&mut self, pc: u64, raw: u32) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(TraceEntry {
+            pc,
+            opcode: (raw >> 26) as u8,
+        });
+    }
+}
+
+impl VM {
+    /// Forward instructions matching `filter` to `sink` as they execute
+    ///
+    /// The filter sees the PC and raw instruction word before anything is
+    /// decoded, so only matching instructions pay for disassembly. While a
+    /// trace is installed the VM is stepped from the host.
+    pub fn set_filtered_trace(&mut self, filter: TraceFilter, sink: TraceSink) {
+        self.filtered_trace = Some(FilteredTrace { filter, sink });
+    }
+
+    /// Remove the filtered trace installed by `set_filtered_trace`
+    pub fn clear_filtered_trace(&mut self) {
+        self.filtered_trace = None;
+    }
+
+    /// Record the PC and opcode of every instruction executed from now on
+    ///
+    /// Keeps the most recent `capacity` entries, dropping the oldest first;
+    /// a capacity of 0 turns recording off. Recording needs to see every
+    /// instruction, so while it is on the VM is stepped from the host, one
+    /// core call per instruction, which is much slower than letting the
+    /// core run freely. Calling this again discards what was recorded.
+    pub fn enable_trace(&mut self, capacity: usize) {
+        self.trace_ring = (capacity > 0).then(|| TraceRing {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        });
+    }
+
+    /// Stop recording and discard the recorded entries
+    pub fn disable_trace(&mut self) {
+        self.trace_ring = None;
+    }
+
+    /// Take the recorded entries, oldest first
+    ///
+    /// Recording carries on into an emptied buffer.
+    pub fn take_trace(&mut self) -> Vec<TraceEntry> {
+        self.trace_ring
+            .as_mut()
+            .map(|ring| ring.entries.drain(..).collect())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,58 @@
+//! JavaScript bindings, behind the `wasm` feature
+//!
+//! Exposes a `Vm` class through `wasm-bindgen`:
+//!
+//! ```js
+//! const vm = new Vm(1024 * 1024);
+//! vm.loadProgram(program, 0x10000);
+//! vm.run(1000);
+//! console.log(vm.getRegister(1), vm.readMemory(0x10000, 16));
+//! ```
+//!
+//! Addresses, lengths and budgets are plain numbers; register values are
+//! `BigInt`s so all 64 bits survive. Build with
+//! `wasm-pack build --target web -- --features wasm,interp`: the C core is
+//! not built for wasm, so the `interp` feature is needed to supply the
+//! core and every `Vm` runs on the pure-Rust interpreter.
+
+use wasm_bindgen::prelude::*;
+
+use crate::{init, VM};
+
+/// A NanoCore VM; methods mirror those of the Rust `VM`
+#[wasm_bindgen(js_name = Vm)]
+pub struct WasmVm {
+    vm: VM,
+}
+
+#[wasm_bindgen(js_class = Vm)]
+impl WasmVm {
+    #[wasm_bindgen(constructor)]
+    pub fn new(memory_size: u32) -> Result<WasmVm, JsError> {
+        init()?;
+        Ok(WasmVm {
+            vm: VM::new(memory_size.into())?,
+        })
+    }
+
+    #[wasm_bindgen(js_name = loadProgram)]
+    pub fn load_program(&mut self, program: &[u8], address: u32) -> Result<(), JsError> {
+        Ok(self.vm.load_program(program, address.into())?)
+    }
+
+    /// Run until halt, breakpoint or `maxInstructions`; returns the status code
+    pub fn run(&mut self, max_instructions: Option<u32>) -> Result<i32, JsError> {
+        Ok(self.vm.run(max_instructions.map(u64::from))? as i32)
+    }
+
+    #[wasm_bindgen(js_name = getRegister)]
+    pub fn get_register(&self, index: u32) -> Result<u64, JsError> {
+        Ok(self.vm.get_register(index)?)
+    }
+
+    /// Copy of `len` bytes of guest memory as a `Uint8Array`
+    #[wasm_bindgen(js_name = readMemory)]
+    pub fn read_memory(&self, address: u32, len: u32) -> Result<Vec<u8>, JsError> {
+        Ok(self.vm.read_memory(address.into(), len.into())?)
+    }
+}